@@ -1,3 +1,49 @@
+use serde::{Deserialize, Serialize};
+
+/// Galvo steps per second for each speed profile, from the Pico firmware's
+/// SPEED_0 to SPEED_6. Profile 7 isn't defined there, so it runs as fast as
+/// profile 6.
+pub const SPEED_PROFILES: [u32; 8] = [500, 1000, 2000, 2500, 5000, 10000, 15000, 15000];
+
+/// A show as it's uploaded to the show-server, which plays the frames along
+/// with the controller
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SerializableShow {
+    pub name: String,
+    pub frames: Vec<SerializableFrame>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SerializableFrame {
+    /// Milliseconds from the start of the show
+    pub timestamp: u64,
+    /// Light levels by ID from 1, `None` leaves the light as it is
+    pub lights: Vec<Option<u8>>,
+    /// Lasers by ID from 1, `None` leaves the laser as it is
+    pub lasers: Vec<Option<SerializableLaser>>,
+    /// Named DMX scene to recall before the channels are set
+    pub dmx_scene: Option<String>,
+    /// DMX channels and their values, in the order they're applied
+    pub dmx: Vec<(u16, u8)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SerializableLaser {
+    pub home: bool,
+    pub enable: bool,
+    pub oneshot: bool,
+    pub speed_profile: u8,
+    pub draw_instructions: Vec<SerializablePoint>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SerializablePoint {
+    pub pattern_id: u8,
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
 pub fn add(left: usize, right: usize) -> usize {
     left + right
 }
@@ -1,8 +1,11 @@
 use std::{
     borrow::Cow,
     io::Cursor,
-    path::Path,
-    sync::{Arc, Mutex},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use anyhow::Error;
@@ -16,17 +19,34 @@ use kira::{
 use log::{error, info};
 use rust_embed::RustEmbed;
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 
-use crate::AudioMessage;
+use crate::{
+    metrics::Metrics, show::prelude::ShowControl, shutdown::recv_until, AudioMessage,
+    InternalMessage, MessageKind,
+};
 
 pub struct Audio {
     manager: Option<AudioManager<CpalBackend>>,
+    pub metrics: Metrics,
 }
 
 #[derive(Clone, Debug)]
 pub struct LoadingSong {
     pub name: String,
     pub stream: Arc<Mutex<Option<StaticSoundData>>>,
+    /// Why the song couldn't be decoded, if it couldn't
+    pub failed: Arc<Mutex<Option<String>>>,
+    /// Set when the song isn't wanted anymore, so it isn't kept once loaded
+    pub cancelled: Arc<AtomicBool>,
+}
+
+impl LoadingSong {
+    /// Stop loading the song. One that's part way through decoding finishes,
+    /// but it's dropped instead of kept.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -50,31 +70,59 @@ impl Audio {
             match AudioManager::<CpalBackend>::new(AudioManagerSettings::default()) {
                 Ok(manager) => Ok(Self {
                     manager: Some(manager),
+                    metrics: Metrics::default(),
                 }),
                 Err(e) => {
                     error!("Error initializing audio: {}", e);
-                    Ok(Self { manager: None })
+                    Ok(Self {
+                        manager: None,
+                        metrics: Metrics::default(),
+                    })
                 }
             }
         } else {
-            Ok(Audio { manager: None })
+            Ok(Audio {
+                manager: None,
+                metrics: Metrics::default(),
+            })
         }
     }
 
-    pub async fn start(mut self, mut receiver: mpsc::Receiver<AudioMessage>) {
-        while let Some(message) = receiver.recv().await {
+    /// Play and control the audio. A song that can't be played fails the
+    /// show it belongs to, through `message_queue`.
+    pub async fn start(
+        mut self,
+        mut receiver: mpsc::Receiver<AudioMessage>,
+        message_queue: mpsc::Sender<MessageKind>,
+        cancel: CancellationToken,
+    ) {
+        while let Some(message) = recv_until(&mut receiver, &cancel).await {
             match message {
                 AudioMessage::Play(sound) => {
                     info!("Playing sound: {}", sound.name);
                     // Create a new audio manager instance for each play
-                    if let Ok(mut manager) = AudioManager::<CpalBackend>::new(AudioManagerSettings::default()) {
-                        if let Err(e) = manager.play(sound.stream) {
+                    let failure = if let Ok(mut manager) = AudioManager::<CpalBackend>::new(AudioManagerSettings::default()) {
+                        let failure = manager.play(sound.stream).err().map(|e| {
                             error!("Failed to play audio: {}", e);
-                        }
+                            format!("couldn't play {}: {}", sound.name, e)
+                        });
                         // Store the new manager
                         self.manager = Some(manager);
+                        failure
                     } else {
                         error!("Failed to create new audio manager");
+                        Some(format!("couldn't start the audio for {}", sound.name))
+                    };
+
+                    // The main queue can be waiting on this task, so never
+                    // hold up on it
+                    if let Some(reason) = failure {
+                        self.metrics.audio_failures.inc();
+                        if let Err(e) = message_queue.try_send(MessageKind::InternalMessage(
+                            InternalMessage::ShowControl(ShowControl::Fail(reason)),
+                        )) {
+                            error!("Couldn't stop the show: {}", e);
+                        }
                     }
                 }
                 AudioMessage::Stop => {
@@ -83,11 +131,46 @@ impl Audio {
                         manager.pause(Tween::default()).unwrap();
                     }
                 }
+                AudioMessage::Pause => {
+                    info!("Pausing audio playback");
+                    if let Some(manager) = self.manager.as_mut() {
+                        manager.pause(Tween::default()).unwrap();
+                    }
+                }
+                AudioMessage::Resume => {
+                    info!("Resuming audio playback");
+                    if let Some(manager) = self.manager.as_mut() {
+                        manager.resume(Tween::default()).unwrap();
+                    }
+                }
+                AudioMessage::FadeOut(duration) => {
+                    info!("Fading out audio playback");
+                    if let Some(manager) = self.manager.as_mut() {
+                        let tween = Tween {
+                            duration,
+                            ..Default::default()
+                        };
+                        manager.pause(tween).unwrap();
+                    }
+                }
             }
         }
     }
 
-    pub fn get_sound(name: &str) -> Result<LoadingSong, Box<dyn std::error::Error>> {
+    /// Where a show's song is kept on disk
+    pub fn song_path(shows_dir: &Path, name: &str) -> PathBuf {
+        shows_dir.join(name).join(format!("{}.mp3", name))
+    }
+
+    /// Whether a song is built into the binary
+    pub fn is_embedded(name: &str) -> bool {
+        AudioAsset::get(name).is_some()
+    }
+
+    pub fn get_sound(
+        name: &str,
+        shows_dir: &Path,
+    ) -> Result<LoadingSong, Box<dyn std::error::Error>> {
         #[allow(unused_variables)]
         let sound_path = format!("src/audio/assets/{}", name);
 
@@ -104,29 +187,52 @@ impl Audio {
             return Ok(LoadingSong {
                 name: name.to_string(),
                 stream: Arc::new(Mutex::new(Some(sound_player))),
+                failed: Arc::new(Mutex::new(None)),
+                cancelled: Arc::new(AtomicBool::new(false)),
             });
         }
 
         // Try to load it from the filesystem at
-        // shows/<song_name>/<song_name>.mp3
-        let sound_path_local = format!("shows/{}/{}.mp3", name, name);
+        // <shows_dir>/<song_name>/<song_name>.mp3
+        let sound_path_local = Audio::song_path(shows_dir, name);
 
-        if Path::new(&sound_path_local).exists() {
+        if sound_path_local.exists() {
             let song_stream = Arc::new(Mutex::new(None));
+            let failed = Arc::new(Mutex::new(None));
+            let cancelled = Arc::new(AtomicBool::new(false));
 
             let song_future = LoadingSong {
                 name: name.to_string(),
                 stream: song_stream.clone(),
+                failed: failed.clone(),
+                cancelled: cancelled.clone(),
             };
 
             // Start loading it in a new thread
             tokio::spawn(async move {
-                // Load the song
-                let sound_player = StaticSoundData::from_file(
-                    Path::new(&sound_path_local),
+                if cancelled.load(Ordering::Relaxed) {
+                    info!("Cancelled loading song");
+                    return;
+                }
+
+                // Load the song. One that can't be decoded fails its show
+                // rather than leaving it waiting.
+                let sound_player = match StaticSoundData::from_file(
+                    &sound_path_local,
                     StaticSoundSettings::default(),
-                )
-                .unwrap();
+                ) {
+                    Ok(sound_player) => sound_player,
+                    Err(e) => {
+                        error!("Couldn't load {}: {}", sound_path_local.display(), e);
+                        *failed.lock().unwrap() = Some(e.to_string());
+                        return;
+                    }
+                };
+
+                if cancelled.load(Ordering::Relaxed) {
+                    info!("Cancelled loading song, dropping it");
+                    return;
+                }
 
                 // Save the song to the stream
                 *song_stream.lock().unwrap() = Some(sound_player);
@@ -138,7 +244,7 @@ impl Audio {
             // it's loaded on the other thread
             return Ok(song_future);
         } else {
-            error!("Sound not found: {}", sound_path_local);
+            error!("Sound not found: {}", sound_path_local.display());
         }
 
         // Return "Sound not found" error
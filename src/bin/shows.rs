@@ -1,4 +1,7 @@
-use rusty_halloween::show::prelude::{ShowManager, UnloadedShow};
+use rusty_halloween::{
+    config::Config,
+    show::prelude::{ShowManager, UnloadedShow},
+};
 
 const BPM: f32 = 166.0;
 
@@ -12,10 +15,13 @@ fn main() {
     let show = UnloadedShow {
         name: "song3.mp3".to_string(),
         frames,
+        looping: false,
+        next: None,
+        tags: Vec::new(),
     };
 
     // Write the show to a json file
-    let data = ShowManager::save_show(show);
+    let data = ShowManager::save_show(show, &Config::default());
 
     // Save the show to a file
     std::fs::write("src/show/assets/halloween.json", data).unwrap();
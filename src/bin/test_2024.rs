@@ -43,7 +43,8 @@ fn main() {
 
     // Try to load the show using UnloadedShow
     println!("\nAttempting to load show...");
-    let show = UnloadedShow::load_show_file(Path::new("src/show/assets/2024/song.json"), &config);
+    let show =
+        UnloadedShow::load_show_file(Path::new("src/show/assets/2024/song.json"), &config).unwrap();
 
     // Debug show
     dbg!(&show);
@@ -63,7 +64,7 @@ fn main() {
                 .lights
                 .iter()
                 .enumerate()
-                .filter(|(_, &light)| light == Some(true))
+                .filter(|(_, &light)| light.is_some_and(|level| level > 0))
                 .map(|(i, _)| format!("light-{}", i + 1))
                 .collect::<Vec<_>>()
                 .join(", ")
@@ -0,0 +1,671 @@
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Error;
+use clap::{Args, Parser, Subcommand};
+use log::info;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::{Config, RunMode, CURRENT_CONFIG_VERSION},
+    dmx::{recorder::read_recorded_frames, recording::DmxRecording},
+    laser::{LaserController, LaserMessage, MessageSendPack, ALL_LASERS},
+    lights::LightController,
+    show::{
+        prelude::{
+            instruction_files, show_dirs, validate_show, ShowManager, ShowStats, UnloadedShow,
+        },
+        MAX_LASERS,
+    },
+    structure::{FileStructure, Prompt},
+    uart::UartController,
+};
+
+/// The hardware config when `--config` isn't given
+pub const DEFAULT_CONFIG: &str = "src/show/assets/2024/hardware.json";
+
+/// Written out by `init-config`
+const EXAMPLE_CONFIG: &str = include_str!("show/assets/2024/hardware.json");
+
+/// Running without a subcommand starts the controller, like `run`
+#[derive(Debug, Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub run: RunArgs,
+    /// Make the config and the folders it points at, asking before each
+    /// step, instead of running
+    #[arg(long)]
+    pub setup: bool,
+}
+
+impl Cli {
+    /// The config the subcommand reads, if it reads one
+    pub fn config(&self) -> Option<&ConfigArgs> {
+        match &self.command {
+            // There mightn't be a config yet
+            None if self.setup => None,
+            None | Some(Command::Replay { .. }) => Some(&self.run.config),
+            Some(Command::Run(run) | Command::Play { run, .. }) => Some(&run.config),
+            Some(
+                Command::LightTest { config }
+                | Command::LaserTest { config, .. }
+                | Command::DmxRecord { config, .. }
+                | Command::ConvertShow { config, .. }
+                | Command::Stats { config }
+                | Command::Validate { config, .. },
+            ) => Some(config),
+            Some(
+                Command::DmxFrames { .. }
+                | Command::DmxDiff { .. }
+                | Command::InitConfig { .. }
+                | Command::Version,
+            ) => None,
+        }
+    }
+}
+
+/// Which hardware config to use
+#[derive(Debug, Clone, Args)]
+pub struct ConfigArgs {
+    #[arg(long, default_value = DEFAULT_CONFIG)]
+    pub config: PathBuf,
+    /// Apply a profile from the config's `profiles` section over the rest of
+    /// it
+    #[arg(long)]
+    pub profile: Option<String>,
+}
+
+impl ConfigArgs {
+    pub fn load(&self) -> Result<Config, Error> {
+        Config::load_profile(&self.config.to_string_lossy(), self.profile.as_deref())
+    }
+}
+
+/// How to run the controller
+#[derive(Debug, Clone, Args)]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    /// Simulate all of the hardware, even in a build for the Pi. This wins
+    /// over the config's `mode`.
+    #[arg(long)]
+    pub simulate: bool,
+    /// Render laser frames to SVGs under target/laser-preview instead of
+    /// sending them
+    #[arg(long)]
+    pub simulate_lasers: bool,
+    /// Draw the lights in the log whenever one changes
+    #[arg(long)]
+    pub simulate_lights: bool,
+    /// Play shows this many times faster, without audio, to rehearse them
+    #[arg(long)]
+    pub time_scale: Option<f32>,
+}
+
+impl RunArgs {
+    /// The config, with the changes asked for on the command line
+    pub fn load_config(&self) -> Result<Config, Error> {
+        let mut config = self.config.load()?;
+        if self.simulate {
+            config.mode = RunMode::Simulate;
+        }
+        config.laser.simulate |= self.simulate_lasers;
+        config.light.simulate |= self.simulate_lights;
+        if let Some(time_scale) = self.time_scale {
+            config.time_scale = time_scale;
+            config.validate()?;
+        }
+
+        Ok(config)
+    }
+}
+
+/// Draw the boundary on a laser and leave it up for
+/// `laser.boundary_check_secs`
+pub async fn boundary_check(config: &Config, laser_id: u8) -> Result<(), Error> {
+    let (uart_tx, uart_rx) = mpsc::channel(10);
+    let uart_controller = UartController::init(&config.uart, config.mode).await;
+    tokio::spawn(uart_controller.start(uart_rx, CancellationToken::new()));
+
+    let (laser_tx, laser_rx) = mpsc::channel(10);
+    let mut laser_controller = LaserController::init(config);
+    tokio::spawn(async move {
+        laser_controller
+            .start(laser_rx, uart_tx, CancellationToken::new())
+            .await;
+    });
+
+    match laser_id {
+        ALL_LASERS => info!("Drawing the boundary on all lasers"),
+        id => info!("Drawing the boundary on laser {}", id),
+    }
+    laser_tx
+        .send(LaserMessage::Frame(
+            MessageSendPack::boundary_message(laser_id).into(),
+        ))
+        .await
+        .map_err(|_| Error::msg("the laser task has stopped"))?;
+
+    tokio::time::sleep(Duration::from_secs(config.laser.boundary_check_secs)).await;
+
+    Ok(())
+}
+
+/// Step a laser, or all of them, through the test sequence, holding each
+/// step for `laser.test_step_secs`
+pub async fn laser_test(config: &Config, laser_id: u8) -> Result<(), Error> {
+    let (uart_tx, uart_rx) = mpsc::channel(10);
+    let uart_controller = UartController::init(&config.uart, config.mode).await;
+    tokio::spawn(uart_controller.start(uart_rx, CancellationToken::new()));
+
+    let (laser_tx, laser_rx) = mpsc::channel(10);
+    let mut laser_controller = LaserController::init(config);
+    tokio::spawn(async move {
+        laser_controller
+            .start(laser_rx, uart_tx, CancellationToken::new())
+            .await;
+    });
+
+    let step = Duration::from_secs(config.laser.test_step_secs);
+    send_laser_test(&laser_tx, laser_id, step).await
+}
+
+async fn send_laser_test(
+    laser_tx: &mpsc::Sender<LaserMessage>,
+    laser_id: u8,
+    step: Duration,
+) -> Result<(), Error> {
+    let laser_ids = match laser_id {
+        ALL_LASERS => (1..=MAX_LASERS as u8).collect(),
+        id => vec![id],
+    };
+
+    for laser_id in laser_ids {
+        info!("Testing laser {}", laser_id);
+        for frame in MessageSendPack::test_sequence(laser_id) {
+            laser_tx
+                .send(LaserMessage::Frame(frame.into()))
+                .await
+                .map_err(|_| Error::msg("the laser task has stopped"))?;
+            tokio::time::sleep(step).await;
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the light test and report how it went, failing if any light
+/// couldn't be turned on
+pub async fn light_test(config: &Config) -> Result<(), Error> {
+    // Nothing reads what the lights send back
+    let (message_tx, _message_rx) = mpsc::channel(10);
+    let mut lights = LightController::init(config, message_tx).await?;
+
+    let report = lights.run_test().await;
+    println!("{}", report);
+
+    if !report.failed.is_empty() {
+        return Err(Error::msg("some lights failed the test"));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Start the controller and play shows
+    Run(RunArgs),
+    /// Start the controller, play one show, then stop
+    Play {
+        /// The show's name, or its folder to pick any of its instruction
+        /// files
+        show: String,
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Turn each light on in turn, then all of them, then exit, failing if
+    /// any light couldn't be turned on
+    LightTest {
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Step the lasers through the test sequence, then exit
+    LaserTest {
+        /// Only test this laser
+        #[arg(default_value_t = ALL_LASERS)]
+        laser_id: u8,
+        /// Draw the boundary instead, and leave it up for
+        /// `laser.boundary_check_secs`
+        #[arg(long)]
+        boundary: bool,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Write out the example hardware config to start a new one from
+    InitConfig {
+        #[arg(default_value = "hardware.json")]
+        path: PathBuf,
+        /// Replace a file that's already there
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the version, and the config version it reads
+    Version,
+    /// Print the last frames from a DMX recorder trace
+    DmxFrames {
+        /// The JSONL file the recorder writes to
+        path: PathBuf,
+        /// How many frames to print
+        #[arg(short = 'n', long, default_value_t = 10)]
+        count: usize,
+    },
+    /// Record every DMX frame a show sends, without waiting on the show's
+    /// timing
+    DmxRecord {
+        /// The show's instructions.json
+        show: PathBuf,
+        /// Where to write the recording
+        output: PathBuf,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Rewrite a show file in the latest format
+    ConvertShow {
+        /// The show's instructions.json, in any format
+        input: PathBuf,
+        /// Where to write the converted show
+        output: PathBuf,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Compare two DMX recordings and report the channels that differ
+    DmxDiff { a: PathBuf, b: PathBuf },
+    /// Start the controller and send it the messages from a recording, with
+    /// their original timing, instead of playing shows
+    Replay {
+        /// A JSONL file from the recording directory
+        path: PathBuf,
+    },
+    /// Print how many times each show has played, and how often it was
+    /// skipped, stopped or failed to play
+    Stats {
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Check show files for mistakes without playing them, failing if any
+    /// show has one
+    Validate {
+        /// An instruction file, or a show's folder to check each of its
+        /// instructions-exported files
+        #[arg(required_unless_present = "all")]
+        path: Option<PathBuf>,
+        /// Check every show in the config's shows_dir
+        #[arg(long, conflicts_with = "path")]
+        all: bool,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+}
+
+impl Command {
+    pub fn run(self) -> Result<(), Error> {
+        match self {
+            Command::DmxFrames { path, count } => {
+                for frame in read_recorded_frames(&path, count)? {
+                    // Only the channels that are on, otherwise a frame is 512
+                    // numbers
+                    let channels = frame
+                        .channels
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, value)| **value != 0)
+                        .map(|(i, value)| format!("{}={}", i + 1, value))
+                        .collect::<Vec<_>>();
+
+                    println!(
+                        "{} {}",
+                        frame.time.format("%Y-%m-%dT%H:%M:%S%.3f"),
+                        channels.join(" ")
+                    );
+                }
+            }
+            Command::DmxRecord {
+                show,
+                output,
+                config,
+            } => {
+                let config = config.load()?;
+                let show = UnloadedShow::load_show_file(&show, &config)?;
+
+                let recording = DmxRecording::record(&show, &config);
+                recording.write(&mut File::create(&output)?)?;
+
+                println!(
+                    "Recorded {} frames to {}",
+                    recording.frames.len(),
+                    output.display()
+                );
+            }
+            Command::ConvertShow {
+                input,
+                output,
+                config,
+            } => {
+                let config = config.load()?;
+                let show = UnloadedShow::load_show_file(&input, &config)?;
+                let frames = show.frames.len();
+
+                std::fs::write(&output, ShowManager::save_show(show, &config))?;
+
+                println!("Converted {} frames to {}", frames, output.display());
+            }
+            Command::DmxDiff { a, b } => {
+                let a = DmxRecording::read(&mut File::open(a)?)?;
+                let b = DmxRecording::read(&mut File::open(b)?)?;
+                let diff = a.diff(&b);
+
+                if diff.frame_counts.0 != diff.frame_counts.1 {
+                    println!(
+                        "Frame counts differ: {} vs {}",
+                        diff.frame_counts.0, diff.frame_counts.1
+                    );
+                }
+                if diff.timestamp_mismatches > 0 {
+                    println!(
+                        "{} frames were sent at different times",
+                        diff.timestamp_mismatches
+                    );
+                }
+                for channel in &diff.channels {
+                    println!(
+                        "Channel {}: {} frames differ, first at {}ms, by up to {}",
+                        channel.channel, channel.frames, channel.first_timestamp, channel.max_delta
+                    );
+                }
+
+                // Fail so CI notices
+                if !diff.is_same() {
+                    return Err(Error::msg("DMX recordings differ"));
+                }
+                println!("DMX recordings match");
+            }
+            Command::Run(_)
+            | Command::Play { .. }
+            | Command::Replay { .. }
+            | Command::LightTest { .. }
+            | Command::LaserTest { .. } => {
+                unreachable!("these need the hardware, main runs them")
+            }
+            Command::InitConfig { path, force } => {
+                init_config(&path, force)?;
+                println!("Wrote {}, edit it to match the hardware", path.display());
+            }
+            Command::Version => {
+                println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+                println!("config version {}", CURRENT_CONFIG_VERSION);
+            }
+            Command::Stats { config } => {
+                let config = config.load()?;
+                let stats = ShowStats::load(&config.stats);
+
+                match stats.plays().is_empty() {
+                    true => println!("No shows have played yet"),
+                    false => print!("{}", stats.table()),
+                }
+            }
+            Command::Validate { path, all, config } => {
+                let config = config.load()?;
+
+                let files = match path {
+                    Some(path) if path.is_dir() => instruction_files(&path)?,
+                    Some(path) => vec![path],
+                    None if all => {
+                        let mut files = Vec::new();
+                        for show_dir in show_dirs(&config.shows_dir)? {
+                            files.extend(instruction_files(&show_dir)?);
+                        }
+                        files
+                    }
+                    None => unreachable!("clap asks for a path without --all"),
+                };
+                if files.is_empty() {
+                    return Err(Error::msg("there are no instruction files to check"));
+                }
+
+                let mut failed = 0;
+                for file in &files {
+                    let (_, issues) = validate_show(file, &config);
+                    if issues.is_empty() {
+                        println!("ok {}", file.display());
+                        continue;
+                    }
+
+                    failed += 1;
+                    for issue in issues {
+                        println!("{}:{}", file.display(), issue);
+                    }
+                }
+
+                if failed > 0 {
+                    return Err(Error::msg(format!(
+                        "{} of {} show files have problems",
+                        failed,
+                        files.len()
+                    )));
+                }
+                println!("{} show files are ok", files.len());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Walk through making the config, if there isn't one, and the folders it
+/// points at, asking on stdin before changing anything
+pub fn setup(args: &ConfigArgs) -> Result<(), Error> {
+    let mut prompt = Prompt::new(std::io::stdin().lock(), std::io::stdout());
+
+    if !args.config.exists() {
+        let question = format!(
+            "There's no config at {}. Write the example one there?",
+            args.config.display()
+        );
+        if !prompt.ask(&question, true)? {
+            return Err(Error::msg("setup needs a config to know where things go"));
+        }
+        if let Some(parent) = args.config.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        init_config(&args.config, false)?;
+        prompt.say(&format!(
+            "Wrote {}, edit it to match the hardware",
+            args.config.display()
+        ))?;
+    }
+
+    let config = args.load()?;
+    let report = FileStructure::from_config(&config, &args.config).setup(&mut prompt)?;
+    if !report.is_ok() {
+        return Err(Error::msg(format!(
+            "{} folders still have problems",
+            report.problems().len()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Write the example config to `path`, leaving a file that's already there
+/// unless `force` is set
+fn init_config(path: &Path, force: bool) -> Result<(), Error> {
+    if path.exists() && !force {
+        return Err(Error::msg(format!(
+            "{} already exists, pass --force to replace it",
+            path.display()
+        )));
+    }
+
+    std::fs::write(path, EXAMPLE_CONFIG)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn parse(args: &[&str]) -> Cli {
+        Cli::try_parse_from([&["rusty-halloween"], args].concat()).unwrap()
+    }
+
+    #[test]
+    fn test_run() {
+        // Without a subcommand it runs like `run`
+        let cli = parse(&["--simulate-lasers"]);
+        assert!(cli.command.is_none());
+        assert!(cli.run.simulate_lasers);
+        assert_eq!(cli.run.config.config, PathBuf::from(DEFAULT_CONFIG));
+
+        let Some(Command::Run(run)) = parse(&[
+            "run",
+            "--config",
+            "hardware.json",
+            "--profile",
+            "bench",
+            "--simulate",
+        ])
+        .command
+        else {
+            panic!("expected run");
+        };
+        assert_eq!(run.config.config, PathBuf::from("hardware.json"));
+        assert_eq!(run.config.profile.as_deref(), Some("bench"));
+
+        let run = RunArgs {
+            config: ConfigArgs {
+                config: PathBuf::from(DEFAULT_CONFIG),
+                profile: None,
+            },
+            ..run
+        };
+        let config = run.load_config().unwrap();
+        assert_eq!(config.mode, RunMode::Simulate);
+        assert!(!config.laser.simulate && !config.light.simulate);
+
+        // Flags for running don't go with other subcommands
+        assert!(Cli::try_parse_from(["rusty-halloween", "--simulate", "version"]).is_err());
+    }
+
+    #[test]
+    fn test_play() {
+        let Some(Command::Play { show, run }) =
+            parse(&["play", "graveyard", "--time-scale", "4"]).command
+        else {
+            panic!("expected play");
+        };
+        assert_eq!(show, "graveyard");
+        assert_eq!(run.load_config().unwrap().time_scale, 4.0);
+
+        assert!(Cli::try_parse_from(["rusty-halloween", "play"]).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let command = parse(&["validate", "tests/fixtures/shows/dmx/instructions.json"]).command;
+        assert!(matches!(command, Some(Command::Validate { .. })));
+
+        // Shows without a song are fine
+        command.unwrap().run().unwrap();
+
+        let command = parse(&[
+            "validate",
+            "tests/fixtures/shows/validate-dmx/instructions.json",
+        ])
+        .command;
+        let error = command.unwrap().run().unwrap_err();
+        assert_eq!(error.to_string(), "1 of 1 show files have problems");
+    }
+
+    #[tokio::test]
+    async fn test_light_test() {
+        let Some(Command::LightTest { config }) = parse(&["light-test"]).command else {
+            panic!("expected light-test");
+        };
+        assert_eq!(config.profile, None);
+
+        // Nothing to turn on, so nothing fails
+        light_test(&Config::default()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_laser_test() {
+        let Some(Command::LaserTest {
+            laser_id, boundary, ..
+        }) = parse(&["laser-test"]).command
+        else {
+            panic!("expected laser-test");
+        };
+        assert_eq!((laser_id, boundary), (ALL_LASERS, false));
+        assert!(matches!(
+            parse(&["laser-test", "2", "--boundary"]).command,
+            Some(Command::LaserTest {
+                laser_id: 2,
+                boundary: true,
+                ..
+            })
+        ));
+
+        // Every step of the sequence, for each laser
+        let (laser_tx, mut laser_rx) = mpsc::channel(100);
+        send_laser_test(&laser_tx, ALL_LASERS, Duration::ZERO)
+            .await
+            .unwrap();
+        drop(laser_tx);
+        let mut frames = 0;
+        while laser_rx.recv().await.is_some() {
+            frames += 1;
+        }
+        assert_eq!(frames, MAX_LASERS * MessageSendPack::test_sequence(1).len());
+    }
+
+    #[test]
+    fn test_init_config() {
+        let dir = TempDir::new("init-config");
+        let path = dir.join("hardware.json");
+
+        let command = parse(&["init-config", &path.to_string_lossy()]).command;
+        command.unwrap().run().unwrap();
+        assert!(Config::load_from_json(&path.to_string_lossy()).is_ok());
+
+        // Only replaced when it's asked for
+        let command = parse(&["init-config", &path.to_string_lossy()]).command;
+        assert!(command.unwrap().run().is_err());
+        let command = parse(&["init-config", &path.to_string_lossy(), "--force"]).command;
+        command.unwrap().run().unwrap();
+    }
+
+    #[test]
+    fn test_setup() {
+        let cli = parse(&["--setup", "--config", "hardware.json"]);
+        assert!(cli.setup && cli.command.is_none());
+        assert!(cli.config().is_none());
+        assert_eq!(cli.run.config.config, PathBuf::from("hardware.json"));
+
+        assert!(Cli::try_parse_from(["rusty-halloween", "--setup", "version"]).is_err());
+    }
+
+    #[test]
+    fn test_version() {
+        let command = parse(&["version"]).command;
+        assert!(matches!(command, Some(Command::Version)));
+        command.unwrap().run().unwrap();
+    }
+}
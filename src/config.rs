@@ -1,22 +1,1083 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
 use anyhow::Error;
+use chrono::{FixedOffset, NaiveTime, Weekday};
+use log::{warn, LevelFilter};
 use pi_pinout::{GpioPin, PhysicalPin, WiringPiPin};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::show::prelude::DmxStateIndex;
+use crate::{
+    dmx::DMX_CHANNELS,
+    health::HEARTBEAT_INTERVAL,
+    lights::LightEffect,
+    show::{
+        prelude::{DmxStateData, DmxStateIndex},
+        MAX_LIGHTS, MAX_TURRETS,
+    },
+};
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+/// The hardware config schema this build understands. Bump this and add a
+/// step to `CONFIG_MIGRATIONS` whenever the format changes.
+pub const CURRENT_CONFIG_VERSION: u32 = 2;
+
+/// Each entry upgrades a config from version `n` to `n + 1`, where `n` is the
+/// entry's position plus one
+const CONFIG_MIGRATIONS: &[fn(Value) -> Value] = &[migrate_light_ids];
+
+const _: () = assert!(CONFIG_MIGRATIONS.len() + 1 == CURRENT_CONFIG_VERSION as usize);
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct Config {
+    /// Configs written before versioning existed are v1
+    #[serde(default = "default_config_version")]
+    pub version: u32,
     pub lights: Vec<Light>,
     pub lasers: Vec<Laser>,
     pub projectors: Vec<Projector>,
     pub turrets: Vec<Turret>,
+    /// Turrets fired over GPIO rather than DMX
+    #[serde(default)]
+    pub turret_triggers: Vec<TurretTrigger>,
+    #[serde(default)]
+    pub audio: AudioConfig,
+    #[serde(default)]
+    pub dmx: DmxConfig,
+    /// Settings shared by every laser
+    #[serde(default)]
+    pub laser: LaserConfig,
+    /// Settings shared by every light
+    #[serde(default)]
+    pub light: LightConfig,
+    #[serde(default)]
+    pub safety: SafetyConfig,
+    /// Buttons and sensors that start shows and effects
+    #[serde(default)]
+    pub inputs: Vec<InputConfig>,
+    #[serde(default)]
+    pub recording: RecordingConfig,
+    #[serde(default)]
+    pub uart: UartConfig,
+    #[serde(default)]
+    pub console: ConsoleConfig,
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+    #[serde(default)]
+    pub playlist: PlaylistConfig,
+    #[serde(default)]
+    pub stats: StatsConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    /// Upload each show to the show-server and start it with the audio
+    #[serde(default)]
+    pub show_server: Option<ShowServerConfig>,
+    /// Answer health checks and status requests over HTTP
+    #[serde(default)]
+    pub health: Option<HealthConfig>,
+    /// Serve the dashboard, in builds with the `dashboard` feature
+    #[serde(default)]
+    pub dashboard: Option<DashboardConfig>,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// Where each show's folder lives
+    #[serde(default = "default_shows_dir")]
+    pub shows_dir: PathBuf,
+    /// How long a transition fades the lights and audio out before the next
+    /// show starts
+    #[serde(default = "default_transition_ms")]
+    pub transition_ms: u64,
+    /// How fast shows play. Anything but 1 is a rehearsal, played without
+    /// audio.
+    #[serde(default = "default_time_scale")]
+    pub time_scale: f32,
+    /// Drive the hardware, or simulate all of it. `--simulate` overrides it.
+    #[serde(default)]
+    pub mode: RunMode,
+}
+
+/// Whether the controller drives the hardware. The `pi` feature decides
+/// whether it can, this decides whether it does.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RunMode {
+    #[default]
+    Hardware,
+    /// Render the lasers, draw the lights in the log, record the DMX and loop
+    /// the UART back, even in a build for the Pi
+    Simulate,
+}
+
+impl RunMode {
+    pub fn is_simulated(self) -> bool {
+        self == RunMode::Simulate
+    }
+}
+
+fn default_config_version() -> u32 {
+    1
+}
+
+fn default_shows_dir() -> PathBuf {
+    PathBuf::from("shows")
+}
+
+fn default_transition_ms() -> u64 {
+    3000
+}
+
+fn default_time_scale() -> f32 {
+    1.0
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            version: CURRENT_CONFIG_VERSION,
+            lights: Vec::new(),
+            lasers: Vec::new(),
+            projectors: Vec::new(),
+            turrets: Vec::new(),
+            turret_triggers: Vec::new(),
+            audio: AudioConfig::default(),
+            dmx: DmxConfig::default(),
+            laser: LaserConfig::default(),
+            light: LightConfig::default(),
+            safety: SafetyConfig::default(),
+            inputs: Vec::new(),
+            recording: RecordingConfig::default(),
+            uart: UartConfig::default(),
+            console: ConsoleConfig::default(),
+            schedule: ScheduleConfig::default(),
+            playlist: PlaylistConfig::default(),
+            stats: StatsConfig::default(),
+            storage: StorageConfig::default(),
+            show_server: None,
+            health: None,
+            dashboard: None,
+            logging: LoggingConfig::default(),
+            shows_dir: default_shows_dir(),
+            transition_ms: default_transition_ms(),
+            time_scale: default_time_scale(),
+            mode: RunMode::default(),
+        }
+    }
+}
+
+/// Where every show that's played is written down, for looking back on the
+/// season
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct StatsConfig {
+    /// The stats are kept in `show-stats.json` in here, which is made if it
+    /// isn't there
+    #[serde(default = "default_stats_data_dir")]
+    pub data_dir: PathBuf,
+}
+
+fn default_stats_data_dir() -> PathBuf {
+    PathBuf::from("data")
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        StatsConfig {
+            data_dir: default_stats_data_dir(),
+        }
+    }
+}
+
+/// What the folders the controller writes to need
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct StorageConfig {
+    /// Less free space than this on any of them is reported at startup
+    #[serde(default = "default_storage_min_free_mb")]
+    pub min_free_mb: u64,
+}
+
+fn default_storage_min_free_mb() -> u64 {
+    500
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            min_free_mb: default_storage_min_free_mb(),
+        }
+    }
+}
+
+/// How much is logged, what it looks like and where it goes
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct LoggingConfig {
+    #[serde(default)]
+    pub format: LogFormat,
+    /// The level for every module that isn't in `levels`
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    /// Levels for single modules, like `"rusty_halloween::uart": "debug"`.
+    /// Setting this replaces the defaults, which quiet symphonia's noisier
+    /// modules.
+    #[serde(default = "default_log_levels")]
+    pub levels: BTreeMap<String, String>,
+    /// Also write the log to files, as well as to stderr
+    #[serde(default)]
+    pub file: Option<LogFileConfig>,
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_log_levels() -> BTreeMap<String, String> {
+    BTreeMap::from([
+        ("symphonia_core::probe".to_string(), "off".to_string()),
+        (
+            "symphonia_bundle_mp3::demuxer".to_string(),
+            "off".to_string(),
+        ),
+    ])
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            format: LogFormat::default(),
+            level: default_log_level(),
+            levels: default_log_levels(),
+            file: None,
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// Check every level is one `log` knows
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.level.parse::<LevelFilter>().is_err() {
+            return Err(Error::msg(format!(
+                "logging.level {:?} isn't a log level",
+                self.level
+            )));
+        }
+
+        for (module, level) in &self.levels {
+            if level.parse::<LevelFilter>().is_err() {
+                return Err(Error::msg(format!(
+                    "logging.levels {} {:?} isn't a log level",
+                    module, level
+                )));
+            }
+        }
+
+        if self.file.as_ref().is_some_and(|file| file.max_bytes == 0) {
+            return Err(Error::msg("logging.file.max_bytes has to be at least 1"));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// A line of text per message, for reading in a terminal
+    #[default]
+    Plain,
+    /// A JSON object per line, for shipping to a log server
+    Json,
+}
+
+/// Log files are written to `rusty-halloween.log` in `dir`, which is moved
+/// to `rusty-halloween.log.1` once it's full, and so on up to `keep`
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct LogFileConfig {
+    #[serde(default = "default_log_dir")]
+    pub dir: PathBuf,
+    #[serde(default = "default_log_max_bytes")]
+    pub max_bytes: u64,
+    /// How many full files to keep before deleting the oldest
+    #[serde(default = "default_log_keep")]
+    pub keep: usize,
+}
+
+fn default_log_dir() -> PathBuf {
+    PathBuf::from("data/logs")
+}
+
+fn default_log_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_log_keep() -> usize {
+    5
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct ShowServerConfig {
+    /// Base URL of the show-server, like `http://192.168.1.70:8080`
+    pub url: String,
+    /// Keep the section but stop uploading and starting shows
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// How long the show-server waits after being told to start, to line up
+    /// with the audio coming out of the speakers
+    #[serde(default)]
+    pub start_delay_ms: u64,
+}
+
+/// An HTTP server for systemd and the monitoring to check on the controller
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct HealthConfig {
+    /// Where to listen, like `0.0.0.0:8081`
+    pub bind: SocketAddr,
+    /// How long a task can go without a heartbeat before `/healthz` reports
+    /// it as dead
+    #[serde(default = "default_health_stale_ms")]
+    pub stale_ms: u64,
+    /// Also serve `/metrics` for Prometheus to scrape
+    #[serde(default)]
+    pub metrics: bool,
+}
+
+fn default_health_stale_ms() -> u64 {
+    5000
+}
+
+/// A web page for watching the shows and stepping in
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct DashboardConfig {
+    /// Where to listen, like `0.0.0.0:8080`
+    pub bind: SocketAddr,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+/// Subsystems are on unless they're turned off
+fn default_enabled() -> bool {
+    true
+}
+
+/// The parts of the controller that can be turned off, such as on a test
+/// bench without a DMX interface. One that's off isn't started, and anything
+/// sent to it is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Subsystem {
+    Audio,
+    Dmx,
+    Lasers,
+    Lights,
+    Dashboard,
+    ShowServer,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 6] = [
+        Subsystem::Audio,
+        Subsystem::Dmx,
+        Subsystem::Lasers,
+        Subsystem::Lights,
+        Subsystem::Dashboard,
+        Subsystem::ShowServer,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::Audio => "audio",
+            Subsystem::Dmx => "DMX",
+            Subsystem::Lasers => "lasers",
+            Subsystem::Lights => "lights",
+            Subsystem::Dashboard => "dashboard",
+            Subsystem::ShowServer => "show-server",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct AudioConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig { enabled: true }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Default)]
+pub struct SafetyConfig {
+    /// Physical pin the e-stop pulls to ground. Without one, only a
+    /// dashboard can stop the outputs.
+    #[serde(default)]
+    pub estop_pin: Option<u8>,
+}
+
+/// A button or sensor on one of the Pi's pins, and what it does when it's
+/// triggered
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct InputConfig {
+    /// For the log, and typed in to trigger it off the Pi
+    pub name: String,
+    /// Physical pin number
+    pub pin: u8,
+    #[serde(default)]
+    pub edge: InputEdge,
+    /// Edges this soon after the last one are contact bounce
+    #[serde(default = "default_input_debounce_ms")]
+    pub debounce_ms: u64,
+    /// Shortest time between actions, so a sensor that keeps seeing someone
+    /// doesn't queue up a show each time
+    #[serde(default = "default_input_min_interval_ms")]
+    pub min_interval_ms: u64,
+    pub action: InputAction,
+}
+
+fn default_input_debounce_ms() -> u64 {
+    50
+}
+
+fn default_input_min_interval_ms() -> u64 {
+    30_000
+}
+
+/// Which change on the pin triggers an input
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InputEdge {
+    /// Low to high, like a motion sensor's output. The pin is pulled down.
+    Rising,
+    /// High to low, like a button wired to ground. The pin is pulled up.
+    #[default]
+    Falling,
+    /// Either way. The pin is pulled up.
+    Both,
+}
+
+/// What an input sends when it's triggered
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub enum InputAction {
+    /// Play a show next, in place of anything that's loading
+    PrepareShow(String),
+    LightEffect {
+        light_id: u8,
+        effect: LightEffect,
+    },
+    TurretFire {
+        turret_id: u8,
+    },
+}
+
+/// Every command sent to the lights, DMX and lasers, written to a file per
+/// show so they can be looked over or replayed afterwards
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct RecordingConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_recording_dir")]
+    pub dir: PathBuf,
+    /// How many recordings to keep before deleting the oldest
+    #[serde(default = "default_recording_keep")]
+    pub keep: usize,
+}
+
+fn default_recording_dir() -> PathBuf {
+    PathBuf::from("recordings")
+}
+
+fn default_recording_keep() -> usize {
+    20
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        RecordingConfig {
+            enabled: false,
+            dir: default_recording_dir(),
+            keep: default_recording_keep(),
+        }
+    }
+}
+
+/// Which shows get picked, and how often
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct PlaylistConfig {
+    /// Shows to pick from, by name. Every show is picked from evenly without
+    /// any.
+    #[serde(default)]
+    pub shows: Vec<PlaylistEntry>,
+    /// Play the shows in order instead of picking them at random
+    #[serde(default)]
+    pub ordered: bool,
+    /// A random pick won't repeat any of this many of the last shows played
+    #[serde(default = "default_playlist_min_gap")]
+    pub min_gap: usize,
+    /// Where the recent shows are kept, so the gap holds over a restart
+    #[serde(default = "default_playlist_state_file")]
+    pub state_file: PathBuf,
+    /// Seed for the random picks, to play a night's shows in the same order
+    /// again. A new one is used each run without it.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+fn default_playlist_min_gap() -> usize {
+    1
+}
+
+fn default_playlist_state_file() -> PathBuf {
+    PathBuf::from("show-history.json")
+}
+
+impl Default for PlaylistConfig {
+    fn default() -> Self {
+        PlaylistConfig {
+            shows: Vec::new(),
+            ordered: false,
+            min_gap: default_playlist_min_gap(),
+            state_file: default_playlist_state_file(),
+            seed: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct PlaylistEntry {
+    pub name: String,
+    /// How likely a random pick is to land on it, relative to the others
+    #[serde(default = "default_playlist_weight")]
+    pub weight: u32,
+}
+
+fn default_playlist_weight() -> u32 {
+    1
+}
+
+/// The serial line to the Picos
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct UartConfig {
+    /// If this isn't set, it's the Pi's UART on the Pi and nothing everywhere
+    /// else
+    #[serde(default)]
+    pub backend: Option<UartBackend>,
+    #[serde(default = "default_uart_device")]
+    pub device: String,
+    #[serde(default = "default_uart_baud")]
+    pub baud: u32,
+    /// Bytes handed to the port at a time. If this isn't set, each message
+    /// is written in one go.
+    #[serde(default)]
+    pub chunk_size: Option<usize>,
+    /// Pause between chunks, for devices that can't keep up otherwise
+    #[serde(default)]
+    pub chunk_pause_us: u64,
+    /// Send a break this long before every DMX frame, for converters that
+    /// don't make their own
+    #[serde(default)]
+    pub dmx_break_us: Option<u64>,
+    /// Have shows mark the end of each frame, so the UART holds a frame's
+    /// laser and DMX messages and writes them back to back
+    #[serde(default)]
+    pub frame_barrier: bool,
+    /// How long a held frame waits for the end of its frame before it's
+    /// written anyway
+    #[serde(default = "default_uart_flush_timeout_ms")]
+    pub flush_timeout_ms: u64,
+}
+
+fn default_uart_flush_timeout_ms() -> u64 {
+    100
+}
+
+fn default_uart_device() -> String {
+    "/dev/serial0".to_string()
+}
+
+fn default_uart_baud() -> u32 {
+    57_600
+}
+
+impl Default for UartConfig {
+    fn default() -> Self {
+        UartConfig {
+            backend: None,
+            device: default_uart_device(),
+            baud: default_uart_baud(),
+            chunk_size: None,
+            chunk_pause_us: 0,
+            dmx_break_us: None,
+            frame_barrier: false,
+            flush_timeout_ms: default_uart_flush_timeout_ms(),
+        }
+    }
+}
+
+/// Typed commands for debugging in the field, read off a spare serial port
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Default)]
+pub struct ConsoleConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Has to be a different port from the one to the Picos
+    #[serde(default)]
+    pub port: UartConfig,
+}
+
+/// When the display runs on its own. Outside of these hours shows are stopped,
+/// though they can still be started by hand.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct ScheduleConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_schedule_start")]
+    pub start: NaiveTime,
+    /// Before `start` if the display runs past midnight
+    #[serde(default = "default_schedule_end")]
+    pub end: NaiveTime,
+    /// Days the display starts on. A night running past midnight belongs to
+    /// the day it started.
+    #[serde(default = "default_schedule_days")]
+    pub days: Vec<Weekday>,
+    /// A UTC offset like `-04:00`. The Pi's local time is used without one.
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// A show to fade into when the hours close, played as the last one
+    #[serde(default)]
+    pub closing_show: Option<String>,
+    /// Times of the night that only play shows with certain tags
+    #[serde(default)]
+    pub blocks: Vec<ScheduleBlock>,
+}
+
+/// A part of the night, every day, when shows are picked by their tags. See
+/// `ShowControl::Tags`.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct ScheduleBlock {
+    pub start: NaiveTime,
+    /// Before `start` if the block runs past midnight
+    pub end: NaiveTime,
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+fn default_schedule_start() -> NaiveTime {
+    NaiveTime::from_hms_opt(18, 0, 0).unwrap()
+}
+
+fn default_schedule_end() -> NaiveTime {
+    NaiveTime::from_hms_opt(22, 0, 0).unwrap()
+}
+
+fn default_schedule_days() -> Vec<Weekday> {
+    vec![
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ]
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        ScheduleConfig {
+            enabled: false,
+            start: default_schedule_start(),
+            end: default_schedule_end(),
+            days: default_schedule_days(),
+            timezone: None,
+            closing_show: None,
+            blocks: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum UartBackend {
+    /// The Pi's own UART, needs the `pi` feature
+    Pi,
+    /// A USB serial adapter, needs the `serial` feature
+    Serial,
+    /// Everything written is read straight back
+    Loopback,
+    /// Writes go nowhere
+    Null,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct LaserConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Words the Pico expects in every transfer, including the header. This
+    /// has to match `TRANSFER_SIZE` in the firmware.
+    #[serde(default = "default_laser_transfer_size")]
+    pub transfer_size: usize,
+    /// Wait for the Pico to ack each addressed frame, resending it if it
+    /// doesn't. Only turn this on with firmware that sends acks.
+    #[serde(default)]
+    pub ack: bool,
+    #[serde(default = "default_laser_ack_timeout_ms")]
+    pub ack_timeout_ms: u64,
+    /// Resends after the first attempt before a frame is given up on
+    #[serde(default = "default_laser_ack_retries")]
+    pub ack_retries: u32,
+    /// Galvo steps a frame is given to draw before the next one is sent. The
+    /// gap between frames is this over the frame's speed profile, so slower
+    /// profiles get longer gaps.
+    #[serde(default = "default_laser_frame_gap_steps")]
+    pub frame_gap_steps: u32,
+    /// Frames that can wait to be sent before the oldest is dropped
+    #[serde(default = "default_laser_queue_depth")]
+    pub queue_depth: usize,
+    /// Seconds a boundary check is left up for
+    #[serde(default = "default_laser_boundary_check_secs")]
+    pub boundary_check_secs: u64,
+    /// Seconds homing is given to finish, or to be reported finished when
+    /// the Picos report it
+    #[serde(default = "default_laser_home_wait_secs")]
+    pub home_wait_secs: u64,
+    /// Times homing is tried again after the Picos report it failed, before
+    /// the shows are stopped
+    #[serde(default = "default_laser_home_retries")]
+    pub home_retries: u32,
+    /// Render frames to SVGs under `target/laser-preview` instead of sending
+    /// them to the lasers
+    #[serde(default)]
+    pub simulate: bool,
+    /// Run every laser through the test pattern when the show starts up
+    #[serde(default)]
+    pub test_on_startup: bool,
+    /// Seconds each step of the laser test is held for
+    #[serde(default = "default_laser_test_step_secs")]
+    pub test_step_secs: u64,
+    /// How show colours are brought down to the Pico's 3 bits a channel
+    #[serde(default)]
+    pub colour_depth: ColourDepth,
+    /// Sent to each laser when the hardware is initialised
+    #[serde(default = "default_laser_acceleration")]
+    pub acceleration: u32,
+    #[serde(default = "default_laser_max_speed")]
+    pub max_speed: u32,
+    #[serde(default = "default_laser_min_speed")]
+    pub min_speed: u16,
+    #[serde(default = "default_laser_x_home")]
+    pub x_home: u16,
+    #[serde(default = "default_laser_y_home")]
+    pub y_home: u16,
+    /// How long the lasers are given to take their config before homing
+    #[serde(default = "default_laser_config_wait_ms")]
+    pub config_wait_ms: u64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ColourDepth {
+    /// Drop the low bits, like shows made before rounding was added expect
+    Truncate,
+    /// Round to the nearest level
+    #[default]
+    Round,
+    /// Round, carrying the error over to the next frame
+    Dither,
+}
+
+fn default_laser_transfer_size() -> usize {
+    51
+}
+
+fn default_laser_ack_timeout_ms() -> u64 {
+    50
+}
+
+fn default_laser_ack_retries() -> u32 {
+    2
+}
+
+fn default_laser_frame_gap_steps() -> u32 {
+    50
+}
+
+fn default_laser_queue_depth() -> usize {
+    16
+}
+
+fn default_laser_boundary_check_secs() -> u64 {
+    30
+}
+
+fn default_laser_home_wait_secs() -> u64 {
+    15
+}
+
+fn default_laser_home_retries() -> u32 {
+    2
+}
+
+fn default_laser_test_step_secs() -> u64 {
+    2
+}
+
+fn default_laser_acceleration() -> u32 {
+    100_000
+}
+
+fn default_laser_max_speed() -> u32 {
+    40_000
+}
+
+fn default_laser_min_speed() -> u16 {
+    5_000
+}
+
+fn default_laser_x_home() -> u16 {
+    580
+}
+
+fn default_laser_y_home() -> u16 {
+    300
+}
+
+fn default_laser_config_wait_ms() -> u64 {
+    500
+}
+
+impl Default for LaserConfig {
+    fn default() -> Self {
+        LaserConfig {
+            enabled: true,
+            transfer_size: default_laser_transfer_size(),
+            ack: false,
+            ack_timeout_ms: default_laser_ack_timeout_ms(),
+            ack_retries: default_laser_ack_retries(),
+            frame_gap_steps: default_laser_frame_gap_steps(),
+            queue_depth: default_laser_queue_depth(),
+            boundary_check_secs: default_laser_boundary_check_secs(),
+            home_wait_secs: default_laser_home_wait_secs(),
+            home_retries: default_laser_home_retries(),
+            simulate: false,
+            test_on_startup: false,
+            test_step_secs: default_laser_test_step_secs(),
+            colour_depth: ColourDepth::Round,
+            acceleration: default_laser_acceleration(),
+            max_speed: default_laser_max_speed(),
+            min_speed: default_laser_min_speed(),
+            x_home: default_laser_x_home(),
+            y_home: default_laser_y_home(),
+            config_wait_ms: default_laser_config_wait_ms(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct LightConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// How long the light test leaves each step on
+    #[serde(default = "default_light_test_step_ms")]
+    pub test_step_ms: u64,
+    /// Lights that switch together, by ID, so shows can set them all with
+    /// one `group-<name>` key
+    #[serde(default)]
+    pub groups: BTreeMap<String, Vec<u8>>,
+    /// Time between starting each light, so they don't all switch at once
+    /// when the controller boots
+    #[serde(default)]
+    pub startup_stagger_ms: u64,
+    /// Draw the lights in the log whenever one changes
+    #[serde(default)]
+    pub simulate: bool,
+}
+
+fn default_light_test_step_ms() -> u64 {
+    1000
+}
+
+impl Default for LightConfig {
+    fn default() -> Self {
+        LightConfig {
+            enabled: true,
+            test_step_ms: default_light_test_step_ms(),
+            groups: BTreeMap::new(),
+            startup_stagger_ms: 0,
+            simulate: false,
+        }
+    }
+}
+
+/// Channel maps applied to the DMX universe outside of show playback. Keys are
+/// DMX addresses, which start at 1.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct DmxConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Which DMX controller on the UART bus the frames are addressed to.
+    /// Controllers reserve addresses 0xA-0xE.
+    #[serde(default = "default_dmx_controller_id")]
+    pub controller_id: u8,
+    /// Universe selector sent in the header
+    #[serde(default)]
+    pub universe: u8,
+    /// Values set when the DMX state is created, before any show runs
+    #[serde(default)]
+    pub startup: BTreeMap<DmxStateIndex, DmxStateData>,
+    /// Values set on blackout. Every channel that isn't listed is zeroed
+    #[serde(default)]
+    pub blackout: BTreeMap<DmxStateIndex, DmxStateData>,
+    /// Channels cleared by a zero out. If this isn't set, every channel is
+    /// cleared
+    #[serde(default)]
+    pub zero_out: Option<Vec<DmxStateIndex>>,
+    /// Named looks that can be recalled by shows. Recalling a scene only
+    /// changes the channels it lists.
+    #[serde(default)]
+    pub scenes: BTreeMap<String, BTreeMap<DmxStateIndex, DmxStateData>>,
+    /// Send frames to an Art-Net node instead of the UART converter
+    #[serde(default)]
+    pub artnet: Option<ArtNetConfig>,
+    /// Send requests that arrive within this many milliseconds of each other
+    /// are folded into one send
+    #[serde(default = "default_dmx_send_window_ms")]
+    pub send_window_ms: u64,
+    /// Skip sends when no channel has changed since the last one
+    #[serde(default)]
+    pub send_only_on_change: bool,
+    /// How often frames are sent while an effect is running
+    #[serde(default = "default_dmx_effect_tick_ms")]
+    pub effect_tick_ms: u64,
+    /// Where frames go. If this isn't set, it's the UART on the Pi and the
+    /// recorder everywhere else
+    #[serde(default)]
+    pub sink: Option<DmxSinkKind>,
+    #[serde(default)]
+    pub recorder: RecorderConfig,
+    /// Also push changed channels to another controller over HTTP
+    #[serde(default)]
+    pub remote_dmx: Option<RemoteDmxConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct RemoteDmxConfig {
+    /// Base URL of the remote controller, like `http://192.168.1.60:3000`
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DmxSinkKind {
+    Uart,
+    Recorder,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct RecorderConfig {
+    /// How many of the latest frames are kept in memory
+    #[serde(default = "default_recorder_capacity")]
+    pub capacity: usize,
+    /// Append every frame to this JSONL file
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+fn default_recorder_capacity() -> usize {
+    1000
+}
+
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        RecorderConfig {
+            capacity: default_recorder_capacity(),
+            path: None,
+        }
+    }
+}
+
+fn default_dmx_send_window_ms() -> u64 {
+    20
+}
+
+fn default_dmx_effect_tick_ms() -> u64 {
+    25
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct ArtNetConfig {
+    /// Address of the Art-Net node
+    pub ip: IpAddr,
+    #[serde(default = "default_artnet_port")]
+    pub port: u16,
+    /// 15-bit port address (net, sub-net and universe)
+    #[serde(default)]
+    pub universe: u16,
+    /// Keep sending to the default sink as well as Art-Net
+    #[serde(default)]
+    pub uart: bool,
+}
+
+fn default_artnet_port() -> u16 {
+    6454
+}
+
+fn default_dmx_controller_id() -> u8 {
+    0xA
+}
+
+impl Default for DmxConfig {
+    fn default() -> Self {
+        DmxConfig {
+            enabled: true,
+            controller_id: default_dmx_controller_id(),
+            universe: 0,
+            startup: BTreeMap::new(),
+            blackout: BTreeMap::new(),
+            zero_out: None,
+            scenes: BTreeMap::new(),
+            artnet: None,
+            send_window_ms: default_dmx_send_window_ms(),
+            send_only_on_change: false,
+            effect_tick_ms: default_dmx_effect_tick_ms(),
+            sink: None,
+            recorder: RecorderConfig::default(),
+            remote_dmx: None,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
 pub struct Light {
     pub pin: Pin,
     pub id: u8,
+    /// The key it's under in the JSON config, like `light-3`
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Dimmed with PWM rather than switched, for lights on a MOSFET board.
+    /// Relays would chatter themselves to death, so they stay on/off.
+    #[serde(default)]
+    pub pwm: bool,
+    /// Whether the light is wired active low, so a high pin is off. The old
+    /// relay boards are; the SSR board isn't. Left out, it's inverted.
+    #[serde(default)]
+    pub inverted: Option<bool>,
+    /// Shortest time between switching on or off, so a show can't chatter a
+    /// relay to death. Anything faster is coalesced to the last request.
+    #[serde(default)]
+    pub min_toggle_interval_ms: u64,
+}
+
+impl Light {
+    pub fn name(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("light-{}", self.id))
+    }
+
+    pub fn inverted(&self) -> bool {
+        self.inverted.unwrap_or(true)
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
@@ -26,38 +1087,103 @@ pub struct Laser {
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
 pub struct Projector {
-    pub id: u8,
+    /// DMX address of the first channel
+    pub id: DmxStateIndex,
     pub format: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
 pub struct Turret {
-    pub id: u8,
+    /// DMX address of the first channel
+    pub id: DmxStateIndex,
     pub format: Vec<String>,
 }
 
+/// An air cannon turret, fired by holding a valve open on a GPIO pin
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct TurretTrigger {
+    pub pin: Pin,
+    /// Matches `turret-N` in show files
+    pub id: u8,
+    /// How long the valve is held open
+    #[serde(default = "default_turret_pulse_ms")]
+    pub pulse_ms: u64,
+    /// Shortest time between shots, so the tank can refill and nobody gets
+    /// hit twice
+    #[serde(default = "default_turret_min_refire_ms")]
+    pub min_refire_ms: u64,
+}
+
+fn default_turret_pulse_ms() -> u64 {
+    100
+}
+
+fn default_turret_min_refire_ms() -> u64 {
+    2000
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
 pub enum Pin {
     Physical(PhysicalPin),
     Gpio(GpioPin),
     WiringPi(WiringPiPin),
+    /// A pin on an MCP23017 I2C expander, from 0 for GPA0 to 15 for GPB7.
+    /// These can't be dimmed.
+    I2cExpander {
+        bus: u8,
+        address: u16,
+        pin: u8,
+    },
 }
 
 impl Config {
     pub fn load() -> Result<Config, Error> {
         let config = std::fs::read_to_string("config.ron")?;
         let config: Config = ron::from_str(&config)?;
+        config.validate()?;
         Ok(config)
     }
 
     pub fn load_from_json(path: &str) -> Result<Config, Error> {
         let json_str = std::fs::read_to_string(path)?;
-        let json: Value = serde_json::from_str(&json_str)?;
+        Config::from_json(&json_str)
+    }
+
+    /// Load a config with one of its profiles applied, see `apply_profile`
+    pub fn load_profile(path: &str, profile: Option<&str>) -> Result<Config, Error> {
+        let json_str = std::fs::read_to_string(path)?;
+        Config::from_json_profile(&json_str, profile)
+    }
+
+    /// Just the logging section, so logging can be set up before the rest of
+    /// the config is loaded and warned about
+    pub fn load_logging(path: &str, profile: Option<&str>) -> Result<LoggingConfig, Error> {
+        let mut json = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        if let Some(profile) = profile {
+            apply_profile(&mut json, profile)?;
+        }
+
+        let logging: LoggingConfig = section(&json, "logging")?;
+        logging.validate()?;
+        Ok(logging)
+    }
+
+    pub fn from_json(json_str: &str) -> Result<Config, Error> {
+        Config::from_json_profile(json_str, None)
+    }
+
+    pub fn from_json_profile(json_str: &str, profile: Option<&str>) -> Result<Config, Error> {
+        let mut json = serde_json::from_str(json_str)?;
+        if let Some(profile) = profile {
+            apply_profile(&mut json, profile)?;
+        }
+        let json = migrate_config(json)?;
 
         let mut lights = Vec::new();
         let mut lasers = Vec::new();
         let mut projectors = Vec::new();
         let mut turrets = Vec::new();
+        let mut turret_triggers = Vec::new();
 
         // Process all entries in the JSON
         for (key, value) in json.as_object().ok_or_else(|| Error::msg("Invalid JSON"))? {
@@ -65,10 +1191,47 @@ impl Config {
                 Some("GPIO") => {
                     if key.starts_with("light-") {
                         lights.push(Light {
+                            pin: Pin::Physical(PhysicalPin(
+                                value["pin"].as_u64().unwrap_or(0) as u8
+                            )),
+                            id: light_id(key, value)?,
+                            name: Some(key.clone()),
+                            pwm: value["pwm"].as_bool().unwrap_or(false),
+                            inverted: value["inverted"].as_bool(),
+                            min_toggle_interval_ms: value["min_toggle_interval_ms"]
+                                .as_u64()
+                                .unwrap_or(0),
+                        });
+                    } else if key.starts_with("turret-") {
+                        turret_triggers.push(TurretTrigger {
                             pin: Pin::Physical(PhysicalPin(
                                 value["pin"].as_u64().unwrap_or(0) as u8
                             )),
                             id: value["id"].as_u64().unwrap_or(0) as u8,
+                            pulse_ms: value["pulse_ms"]
+                                .as_u64()
+                                .unwrap_or_else(default_turret_pulse_ms),
+                            min_refire_ms: value["min_refire_ms"]
+                                .as_u64()
+                                .unwrap_or_else(default_turret_min_refire_ms),
+                        });
+                    }
+                }
+                Some("I2C") => {
+                    if key.starts_with("light-") {
+                        lights.push(Light {
+                            pin: Pin::I2cExpander {
+                                bus: value["bus"].as_u64().unwrap_or(1) as u8,
+                                address: value["address"].as_u64().unwrap_or(0x20) as u16,
+                                pin: value["pin"].as_u64().unwrap_or(0) as u8,
+                            },
+                            id: light_id(key, value)?,
+                            name: Some(key.clone()),
+                            pwm: value["pwm"].as_bool().unwrap_or(false),
+                            inverted: value["inverted"].as_bool(),
+                            min_toggle_interval_ms: value["min_toggle_interval_ms"]
+                                .as_u64()
+                                .unwrap_or(0),
                         });
                     }
                 }
@@ -87,71 +1250,481 @@ impl Config {
                         .map(|v| v.as_str().unwrap_or("").to_string())
                         .collect();
 
-                    if key.starts_with("lp-") {
-                        projectors.push(Projector {
-                            id: value["id"].as_u64().unwrap_or(0) as u8,
-                            format,
-                        });
-                    } else if key.starts_with("turret-") {
-                        turrets.push(Turret {
-                            id: value["id"].as_u64().unwrap_or(0) as u8,
-                            format,
-                        });
-                    }
+                    if key.starts_with("lp-") {
+                        projectors.push(Projector {
+                            id: value["id"].as_u64().unwrap_or(0) as DmxStateIndex,
+                            format,
+                        });
+                    } else if key.starts_with("turret-") {
+                        turrets.push(Turret {
+                            id: value["id"].as_u64().unwrap_or(0) as DmxStateIndex,
+                            format,
+                        });
+                    }
+                }
+                _ => continue,
+            }
+        }
+
+        // Sort all vectors by ID for consistency
+        lights.sort_by_key(|l| l.id);
+        lasers.sort_by_key(|l| l.id);
+        projectors.sort_by_key(|p| p.id);
+        turrets.sort_by_key(|t| t.id);
+
+        // Everything else is a section of its own
+        let audio = section(&json, "audio")?;
+        let dmx = section(&json, "dmx")?;
+        let laser = section(&json, "laser")?;
+        let light = section(&json, "light")?;
+        let safety = section(&json, "safety")?;
+        let recording = section(&json, "recording")?;
+        let uart = section(&json, "uart")?;
+        let console = section(&json, "console")?;
+        let schedule = section(&json, "schedule")?;
+        let playlist = section(&json, "playlist")?;
+        let stats = section(&json, "stats")?;
+        let inputs = section(&json, "inputs")?;
+        let storage = section(&json, "storage")?;
+        let logging = section(&json, "logging")?;
+        let show_server = section(&json, "show_server")?;
+        let health = section(&json, "health")?;
+        let dashboard = section(&json, "dashboard")?;
+        let shows_dir = section_or(&json, "shows_dir", default_shows_dir)?;
+        let transition_ms = section_or(&json, "transition_ms", default_transition_ms)?;
+        let time_scale = section_or(&json, "time_scale", default_time_scale)?;
+        let mode = section(&json, "mode")?;
+
+        let config = Config {
+            version: CURRENT_CONFIG_VERSION,
+            lights,
+            lasers,
+            projectors,
+            turrets,
+            turret_triggers,
+            audio,
+            dmx,
+            laser,
+            light,
+            safety,
+            inputs,
+            recording,
+            uart,
+            console,
+            schedule,
+            playlist,
+            stats,
+            storage,
+            show_server,
+            health,
+            dashboard,
+            logging,
+            shows_dir,
+            transition_ms,
+            time_scale,
+            mode,
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Whether `subsystem` is started
+    pub fn enabled(&self, subsystem: Subsystem) -> bool {
+        match subsystem {
+            Subsystem::Audio => self.audio.enabled,
+            Subsystem::Dmx => self.dmx.enabled,
+            Subsystem::Lasers => self.laser.enabled,
+            Subsystem::Lights => self.light.enabled,
+            Subsystem::Dashboard => self.dashboard.as_ref().is_some_and(|d| d.enabled),
+            Subsystem::ShowServer => self.show_server.as_ref().is_some_and(|s| s.enabled),
+        }
+    }
+
+    /// The subsystems that are started
+    pub fn subsystems(&self) -> BTreeSet<Subsystem> {
+        Subsystem::ALL
+            .into_iter()
+            .filter(|subsystem| self.enabled(*subsystem))
+            .collect()
+    }
+
+    /// Check the parts of the config that can't be expressed by the types
+    /// alone
+    pub fn validate(&self) -> Result<(), Error> {
+        check_config_version(self.version as u64)?;
+
+        if !(self.time_scale.is_finite() && self.time_scale > 0.0) {
+            return Err(Error::msg(format!(
+                "time_scale {} has to be above 0",
+                self.time_scale
+            )));
+        }
+
+        if self.laser.transfer_size == 0 {
+            return Err(Error::msg("laser.transfer_size has to fit the header"));
+        }
+
+        if self.laser.queue_depth == 0 {
+            return Err(Error::msg(
+                "laser.queue_depth has to hold at least one frame",
+            ));
+        }
+
+        if self.uart.chunk_size == Some(0) {
+            return Err(Error::msg("uart.chunk_size has to be at least one byte"));
+        }
+
+        if self.uart.dmx_break_us == Some(0) {
+            return Err(Error::msg("uart.dmx_break_us has to be at least 1us"));
+        }
+
+        if self.console.enabled && self.console.port.device == self.uart.device {
+            return Err(Error::msg(format!(
+                "console.port.device {} is already the UART to the Picos",
+                self.uart.device
+            )));
+        }
+
+        if self.schedule.start == self.schedule.end {
+            return Err(Error::msg(
+                "schedule.start and schedule.end can't be the same",
+            ));
+        }
+
+        self.logging.validate()?;
+
+        if let Some(health) = &self.health {
+            if health.stale_ms <= HEARTBEAT_INTERVAL.as_millis() as u64 {
+                return Err(Error::msg(format!(
+                    "health.stale_ms has to be longer than the {}ms between heartbeats",
+                    HEARTBEAT_INTERVAL.as_millis()
+                )));
+            }
+        }
+
+        if let Some(timezone) = &self.schedule.timezone {
+            if timezone.parse::<FixedOffset>().is_err() {
+                return Err(Error::msg(format!(
+                    "schedule.timezone {:?} isn't a UTC offset like -04:00",
+                    timezone
+                )));
+            }
+        }
+
+        if let Some(entry) = self.playlist.shows.iter().find(|entry| entry.weight == 0) {
+            return Err(Error::msg(format!(
+                "playlist show {} needs a weight of at least 1",
+                entry.name
+            )));
+        }
+
+        if !(0xA..=0xE).contains(&self.dmx.controller_id) {
+            return Err(Error::msg(format!(
+                "dmx.controller_id {:#X} is outside of the reserved 0xA-0xE range",
+                self.dmx.controller_id
+            )));
+        }
+
+        if self.dmx.universe > 0xF {
+            return Err(Error::msg(format!(
+                "dmx.universe {} doesn't fit in the 4-bit selector",
+                self.dmx.universe
+            )));
+        }
+
+        if let Some(artnet) = &self.dmx.artnet {
+            if artnet.universe > 0x7FFF {
+                return Err(Error::msg(format!(
+                    "dmx.artnet.universe {} doesn't fit in a 15-bit port address",
+                    artnet.universe
+                )));
+            }
+        }
+
+        let mut light_ids = HashSet::new();
+        for light in &self.lights {
+            // Shows address lights by ID
+            if !(1..=MAX_LIGHTS).contains(&(light.id as usize)) {
+                return Err(Error::msg(format!(
+                    "Light ID {} is outside of 1..={}",
+                    light.id, MAX_LIGHTS
+                )));
+            }
+            if !light_ids.insert(light.id) {
+                return Err(Error::msg(format!(
+                    "Light ID {} is used more than once",
+                    light.id
+                )));
+            }
+
+            // Lights used to all be active low, so the default keeps old
+            // configs working, but the SSR board needs it set to false
+            if light.inverted.is_none() {
+                warn!(
+                    "{} doesn't say whether it's inverted, assuming it's active low",
+                    light.name()
+                );
+            }
+
+            if let Pin::I2cExpander { pin, .. } = light.pin {
+                if pin > 15 {
+                    return Err(Error::msg(format!(
+                        "Light {} is on expander pin {}, but an MCP23017 only has 16",
+                        light.id, pin
+                    )));
+                }
+            }
+        }
+
+        let mut turret_ids = HashSet::new();
+        for turret in &self.turret_triggers {
+            if !(1..=MAX_TURRETS).contains(&(turret.id as usize)) {
+                return Err(Error::msg(format!(
+                    "Turret ID {} is outside of 1..={}",
+                    turret.id, MAX_TURRETS
+                )));
+            }
+            if !turret_ids.insert(turret.id) {
+                return Err(Error::msg(format!(
+                    "Turret ID {} is used more than once",
+                    turret.id
+                )));
+            }
+            if matches!(turret.pin, Pin::I2cExpander { .. }) {
+                return Err(Error::msg(format!(
+                    "Turret {} has to be on one of the Pi's pins",
+                    turret.id
+                )));
+            }
+        }
+
+        for (group, members) in &self.light.groups {
+            if members.is_empty() {
+                return Err(Error::msg(format!("Light group {} has no lights", group)));
+            }
+            if let Some(id) = members.iter().find(|id| !light_ids.contains(*id)) {
+                return Err(Error::msg(format!(
+                    "Light group {} has light {}, which isn't in the config",
+                    group, id
+                )));
+            }
+        }
+
+        let (mut input_names, mut input_pins) = (HashSet::new(), HashSet::new());
+        for input in &self.inputs {
+            if !input_names.insert(&input.name) {
+                return Err(Error::msg(format!(
+                    "Input {} is used more than once",
+                    input.name
+                )));
+            }
+            if !input_pins.insert(input.pin) || self.safety.estop_pin == Some(input.pin) {
+                return Err(Error::msg(format!(
+                    "Input {} is on pin {}, which is already in use",
+                    input.name, input.pin
+                )));
+            }
+            if let InputAction::LightEffect { light_id, .. } = input.action {
+                if !light_ids.contains(&light_id) {
+                    return Err(Error::msg(format!(
+                        "Input {} runs an effect on light {}, which isn't in the config",
+                        input.name, light_id
+                    )));
                 }
-                _ => continue,
             }
         }
 
-        // Sort all vectors by ID for consistency
-        lights.sort_by_key(|l| l.id);
-        lasers.sort_by_key(|l| l.id);
-        projectors.sort_by_key(|p| p.id);
-        turrets.sort_by_key(|t| t.id);
+        for (name, channels) in [
+            ("startup", &self.dmx.startup),
+            ("blackout", &self.dmx.blackout),
+        ] {
+            for &channel in channels.keys() {
+                validate_dmx_channel(channel)
+                    .map_err(|e| Error::msg(format!("dmx.{}: {}", name, e)))?;
+            }
+        }
 
-        Ok(Config {
-            lights,
-            lasers,
-            projectors,
-            turrets,
-        })
+        for (name, scene) in &self.dmx.scenes {
+            for &channel in scene.keys() {
+                validate_dmx_channel(channel)
+                    .map_err(|e| Error::msg(format!("dmx.scenes.{}: {}", name, e)))?;
+            }
+        }
+
+        for &channel in self.dmx.zero_out.iter().flatten() {
+            validate_dmx_channel(channel)
+                .map_err(|e| Error::msg(format!("dmx.zero_out: {}", e)))?;
+        }
+
+        Ok(())
     }
 
-    pub fn get_dmx_state_var_position(&self, device_name: &str, var_name: &str) -> DmxStateIndex {
+    pub fn get_dmx_state_var_position(
+        &self,
+        device_name: &str,
+        var_name: &str,
+    ) -> Result<DmxStateIndex, Error> {
         // Look through either projectors or turrets
-        if let Some(project_num) = device_name.strip_prefix("lp-") {
-            let id = project_num.parse::<u8>().unwrap();
-
-            // Find the index of the var_name in the format
-            let var_name_index: u8 = self.projectors[id as usize - 1]
-                .format
+        let (id, devices) = if let Some(project_num) = device_name.strip_prefix("lp-") {
+            let devices = self
+                .projectors
                 .iter()
-                .position(|v| v == var_name)
-                .unwrap() as u8;
+                .map(|projector| (projector.id, &projector.format))
+                .collect::<Vec<_>>();
+            (project_num, devices)
+        } else if let Some(turret_num) = device_name.strip_prefix("turret-") {
+            let devices = self
+                .turrets
+                .iter()
+                .map(|turret| (turret.id, &turret.format))
+                .collect::<Vec<_>>();
+            (turret_num, devices)
+        } else {
+            // If it wasn't a projector or turret then throw an error
+            return Err(Error::msg(format!("Invalid device name: {}", device_name)));
+        };
 
-            // Find the DMX address for this hardware device
-            let dmx_address = self.projectors[id as usize - 1].id;
+        // The DMX address for this hardware device, and its format
+        let (dmx_address, format) = id
+            .parse::<usize>()
+            .ok()
+            .and_then(|id| devices.get(id.checked_sub(1)?))
+            .ok_or_else(|| Error::msg(format!("{} isn't in the config", device_name)))?;
 
-            return dmx_address + var_name_index;
-        } else if let Some(turret_num) = device_name.strip_prefix("turret-") {
-            let id = turret_num.parse::<u8>().unwrap();
+        // Find the index of the var_name in the format
+        let var_name_index = format
+            .iter()
+            .position(|v| v == var_name)
+            .ok_or_else(|| Error::msg(format!("{} has no {} channel", device_name, var_name)))?;
 
-            // Find the index of the var_name in the format
-            let var_name_index: u8 = self.turrets[id as usize - 1]
-                .format
-                .iter()
-                .position(|v| v == var_name)
-                .unwrap() as u8;
+        Ok(dmx_address + var_name_index as DmxStateIndex)
+    }
+}
 
-            // Find the DMX address for this hardware device
-            let dmx_address = self.turrets[id as usize - 1].id;
+fn check_config_version(version: u64) -> Result<(), Error> {
+    if version > CURRENT_CONFIG_VERSION as u64 {
+        return Err(Error::msg(format!(
+            "Config version {} is newer than this build supports (up to {}), update rusty-halloween",
+            version, CURRENT_CONFIG_VERSION
+        )));
+    }
 
-            return dmx_address + var_name_index;
-        } else {
-            // If it wasn't a projector or turret then throw an error
-            panic!("Invalid device name: {}", device_name);
+    if version == 0 {
+        return Err(Error::msg(
+            "Config version 0 doesn't exist, versions start at 1",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Bring a hardware config up to `CURRENT_CONFIG_VERSION`. Files without a
+/// version field are treated as v1.
+fn migrate_config(mut json: Value) -> Result<Value, Error> {
+    let version = match json.get("version") {
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| Error::msg("Config version must be a positive integer"))?,
+        None => 1,
+    };
+    check_config_version(version)?;
+
+    for migration in &CONFIG_MIGRATIONS[version as usize - 1..] {
+        json = migration(json);
+    }
+
+    if let Some(json) = json.as_object_mut() {
+        json.insert("version".to_string(), CURRENT_CONFIG_VERSION.into());
+    }
+
+    Ok(json)
+}
+
+/// The `key` section of the config, or its default if it's left out
+fn section<T: DeserializeOwned + Default>(json: &Value, key: &str) -> Result<T, Error> {
+    section_or(json, key, T::default)
+}
+
+/// The `key` section of the config, or `default` if it's left out
+fn section_or<T: DeserializeOwned>(
+    json: &Value,
+    key: &str,
+    default: impl FnOnce() -> T,
+) -> Result<T, Error> {
+    match json.get(key) {
+        Some(value) => serde_json::from_value(value.clone())
+            .map_err(|e| Error::msg(format!("Invalid {} section: {}", key, e))),
+        None => Ok(default()),
+    }
+}
+
+/// Lay `profiles.<name>` over the rest of the config, so a profile only needs
+/// the settings it changes. Objects are merged key by key, anything else is
+/// replaced.
+fn apply_profile(json: &mut Value, name: &str) -> Result<(), Error> {
+    let profile = json
+        .get("profiles")
+        .and_then(|profiles| profiles.get(name))
+        .cloned()
+        .ok_or_else(|| Error::msg(format!("There's no {} profile in the config", name)))?;
+    merge_json(json, profile);
+    Ok(())
+}
+
+fn merge_json(base: &mut Value, over: Value) {
+    match (base, over) {
+        (Value::Object(base), Value::Object(over)) => {
+            for (key, value) in over {
+                merge_json(base.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+/// Lights are addressed by their `id`, so one that's missing or isn't a
+/// number can't be left to default
+fn light_id(key: &str, value: &Value) -> Result<u8, Error> {
+    match value.get("id") {
+        None => Err(Error::msg(format!("{} has no id", key))),
+        Some(id) => id
+            .as_u64()
+            .and_then(|id| u8::try_from(id).ok())
+            .ok_or_else(|| Error::msg(format!("{} has an invalid id {}", key, id))),
+    }
+}
+
+/// v1 to v2: lights are addressed by their `id` instead of where they sit in
+/// the file. Lights without one take the number from their `light-N` key.
+fn migrate_light_ids(mut json: Value) -> Value {
+    if let Some(entries) = json.as_object_mut() {
+        for (key, value) in entries.iter_mut() {
+            let Some(id) = key
+                .strip_prefix("light-")
+                .and_then(|id| id.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            if let Some(light) = value.as_object_mut() {
+                light.entry("id").or_insert(id.into());
+            }
         }
     }
+
+    json
+}
+
+/// Make sure a DMX address is inside of the universe
+pub fn validate_dmx_channel(channel: DmxStateIndex) -> Result<(), Error> {
+    if !(1..=DMX_CHANNELS).contains(&(channel as usize)) {
+        return Err(Error::msg(format!(
+            "DMX channel {} is outside of 1..={}",
+            channel, DMX_CHANNELS
+        )));
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -230,34 +1803,63 @@ mod tests {
         assert_eq!(
             config,
             Config {
+                version: 1,
                 lights: vec![
                     Light {
                         pin: Pin::Physical(pi_pinout::PhysicalPin(8)),
                         id: 1,
+                        name: None,
+                        pwm: false,
+                        inverted: None,
+                        min_toggle_interval_ms: 0,
                     },
                     Light {
                         pin: Pin::Physical(pi_pinout::PhysicalPin(10)),
                         id: 2,
+                        name: None,
+                        pwm: false,
+                        inverted: None,
+                        min_toggle_interval_ms: 0,
                     },
                     Light {
                         pin: Pin::Physical(pi_pinout::PhysicalPin(16)),
                         id: 3,
+                        name: None,
+                        pwm: false,
+                        inverted: None,
+                        min_toggle_interval_ms: 0,
                     },
                     Light {
                         pin: Pin::Physical(pi_pinout::PhysicalPin(18)),
                         id: 4,
+                        name: None,
+                        pwm: false,
+                        inverted: None,
+                        min_toggle_interval_ms: 0,
                     },
                     Light {
                         pin: Pin::Physical(pi_pinout::PhysicalPin(22)),
                         id: 5,
+                        name: None,
+                        pwm: false,
+                        inverted: None,
+                        min_toggle_interval_ms: 0,
                     },
                     Light {
                         pin: Pin::Physical(pi_pinout::PhysicalPin(24)),
                         id: 6,
+                        name: None,
+                        pwm: false,
+                        inverted: None,
+                        min_toggle_interval_ms: 0,
                     },
                     Light {
                         pin: Pin::Physical(pi_pinout::PhysicalPin(26)),
                         id: 7,
+                        name: None,
+                        pwm: false,
+                        inverted: None,
+                        min_toggle_interval_ms: 0,
                     },
                 ],
                 lasers: vec![Laser { id: 1 }, Laser { id: 2 },],
@@ -281,6 +1883,28 @@ mod tests {
                         format: vec!["pan".to_string(), "tilt".to_string(), "state".to_string(),],
                     },
                 ],
+                turret_triggers: Vec::new(),
+                audio: AudioConfig::default(),
+                dmx: DmxConfig::default(),
+                laser: LaserConfig::default(),
+                light: LightConfig::default(),
+                safety: SafetyConfig::default(),
+                inputs: Vec::new(),
+                recording: RecordingConfig::default(),
+                uart: UartConfig::default(),
+                console: ConsoleConfig::default(),
+                schedule: ScheduleConfig::default(),
+                playlist: PlaylistConfig::default(),
+                stats: StatsConfig::default(),
+                storage: StorageConfig::default(),
+                show_server: None,
+                health: None,
+                dashboard: None,
+                logging: LoggingConfig::default(),
+                shows_dir: PathBuf::from("shows"),
+                transition_ms: 3000,
+                time_scale: 1.0,
+                mode: RunMode::Hardware,
             }
         );
     }
@@ -299,6 +1923,10 @@ mod tests {
         // Check a few specific items
         assert_eq!(config.lights[0].pin, Pin::Physical(PhysicalPin(28)));
         assert_eq!(config.lights[0].id, 1);
+        assert!(config
+            .lights
+            .iter()
+            .all(|light| light.inverted == Some(true)));
         assert_eq!(config.lasers[0].id, 1);
         assert_eq!(config.projectors[0].id, 1);
         assert_eq!(
@@ -311,4 +1939,536 @@ mod tests {
         assert_eq!(config.turrets[0].id, 41);
         assert_eq!(config.turrets[0].format, vec!["pan", "tilt", "state"]);
     }
+
+    #[test]
+    fn test_dmx_startup_and_blackout() {
+        let config = Config::from_json(
+            r#"{
+                "dmx": {
+                    "startup": { "10": 128 },
+                    "blackout": { "1": 0, "10": 64 },
+                    "zero_out": [1, 2, 3]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.dmx.startup, BTreeMap::from([(10, 128)]));
+        assert_eq!(config.dmx.blackout, BTreeMap::from([(1, 0), (10, 64)]));
+        assert_eq!(config.dmx.zero_out, Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_dmx_channel_out_of_range() {
+        for channel in ["0", "513", "600"] {
+            let json = format!(r#"{{ "dmx": {{ "startup": {{ "{}": 1 }} }} }}"#, channel);
+            assert!(Config::from_json(&json).is_err(), "channel {}", channel);
+
+            let json = format!(r#"{{ "dmx": {{ "zero_out": [{}] }} }}"#, channel);
+            assert!(Config::from_json(&json).is_err(), "channel {}", channel);
+        }
+
+        // Configs built from RON go through the same validation
+        let config = Config {
+            dmx: DmxConfig {
+                blackout: BTreeMap::from([(0, 0)]),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_dmx_scenes() {
+        let config = Config::from_json(
+            r#"{
+                "dmx": {
+                    "scenes": {
+                        "graveyard blue": { "1": 0, "12": 200 },
+                        "lightning flash": { "1": 255 }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.dmx.scenes["graveyard blue"],
+            BTreeMap::from([(1, 0), (12, 200)])
+        );
+        assert_eq!(
+            config.dmx.scenes["lightning flash"],
+            BTreeMap::from([(1, 255)])
+        );
+
+        assert!(Config::from_json(r#"{ "dmx": { "scenes": { "bad": { "0": 1 } } } }"#).is_err());
+    }
+
+    #[test]
+    fn test_dmx_artnet_config() {
+        let config = Config::from_json(
+            r#"{ "dmx": { "artnet": { "ip": "192.168.1.50", "universe": 3 } } }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.dmx.artnet,
+            Some(ArtNetConfig {
+                ip: "192.168.1.50".parse().unwrap(),
+                port: 6454,
+                universe: 3,
+                uart: false,
+            })
+        );
+        assert_eq!(Config::from_json("{}").unwrap().dmx.artnet, None);
+
+        assert!(Config::from_json(
+            r#"{ "dmx": { "artnet": { "ip": "192.168.1.50", "universe": 32768 } } }"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_laser_transfer_size() {
+        assert_eq!(Config::from_json("{}").unwrap().laser.transfer_size, 51);
+        assert_eq!(
+            Config::from_json(r#"{ "laser": { "transfer_size": 65 } }"#)
+                .unwrap()
+                .laser
+                .transfer_size,
+            65
+        );
+        assert!(Config::from_json(r#"{ "laser": { "transfer_size": 0 } }"#).is_err());
+    }
+
+    #[test]
+    fn test_dmx_header_config() {
+        let config =
+            Config::from_json(r#"{ "dmx": { "controller_id": 11, "universe": 2 } }"#).unwrap();
+        assert_eq!(config.dmx.controller_id, 0xB);
+        assert_eq!(config.dmx.universe, 2);
+
+        // Defaults to the first controller
+        let config = Config::from_json("{}").unwrap();
+        assert_eq!(config.dmx.controller_id, 0xA);
+        assert_eq!(config.dmx.universe, 0);
+
+        assert!(Config::from_json(r#"{ "dmx": { "controller_id": 1 } }"#).is_err());
+        assert!(Config::from_json(r#"{ "dmx": { "universe": 16 } }"#).is_err());
+    }
+
+    #[test]
+    fn test_light_pwm() {
+        let config = Config::from_json(
+            r#"{
+                "light-1": { "protocol": "GPIO", "pin": 12, "id": 1, "pwm": true },
+                "light-2": { "protocol": "GPIO", "pin": 15, "id": 2 }
+            }"#,
+        )
+        .unwrap();
+
+        // Relays are the default
+        assert!(config.lights[0].pwm);
+        assert!(!config.lights[1].pwm);
+    }
+
+    #[test]
+    fn test_light_inverted() {
+        let config = Config::from_json(
+            r#"{
+                "light-1": { "protocol": "GPIO", "pin": 12, "id": 1, "inverted": false },
+                "light-2": { "protocol": "GPIO", "pin": 15, "id": 2 }
+            }"#,
+        )
+        .unwrap();
+
+        // Lights are active low unless they say otherwise
+        assert!(!config.lights[0].inverted());
+        assert_eq!(config.lights[1].inverted, None);
+        assert!(config.lights[1].inverted());
+    }
+
+    #[test]
+    fn test_light_groups() {
+        let config = Config::from_json(
+            r#"{
+                "light-1": { "protocol": "GPIO", "pin": 12, "id": 1 },
+                "light-2": { "protocol": "GPIO", "pin": 15, "id": 2 },
+                "light": { "groups": { "pumpkin": [1, 2] } }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.light.groups["pumpkin"], vec![1, 2]);
+
+        // Every member has to be a configured light
+        let err = Config::from_json(
+            r#"{
+                "light-1": { "protocol": "GPIO", "pin": 12, "id": 1 },
+                "light": { "groups": { "pumpkin": [1, 3] } }
+            }"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("light 3"), "{}", err);
+    }
+
+    #[test]
+    fn test_light_on_expander() {
+        let config = Config::from_json(
+            r#"{
+                "light-9": { "protocol": "I2C", "bus": 1, "address": 33, "pin": 12, "id": 9 }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.lights[0].pin,
+            Pin::I2cExpander {
+                bus: 1,
+                address: 0x21,
+                pin: 12
+            }
+        );
+
+        assert!(Config::from_json(
+            r#"{ "light-9": { "protocol": "I2C", "address": 33, "pin": 16, "id": 9 } }"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_migrate_v1_unversioned() {
+        let config = Config::load_from_json("tests/fixtures/config/v1-unversioned.json").unwrap();
+
+        assert_eq!(config.version, CURRENT_CONFIG_VERSION);
+        assert_eq!(config.lights.len(), 2);
+        assert_eq!(config.lasers.len(), 1);
+        assert_eq!(config.turrets[0].format, vec!["pan", "tilt", "state"]);
+    }
+
+    #[test]
+    fn test_migrate_v1() {
+        let unversioned =
+            Config::load_from_json("tests/fixtures/config/v1-unversioned.json").unwrap();
+        let versioned = Config::load_from_json("tests/fixtures/config/v1.json").unwrap();
+
+        assert_eq!(unversioned, versioned);
+    }
+
+    #[test]
+    fn test_v2_is_current() {
+        let path = "tests/fixtures/config/v2.json";
+        let json: Value = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(migrate_config(json.clone()).unwrap(), json);
+
+        // Only the light's name differs from the v1 fixture
+        let config = Config::load_from_json(path).unwrap();
+        let mut v1 = Config::load_from_json("tests/fixtures/config/v1.json").unwrap();
+        assert_eq!(config.version, 2);
+        assert_eq!(config.lights[1].name.as_deref(), Some("light-porch"));
+        v1.lights[1].name = Some("light-porch".to_string());
+        assert_eq!(config, v1);
+    }
+
+    #[test]
+    fn test_migrate_light_ids() {
+        // v1 lights could leave their ID out
+        let config =
+            Config::from_json(r#"{ "version": 1, "light-4": { "protocol": "GPIO", "pin": 16 } }"#)
+                .unwrap();
+        assert_eq!(config.lights[0].id, 4);
+
+        // But from v2 they're needed, and can't be shared
+        let error =
+            Config::from_json(r#"{ "version": 2, "light-4": { "protocol": "GPIO", "pin": 16 } }"#)
+                .unwrap_err();
+        assert_eq!(error.to_string(), "light-4 has no id");
+        let error = Config::from_json(
+            r#"{ "version": 2, "light-4": { "protocol": "I2C", "pin": 3, "id": "four" } }"#,
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "light-4 has an invalid id \"four\"");
+        assert!(Config::from_json(
+            r#"{
+                "light-1": { "protocol": "GPIO", "id": 1, "pin": 16 },
+                "light-2": { "protocol": "GPIO", "id": 1, "pin": 18 }
+            }"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_schedule() {
+        let config = Config::from_json(
+            r#"{
+                "schedule": {
+                    "enabled": true,
+                    "start": "19:30",
+                    "end": "01:00",
+                    "days": ["Fri", "sat"],
+                    "timezone": "-04:00",
+                    "closing_show": "goodnight",
+                    "blocks": [
+                        { "start": "19:30", "end": "20:30", "include": ["kids"] }
+                    ]
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.schedule.start,
+            NaiveTime::from_hms_opt(19, 30, 0).unwrap()
+        );
+        assert_eq!(config.schedule.days, vec![Weekday::Fri, Weekday::Sat]);
+        assert_eq!(config.schedule.closing_show.as_deref(), Some("goodnight"));
+        assert_eq!(config.schedule.blocks[0].include, vec!["kids".to_string()]);
+        assert!(config.schedule.blocks[0].exclude.is_empty());
+
+        assert!(Config::from_json(r#"{ "schedule": { "start": "22:00" } }"#).is_err());
+        assert!(Config::from_json(r#"{ "schedule": { "timezone": "EST" } }"#).is_err());
+    }
+
+    #[test]
+    fn test_playlist() {
+        let config = Config::from_json(
+            r#"{
+                "playlist": {
+                    "shows": [{ "name": "thriller" }, { "name": "finale", "weight": 3 }],
+                    "min_gap": 2,
+                    "seed": 31
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.playlist.shows[0].weight, 1);
+        assert_eq!(config.playlist.shows[1].weight, 3);
+        assert!(!config.playlist.ordered);
+        assert_eq!(config.playlist.seed, Some(31));
+        assert_eq!(Config::default().playlist.seed, None);
+
+        assert!(Config::from_json(
+            r#"{ "playlist": { "shows": [{ "name": "finale", "weight": 0 }] } }"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_profiles() {
+        let json = r#"{
+            "uart": { "device": "/dev/ttyAMA0", "baud": 57600 },
+            "time_scale": 1.0,
+            "profiles": {
+                "bench": {
+                    "uart": { "device": "/dev/ttyUSB0" },
+                    "laser": { "simulate": true },
+                    "time_scale": 4.0
+                }
+            }
+        }"#;
+
+        let config = Config::from_json(json).unwrap();
+        assert_eq!(config.uart.device, "/dev/ttyAMA0");
+        assert!(!config.laser.simulate);
+
+        // Only what the profile sets changes
+        let config = Config::from_json_profile(json, Some("bench")).unwrap();
+        assert_eq!(config.uart.device, "/dev/ttyUSB0");
+        assert_eq!(config.uart.baud, 57600);
+        assert!(config.laser.simulate);
+        assert_eq!(config.time_scale, 4.0);
+
+        let error = Config::from_json_profile(json, Some("show")).unwrap_err();
+        assert_eq!(error.to_string(), "There's no show profile in the config");
+    }
+
+    #[test]
+    fn test_logging() {
+        let config = Config::from_json(
+            r#"{
+                "logging": {
+                    "format": "json",
+                    "levels": { "rusty_halloween::uart": "debug" },
+                    "file": { "keep": 2 }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.logging.format, LogFormat::Json);
+        assert_eq!(config.logging.level, "info");
+        assert_eq!(config.logging.levels.len(), 1);
+        let file = config.logging.file.unwrap();
+        assert_eq!(file.dir, PathBuf::from("data/logs"));
+        assert_eq!(file.keep, 2);
+
+        // Quiet by default
+        assert_eq!(
+            Config::default().logging.levels["symphonia_core::probe"],
+            "off"
+        );
+
+        let error = Config::from_json(r#"{ "logging": { "level": "loud" } }"#).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "logging.level \"loud\" isn't a log level"
+        );
+    }
+
+    #[test]
+    fn test_health() {
+        assert_eq!(Config::default().health, None);
+
+        let config = Config::from_json(r#"{ "health": { "bind": "127.0.0.1:8081" } }"#).unwrap();
+        let health = config.health.unwrap();
+        assert_eq!(health.bind, "127.0.0.1:8081".parse().unwrap());
+        assert_eq!(health.stale_ms, 5000);
+
+        let error =
+            Config::from_json(r#"{ "health": { "bind": "127.0.0.1:8081", "stale_ms": 10 } }"#)
+                .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("health.stale_ms has to be longer"));
+    }
+
+    #[test]
+    fn test_inputs() {
+        let config = Config::from_json(
+            r#"{
+                "inputs": [
+                    { "name": "big red button", "pin": 11, "action": { "PrepareShow": "finale" } },
+                    {
+                        "name": "porch",
+                        "pin": 13,
+                        "edge": "rising",
+                        "min_interval_ms": 60000,
+                        "action": { "TurretFire": { "turret_id": 1 } }
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.inputs[0],
+            InputConfig {
+                name: "big red button".to_string(),
+                pin: 11,
+                edge: InputEdge::Falling,
+                debounce_ms: 50,
+                min_interval_ms: 30_000,
+                action: InputAction::PrepareShow("finale".to_string()),
+            }
+        );
+        assert_eq!(config.inputs[1].edge, InputEdge::Rising);
+        assert_eq!(config.inputs[1].min_interval_ms, 60_000);
+
+        let error = Config::from_json(
+            r#"{
+                "safety": { "estop_pin": 11 },
+                "inputs": [{ "name": "button", "pin": 11, "action": { "PrepareShow": "finale" } }]
+            }"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Input button is on pin 11, which is already in use"
+        );
+
+        let error = Config::from_json(
+            r#"{ "inputs": [{
+                "name": "button",
+                "pin": 11,
+                "action": { "LightEffect": { "light_id": 4, "effect": { "FlickerCandle": { "intensity": 0.5 } } } }
+            }] }"#,
+        )
+        .unwrap_err();
+        assert!(error.to_string().ends_with("which isn't in the config"));
+    }
+
+    #[test]
+    fn test_storage() {
+        assert_eq!(Config::default().storage.min_free_mb, 500);
+
+        let config = Config::from_json(r#"{ "storage": { "min_free_mb": 50 } }"#).unwrap();
+        assert_eq!(config.storage.min_free_mb, 50);
+        assert!(Config::from_json(r#"{ "storage": { "min_free_mb": -1 } }"#).is_err());
+    }
+
+    #[test]
+    fn test_mode() {
+        assert_eq!(Config::default().mode, RunMode::Hardware);
+
+        let config = Config::from_json(r#"{ "mode": "simulate" }"#).unwrap();
+        assert!(config.mode.is_simulated());
+        assert!(Config::from_json(r#"{ "mode": "pretend" }"#).is_err());
+    }
+
+    #[test]
+    fn test_subsystems() {
+        // Everything's on, apart from the servers that aren't set up
+        assert_eq!(
+            Config::default().subsystems(),
+            BTreeSet::from([
+                Subsystem::Audio,
+                Subsystem::Dmx,
+                Subsystem::Lasers,
+                Subsystem::Lights
+            ])
+        );
+
+        // A test bench without a DMX interface or speakers
+        let config = Config::from_json(
+            r#"{
+                "audio": { "enabled": false },
+                "dmx": { "enabled": false },
+                "dashboard": { "bind": "127.0.0.1:8080" },
+                "show_server": { "url": "http://127.0.0.1:9000", "enabled": false }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.subsystems(),
+            BTreeSet::from([Subsystem::Lasers, Subsystem::Lights, Subsystem::Dashboard])
+        );
+
+        // Only the shows' timing, with nothing to drive
+        let config = Config::from_json(
+            r#"{
+                "laser": { "enabled": false },
+                "light": { "enabled": false },
+                "dmx": { "enabled": false }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.subsystems(), BTreeSet::from([Subsystem::Audio]));
+
+        // A dashboard that's set up but turned off isn't served
+        let config = Config::from_json(
+            r#"{
+                "laser": { "enabled": false },
+                "dashboard": { "bind": "127.0.0.1:8080", "enabled": false },
+                "show_server": { "url": "http://127.0.0.1:9000" }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.subsystems(),
+            BTreeSet::from([
+                Subsystem::Audio,
+                Subsystem::Dmx,
+                Subsystem::Lights,
+                Subsystem::ShowServer
+            ])
+        );
+        assert!(Config::from_json(r#"{ "audio": { "enabled": "no" } }"#).is_err());
+    }
+
+    #[test]
+    fn test_config_too_new() {
+        let error = Config::from_json(&format!(
+            r#"{{ "version": {} }}"#,
+            CURRENT_CONFIG_VERSION + 1
+        ))
+        .unwrap_err();
+
+        assert!(error.to_string().contains("newer than this build supports"));
+    }
 }
@@ -0,0 +1,409 @@
+use std::{str::FromStr, time::Duration};
+
+use anyhow::Error;
+use log::{error, info};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::{ConsoleConfig, RunMode},
+    show::prelude::{DmxStateData, DmxStateIndex, ShowControl, SnapshotReply},
+    uart::{self, port::SerialPort},
+    InternalMessage, MessageKind,
+};
+
+/// How often the console port is read
+pub const CONSOLE_POLL: Duration = Duration::from_millis(20);
+
+/// How long to wait on the show worker for the queue
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(2);
+
+const USAGE: &str = "commands:
+  light <id> on|off|<0-255>
+  lights off
+  dmx <channel> <0-255>
+  dmx zero|blackout
+  scene <name>
+  show pause|resume|skip|start|stop|rescan
+  show speed <scale>
+  queue
+  queue clear|remove <index>
+  home
+  init
+  laser on|off
+  fire <turret>
+  rearm
+  help";
+
+/// A line typed into the console
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConsoleCommand {
+    Light {
+        light_id: u8,
+        enable: bool,
+    },
+    LightLevel {
+        light_id: u8,
+        level: u8,
+    },
+    AllLightsOff,
+    Dmx {
+        channel: DmxStateIndex,
+        value: DmxStateData,
+    },
+    DmxZeroOut,
+    DmxBlackout,
+    DmxScene(String),
+    Show(ShowControl),
+    /// Print what's in the show queue
+    Queue,
+    LaserHome,
+    LaserEnable(bool),
+    TurretFire(u8),
+    Rearm,
+    Help,
+}
+
+impl ConsoleCommand {
+    pub fn parse(line: &str) -> Result<Self, Error> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default().to_lowercase();
+        let mut arg = || words.next();
+
+        let parsed = match command.as_str() {
+            "light" => {
+                let light_id = number(arg(), "light id")?;
+                match arg() {
+                    Some("on") => ConsoleCommand::Light {
+                        light_id,
+                        enable: true,
+                    },
+                    Some("off") => ConsoleCommand::Light {
+                        light_id,
+                        enable: false,
+                    },
+                    level => ConsoleCommand::LightLevel {
+                        light_id,
+                        level: number(level, "on, off or a level")?,
+                    },
+                }
+            }
+            "lights" => match arg() {
+                Some("off") => ConsoleCommand::AllLightsOff,
+                _ => return Err(Error::msg("lights only takes off")),
+            },
+            "dmx" => match arg() {
+                Some("zero") => ConsoleCommand::DmxZeroOut,
+                Some("blackout") => ConsoleCommand::DmxBlackout,
+                channel => ConsoleCommand::Dmx {
+                    channel: number(channel, "DMX channel")?,
+                    value: number(arg(), "DMX value")?,
+                },
+            },
+            "scene" => match arg() {
+                Some(name) => ConsoleCommand::DmxScene(name.to_string()),
+                None => return Err(Error::msg("missing scene name")),
+            },
+            "show" => match arg() {
+                Some("pause") => ConsoleCommand::Show(ShowControl::Pause),
+                Some("resume") => ConsoleCommand::Show(ShowControl::Resume),
+                Some("skip") => ConsoleCommand::Show(ShowControl::Skip),
+                Some("start") => ConsoleCommand::Show(ShowControl::Start),
+                Some("stop") => ConsoleCommand::Show(ShowControl::Stop),
+                Some("rescan") => ConsoleCommand::Show(ShowControl::Rescan),
+                Some("speed") => {
+                    ConsoleCommand::Show(ShowControl::SetTimeScale(number(arg(), "time scale")?))
+                }
+                _ => {
+                    return Err(Error::msg(
+                        "show takes pause, resume, skip, start, stop, rescan or speed",
+                    ))
+                }
+            },
+            "queue" => match arg() {
+                None => ConsoleCommand::Queue,
+                Some("clear") => ConsoleCommand::Show(ShowControl::ClearQueue),
+                Some("remove") => {
+                    ConsoleCommand::Show(ShowControl::RemoveAt(number(arg(), "queue index")?))
+                }
+                Some(_) => return Err(Error::msg("queue takes clear or remove")),
+            },
+            "home" => ConsoleCommand::LaserHome,
+            "init" => ConsoleCommand::Show(ShowControl::Init),
+            "laser" => match arg() {
+                Some("on") => ConsoleCommand::LaserEnable(true),
+                Some("off") => ConsoleCommand::LaserEnable(false),
+                _ => return Err(Error::msg("laser takes on or off")),
+            },
+            "fire" => ConsoleCommand::TurretFire(number(arg(), "turret id")?),
+            "rearm" => ConsoleCommand::Rearm,
+            "help" => ConsoleCommand::Help,
+            command => return Err(Error::msg(format!("unknown command {:?}", command))),
+        };
+
+        match arg() {
+            Some(extra) => Err(Error::msg(format!("unexpected {:?}", extra))),
+            None => Ok(parsed),
+        }
+    }
+
+    /// What gets sent to the main queue
+    pub fn into_messages(self) -> Vec<InternalMessage> {
+        match self {
+            ConsoleCommand::Light { light_id, enable } => {
+                vec![InternalMessage::Light { light_id, enable }]
+            }
+            ConsoleCommand::LightLevel { light_id, level } => {
+                vec![InternalMessage::LightLevel { light_id, level }]
+            }
+            ConsoleCommand::AllLightsOff => {
+                // Nothing waits for the lights
+                let (done, _) = oneshot::channel();
+                vec![InternalMessage::AllLightsOff(done)]
+            }
+            ConsoleCommand::Dmx { channel, value } => vec![
+                InternalMessage::DmxUpdateState(vec![(channel, value)]),
+                InternalMessage::DmxSendRequest,
+            ],
+            ConsoleCommand::DmxZeroOut => vec![InternalMessage::DmxZeroOut],
+            ConsoleCommand::DmxBlackout => vec![InternalMessage::DmxBlackout],
+            ConsoleCommand::DmxScene(name) => vec![InternalMessage::DmxScene(name)],
+            ConsoleCommand::Show(control) => vec![InternalMessage::ShowControl(control)],
+            ConsoleCommand::LaserHome => vec![InternalMessage::LaserHome],
+            ConsoleCommand::LaserEnable(enable) => vec![InternalMessage::LaserEnable(enable)],
+            ConsoleCommand::TurretFire(turret_id) => {
+                vec![InternalMessage::TurretFire { turret_id }]
+            }
+            ConsoleCommand::Rearm => vec![InternalMessage::OutputsRearm],
+            // Both are answered straight from the console
+            ConsoleCommand::Queue | ConsoleCommand::Help => Vec::new(),
+        }
+    }
+}
+
+fn number<T: FromStr>(word: Option<&str>, what: &str) -> Result<T, Error> {
+    let word = word.ok_or_else(|| Error::msg(format!("missing {}", what)))?;
+    word.parse()
+        .map_err(|_| Error::msg(format!("{:?} isn't a valid {}", word, what)))
+}
+
+/// Read commands off the console port until it fails, answering each one
+pub async fn run(
+    config: &ConsoleConfig,
+    message_queue: mpsc::Sender<MessageKind>,
+    cancel: CancellationToken,
+) -> Result<(), Error> {
+    // It's how someone gets at the controller, so it's opened even when
+    // simulating
+    let mut port = uart::open_port(&config.port, RunMode::Hardware)?;
+    info!("Console listening on {}", config.port.device);
+    reply(
+        port.as_mut(),
+        "rusty-halloween console, type help for commands\n",
+    )?;
+
+    let mut poll = tokio::time::interval(CONSOLE_POLL);
+    let mut line = Vec::new();
+    loop {
+        tokio::select! {
+            _ = poll.tick() => {}
+            _ = cancel.cancelled() => return Ok(()),
+        }
+
+        let mut buf = [0; 64];
+        let read = port.read(&mut buf, Duration::ZERO)?;
+        for byte in &buf[..read] {
+            match byte {
+                b'\r' | b'\n' => {
+                    reply(port.as_mut(), "\n")?;
+                    let typed = String::from_utf8_lossy(&line).to_string();
+                    line.clear();
+                    let answer = handle_line(&typed, &message_queue).await;
+                    if let Some(answer) = answer {
+                        reply(port.as_mut(), &format!("{}\n", answer))?;
+                    }
+                }
+                // Backspace and delete
+                0x08 | 0x7F => {
+                    if line.pop().is_some() {
+                        reply(port.as_mut(), "\x08 \x08")?;
+                    }
+                }
+                byte => {
+                    line.push(*byte);
+                    port.write(&[*byte])?;
+                    port.drain()?;
+                }
+            }
+        }
+    }
+}
+
+async fn handle_line(line: &str, message_queue: &mpsc::Sender<MessageKind>) -> Option<String> {
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+
+    let command = match ConsoleCommand::parse(line) {
+        Ok(ConsoleCommand::Help) => return Some(USAGE.to_string()),
+        Ok(ConsoleCommand::Queue) => return Some(queue_snapshot(message_queue).await),
+        Ok(command) => command,
+        Err(e) => return Some(format!("{}\n{}", e, USAGE)),
+    };
+
+    info!("Console: {}", line);
+    for message in command.into_messages() {
+        if message_queue
+            .send(MessageKind::InternalMessage(message))
+            .await
+            .is_err()
+        {
+            error!("The message queue has stopped");
+            return Some("error: the message queue has stopped".to_string());
+        }
+    }
+
+    Some("ok".to_string())
+}
+
+/// Ask the show worker what's in its queue, one element to a line
+async fn queue_snapshot(message_queue: &mpsc::Sender<MessageKind>) -> String {
+    let (reply, answer) = SnapshotReply::new();
+    if message_queue
+        .send(MessageKind::InternalMessage(InternalMessage::ShowControl(
+            ShowControl::QueueSnapshot(reply),
+        )))
+        .await
+        .is_err()
+    {
+        error!("The message queue has stopped");
+        return "error: the message queue has stopped".to_string();
+    }
+
+    match tokio::time::timeout(QUEUE_TIMEOUT, answer).await {
+        Ok(Ok(elements)) if elements.is_empty() => "the queue is empty".to_string(),
+        Ok(Ok(elements)) => elements
+            .iter()
+            .map(|element| format!("{}: {}", element.index, element.description))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => "error: the show worker didn't answer".to_string(),
+    }
+}
+
+/// Terminals want a carriage return with every newline
+fn reply(port: &mut dyn SerialPort, text: &str) -> Result<(), Error> {
+    port.write(text.replace('\n', "\r\n").as_bytes())?;
+    port.drain()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for (line, command) in [
+            (
+                "light 3 on",
+                ConsoleCommand::Light {
+                    light_id: 3,
+                    enable: true,
+                },
+            ),
+            (
+                "LIGHT 3 off",
+                ConsoleCommand::Light {
+                    light_id: 3,
+                    enable: false,
+                },
+            ),
+            (
+                "light 4 128",
+                ConsoleCommand::LightLevel {
+                    light_id: 4,
+                    level: 128,
+                },
+            ),
+            ("lights off", ConsoleCommand::AllLightsOff),
+            (
+                "  dmx 10   255 ",
+                ConsoleCommand::Dmx {
+                    channel: 10,
+                    value: 255,
+                },
+            ),
+            ("dmx zero", ConsoleCommand::DmxZeroOut),
+            ("dmx blackout", ConsoleCommand::DmxBlackout),
+            (
+                "scene spooky",
+                ConsoleCommand::DmxScene("spooky".to_string()),
+            ),
+            ("show skip", ConsoleCommand::Show(ShowControl::Skip)),
+            (
+                "show speed 4",
+                ConsoleCommand::Show(ShowControl::SetTimeScale(4.0)),
+            ),
+            ("queue", ConsoleCommand::Queue),
+            ("queue clear", ConsoleCommand::Show(ShowControl::ClearQueue)),
+            (
+                "queue remove 2",
+                ConsoleCommand::Show(ShowControl::RemoveAt(2)),
+            ),
+            ("home", ConsoleCommand::LaserHome),
+            ("init", ConsoleCommand::Show(ShowControl::Init)),
+            ("laser off", ConsoleCommand::LaserEnable(false)),
+            ("fire 2", ConsoleCommand::TurretFire(2)),
+            ("rearm", ConsoleCommand::Rearm),
+            ("help", ConsoleCommand::Help),
+        ] {
+            assert_eq!(ConsoleCommand::parse(line).unwrap(), command, "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for line in [
+            "jump",
+            "light",
+            "light three on",
+            "light 3 dim",
+            "light 3 256",
+            "dmx 10",
+            "dmx 10 255 1",
+            "laser",
+            "fire",
+            "show halt",
+            "queue remove",
+            "queue shuffle",
+            "home now",
+        ] {
+            assert!(ConsoleCommand::parse(line).is_err(), "{}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_command_prints_usage() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let answer = handle_line("jump", &tx).await.unwrap();
+        assert!(answer.starts_with("unknown command \"jump\""));
+        assert!(answer.ends_with(USAGE));
+        assert!(rx.try_recv().is_err());
+
+        assert_eq!(handle_line("dmx 10 255", &tx).await.unwrap(), "ok");
+        let Ok(MessageKind::InternalMessage(InternalMessage::DmxUpdateState(values))) =
+            rx.try_recv()
+        else {
+            panic!("Expected a DMX update");
+        };
+        assert_eq!(values, vec![(10, 255)]);
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(MessageKind::InternalMessage(
+                InternalMessage::DmxSendRequest
+            ))
+        ));
+        assert!(handle_line("  ", &tx).await.is_none());
+    }
+}
@@ -0,0 +1,508 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Error;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Html,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use log::{error, warn};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, mpsc, oneshot},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::StatsConfig,
+    dmx::DmxHandle,
+    health::{laser_json, laser_stats, within, NowPlaying},
+    laser::{LaserMessage, ALL_LASERS},
+    lights::LightState,
+    recorder::{RecordedEntry, RecordedMessage},
+    show::prelude::{ShowControl, ShowElementSummary, ShowStats, SnapshotReply},
+    supervisor::TaskSender,
+    InternalMessage, MessageKind,
+};
+
+/// The whole page, which polls `/api/state`, posts to `/api/control` and
+/// deletes from `/api/queue`
+const PAGE: &str = include_str!("index.html");
+
+/// How many events the page lists
+const EVENTS_KEPT: usize = 50;
+
+/// How far the show that's playing is, from its last progress report
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct Progress {
+    name: String,
+    elapsed_ms: u64,
+    total_ms: u64,
+}
+
+/// What's been picked up from the copies of the messages
+#[derive(Debug, Default)]
+struct Watched {
+    progress: Option<Progress>,
+    /// Newest last, leaving out frames and anything else a show sends many
+    /// of
+    events: VecDeque<String>,
+}
+
+impl Watched {
+    fn note(&mut self, entry: RecordedEntry) {
+        let event = match entry.message {
+            RecordedMessage::ShowProgress {
+                name,
+                elapsed_ms,
+                total_ms,
+                ..
+            } => {
+                self.progress = Some(Progress {
+                    name,
+                    elapsed_ms,
+                    total_ms,
+                });
+                return;
+            }
+            RecordedMessage::ShowStarted(name) => {
+                let event = format!("Started {}", name);
+                self.progress = Some(Progress {
+                    name,
+                    elapsed_ms: 0,
+                    total_ms: 0,
+                });
+                event
+            }
+            // The panels show where these end up
+            RecordedMessage::Laser(_)
+            | RecordedMessage::LaserBatch(_)
+            | RecordedMessage::DmxUpdateState(_)
+            | RecordedMessage::DmxSendRequest
+            | RecordedMessage::FrameFlush { .. }
+            | RecordedMessage::Light { .. }
+            | RecordedMessage::LightLevel { .. } => return,
+            message => format!("{:?}", message),
+        };
+
+        self.events
+            .push_back(format!("{} {}", entry.time.format("%H:%M:%S"), event));
+        if self.events.len() > EVENTS_KEPT {
+            self.events.pop_front();
+        }
+    }
+}
+
+/// A web page for keeping an eye on the shows and stepping in. Its buttons
+/// send `InternalMessage`s on the message queue like anything else.
+#[derive(Clone)]
+pub struct Dashboard {
+    message_queue: mpsc::Sender<MessageKind>,
+    lasers: TaskSender<LaserMessage>,
+    dmx: DmxHandle,
+    now_playing: NowPlaying,
+    stats: StatsConfig,
+    watched: Arc<Mutex<Watched>>,
+}
+
+impl Dashboard {
+    pub fn new(
+        message_queue: mpsc::Sender<MessageKind>,
+        lasers: impl Into<TaskSender<LaserMessage>>,
+        dmx: DmxHandle,
+        now_playing: NowPlaying,
+        stats: StatsConfig,
+    ) -> Self {
+        Dashboard {
+            message_queue,
+            lasers: lasers.into(),
+            dmx,
+            now_playing,
+            stats,
+            watched: Arc::new(Mutex::new(Watched::default())),
+        }
+    }
+
+    pub fn router(self) -> Router {
+        Router::new()
+            .route("/", get(|| async { Html(PAGE) }))
+            .route("/api/state", get(state))
+            .route("/api/control/{action}", post(control))
+            .route("/api/queue/{index}", delete(remove))
+            .with_state(self)
+    }
+
+    /// Answer requests on `listener` until `cancel` fires, keeping up with
+    /// `copies` of the messages in the meantime
+    pub async fn serve(
+        self,
+        listener: TcpListener,
+        copies: broadcast::Receiver<RecordedEntry>,
+        cancel: CancellationToken,
+    ) {
+        let watching = tokio::spawn(watch(copies, self.watched.clone()));
+
+        if let Err(e) = axum::serve(listener, self.router())
+            .with_graceful_shutdown(cancel.cancelled_owned())
+            .await
+        {
+            error!("The dashboard stopped: {}", e);
+        }
+        watching.abort();
+    }
+
+    async fn send(&self, message: InternalMessage) -> Result<(), Error> {
+        self.message_queue
+            .send(MessageKind::InternalMessage(message))
+            .await
+            .map_err(|_| Error::msg("the message queue has stopped"))
+    }
+
+    async fn queue(&self) -> Result<Vec<ShowElementSummary>, Error> {
+        let (reply, reply_rx) = SnapshotReply::new();
+        self.send(InternalMessage::ShowControl(ShowControl::QueueSnapshot(
+            reply,
+        )))
+        .await?;
+
+        reply_rx
+            .await
+            .map_err(|_| Error::msg("the show worker dropped the query"))
+    }
+
+    async fn lights(&self) -> Result<Vec<LightState>, Error> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(InternalMessage::LightQuery(reply_tx)).await?;
+
+        reply_rx
+            .await
+            .map_err(|_| Error::msg("the lights dropped the query"))
+    }
+
+    /// Each show's totals, read from the stats file the show worker keeps
+    async fn stats(&self) -> Result<Value, Error> {
+        let config = self.stats.clone();
+        let totals = tokio::task::spawn_blocking(move || ShowStats::load(&config).totals()).await?;
+
+        Ok(totals
+            .into_iter()
+            .map(|(name, show)| {
+                json!({
+                    "name": name,
+                    "plays": show.plays,
+                    "completed": show.completed,
+                    "skipped": show.skipped,
+                    "stopped": show.stopped,
+                    "errored": show.errored,
+                    "played_secs": show.played.as_secs(),
+                    "max_lateness_ms": show.max_lateness.as_millis() as u64,
+                })
+            })
+            .collect())
+    }
+}
+
+async fn watch(mut copies: broadcast::Receiver<RecordedEntry>, watched: Arc<Mutex<Watched>>) {
+    loop {
+        match copies.recv().await {
+            Ok(entry) => watched.lock().unwrap().note(entry),
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("The dashboard missed {} messages", missed)
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Everything the page shows. A panel whose task doesn't answer is `null`,
+/// and why is listed in `errors`.
+async fn state(State(dashboard): State<Dashboard>) -> Json<Value> {
+    let (queue, lights, lasers, dmx, stats) = tokio::join!(
+        within("the show worker", dashboard.queue()),
+        within("the lights", dashboard.lights()),
+        within("the lasers", laser_stats(&dashboard.lasers)),
+        within("the DMX", dashboard.dmx.query()),
+        within("the show stats", dashboard.stats()),
+    );
+
+    let errors = [
+        queue.as_ref().err(),
+        lights.as_ref().err(),
+        lasers.as_ref().err(),
+        dmx.as_ref().err(),
+        stats.as_ref().err(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|e| e.to_string())
+    .collect::<Vec<_>>();
+
+    let show = dashboard.now_playing.get();
+    let (progress, events) = {
+        let watched = dashboard.watched.lock().unwrap();
+        (watched.progress.clone(), watched.events.clone())
+    };
+    // Left over from the last show otherwise
+    let progress = progress.filter(|progress| Some(&progress.name) == show.as_ref());
+
+    Json(json!({
+        "show": show,
+        "progress": progress,
+        "queue": queue.ok(),
+        "lights": lights
+            .ok()
+            .map(|lights| lights.iter().map(|light| light.level).collect::<Vec<_>>()),
+        "dmx": dmx.ok().map(|snapshot| snapshot.values.to_vec()),
+        "lasers": lasers.ok().as_ref().map(laser_json),
+        "stats": stats.ok(),
+        "events": events,
+        "errors": errors,
+    }))
+}
+
+async fn control(
+    State(dashboard): State<Dashboard>,
+    Path(action): Path<String>,
+) -> (StatusCode, String) {
+    let message = match action.as_str() {
+        "pause" => InternalMessage::ShowControl(ShowControl::Pause),
+        "resume" => InternalMessage::ShowControl(ShowControl::Resume),
+        "skip" => InternalMessage::ShowControl(ShowControl::Skip),
+        "clear" => InternalMessage::ShowControl(ShowControl::ClearQueue),
+        "init" => InternalMessage::ShowControl(ShowControl::Init),
+        "boundary" => InternalMessage::ShowControl(ShowControl::LaserTest {
+            laser_id: ALL_LASERS,
+            boundary: true,
+        }),
+        "laser-test" => InternalMessage::ShowControl(ShowControl::LaserTest {
+            laser_id: ALL_LASERS,
+            boundary: false,
+        }),
+        "home" => InternalMessage::LaserHome,
+        "blackout" => InternalMessage::DmxBlackout,
+        _ => {
+            return (
+                StatusCode::NOT_FOUND,
+                format!("There's no {} button", action),
+            )
+        }
+    };
+
+    sent(dashboard.send(message).await)
+}
+
+/// Drop one element of the show queue, by where the page last saw it
+async fn remove(
+    State(dashboard): State<Dashboard>,
+    Path(index): Path<usize>,
+) -> (StatusCode, String) {
+    sent(
+        dashboard
+            .send(InternalMessage::ShowControl(ShowControl::RemoveAt(index)))
+            .await,
+    )
+}
+
+fn sent(result: Result<(), Error>) -> (StatusCode, String) {
+    match result {
+        Ok(()) => (StatusCode::NO_CONTENT, String::new()),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Local;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        dmx::DmxState,
+        laser::LaserController,
+        prelude::FrameSendPack,
+        recorder::MessageCopies,
+        show::prelude::{PlayOutcome, ShowPlay},
+        test_util::TempDir,
+    };
+
+    fn entry(message: RecordedMessage) -> RecordedEntry {
+        RecordedEntry {
+            time: Local::now(),
+            message,
+        }
+    }
+
+    #[test]
+    fn test_watched() {
+        let mut watched = Watched::default();
+        watched.note(entry(RecordedMessage::ShowStarted("graveyard".to_string())));
+        watched.note(entry(RecordedMessage::Laser(FrameSendPack::null_out())));
+        watched.note(entry(RecordedMessage::ShowProgress {
+            name: "graveyard".to_string(),
+            elapsed_ms: 1000,
+            total_ms: 60000,
+            frame_index: 10,
+        }));
+        watched.note(entry(RecordedMessage::DmxBlackout));
+
+        assert_eq!(
+            watched.progress,
+            Some(Progress {
+                name: "graveyard".to_string(),
+                elapsed_ms: 1000,
+                total_ms: 60000,
+            })
+        );
+        let events = watched
+            .events
+            .iter()
+            .map(|event| event.split_once(' ').unwrap().1)
+            .collect::<Vec<_>>();
+        assert_eq!(events, ["Started graveyard", "DmxBlackout"]);
+
+        for _ in 0..EVENTS_KEPT {
+            watched.note(entry(RecordedMessage::LaserHome));
+        }
+        assert_eq!(watched.events.len(), EVENTS_KEPT);
+        assert!(watched.events[0].ends_with("LaserHome"));
+    }
+
+    #[tokio::test]
+    async fn test_dashboard() {
+        let config = Config::default();
+        let cancel = CancellationToken::new();
+
+        let (uart_tx, _uart_rx) = mpsc::channel(100);
+        let (laser_tx, laser_rx) = mpsc::channel(100);
+        let (uart_tx_clone, cancel_clone) = (uart_tx.clone(), cancel.clone());
+        tokio::spawn(async move {
+            let mut lasers = LaserController::init(&config);
+            lasers.start(laser_rx, uart_tx_clone, cancel_clone).await;
+        });
+        let (dmx_tx, dmx_rx) = mpsc::channel(100);
+        let dmx = DmxState::init(Config::default());
+        tokio::spawn(dmx.start(dmx_rx, uart_tx, cancel.clone()));
+
+        // Stands in for the receiver, answering the queries and passing on
+        // the rest
+        let (message_tx, mut message_rx) = mpsc::channel(100);
+        let (sent_tx, mut sent_rx) = mpsc::channel(10);
+        tokio::spawn(async move {
+            while let Some(MessageKind::InternalMessage(message)) = message_rx.recv().await {
+                match message {
+                    InternalMessage::LightQuery(reply) => {
+                        let _ = reply.send(vec![LightState::default(); 2]);
+                    }
+                    InternalMessage::ShowControl(ShowControl::QueueSnapshot(reply)) => {
+                        reply.send(Vec::new());
+                    }
+                    message => sent_tx.send(format!("{:?}", message)).await.unwrap(),
+                }
+            }
+        });
+
+        let dir = TempDir::new("dashboard");
+        let stats = StatsConfig {
+            data_dir: dir.join("data"),
+        };
+        ShowStats::load(&stats).record(ShowPlay {
+            name: "graveyard".to_string(),
+            started: Local::now(),
+            duration_ms: 60000,
+            outcome: PlayOutcome::Completed,
+            max_lateness_ms: 12,
+        });
+
+        let now_playing = NowPlaying::default();
+        let copies = MessageCopies::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let dashboard = Dashboard::new(
+            message_tx,
+            laser_tx,
+            DmxHandle::new(dmx_tx),
+            now_playing.clone(),
+            stats,
+        );
+        tokio::spawn(dashboard.serve(listener, copies.subscribe(), cancel.clone()));
+
+        let page = reqwest::get(&url).await.unwrap().text().await.unwrap();
+        assert!(page.contains("<title>Rusty Halloween</title>"));
+
+        now_playing.set(Some("graveyard".to_string()));
+        copies.copy(&InternalMessage::ShowProgress {
+            name: "graveyard".to_string(),
+            elapsed_ms: 2000,
+            total_ms: 60000,
+            frame_index: 20,
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let state = reqwest::get(format!("{}/api/state", url))
+            .await
+            .unwrap()
+            .json::<Value>()
+            .await
+            .unwrap();
+        assert_eq!(state["show"], "graveyard");
+        assert_eq!(state["progress"]["elapsed_ms"], 2000);
+        assert_eq!(state["queue"], json!([]));
+        assert_eq!(state["lights"], json!([0, 0]));
+        assert_eq!(state["dmx"].as_array().unwrap().len(), 512);
+        assert_eq!(state["lasers"]["sent"], 0);
+        assert_eq!(state["stats"][0]["name"], "graveyard");
+        assert_eq!(state["stats"][0]["completed"], 1);
+        assert_eq!(state["stats"][0]["max_lateness_ms"], 12);
+        assert_eq!(state["errors"], json!([]));
+
+        let client = reqwest::Client::new();
+        let post = |action: &str| {
+            client
+                .post(format!("{}/api/control/{}", url, action))
+                .send()
+        };
+        assert_eq!(
+            post("skip").await.unwrap().status(),
+            reqwest::StatusCode::NO_CONTENT
+        );
+        assert_eq!(sent_rx.recv().await.unwrap(), "ShowControl(Skip)");
+        assert_eq!(
+            post("blackout").await.unwrap().status(),
+            reqwest::StatusCode::NO_CONTENT
+        );
+        assert_eq!(sent_rx.recv().await.unwrap(), "DmxBlackout");
+        assert_eq!(
+            post("boundary").await.unwrap().status(),
+            reqwest::StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            sent_rx.recv().await.unwrap(),
+            "ShowControl(LaserTest { laser_id: 15, boundary: true })"
+        );
+        assert_eq!(
+            client
+                .delete(format!("{}/api/queue/2", url))
+                .send()
+                .await
+                .unwrap()
+                .status(),
+            reqwest::StatusCode::NO_CONTENT
+        );
+        assert_eq!(sent_rx.recv().await.unwrap(), "ShowControl(RemoveAt(2))");
+        assert_eq!(
+            post("dance").await.unwrap().status(),
+            reqwest::StatusCode::NOT_FOUND
+        );
+
+        cancel.cancel();
+    }
+}
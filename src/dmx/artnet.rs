@@ -0,0 +1,129 @@
+use std::net::SocketAddr;
+
+use anyhow::Error;
+use tokio::net::UdpSocket;
+
+use crate::config::ArtNetConfig;
+
+const ART_NET_ID: &[u8; 8] = b"Art-Net\0";
+const OP_DMX: u16 = 0x5000;
+const PROTOCOL_VERSION: u16 = 14;
+
+/// Sends DMX frames to an Art-Net node as ArtDMX packets
+pub struct ArtNetSender {
+    socket: UdpSocket,
+    target: SocketAddr,
+    universe: u16,
+    sequence: u8,
+}
+
+impl ArtNetSender {
+    pub async fn bind(config: &ArtNetConfig) -> Result<Self, Error> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+
+        Ok(ArtNetSender {
+            socket,
+            target: SocketAddr::new(config.ip, config.port),
+            universe: config.universe,
+            sequence: 0,
+        })
+    }
+
+    pub async fn send(&mut self, channels: &[u8]) -> Result<(), Error> {
+        // A sequence of 0 tells the node not to reorder packets, so wrap
+        // around to 1 instead
+        self.sequence = self.sequence.checked_add(1).unwrap_or(1);
+
+        let packet = art_dmx_packet(self.sequence, self.universe, channels);
+        self.socket.send_to(&packet, self.target).await?;
+
+        Ok(())
+    }
+}
+
+/// Build an ArtDMX packet. The data length has to be even, so an odd number
+/// of channels gets a trailing zero.
+pub fn art_dmx_packet(sequence: u8, universe: u16, channels: &[u8]) -> Vec<u8> {
+    let length = channels.len() + channels.len() % 2;
+
+    let mut packet = Vec::with_capacity(18 + length);
+    packet.extend_from_slice(ART_NET_ID);
+    packet.extend_from_slice(&OP_DMX.to_le_bytes());
+    packet.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    packet.push(sequence);
+    // Physical input port, informational only
+    packet.push(0);
+    // SubUni is the low byte of the port address, Net is the high 7 bits
+    packet.push((universe & 0xFF) as u8);
+    packet.push(((universe >> 8) & 0x7F) as u8);
+    packet.extend_from_slice(&(length as u16).to_be_bytes());
+    packet.extend_from_slice(channels);
+    packet.resize(18 + length, 0);
+
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_art_dmx_packet() {
+        let packet = art_dmx_packet(1, 0x0123, &[0xFF, 0x80, 0x00, 0x01]);
+
+        assert_eq!(
+            packet,
+            [
+                0x41, 0x72, 0x74, 0x2D, 0x4E, 0x65, 0x74, 0x00, // "Art-Net\0"
+                0x00, 0x50, // OpDmx
+                0x00, 0x0E, // protocol version 14
+                0x01, // sequence
+                0x00, // physical
+                0x23, 0x01, // SubUni, Net
+                0x00, 0x04, // length
+                0xFF, 0x80, 0x00, 0x01,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_art_dmx_packet_odd_length() {
+        let packet = art_dmx_packet(0x7F, 0, &[0x10, 0x20, 0x30]);
+
+        assert_eq!(
+            packet,
+            [
+                0x41, 0x72, 0x74, 0x2D, 0x4E, 0x65, 0x74, 0x00, // "Art-Net\0"
+                0x00, 0x50, // OpDmx
+                0x00, 0x0E, // protocol version 14
+                0x7F, // sequence
+                0x00, // physical
+                0x00, 0x00, // SubUni, Net
+                0x00, 0x04, // length, padded to even
+                0x10, 0x20, 0x30, 0x00,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_wraps_sequence() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = receiver.local_addr().unwrap();
+
+        let mut sender = ArtNetSender::bind(&ArtNetConfig {
+            ip: addr.ip(),
+            port: addr.port(),
+            universe: 2,
+            uart: false,
+        })
+        .await
+        .unwrap();
+        sender.sequence = 255;
+
+        sender.send(&[1, 2]).await.unwrap();
+
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], art_dmx_packet(1, 2, &[1, 2]).as_slice());
+    }
+}
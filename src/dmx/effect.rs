@@ -0,0 +1,152 @@
+use std::time::Duration;
+
+use crate::show::prelude::{DmxStateData, DmxStateIndex, DmxStateVarPosition};
+
+/// Time based effects that are generated by the DMX task instead of being
+/// written out frame by frame in a show
+#[derive(Clone, Debug, PartialEq)]
+pub enum DmxEffect {
+    /// Flash a channel between `level` and off, `rate_hz` times a second
+    Strobe {
+        channel: DmxStateIndex,
+        rate_hz: f32,
+        level: DmxStateData,
+    },
+    /// Turn the channels on one at a time, moving on every `step_ms`
+    Chase {
+        channels: Vec<DmxStateIndex>,
+        step_ms: u64,
+        level: DmxStateData,
+    },
+    /// Move each channel from one value to another over `duration_ms`, then
+    /// hold it there
+    Fade {
+        /// Each channel with the value it starts from and ends on
+        channels: Vec<(DmxStateIndex, DmxStateData, DmxStateData)>,
+        duration_ms: u64,
+    },
+}
+
+impl DmxEffect {
+    /// Every channel that this effect writes to
+    pub fn channels(&self) -> Vec<DmxStateIndex> {
+        match self {
+            DmxEffect::Strobe { channel, .. } => vec![*channel],
+            DmxEffect::Chase { channels, .. } => channels.clone(),
+            DmxEffect::Fade { channels, .. } => {
+                channels.iter().map(|(channel, _, _)| *channel).collect()
+            }
+        }
+    }
+
+    /// The channel values at `elapsed` since the effect started
+    pub fn values(&self, elapsed: Duration) -> Vec<DmxStateVarPosition> {
+        match self {
+            DmxEffect::Strobe {
+                channel,
+                rate_hz,
+                level,
+            } => {
+                // A rate that doesn't make sense just holds the level
+                if *rate_hz <= 0.0 {
+                    return vec![(*channel, *level)];
+                }
+
+                // On for the first half of each period
+                let period = 1.0 / *rate_hz as f64;
+                let phase = (elapsed.as_secs_f64() % period) / period;
+                let value = if phase < 0.5 { *level } else { 0 };
+
+                vec![(*channel, value)]
+            }
+            DmxEffect::Chase {
+                channels,
+                step_ms,
+                level,
+            } => {
+                if channels.is_empty() {
+                    return Vec::new();
+                }
+
+                let step = (elapsed.as_millis() / (*step_ms).max(1) as u128) as usize;
+                let active = step % channels.len();
+
+                channels
+                    .iter()
+                    .enumerate()
+                    .map(|(i, channel)| (*channel, if i == active { *level } else { 0 }))
+                    .collect()
+            }
+            DmxEffect::Fade {
+                channels,
+                duration_ms,
+            } => {
+                let progress = match *duration_ms {
+                    0 => 1.0,
+                    duration_ms => (elapsed.as_millis() as f64 / duration_ms as f64).min(1.0),
+                };
+
+                channels
+                    .iter()
+                    .map(|(channel, from, to)| {
+                        let value = *from as f64 + (*to as f64 - *from as f64) * progress;
+                        (*channel, value.round() as DmxStateData)
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(effect: &DmxEffect, ms: u64) -> Vec<DmxStateVarPosition> {
+        effect.values(Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn test_strobe() {
+        let strobe = DmxEffect::Strobe {
+            channel: 4,
+            rate_hz: 10.0,
+            level: 200,
+        };
+
+        assert_eq!(sample(&strobe, 0), vec![(4, 200)]);
+        assert_eq!(sample(&strobe, 49), vec![(4, 200)]);
+        assert_eq!(sample(&strobe, 50), vec![(4, 0)]);
+        assert_eq!(sample(&strobe, 99), vec![(4, 0)]);
+        assert_eq!(sample(&strobe, 100), vec![(4, 200)]);
+        assert_eq!(sample(&strobe, 1_020), vec![(4, 200)]);
+        assert_eq!(sample(&strobe, 1_070), vec![(4, 0)]);
+    }
+
+    #[test]
+    fn test_chase() {
+        let chase = DmxEffect::Chase {
+            channels: vec![1, 2, 3],
+            step_ms: 100,
+            level: 255,
+        };
+
+        assert_eq!(sample(&chase, 0), vec![(1, 255), (2, 0), (3, 0)]);
+        assert_eq!(sample(&chase, 150), vec![(1, 0), (2, 255), (3, 0)]);
+        assert_eq!(sample(&chase, 250), vec![(1, 0), (2, 0), (3, 255)]);
+        assert_eq!(sample(&chase, 300), vec![(1, 255), (2, 0), (3, 0)]);
+    }
+
+    #[test]
+    fn test_fade() {
+        let fade = DmxEffect::Fade {
+            channels: vec![(1, 200, 0), (2, 0, 100)],
+            duration_ms: 1_000,
+        };
+
+        assert_eq!(sample(&fade, 0), vec![(1, 200), (2, 0)]);
+        assert_eq!(sample(&fade, 250), vec![(1, 150), (2, 25)]);
+        assert_eq!(sample(&fade, 1_000), vec![(1, 0), (2, 100)]);
+        assert_eq!(sample(&fade, 5_000), vec![(1, 0), (2, 100)]);
+    }
+}
@@ -1,24 +1,178 @@
-use std::fmt::Debug;
-use log::error;
-use tokio::sync::mpsc;
+use anyhow::Error;
+use chrono::{DateTime, Local};
+use log::{error, info};
+use packed_struct::PackingError;
+use std::{collections::BTreeMap, fmt::Debug, time::Duration};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{interval, sleep_until, Instant, MissedTickBehavior},
+};
+use tokio_util::sync::CancellationToken;
 
-use crate::{config::Config, show::prelude::DmxStateVarPosition, uart::UartMessage};
+use crate::{
+    config::{Config, DmxSinkKind},
+    health::Heartbeat,
+    metrics::Metrics,
+    safety::OutputsEnabled,
+    show::prelude::{DmxStateData, DmxStateIndex, DmxStateVarPosition},
+    shutdown::recv_until,
+    state::StateStore,
+    supervisor::TaskSender,
+    uart::UartMessage,
+};
 
+use self::{
+    artnet::ArtNetSender,
+    effect::DmxEffect,
+    pack::{pack_frame, DmxHeaderPack},
+    recorder::{RecordedFrame, RecorderSink},
+    remote::RemoteSink,
+    sink::{DmxSink, UartSink},
+};
+
+pub mod artnet;
+pub mod effect;
 pub mod pack;
+pub mod recorder;
+pub mod recording;
+pub mod remote;
+pub mod sink;
 
 type DmxFrame = u8;
 
-const DMX_CHANNELS: usize = 255;
+pub const DMX_CHANNELS: usize = 512;
 
 pub enum DmxMessage {
     Send,
     UpdateState(Vec<DmxStateVarPosition>),
     ZeroOut,
+    /// Apply the configured blackout scene and send it right away
+    Blackout,
+    /// Fade from what's showing to the blackout scene over a while. The fade
+    /// holds the blackout once it's done, until something else is sent.
+    FadeToBlackout(Duration),
+    /// Merge a named scene from the config onto the current state
+    RecallScene(String),
+    /// Read back the current state
+    Query(oneshot::Sender<DmxSnapshot>),
+    /// Hold a channel at a value regardless of what shows send, or release it
+    /// with `None`
+    Override {
+        channel: DmxStateIndex,
+        value: Option<DmxStateData>,
+    },
+    /// Release every override
+    ClearOverrides,
+    /// Run an effect on top of the show state. Starting an effect with an id
+    /// that's already running replaces it.
+    StartEffect {
+        id: String,
+        effect: DmxEffect,
+    },
+    StopEffect {
+        id: String,
+    },
+    /// The UART task was restarted, send to it from now on
+    Reconnect(mpsc::Sender<UartMessage>),
+    /// A show frame is done. Any send still waiting on its window goes out
+    /// first, then the sinks are told.
+    Flush {
+        frame_id: u64,
+    },
+    /// Read back the last frames kept by the sinks, oldest first
+    RecentFrames {
+        count: usize,
+        reply: oneshot::Sender<Vec<RecordedFrame>>,
+    },
+}
+
+/// A copy of what the DMX state holds at the time it was queried
+#[derive(Clone, Debug, PartialEq)]
+pub struct DmxSnapshot {
+    /// The merged output, which is what gets sent
+    pub values: [DmxFrame; DMX_CHANNELS],
+    pub overrides: [Option<DmxFrame>; DMX_CHANNELS],
+    pub last_sent: Option<DateTime<Local>>,
+    pub updates: u64,
+    pub rejected_updates: u64,
+    pub coalesced_sends: u64,
+    pub healthy: bool,
+}
+
+/// Wraps the sender for the DMX task so that callers don't have to build the
+/// reply channels themselves
+#[derive(Clone)]
+pub struct DmxHandle {
+    pub tx: TaskSender<DmxMessage>,
+}
+
+impl DmxHandle {
+    pub fn new(tx: impl Into<TaskSender<DmxMessage>>) -> Self {
+        DmxHandle { tx: tx.into() }
+    }
+
+    pub async fn query(&self) -> Result<DmxSnapshot, Error> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.tx
+            .send(DmxMessage::Query(reply_tx))
+            .await
+            .map_err(|_| Error::msg("DMX task has stopped"))?;
+
+        reply_rx
+            .await
+            .map_err(|_| Error::msg("DMX task dropped the query"))
+    }
+
+    /// The last `count` frames that were sent. This is empty unless the
+    /// recorder is one of the sinks.
+    pub async fn recent_frames(&self, count: usize) -> Result<Vec<RecordedFrame>, Error> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.tx
+            .send(DmxMessage::RecentFrames {
+                count,
+                reply: reply_tx,
+            })
+            .await
+            .map_err(|_| Error::msg("DMX task has stopped"))?;
+
+        reply_rx
+            .await
+            .map_err(|_| Error::msg("DMX task dropped the query"))
+    }
 }
 
 pub struct DmxState {
     pub config: Config,
+    /// The show layer, written by shows, scenes and the config
     pub values: [DmxFrame; DMX_CHANNELS],
+    /// The override layer, set by the operator. An overridden channel keeps
+    /// its value until the override is released, no matter what the show
+    /// layer does underneath it.
+    pub overrides: [Option<DmxFrame>; DMX_CHANNELS],
+    /// How many channel updates were skipped because they were outside of the
+    /// universe
+    pub rejected_updates: u64,
+    /// How many channel updates were applied
+    pub updates: u64,
+    /// When a frame was last handed to a transport
+    pub last_sent: Option<DateTime<Local>>,
+    /// Send requests that were folded into another send
+    pub coalesced_sends: u64,
+    /// Whether the last frame made it to every sink
+    pub healthy: bool,
+    /// The values from the last send, used by `send_only_on_change`
+    last_sent_values: Option<[DmxFrame; DMX_CHANNELS]>,
+    /// Running effects by id, with when they started
+    pub effects: BTreeMap<String, (DmxEffect, Instant)>,
+    /// Where frames go, set up in `start`
+    sinks: Vec<Box<dyn DmxSink>>,
+    /// Only zeroed frames go out while the e-stop is tripped
+    pub outputs_enabled: OutputsEnabled,
+    /// Beats while the loop is running, for the health checks
+    pub heartbeat: Heartbeat,
+    pub metrics: Metrics,
+    /// Where the overrides are kept across restarts
+    saved_state: Option<StateStore>,
 }
 
 pub struct DmxStateChange {
@@ -28,73 +182,1053 @@ pub struct DmxStateChange {
 
 impl DmxState {
     pub fn init(config: Config) -> Self {
-        DmxState {
+        let mut state = DmxState {
             config,
             values: [0; DMX_CHANNELS],
+            overrides: [None; DMX_CHANNELS],
+            rejected_updates: 0,
+            updates: 0,
+            last_sent: None,
+            coalesced_sends: 0,
+            healthy: true,
+            last_sent_values: None,
+            effects: BTreeMap::new(),
+            sinks: Vec::new(),
+            outputs_enabled: OutputsEnabled::default(),
+            heartbeat: Heartbeat::default(),
+            metrics: Metrics::default(),
+            saved_state: None,
+        };
+
+        // Some fixtures (the hazer) fault if they sit at zero, so start from
+        // the configured values instead
+        let startup = state.config.dmx.startup.clone();
+        state.apply(startup.into_iter());
+
+        state
+    }
+
+    /// Set channels from (address, value) pairs. DMX addresses start at 1, not
+    /// 0, so they're translated to the array index here. Addresses outside of
+    /// the universe are skipped, and the number skipped is returned.
+    fn apply(&mut self, positions: impl Iterator<Item = DmxStateVarPosition>) -> u64 {
+        let mut rejected = 0;
+
+        for (index, value) in positions {
+            match (index as usize)
+                .checked_sub(1)
+                .and_then(|index| self.values.get_mut(index))
+            {
+                Some(channel) => *channel = value,
+                None => {
+                    error!("DMX channel {} is out of range", index);
+                    rejected += 1;
+                }
+            }
+        }
+
+        rejected
+    }
+
+    /// Apply a state update from a show
+    pub fn update(&mut self, state: Vec<DmxStateVarPosition>) -> u64 {
+        // A show writing to a channel takes it back from any effect on it
+        self.effects.retain(|id, (effect, _)| {
+            let stomped = effect
+                .channels()
+                .iter()
+                .any(|channel| state.iter().any(|(index, _)| index == channel));
+            if stomped {
+                info!("Stopping DMX effect {}, the show wrote to its channels", id);
+            }
+
+            !stomped
+        });
+
+        let count = state.len() as u64;
+        let rejected = self.apply(state.into_iter());
+        self.updates += count - rejected;
+        self.rejected_updates += rejected;
+
+        rejected
+    }
+
+    /// Set or release an override. Returns false if the channel is outside of
+    /// the universe.
+    pub fn set_override(&mut self, channel: DmxStateIndex, value: Option<DmxStateData>) -> bool {
+        match (channel as usize)
+            .checked_sub(1)
+            .and_then(|index| self.overrides.get_mut(index))
+        {
+            Some(slot) => {
+                *slot = value;
+                true
+            }
+            None => {
+                error!("DMX override channel {} is out of range", channel);
+                false
+            }
+        }
+    }
+
+    /// Hold the channels that were overridden before a restart, and keep
+    /// `state` up to date as they change
+    pub fn resume(&mut self, state: StateStore) {
+        for (channel, value) in state.get().dmx_overrides {
+            self.set_override(channel, Some(value));
+        }
+        self.saved_state = Some(state);
+    }
+
+    fn remember_overrides(&self) {
+        let Some(state) = &self.saved_state else {
+            return;
+        };
+        let overrides = self
+            .overrides
+            .iter()
+            .enumerate()
+            .filter_map(|(index, value)| Some((index as DmxStateIndex + 1, (*value)?)))
+            .collect();
+        state.update(|state| state.dmx_overrides = overrides);
+    }
+
+    /// Start a fade from the current output to the blackout scene, replacing
+    /// any other effects
+    pub fn fade_to_blackout(&mut self, duration: Duration) {
+        let channels = self
+            .output()
+            .iter()
+            .enumerate()
+            .map(|(index, from)| {
+                // DMX addresses start at 1
+                let channel = index as DmxStateIndex + 1;
+                let to = self.config.dmx.blackout.get(&channel).copied().unwrap_or(0);
+                (channel, *from, to)
+            })
+            .filter(|(_, from, to)| from != to)
+            .collect();
+
+        self.effects.clear();
+        self.effects.insert(
+            "fade".to_string(),
+            (
+                DmxEffect::Fade {
+                    channels,
+                    duration_ms: duration.as_millis() as u64,
+                },
+                Instant::now(),
+            ),
+        );
+    }
+
+    /// Merge the layers into what gets sent
+    pub fn output(&self) -> [DmxFrame; DMX_CHANNELS] {
+        self.output_at(Instant::now())
+    }
+
+    /// Merge the layers as they would be at `now`. Effects go on top of the
+    /// show layer, and overrides go on top of everything.
+    pub fn output_at(&self, now: Instant) -> [DmxFrame; DMX_CHANNELS] {
+        let mut output = self.values;
+
+        for (effect, started) in self.effects.values() {
+            for (channel, value) in effect.values(now.saturating_duration_since(*started)) {
+                if let Some(slot) = (channel as usize)
+                    .checked_sub(1)
+                    .and_then(|index| output.get_mut(index))
+                {
+                    *slot = value;
+                }
+            }
+        }
+
+        for (value, over) in output.iter_mut().zip(&self.overrides) {
+            if let Some(over) = over {
+                *value = *over;
+            }
+        }
+
+        output
+    }
+
+    pub fn snapshot(&self) -> DmxSnapshot {
+        DmxSnapshot {
+            values: self.output(),
+            overrides: self.overrides,
+            last_sent: self.last_sent,
+            updates: self.updates,
+            rejected_updates: self.rejected_updates,
+            coalesced_sends: self.coalesced_sends,
+            healthy: self.healthy,
+        }
+    }
+
+    /// Build the bytes that get sent over UART for the current state,
+    /// addressed to the configured controller and universe
+    pub fn frame(&self) -> Result<Vec<u8>, PackingError> {
+        let header = DmxHeaderPack {
+            controller_id: self.config.dmx.controller_id.into(),
+            universe: self.config.dmx.universe.into(),
+        };
+
+        pack_frame(&header, &self.output())
+    }
+
+    /// Send the current state to every sink
+    async fn send(&mut self) {
+        let output = match self.outputs_enabled.is_enabled() {
+            true => self.output(),
+            false => [0; DMX_CHANNELS],
+        };
+        if self.config.dmx.send_only_on_change && self.last_sent_values == Some(output) {
+            return;
+        }
+        self.last_sent = Some(Local::now());
+        self.last_sent_values = Some(output);
+
+        // Errors are only logged when a send first fails, so a dead UART
+        // doesn't flood the log
+        let mut error = None;
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.send(&output).await {
+                error = Some(e);
+            }
         }
+
+        match error {
+            None if !self.healthy => {
+                info!("DMX frames are being sent again");
+                self.healthy = true;
+            }
+            None => {}
+            Some(e) => {
+                if self.healthy {
+                    error!("Failed to send DMX data: {}", e);
+                }
+                self.healthy = false;
+                // Make sure it goes out again once the sinks are back
+                self.last_sent_values = None;
+            }
+        }
+    }
+
+    /// The sink used unless Art-Net replaces it. The UART is the default on
+    /// the Pi, and the recorder everywhere else or when simulating.
+    pub fn default_sink(config: &Config) -> DmxSinkKind {
+        if config.mode.is_simulated() {
+            return DmxSinkKind::Recorder;
+        }
+
+        config.dmx.sink.unwrap_or(if cfg!(feature = "pi") {
+            DmxSinkKind::Uart
+        } else {
+            DmxSinkKind::Recorder
+        })
+    }
+
+    /// Art-Net replaces the default sink unless both are asked for. A remote
+    /// controller is added on top of whatever else is used. Simulating leaves
+    /// out both, since they drive real fixtures.
+    async fn open_sinks(
+        config: &Config,
+        uart_tx: mpsc::Sender<UartMessage>,
+    ) -> Vec<Box<dyn DmxSink>> {
+        let mut sinks: Vec<Box<dyn DmxSink>> = Vec::new();
+        let mut use_default = true;
+        let simulated = config.mode.is_simulated();
+
+        if let Some(artnet) = config.dmx.artnet.as_ref().filter(|_| !simulated) {
+            match ArtNetSender::bind(artnet).await {
+                Ok(sender) => {
+                    info!("Sending DMX over Art-Net to {}:{}", artnet.ip, artnet.port);
+                    sinks.push(Box::new(sender));
+                    use_default = artnet.uart;
+                }
+                Err(e) => error!("Failed to set up Art-Net, using the default sink: {}", e),
+            }
+        }
+
+        if use_default {
+            match Self::default_sink(config) {
+                DmxSinkKind::Uart => sinks.push(Box::new(UartSink::new(config, uart_tx))),
+                DmxSinkKind::Recorder => match RecorderSink::new(&config.dmx.recorder) {
+                    Ok(recorder) => {
+                        info!("Recording DMX frames instead of sending them");
+                        sinks.push(Box::new(recorder));
+                    }
+                    Err(e) => error!("Failed to open the DMX recorder: {}", e),
+                },
+            }
+        }
+
+        if let Some(remote) = config.dmx.remote_dmx.as_ref().filter(|_| !simulated) {
+            info!("Pushing DMX to {}", remote.url);
+            sinks.push(Box::new(RemoteSink::new(remote)));
+        }
+
+        sinks
     }
 
     pub async fn start(
         mut self,
         mut rx: mpsc::Receiver<DmxMessage>,
         uart_tx: mpsc::Sender<UartMessage>,
+        cancel: CancellationToken,
     ) {
-        while let Some(message) = rx.recv().await {
+        self.sinks = Self::open_sinks(&self.config, uart_tx).await;
+
+        // When a coalesced send is due
+        let mut pending: Option<Instant> = None;
+        let window = Duration::from_millis(self.config.dmx.send_window_ms);
+
+        // Effects need new frames even when the show isn't sending any
+        let mut effect_tick =
+            interval(Duration::from_millis(self.config.dmx.effect_tick_ms.max(1)));
+        effect_tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut heartbeat = Heartbeat::ticker();
+
+        loop {
+            let message = tokio::select! {
+                message = recv_until(&mut rx, &cancel) => message,
+                _ = sleep_until(pending.unwrap_or_else(Instant::now)), if pending.is_some() => {
+                    pending = None;
+                    self.send().await;
+                    continue;
+                }
+                _ = effect_tick.tick(), if !self.effects.is_empty() => {
+                    self.send().await;
+                    continue;
+                }
+                _ = heartbeat.tick() => {
+                    self.heartbeat.beat();
+                    continue;
+                }
+            };
+
+            let Some(message) = message else {
+                break;
+            };
+
+            let mut send_requested = false;
+
             match message {
                 DmxMessage::Send => {
                     // Debug print the values
                     // println!("{:?}", self);
 
-                    let mut data = Vec::new();
-
-                    // Add the header to the start of the array
-                    // TODO: Set this up correctly
-                    data.push(0xA0);
-
-                    // Add the rest of the values
-                    data.extend_from_slice(&self.values);
-
-                    uart_tx.send(UartMessage::DMX(data)).await.unwrap();
+                    send_requested = true;
                 }
                 DmxMessage::UpdateState(state) => {
-                    for (index, value) in state {
-                        // DMX addresses start at 1, not 0. Translate the DMX index to the
-                        // correct array index
-                        let index = index as usize - 1;
-
-                        self.values[index] = value;
-                    }
+                    self.update(state);
                 }
                 DmxMessage::ZeroOut => {
-                    // Zero out all channels
+                    self.zero_out();
+
+                    // Send the zeroed state right away, it covers anything
+                    // that was waiting
+                    pending = None;
+                    self.send().await;
+                }
+                DmxMessage::Blackout => {
+                    // Everything goes dark except what the blackout scene
+                    // keeps alive
                     self.values = [0; DMX_CHANNELS];
-                    
-                    // Send the zeroed state
-                    let mut data = Vec::new();
-                    data.push(0xA0); // Add header
-                    data.extend_from_slice(&self.values);
-                    
-                    if let Err(e) = uart_tx.send(UartMessage::DMX(data)).await {
-                        error!("Failed to send zeroed DMX data: {}", e);
+                    self.effects.clear();
+                    let blackout = self.config.dmx.blackout.clone();
+                    self.apply(blackout.into_iter());
+
+                    pending = None;
+                    self.send().await;
+                }
+                DmxMessage::FadeToBlackout(duration) => {
+                    self.fade_to_blackout(duration);
+                    send_requested = true;
+                }
+                DmxMessage::RecallScene(name) => {
+                    self.recall_scene(&name);
+                }
+                DmxMessage::Query(reply) => {
+                    // The caller may have given up waiting, which is fine
+                    let _ = reply.send(self.snapshot());
+                }
+                DmxMessage::Override { channel, value } => {
+                    // The operator expects to see this right away, not on the
+                    // next show frame
+                    send_requested = self.set_override(channel, value);
+                    self.remember_overrides();
+                }
+                DmxMessage::ClearOverrides => {
+                    self.overrides = [None; DMX_CHANNELS];
+                    send_requested = true;
+                    self.remember_overrides();
+                }
+                DmxMessage::StartEffect { id, effect } => {
+                    self.effects.insert(id, (effect, Instant::now()));
+                    send_requested = true;
+                }
+                DmxMessage::StopEffect { id } => {
+                    // The channels fall back to the show state
+                    send_requested = self.effects.remove(&id).is_some();
+                }
+                DmxMessage::Flush { frame_id } => {
+                    if pending.take().is_some() {
+                        self.send().await;
+                    }
+                    for sink in self.sinks.iter_mut() {
+                        sink.flush(frame_id).await;
                     }
                 }
+                DmxMessage::Reconnect(uart_tx) => {
+                    info!("Reconnecting DMX to the UART");
+                    for sink in self.sinks.iter_mut() {
+                        sink.reconnect(uart_tx.clone());
+                    }
+                }
+                DmxMessage::RecentFrames { count, reply } => {
+                    let frames = self
+                        .sinks
+                        .iter()
+                        .find_map(|sink| sink.recent_frames(count))
+                        .unwrap_or_default();
+                    let _ = reply.send(frames);
+                }
+            }
+
+            // Shows can ask for many sends per frame, but the UART can only
+            // keep up with one per window. Whatever the state is when the
+            // window closes is what gets sent.
+            if send_requested {
+                if pending.is_some() {
+                    self.coalesced_sends += 1;
+                    self.metrics.dmx_coalesced_sends.inc();
+                } else if window.is_zero() {
+                    self.send().await;
+                } else {
+                    pending = Some(Instant::now() + window);
+                }
+            }
+        }
+
+        // Don't drop a send that was still waiting on its window
+        if pending.is_some() {
+            self.send().await;
+        }
+    }
+
+    /// Zero out the configured channels, or all of them if there isn't a
+    /// list. This is the end of a show, so its effects stop too.
+    pub fn zero_out(&mut self) {
+        match self.config.dmx.zero_out.clone() {
+            Some(channels) => {
+                self.apply(channels.into_iter().map(|c| (c, 0)));
+            }
+            None => self.values = [0; DMX_CHANNELS],
+        }
+
+        self.effects.clear();
+    }
+
+    /// Merge a scene onto the current state. Channels that the scene doesn't
+    /// list are left alone.
+    pub fn recall_scene(&mut self, name: &str) {
+        match self.config.dmx.scenes.get(name).cloned() {
+            Some(scene) => {
+                self.apply(scene.into_iter());
             }
+            None => error!("DMX scene {} doesn't exist", name),
         }
     }
 }
 
-// Implement debug for DmxState. It should print out the values in a readable
-// hex table. It should have 16 bytes per row, and the index at the beginning of
-// each row
+// Implement debug for DmxState. It prints the values as a hex table with 16
+// bytes per row and the address of the first channel at the start of each row.
+// Rows that are all zero are skipped, otherwise a full universe is mostly
+// noise.
 impl Debug for DmxState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for (i, value) in self.values.iter().enumerate() {
-            if i % 16 == 0 && i != 0 {
-                writeln!(f)?;
+        for (row, values) in self.output().chunks(16).enumerate() {
+            if values.iter().all(|v| *v == 0) {
+                continue;
             }
-            write!(f, "{:02X} ", value)?;
+
+            write!(f, "{:03}:", row * 16 + 1)?;
+            for value in values {
+                write!(f, " {:02X}", value)?;
+            }
+            writeln!(f)?;
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::config::{ArtNetConfig, DmxConfig, RunMode};
+
+    /// Tests watch the UART unless they ask for something else
+    fn test_config(dmx: DmxConfig) -> Config {
+        Config {
+            dmx: DmxConfig {
+                sink: dmx.sink.or(Some(DmxSinkKind::Uart)),
+                ..dmx
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_default_sink() {
+        let uart = test_config(DmxConfig::default());
+        assert_eq!(DmxState::default_sink(&uart), DmxSinkKind::Uart);
+
+        // Simulating records frames even when the UART's asked for
+        let simulated = Config {
+            mode: RunMode::Simulate,
+            ..uart
+        };
+        assert_eq!(DmxState::default_sink(&simulated), DmxSinkKind::Recorder);
+    }
+
+    #[test]
+    fn test_init_applies_startup() {
+        let state = DmxState::init(test_config(DmxConfig {
+            startup: BTreeMap::from([(10, 128)]),
+            ..Default::default()
+        }));
+
+        assert_eq!(state.values[9], 128);
+        assert_eq!(state.values.iter().filter(|v| **v != 0).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_survive_uart_stopping() {
+        let state = DmxState::init(test_config(DmxConfig::default()));
+
+        let (dmx_tx, dmx_rx) = mpsc::channel(10);
+        let (uart_tx, uart_rx) = mpsc::channel(10);
+        let dmx = tokio::spawn(state.start(dmx_rx, uart_tx, CancellationToken::new()));
+        let handle = DmxHandle::new(dmx_tx);
+
+        drop(uart_rx);
+        handle.tx.send(DmxMessage::ZeroOut).await.unwrap();
+
+        let snapshot = handle.query().await.unwrap();
+        assert!(!dmx.is_finished());
+        assert!(!snapshot.healthy);
+
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        handle
+            .tx
+            .send(DmxMessage::Reconnect(uart_tx))
+            .await
+            .unwrap();
+        handle.tx.send(DmxMessage::ZeroOut).await.unwrap();
+        uart_rx.recv().await.unwrap();
+
+        assert!(handle.query().await.unwrap().healthy);
+    }
+
+    #[tokio::test]
+    async fn test_blackout_sends_scene() {
+        let mut state = DmxState::init(test_config(DmxConfig {
+            blackout: BTreeMap::from([(10, 64)]),
+            ..Default::default()
+        }));
+        state.values[0] = 255;
+
+        let (dmx_tx, dmx_rx) = mpsc::channel(10);
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        tokio::spawn(state.start(dmx_rx, uart_tx, CancellationToken::new()));
+
+        dmx_tx.send(DmxMessage::Blackout).await.unwrap();
+
+        let UartMessage::DMX(data) = uart_rx.recv().await.unwrap() else {
+            panic!("Expected a DMX frame");
+        };
+        assert_eq!(data[1], 0);
+        assert_eq!(data[10], 64);
+    }
+
+    #[tokio::test]
+    async fn test_zero_out_sends_zeroed_frame() {
+        let mut state = DmxState::init(test_config(DmxConfig::default()));
+        state.values = [0xFF; DMX_CHANNELS];
+
+        let (dmx_tx, dmx_rx) = mpsc::channel(10);
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        tokio::spawn(state.start(dmx_rx, uart_tx, CancellationToken::new()));
+
+        dmx_tx.send(DmxMessage::ZeroOut).await.unwrap();
+
+        let UartMessage::DMX(data) = uart_rx.recv().await.unwrap() else {
+            panic!("Expected a DMX frame");
+        };
+        assert_eq!(data.len(), DMX_CHANNELS + 1);
+        assert!(data[1..].iter().all(|v| *v == 0));
+    }
+
+    #[tokio::test]
+    async fn test_zero_out_configured_channels() {
+        let mut state = DmxState::init(test_config(DmxConfig {
+            zero_out: Some(vec![1, 2]),
+            ..Default::default()
+        }));
+        state.values = [0xFF; DMX_CHANNELS];
+
+        let (dmx_tx, dmx_rx) = mpsc::channel(10);
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        tokio::spawn(state.start(dmx_rx, uart_tx, CancellationToken::new()));
+
+        dmx_tx.send(DmxMessage::ZeroOut).await.unwrap();
+
+        let UartMessage::DMX(data) = uart_rx.recv().await.unwrap() else {
+            panic!("Expected a DMX frame");
+        };
+        assert_eq!(&data[1..4], &[0, 0, 0xFF]);
+    }
+
+    #[tokio::test]
+    async fn test_estop_sends_zeroes() {
+        let mut state = DmxState::init(test_config(DmxConfig::default()));
+        state.values = [0xFF; DMX_CHANNELS];
+        state.outputs_enabled.disable();
+
+        let (dmx_tx, dmx_rx) = mpsc::channel(10);
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        tokio::spawn(state.start(dmx_rx, uart_tx, CancellationToken::new()));
+
+        dmx_tx.send(DmxMessage::Send).await.unwrap();
+
+        let UartMessage::DMX(data) = uart_rx.recv().await.unwrap() else {
+            panic!("Expected a DMX frame");
+        };
+        assert!(data[1..].iter().all(|v| *v == 0));
+    }
+
+    #[test]
+    fn test_update_bounds() {
+        let mut state = DmxState::init(test_config(DmxConfig::default()));
+
+        // Addresses start at 1, so there is no channel 0
+        assert_eq!(state.update(vec![(0, 10)]), 1);
+        assert!(state.values.iter().all(|v| *v == 0));
+
+        assert_eq!(state.update(vec![(1, 20)]), 0);
+        assert_eq!(state.values[0], 20);
+
+        assert_eq!(state.update(vec![(310, 30)]), 0);
+        assert_eq!(state.values[309], 30);
+
+        // Past the end of the universe, the rest of the update still applies
+        assert_eq!(state.update(vec![(513, 40), (2, 50)]), 1);
+        assert_eq!(state.values[1], 50);
+
+        assert_eq!(state.rejected_updates, 2);
+    }
+
+    #[tokio::test]
+    async fn test_send_frame_bytes() {
+        let state = DmxState::init(test_config(DmxConfig {
+            controller_id: 0xB,
+            universe: 1,
+            startup: BTreeMap::from([(1, 0x10), (255, 0xFF)]),
+            ..Default::default()
+        }));
+
+        let (dmx_tx, dmx_rx) = mpsc::channel(10);
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        tokio::spawn(state.start(dmx_rx, uart_tx, CancellationToken::new()));
+
+        dmx_tx.send(DmxMessage::Send).await.unwrap();
+
+        let UartMessage::DMX(data) = uart_rx.recv().await.unwrap() else {
+            panic!("Expected a DMX frame");
+        };
+
+        let mut expected = vec![0; DMX_CHANNELS + 1];
+        expected[0] = 0xB1;
+        expected[1] = 0x10;
+        expected[255] = 0xFF;
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn test_last_channel_in_frame() {
+        let mut state = DmxState::init(test_config(DmxConfig::default()));
+        assert_eq!(state.update(vec![(512, 0xAB)]), 0);
+
+        let frame = state.frame().unwrap();
+        assert_eq!(frame.len(), 513);
+        assert_eq!(frame[512], 0xAB);
+    }
+
+    #[test]
+    fn test_debug_skips_empty_rows() {
+        let mut state = DmxState::init(test_config(DmxConfig::default()));
+        state.update(vec![(1, 0x01), (310, 0xFF)]);
+
+        assert_eq!(
+            format!("{:?}", state),
+            "001: 01 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n\
+             305: 00 00 00 00 00 FF 00 00 00 00 00 00 00 00 00 00\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_artnet() {
+        let receiver = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = receiver.local_addr().unwrap();
+
+        let state = DmxState::init(test_config(DmxConfig {
+            startup: BTreeMap::from([(1, 0x10)]),
+            artnet: Some(ArtNetConfig {
+                ip: addr.ip(),
+                port: addr.port(),
+                universe: 0,
+                uart: false,
+            }),
+            ..Default::default()
+        }));
+
+        let (dmx_tx, dmx_rx) = mpsc::channel(10);
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        tokio::spawn(state.start(dmx_rx, uart_tx, CancellationToken::new()));
+
+        dmx_tx.send(DmxMessage::Send).await.unwrap();
+
+        let mut buf = [0; 1024];
+        let len = receiver.recv(&mut buf).await.unwrap();
+        let mut channels = [0; DMX_CHANNELS];
+        channels[0] = 0x10;
+        assert_eq!(
+            &buf[..len],
+            artnet::art_dmx_packet(1, 0, &channels).as_slice()
+        );
+
+        // Art-Net replaces the UART unless both are asked for
+        drop(dmx_tx);
+        assert!(uart_rx.recv().await.is_none());
+    }
+
+    #[test]
+    fn test_recall_scene_merges() {
+        let mut state = DmxState::init(test_config(DmxConfig {
+            scenes: BTreeMap::from([("flash".to_string(), BTreeMap::from([(1, 255)]))]),
+            ..Default::default()
+        }));
+        state.values[1] = 42;
+
+        state.recall_scene("flash");
+        assert_eq!(state.values[0], 255);
+        assert_eq!(state.values[1], 42);
+
+        // Unknown scenes don't change anything
+        state.recall_scene("missing");
+        assert_eq!(&state.values[..2], &[255, 42]);
+    }
+
+    #[tokio::test]
+    async fn test_query_snapshot() {
+        let state = DmxState::init(test_config(DmxConfig::default()));
+
+        let (dmx_tx, dmx_rx) = mpsc::channel(10);
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        tokio::spawn(state.start(dmx_rx, uart_tx, CancellationToken::new()));
+        let handle = DmxHandle::new(dmx_tx);
+
+        let snapshot = handle.query().await.unwrap();
+        assert_eq!(snapshot.last_sent, None);
+        assert_eq!(snapshot.updates, 0);
+
+        handle
+            .tx
+            .send(DmxMessage::UpdateState(vec![(1, 10), (310, 20), (600, 30)]))
+            .await
+            .unwrap();
+        handle.tx.send(DmxMessage::Send).await.unwrap();
+        uart_rx.recv().await.unwrap();
+
+        let snapshot = handle.query().await.unwrap();
+        let mut expected = [0; DMX_CHANNELS];
+        expected[0] = 10;
+        expected[309] = 20;
+        assert_eq!(snapshot.values, expected);
+        assert!(snapshot.last_sent.is_some());
+        assert_eq!(snapshot.updates, 2);
+        assert_eq!(snapshot.rejected_updates, 1);
+    }
+
+    #[tokio::test]
+    async fn test_rapid_sends_coalesce() {
+        let state = DmxState::init(test_config(DmxConfig::default()));
+
+        let (dmx_tx, dmx_rx) = mpsc::channel(100);
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        tokio::spawn(state.start(dmx_rx, uart_tx, CancellationToken::new()));
+        let handle = DmxHandle::new(dmx_tx);
+
+        for i in 0..10 {
+            handle
+                .tx
+                .send(DmxMessage::UpdateState(vec![(1, i)]))
+                .await
+                .unwrap();
+            handle.tx.send(DmxMessage::Send).await.unwrap();
+        }
+
+        // Only the latest state goes out
+        let UartMessage::DMX(data) = uart_rx.recv().await.unwrap() else {
+            panic!("Expected a DMX frame");
+        };
+        assert_eq!(data[1], 9);
+
+        let snapshot = handle.query().await.unwrap();
+        assert_eq!(snapshot.coalesced_sends, 9);
+        assert!(uart_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_only_on_change() {
+        let state = DmxState::init(test_config(DmxConfig {
+            send_window_ms: 0,
+            send_only_on_change: true,
+            ..Default::default()
+        }));
+
+        let (dmx_tx, dmx_rx) = mpsc::channel(10);
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        tokio::spawn(state.start(dmx_rx, uart_tx, CancellationToken::new()));
+
+        dmx_tx.send(DmxMessage::Send).await.unwrap();
+        dmx_tx.send(DmxMessage::Send).await.unwrap();
+        dmx_tx
+            .send(DmxMessage::UpdateState(vec![(1, 10)]))
+            .await
+            .unwrap();
+        dmx_tx.send(DmxMessage::Send).await.unwrap();
+        drop(dmx_tx);
+
+        // The second send had nothing new in it
+        let mut frames = Vec::new();
+        while let Some(UartMessage::DMX(data)) = uart_rx.recv().await {
+            frames.push(data[1]);
+        }
+        assert_eq!(frames, vec![0, 10]);
+    }
+
+    #[test]
+    fn test_override_beats_show_layer() {
+        let mut state = DmxState::init(test_config(DmxConfig::default()));
+        state.update(vec![(1, 10), (2, 20)]);
+
+        assert!(state.set_override(1, Some(200)));
+        assert_eq!(&state.output()[..2], &[200, 20]);
+
+        // Show frames keep writing underneath the override
+        state.update(vec![(1, 30), (2, 40)]);
+        assert_eq!(&state.output()[..2], &[200, 40]);
+        assert_eq!(state.values[0], 30);
+
+        // Releasing it shows the latest show value
+        assert!(state.set_override(1, None));
+        assert_eq!(&state.output()[..2], &[30, 40]);
+
+        assert!(!state.set_override(0, Some(1)));
+        assert!(!state.set_override(513, Some(1)));
+    }
+
+    #[test]
+    fn test_override_can_hold_zero() {
+        let mut state = DmxState::init(test_config(DmxConfig::default()));
+        state.update(vec![(5, 99)]);
+        state.set_override(5, Some(0));
+
+        assert_eq!(state.output()[4], 0);
+        assert_eq!(state.frame().unwrap()[5], 0);
+    }
+
+    #[tokio::test]
+    async fn test_overrides_survive_zero_out() {
+        let state = DmxState::init(test_config(DmxConfig {
+            send_window_ms: 0,
+            ..Default::default()
+        }));
+
+        let (dmx_tx, dmx_rx) = mpsc::channel(10);
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        tokio::spawn(state.start(dmx_rx, uart_tx, CancellationToken::new()));
+        let handle = DmxHandle::new(dmx_tx);
+
+        handle
+            .tx
+            .send(DmxMessage::Override {
+                channel: 3,
+                value: Some(77),
+            })
+            .await
+            .unwrap();
+        let UartMessage::DMX(data) = uart_rx.recv().await.unwrap() else {
+            panic!("Expected a DMX frame");
+        };
+        assert_eq!(data[3], 77);
+
+        // The end of a show doesn't release overrides
+        handle.tx.send(DmxMessage::ZeroOut).await.unwrap();
+        let UartMessage::DMX(data) = uart_rx.recv().await.unwrap() else {
+            panic!("Expected a DMX frame");
+        };
+        assert_eq!(data[3], 77);
+
+        handle.tx.send(DmxMessage::ClearOverrides).await.unwrap();
+        let UartMessage::DMX(data) = uart_rx.recv().await.unwrap() else {
+            panic!("Expected a DMX frame");
+        };
+        assert_eq!(data[3], 0);
+
+        let snapshot = handle.query().await.unwrap();
+        assert!(snapshot.overrides.iter().all(Option::is_none));
+    }
+
+    #[tokio::test]
+    async fn test_recorder_sink() {
+        let state = DmxState::init(test_config(DmxConfig {
+            send_window_ms: 0,
+            sink: Some(DmxSinkKind::Recorder),
+            ..Default::default()
+        }));
+
+        let (dmx_tx, dmx_rx) = mpsc::channel(10);
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        tokio::spawn(state.start(dmx_rx, uart_tx, CancellationToken::new()));
+        let handle = DmxHandle::new(dmx_tx);
+
+        for i in 1..=3 {
+            handle
+                .tx
+                .send(DmxMessage::UpdateState(vec![(1, i)]))
+                .await
+                .unwrap();
+            handle.tx.send(DmxMessage::Send).await.unwrap();
+        }
+
+        let frames = handle.recent_frames(2).await.unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].channels[0], 2);
+        assert_eq!(frames[1].channels[0], 3);
+
+        // Nothing went to the UART
+        drop(handle);
+        assert!(uart_rx.recv().await.is_none());
+    }
+
+    #[test]
+    fn test_effect_layers() {
+        let mut state = DmxState::init(test_config(DmxConfig::default()));
+        state.update(vec![(1, 10), (2, 20), (3, 30)]);
+
+        let start = Instant::now();
+        state.effects.insert(
+            "strobe".to_string(),
+            (
+                DmxEffect::Strobe {
+                    channel: 1,
+                    rate_hz: 10.0,
+                    level: 255,
+                },
+                start,
+            ),
+        );
+        state.set_override(2, Some(99));
+
+        // The strobe sits on top of the show, and overrides sit on top of
+        // both
+        assert_eq!(&state.output_at(start)[..3], &[255, 99, 30]);
+        assert_eq!(
+            &state.output_at(start + Duration::from_millis(60))[..3],
+            &[0, 99, 30]
+        );
+
+        // Writing to another channel leaves the effect running
+        state.update(vec![(3, 40)]);
+        assert!(state.effects.contains_key("strobe"));
+
+        // Writing to the strobe's channel stops it
+        state.update(vec![(1, 50)]);
+        assert!(state.effects.is_empty());
+        assert_eq!(&state.output_at(start)[..3], &[50, 99, 40]);
+    }
+
+    #[test]
+    fn test_fade_to_blackout() {
+        let mut state = DmxState::init(test_config(DmxConfig {
+            blackout: BTreeMap::from([(3, 64)]),
+            ..Default::default()
+        }));
+        state.update(vec![(1, 200), (2, 100)]);
+
+        state.fade_to_blackout(Duration::from_millis(1_000));
+        let (_, started) = state.effects["fade"];
+
+        assert_eq!(&state.output_at(started)[..3], &[200, 100, 0]);
+        assert_eq!(
+            &state.output_at(started + Duration::from_millis(500))[..3],
+            &[100, 50, 32]
+        );
+        assert_eq!(
+            &state.output_at(started + Duration::from_secs(2))[..3],
+            &[0, 0, 64]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_effects_tick_and_stop_on_blackout() {
+        let state = DmxState::init(test_config(DmxConfig {
+            send_window_ms: 0,
+            effect_tick_ms: 5,
+            ..Default::default()
+        }));
+
+        let (dmx_tx, dmx_rx) = mpsc::channel(10);
+        let (uart_tx, mut uart_rx) = mpsc::channel(100);
+        tokio::spawn(state.start(dmx_rx, uart_tx, CancellationToken::new()));
+        let handle = DmxHandle::new(dmx_tx);
+
+        handle
+            .tx
+            .send(DmxMessage::StartEffect {
+                id: "chase".to_string(),
+                effect: DmxEffect::Chase {
+                    channels: vec![1, 2],
+                    step_ms: 1_000,
+                    level: 128,
+                },
+            })
+            .await
+            .unwrap();
+
+        // The effect keeps producing frames without any sends
+        for _ in 0..3 {
+            let UartMessage::DMX(data) = uart_rx.recv().await.unwrap() else {
+                panic!("Expected a DMX frame");
+            };
+            assert_eq!(&data[1..3], &[128, 0]);
+        }
+
+        handle.tx.send(DmxMessage::Blackout).await.unwrap();
+        let snapshot = handle.query().await.unwrap();
+        assert_eq!(&snapshot.values[..2], &[0, 0]);
+
+        // Once the blackout frame is out, nothing else is sent
+        drop(handle);
+        let mut last = None;
+        while let Some(UartMessage::DMX(data)) = uart_rx.recv().await {
+            last = Some(data);
+        }
+        assert_eq!(&last.unwrap()[1..3], &[0, 0]);
+    }
+}
@@ -21,10 +21,10 @@ impl DmxHeaderPack {
 
 // DMX Data (8-bit Packet):
 // Byte #    | Bits      | Definition
-// 1 -> 255  | 0xFF      | DMX Channel Data - Forward the DMX data as required by the channel
+// 1 -> 512  | 0xFF      | DMX Channel Data - Forward the DMX data as required by the channel
 //
 // Note: Writing again to the DMX controller requires addressing it again
-// and sending another 255 bytes
+// and sending another 512 bytes
 #[derive(PackedStruct, Default, Debug, PartialEq, Clone)]
 #[packed_struct(bit_numbering = "msb0", size = "1")]
 pub struct DmxDataPack {
@@ -38,6 +38,25 @@ impl DmxDataPack {
     }
 }
 
+/// Pack a full transmission for a DMX controller: the header, then one data
+/// pack per channel. The controller counts channels from the byte after the
+/// header, so every channel in the universe is always sent. Neither pack type
+/// carries a checksum.
+pub fn pack_frame(header: &DmxHeaderPack, channels: &[u8]) -> Result<Vec<u8>, PackingError> {
+    let mut data = Vec::with_capacity(channels.len() + 1);
+
+    data.extend_from_slice(&header.pack_header()?);
+
+    for channel in channels {
+        let pack = DmxDataPack {
+            channel_data: (*channel).into(),
+        };
+        data.extend_from_slice(&pack.pack_data()?);
+    }
+
+    Ok(data)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -83,4 +102,53 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_dmx_header_round_trip() -> Result<(), PackingError> {
+        for controller_id in 0xA..=0xE {
+            for universe in 0..=0xF {
+                let header = DmxHeaderPack {
+                    controller_id: controller_id.into(),
+                    universe: universe.into(),
+                };
+                let packed = header.pack_header()?;
+
+                assert_eq!(packed[0] >> 4, controller_id);
+                assert_eq!(packed[0] & 0x0F, universe);
+                assert_eq!(DmxHeaderPack::unpack(&packed)?, header);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dmx_data_round_trip() -> Result<(), PackingError> {
+        for value in 0..=u8::MAX {
+            let data = DmxDataPack {
+                channel_data: value.into(),
+            };
+            let packed = data.pack_data()?;
+
+            assert_eq!(packed, [value]);
+            assert_eq!(DmxDataPack::unpack(&packed)?, data);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pack_frame() -> Result<(), PackingError> {
+        let header = DmxHeaderPack {
+            controller_id: 0xA.into(),
+            universe: 3.into(),
+        };
+
+        assert_eq!(
+            pack_frame(&header, &[0x00, 0x7F, 0xFF])?,
+            vec![0xA3, 0x00, 0x7F, 0xFF]
+        );
+
+        Ok(())
+    }
 }
@@ -0,0 +1,150 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+use anyhow::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::config::RecorderConfig;
+
+use super::{sink::DmxSink, DMX_CHANNELS};
+
+/// A frame as it would have gone out, with when it was sent
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    pub time: DateTime<Local>,
+    pub channels: Vec<u8>,
+}
+
+/// Keeps the most recent frames in memory, and optionally appends every frame
+/// to a JSONL file, so that shows can be checked without any DMX hardware
+pub struct RecorderSink {
+    frames: VecDeque<RecordedFrame>,
+    capacity: usize,
+    file: Option<File>,
+}
+
+impl RecorderSink {
+    pub fn new(config: &RecorderConfig) -> Result<Self, Error> {
+        let file = match &config.path {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+
+        Ok(RecorderSink {
+            frames: VecDeque::with_capacity(config.capacity),
+            capacity: config.capacity,
+            file,
+        })
+    }
+}
+
+#[async_trait]
+impl DmxSink for RecorderSink {
+    async fn send(&mut self, channels: &[u8; DMX_CHANNELS]) -> Result<(), Error> {
+        let frame = RecordedFrame {
+            time: Local::now(),
+            channels: channels.to_vec(),
+        };
+
+        if let Some(file) = &mut self.file {
+            writeln!(file, "{}", serde_json::to_string(&frame)?)?;
+        }
+
+        if self.capacity > 0 {
+            if self.frames.len() == self.capacity {
+                self.frames.pop_front();
+            }
+            self.frames.push_back(frame);
+        }
+
+        Ok(())
+    }
+
+    fn recent_frames(&self, count: usize) -> Option<Vec<RecordedFrame>> {
+        let skip = self.frames.len().saturating_sub(count);
+        Some(self.frames.iter().skip(skip).cloned().collect())
+    }
+}
+
+/// Read the last `count` frames back out of a recorder's JSONL file
+pub fn read_recorded_frames(path: &Path, count: usize) -> Result<Vec<RecordedFrame>, Error> {
+    let mut frames = VecDeque::with_capacity(count);
+
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if frames.len() == count {
+            frames.pop_front();
+        }
+        if count > 0 {
+            frames.push_back(serde_json::from_str(&line)?);
+        }
+    }
+
+    Ok(frames.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn channels(first: u8) -> [u8; DMX_CHANNELS] {
+        let mut channels = [0; DMX_CHANNELS];
+        channels[0] = first;
+        channels
+    }
+
+    #[tokio::test]
+    async fn test_ring_buffer_keeps_latest() {
+        let mut recorder = RecorderSink::new(&RecorderConfig {
+            capacity: 3,
+            path: None,
+        })
+        .unwrap();
+
+        for i in 0..5 {
+            recorder.send(&channels(i)).await.unwrap();
+        }
+
+        let firsts = |frames: Vec<RecordedFrame>| -> Vec<u8> {
+            frames.iter().map(|f| f.channels[0]).collect()
+        };
+        assert_eq!(firsts(recorder.recent_frames(10).unwrap()), vec![2, 3, 4]);
+        assert_eq!(firsts(recorder.recent_frames(2).unwrap()), vec![3, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_jsonl_trace() {
+        let dir = TempDir::new("dmx");
+        let path = dir.join("trace.jsonl");
+
+        let mut recorder = RecorderSink::new(&RecorderConfig {
+            capacity: 0,
+            path: Some(path.clone()),
+        })
+        .unwrap();
+        for i in 0..4 {
+            recorder.send(&channels(i)).await.unwrap();
+        }
+
+        // Nothing is kept in memory, but the file has everything
+        assert_eq!(recorder.recent_frames(10), Some(vec![]));
+
+        let frames = read_recorded_frames(&path, 2).unwrap();
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].channels[0], 2);
+        assert_eq!(frames[1].channels[0], 3);
+        assert_eq!(frames[1].channels.len(), DMX_CHANNELS);
+    }
+}
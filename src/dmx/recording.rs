@@ -0,0 +1,326 @@
+use std::{
+    io::{Read, Write},
+    time::Duration,
+};
+
+use anyhow::Error;
+use tokio::time::Instant;
+
+use crate::{config::Config, show::prelude::UnloadedShow, InternalMessage};
+
+use super::DmxState;
+
+const MAGIC: &[u8; 5] = b"RHDMX";
+const FORMAT_VERSION: u8 = 1;
+
+/// Every DMX frame a show sends, with the show timestamp it was sent at. Used
+/// to check that changes to the engine don't change how existing shows look.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DmxRecording {
+    pub frames: Vec<RecordingFrame>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordingFrame {
+    /// Milliseconds since the start of the show
+    pub timestamp: u64,
+    pub channels: Vec<u8>,
+}
+
+impl DmxRecording {
+    /// Run a show's frames through the DMX engine without waiting on the
+    /// timestamps, and keep every frame it would send. The zero out at the end
+    /// of the show is the last frame.
+    pub fn record(show: &UnloadedShow, config: &Config) -> Self {
+        let mut state = DmxState::init(config.clone());
+        let start = Instant::now();
+        let mut recording = DmxRecording::default();
+
+        let mut capture = |state: &DmxState, timestamp: u64| {
+            let now = start + Duration::from_millis(timestamp);
+            recording.frames.push(RecordingFrame {
+                timestamp,
+                channels: state.output_at(now).to_vec(),
+            });
+        };
+
+        for frame in &show.frames {
+            for message in frame.dmx_messages() {
+                match message {
+                    InternalMessage::DmxScene(name) => state.recall_scene(&name),
+                    InternalMessage::DmxUpdateState(positions) => {
+                        state.update(positions);
+                    }
+                    InternalMessage::DmxSendRequest => capture(&state, frame.timestamp),
+                    _ => {}
+                }
+            }
+        }
+
+        let end = show.frames.last().map_or(0, |frame| frame.timestamp);
+        state.zero_out();
+        capture(&state, end);
+
+        recording
+    }
+
+    /// Each frame only stores the channels that changed since the frame
+    /// before it, since most of a universe sits still between frames.
+    ///
+    /// ```text
+    /// "RHDMX" | version: u8 | channels: u16
+    /// per frame: timestamp: u64 | changed: u16 | changed * (channel: u16, value: u8)
+    /// ```
+    ///
+    /// Numbers are little endian.
+    pub fn write(&self, writer: &mut impl Write) -> Result<(), Error> {
+        let channels = self.frames.first().map_or(0, |frame| frame.channels.len());
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[FORMAT_VERSION])?;
+        writer.write_all(&(channels as u16).to_le_bytes())?;
+
+        let mut previous = vec![0; channels];
+        for frame in &self.frames {
+            if frame.channels.len() != channels {
+                return Err(Error::msg(format!(
+                    "Frame at {}ms has {} channels, expected {}",
+                    frame.timestamp,
+                    frame.channels.len(),
+                    channels
+                )));
+            }
+
+            let changed = frame
+                .channels
+                .iter()
+                .zip(&previous)
+                .enumerate()
+                .filter(|(_, (value, previous))| value != previous)
+                .map(|(index, (value, _))| (index as u16, *value))
+                .collect::<Vec<_>>();
+
+            writer.write_all(&frame.timestamp.to_le_bytes())?;
+            writer.write_all(&(changed.len() as u16).to_le_bytes())?;
+            for (index, value) in changed {
+                writer.write_all(&index.to_le_bytes())?;
+                writer.write_all(&[value])?;
+            }
+
+            previous.clone_from(&frame.channels);
+        }
+
+        Ok(())
+    }
+
+    pub fn read(reader: &mut impl Read) -> Result<Self, Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let mut bytes = bytes.as_slice();
+
+        if take(&mut bytes, MAGIC.len())? != MAGIC {
+            return Err(Error::msg("Not a DMX recording"));
+        }
+        let version = take(&mut bytes, 1)?[0];
+        if version != FORMAT_VERSION {
+            return Err(Error::msg(format!(
+                "DMX recording version {} isn't supported",
+                version
+            )));
+        }
+        let channels = u16::from_le_bytes(take(&mut bytes, 2)?.try_into()?) as usize;
+
+        let mut recording = DmxRecording::default();
+        let mut current = vec![0; channels];
+        while !bytes.is_empty() {
+            let timestamp = u64::from_le_bytes(take(&mut bytes, 8)?.try_into()?);
+            let changed = u16::from_le_bytes(take(&mut bytes, 2)?.try_into()?);
+
+            for _ in 0..changed {
+                let index = u16::from_le_bytes(take(&mut bytes, 2)?.try_into()?) as usize;
+                let value = take(&mut bytes, 1)?[0];
+                *current.get_mut(index).ok_or_else(|| {
+                    Error::msg(format!("Channel {} is out of range", index + 1))
+                })? = value;
+            }
+
+            recording.frames.push(RecordingFrame {
+                timestamp,
+                channels: current.clone(),
+            });
+        }
+
+        Ok(recording)
+    }
+
+    /// Compare two recordings frame by frame
+    pub fn diff(&self, other: &DmxRecording) -> RecordingDiff {
+        let mut diff = RecordingDiff {
+            frame_counts: (self.frames.len(), other.frames.len()),
+            ..Default::default()
+        };
+
+        for (a, b) in self.frames.iter().zip(&other.frames) {
+            if a.timestamp != b.timestamp {
+                diff.timestamp_mismatches += 1;
+            }
+
+            let len = a.channels.len().max(b.channels.len());
+            for index in 0..len {
+                let a_value = a.channels.get(index).copied().unwrap_or(0);
+                let b_value = b.channels.get(index).copied().unwrap_or(0);
+                if a_value == b_value {
+                    continue;
+                }
+
+                let channel = index + 1;
+                let delta = a_value.abs_diff(b_value);
+                match diff.channels.iter_mut().find(|c| c.channel == channel) {
+                    Some(divergence) => {
+                        divergence.frames += 1;
+                        divergence.max_delta = divergence.max_delta.max(delta);
+                    }
+                    None => diff.channels.push(ChannelDivergence {
+                        channel,
+                        frames: 1,
+                        first_timestamp: a.timestamp,
+                        max_delta: delta,
+                    }),
+                }
+            }
+        }
+
+        diff.channels.sort_by_key(|c| c.channel);
+
+        diff
+    }
+}
+
+fn take<'a>(bytes: &mut &'a [u8], count: usize) -> Result<&'a [u8], Error> {
+    if bytes.len() < count {
+        return Err(Error::msg("DMX recording ends part way through a frame"));
+    }
+
+    let (taken, rest) = bytes.split_at(count);
+    *bytes = rest;
+
+    Ok(taken)
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RecordingDiff {
+    pub frame_counts: (usize, usize),
+    /// Frames at the same position that were sent at different times
+    pub timestamp_mismatches: usize,
+    pub channels: Vec<ChannelDivergence>,
+}
+
+impl RecordingDiff {
+    pub fn is_same(&self) -> bool {
+        self.frame_counts.0 == self.frame_counts.1
+            && self.timestamp_mismatches == 0
+            && self.channels.is_empty()
+    }
+}
+
+/// How far a single channel drifted between two recordings
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelDivergence {
+    /// DMX address, starting at 1
+    pub channel: usize,
+    /// How many frames had a different value
+    pub frames: usize,
+    /// The timestamp of the first frame that differed
+    pub first_timestamp: u64,
+    /// The biggest difference between the two values
+    pub max_delta: u8,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::config::Projector;
+
+    fn record_fixture() -> DmxRecording {
+        let config = Config {
+            projectors: vec![Projector {
+                id: 20,
+                format: vec!["state".into(), "gallery".into(), "pattern".into()],
+            }],
+            ..Default::default()
+        };
+        let show = UnloadedShow::load_show_file(
+            Path::new("tests/fixtures/shows/dmx/instructions.json"),
+            &config,
+        )
+        .unwrap();
+
+        DmxRecording::record(&show, &config)
+    }
+
+    #[test]
+    fn test_record_show() {
+        let recording = record_fixture();
+
+        let timestamps = recording
+            .frames
+            .iter()
+            .map(|frame| frame.timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, vec![0, 500, 1000, 1000]);
+
+        assert_eq!(recording.frames[0].channels[0], 255);
+        assert_eq!(recording.frames[0].channels[21], 5);
+        assert_eq!(recording.frames[1].channels[0], 0);
+        assert_eq!(recording.frames[1].channels[309], 128);
+        // Zeroed out at the end
+        assert!(recording.frames[3].channels.iter().all(|v| *v == 0));
+    }
+
+    #[test]
+    fn test_write_read_round_trip() {
+        let recording = record_fixture();
+
+        let mut bytes = Vec::new();
+        recording.write(&mut bytes).unwrap();
+
+        // Only the changes are stored, not four full universes
+        assert!(bytes.len() < 100);
+        assert_eq!(&bytes[..6], b"RHDMX\x01");
+
+        let read = DmxRecording::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read, recording);
+
+        assert!(DmxRecording::read(&mut &bytes[..bytes.len() - 1]).is_err());
+        assert!(DmxRecording::read(&mut &b"nope"[..]).is_err());
+    }
+
+    #[test]
+    fn test_diff() {
+        let a = record_fixture();
+        assert!(a.diff(&a).is_same());
+
+        let mut b = a.clone();
+        b.frames[1].channels[309] = 100;
+        b.frames[2].channels[309] = 100;
+        b.frames[2].timestamp = 1001;
+
+        let diff = a.diff(&b);
+        assert!(!diff.is_same());
+        assert_eq!(diff.timestamp_mismatches, 1);
+        assert_eq!(
+            diff.channels,
+            vec![ChannelDivergence {
+                channel: 310,
+                frames: 2,
+                first_timestamp: 500,
+                max_delta: 28,
+            }]
+        );
+
+        b.frames.pop();
+        assert_eq!(a.diff(&b).frame_counts, (4, 3));
+    }
+}
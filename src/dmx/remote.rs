@@ -0,0 +1,189 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::Error;
+use async_trait::async_trait;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::{
+    config::RemoteDmxConfig,
+    show::prelude::{DmxStateData, DmxStateIndex, DmxStateVarPosition},
+};
+
+use super::{sink::DmxSink, DMX_CHANNELS};
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+/// A remote that hangs is given up on and retried like one that's down, so
+/// later changes still get through once it answers
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The body POSTed to a remote controller's `/dmx/update`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RemoteDmxUpdate {
+    pub channels: Vec<DmxStateVarPosition>,
+}
+
+/// Pushes changed channels to another controller over HTTP. The requests
+/// happen on their own task, so a slow or missing remote never holds up the
+/// local sinks.
+pub struct RemoteSink {
+    last: [u8; DMX_CHANNELS],
+    tx: mpsc::UnboundedSender<Vec<DmxStateVarPosition>>,
+}
+
+impl RemoteSink {
+    pub fn new(config: &RemoteDmxConfig) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let url = format!("{}/dmx/update", config.url.trim_end_matches('/'));
+        tokio::spawn(push_updates(reqwest::Client::new(), url, rx));
+
+        RemoteSink {
+            // The remote starts out dark, so only lit channels need sending
+            last: [0; DMX_CHANNELS],
+            tx,
+        }
+    }
+}
+
+#[async_trait]
+impl DmxSink for RemoteSink {
+    async fn send(&mut self, channels: &[u8; DMX_CHANNELS]) -> Result<(), Error> {
+        let changed = channels
+            .iter()
+            .zip(&self.last)
+            .enumerate()
+            .filter(|(_, (value, last))| value != last)
+            .map(|(index, (value, _))| ((index + 1) as DmxStateIndex, *value))
+            .collect::<Vec<_>>();
+        self.last = *channels;
+
+        if changed.is_empty() {
+            return Ok(());
+        }
+
+        self.tx
+            .send(changed)
+            .map_err(|_| Error::msg("Remote DMX task has stopped"))
+    }
+}
+
+/// Post changes as they come in. Anything that fails to post is merged with
+/// later changes and retried with a growing delay, so the remote catches up
+/// with the latest state once it's reachable again.
+async fn push_updates(
+    client: reqwest::Client,
+    url: String,
+    mut rx: mpsc::UnboundedReceiver<Vec<DmxStateVarPosition>>,
+) {
+    let mut pending: BTreeMap<DmxStateIndex, DmxStateData> = BTreeMap::new();
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        if pending.is_empty() {
+            match rx.recv().await {
+                Some(changed) => pending.extend(changed),
+                None => return,
+            }
+        }
+        while let Ok(changed) = rx.try_recv() {
+            pending.extend(changed);
+        }
+
+        let update = RemoteDmxUpdate {
+            channels: pending.iter().map(|(c, v)| (*c, *v)).collect(),
+        };
+
+        let result = client
+            .post(&url)
+            .json(&update)
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        match result {
+            Ok(_) => {
+                pending.clear();
+                backoff = MIN_BACKOFF;
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to push DMX to {}, retrying in {:?}: {}",
+                    url, backoff, e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Remote {
+        channels: Arc<Mutex<BTreeMap<DmxStateIndex, DmxStateData>>>,
+        requests: Arc<AtomicUsize>,
+    }
+
+    /// Stands in for the remote controller. The first request fails so the
+    /// retry path gets used.
+    async fn update(
+        State(remote): State<Remote>,
+        Json(update): Json<RemoteDmxUpdate>,
+    ) -> StatusCode {
+        if remote.requests.fetch_add(1, Ordering::SeqCst) == 0 {
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+
+        remote.channels.lock().unwrap().extend(update.channels);
+        StatusCode::OK
+    }
+
+    #[tokio::test]
+    async fn test_remote_converges() {
+        let remote = Remote::default();
+        let router = Router::new()
+            .route("/dmx/update", post(update))
+            .with_state(remote.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        let mut sink = RemoteSink::new(&RemoteDmxConfig {
+            url: format!("http://{}/", addr),
+        });
+
+        let mut channels = [0; DMX_CHANNELS];
+        channels[0] = 255;
+        channels[9] = 10;
+        sink.send(&channels).await.unwrap();
+        channels[9] = 20;
+        channels[511] = 1;
+        sink.send(&channels).await.unwrap();
+        channels[0] = 0;
+        sink.send(&channels).await.unwrap();
+
+        let expected = BTreeMap::from([(1, 0), (10, 20), (512, 1)]);
+        for _ in 0..100 {
+            if *remote.channels.lock().unwrap() == expected {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        assert_eq!(*remote.channels.lock().unwrap(), expected);
+        assert!(remote.requests.load(Ordering::SeqCst) >= 2);
+    }
+}
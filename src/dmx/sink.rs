@@ -0,0 +1,76 @@
+use anyhow::Error;
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+
+use crate::{config::Config, uart::UartMessage};
+
+use super::{
+    artnet::ArtNetSender,
+    pack::{pack_frame, DmxHeaderPack},
+    recorder::RecordedFrame,
+    DMX_CHANNELS,
+};
+
+/// Somewhere that DMX frames can be sent
+#[async_trait]
+pub trait DmxSink: Send {
+    async fn send(&mut self, channels: &[u8; DMX_CHANNELS]) -> Result<(), Error>;
+
+    /// The last `count` frames, oldest first, for sinks that keep them
+    fn recent_frames(&self, _count: usize) -> Option<Vec<RecordedFrame>> {
+        None
+    }
+
+    /// The UART task was restarted
+    fn reconnect(&mut self, _uart_tx: mpsc::Sender<UartMessage>) {}
+
+    /// Everything for a show frame has been sent
+    async fn flush(&mut self, _frame_id: u64) {}
+}
+
+/// Frames the channels for the DMX controller on the UART bus
+pub struct UartSink {
+    header: DmxHeaderPack,
+    uart_tx: mpsc::Sender<UartMessage>,
+}
+
+impl UartSink {
+    pub fn new(config: &Config, uart_tx: mpsc::Sender<UartMessage>) -> Self {
+        UartSink {
+            header: DmxHeaderPack {
+                controller_id: config.dmx.controller_id.into(),
+                universe: config.dmx.universe.into(),
+            },
+            uart_tx,
+        }
+    }
+}
+
+#[async_trait]
+impl DmxSink for UartSink {
+    async fn send(&mut self, channels: &[u8; DMX_CHANNELS]) -> Result<(), Error> {
+        let data = pack_frame(&self.header, channels)
+            .map_err(|e| Error::msg(format!("Failed to pack DMX frame: {:?}", e)))?;
+
+        self.uart_tx
+            .send(UartMessage::DMX(data))
+            .await
+            .map_err(|e| Error::msg(format!("UART task has stopped: {}", e)))
+    }
+
+    fn reconnect(&mut self, uart_tx: mpsc::Sender<UartMessage>) {
+        self.uart_tx = uart_tx;
+    }
+
+    async fn flush(&mut self, frame_id: u64) {
+        // A stopped UART is already reported by `send`
+        let _ = self.uart_tx.send(UartMessage::Flush { frame_id }).await;
+    }
+}
+
+#[async_trait]
+impl DmxSink for ArtNetSender {
+    async fn send(&mut self, channels: &[u8; DMX_CHANNELS]) -> Result<(), Error> {
+        ArtNetSender::send(self, channels).await
+    }
+}
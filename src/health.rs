@@ -0,0 +1,534 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Error;
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use log::error;
+use prometheus::TEXT_FORMAT;
+use serde_json::{json, Value};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, oneshot, watch},
+    time::{interval, Instant, Interval, MissedTickBehavior},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    dmx::{DmxHandle, DmxSnapshot},
+    laser::{LaserMessage, LaserStats},
+    metrics::Metrics,
+    show::prelude::{ShowControl, ShowName, SnapshotReply},
+    structure::FileStructure,
+    supervisor::{Restarts, TaskSender, RESTART_WINDOW},
+    uart::{TrafficStats, UartMessage, UartStats},
+};
+
+/// How often each task beats while it's running
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long `/status` waits on each task before leaving it out
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// When a task last showed it was still running. Clones share the same time,
+/// so the task beats on one and the health server reads another.
+#[derive(Debug, Clone, Default)]
+pub struct Heartbeat(Arc<Mutex<Option<Instant>>>);
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        *self.0.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// How long since the last beat, or `None` if it's never beat
+    pub fn age(&self) -> Option<Duration> {
+        self.0.lock().unwrap().map(|last| last.elapsed())
+    }
+
+    /// Ticks every `HEARTBEAT_INTERVAL`, for a task's loop to beat on. A task
+    /// stuck part way through its loop stops beating.
+    pub fn ticker() -> Interval {
+        let mut ticker = interval(HEARTBEAT_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        ticker
+    }
+
+    /// Run `task`, beating for as long as it runs. This is for tasks like the
+    /// show worker that spend a long time waiting on purpose, where beating
+    /// in their loop would make them look stuck.
+    pub async fn beat_while<F: Future>(&self, task: F) -> F::Output {
+        tokio::pin!(task);
+        let mut ticker = Self::ticker();
+        loop {
+            tokio::select! {
+                output = &mut task => return output,
+                _ = ticker.tick() => self.beat(),
+            }
+        }
+    }
+}
+
+/// The heartbeat of every task `/healthz` checks, by name
+#[derive(Debug, Clone, Default)]
+pub struct Heartbeats(Vec<(&'static str, Heartbeat)>);
+
+impl Heartbeats {
+    /// A heartbeat for the task called `name`
+    pub fn add(&mut self, name: &'static str) -> Heartbeat {
+        let heartbeat = Heartbeat::default();
+        self.0.push((name, heartbeat.clone()));
+        heartbeat
+    }
+
+    /// The tasks that haven't beat within `stale_after`, or ever
+    pub fn stale(&self, stale_after: Duration) -> Vec<&'static str> {
+        self.0
+            .iter()
+            .filter(|(_, heartbeat)| heartbeat.age().is_none_or(|age| age > stale_after))
+            .map(|(name, _)| *name)
+            .collect()
+    }
+}
+
+/// The show that's playing, set by the show worker
+#[derive(Debug, Clone, Default)]
+pub struct NowPlaying(Arc<Mutex<Option<ShowName>>>);
+
+impl NowPlaying {
+    pub fn set(&self, name: Option<ShowName>) {
+        *self.0.lock().unwrap() = name;
+    }
+
+    pub fn get(&self) -> Option<ShowName> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Everything the health server asks about the running tasks
+#[derive(Clone)]
+pub struct HealthState {
+    pub heartbeats: Heartbeats,
+    /// How long a task can go without beating before it's reported dead
+    pub stale_after: Duration,
+    pub now_playing: NowPlaying,
+    pub lasers: TaskSender<LaserMessage>,
+    pub dmx: DmxHandle,
+    /// The UART task is replaced when it restarts, so this follows it
+    pub uart: watch::Receiver<mpsc::Sender<UartMessage>>,
+    /// The show worker is replaced when it restarts, so this follows it
+    pub show_controls: TaskSender<ShowControl>,
+    /// Served on `/metrics`, if that's turned on
+    pub metrics: Option<Metrics>,
+    /// Checked again for each status request, without making anything
+    pub structure: FileStructure,
+    pub restarts: Restarts,
+    pub started: Instant,
+}
+
+/// `/healthz` for systemd, which fails if any task has stopped beating or
+/// been left stopped after restarting too often, and says how often each
+/// has restarted,
+/// `/status` for the monitoring, and
+/// `/metrics` for Prometheus, if there are metrics
+pub fn router(state: HealthState) -> Router {
+    let mut router = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/status", get(status));
+    if state.metrics.is_some() {
+        router = router.route("/metrics", get(metrics));
+    }
+    router.with_state(state)
+}
+
+/// Answer requests on `listener` until `cancel` fires
+pub async fn serve(listener: TcpListener, state: HealthState, cancel: CancellationToken) {
+    if let Err(e) = axum::serve(listener, router(state))
+        .with_graceful_shutdown(cancel.cancelled_owned())
+        .await
+    {
+        error!("The health server stopped: {}", e);
+    }
+}
+
+async fn healthz(State(state): State<HealthState>) -> (StatusCode, Json<Value>) {
+    let stale = state.heartbeats.stale(state.stale_after);
+    let stopped = state.restarts.given_up();
+    let healthy = stale.is_empty() && stopped.is_empty();
+    let code = match healthy {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (
+        code,
+        Json(json!({
+            "healthy": healthy,
+            "stale": stale,
+            "stopped": stopped,
+            "restarts": state.restarts.report(RESTART_WINDOW),
+        })),
+    )
+}
+
+/// Asks each task at once. A task that doesn't answer is left out and named
+/// in `errors`, rather than failing the whole request.
+async fn status(State(state): State<HealthState>) -> Json<Value> {
+    let uart = state.uart.borrow().clone();
+    let (queue, lasers, dmx, uart) = tokio::join!(
+        within("the show worker", queue_length(&state.show_controls)),
+        within("the lasers", laser_stats(&state.lasers)),
+        within("the DMX", state.dmx.query()),
+        within("the UART", uart_stats(&uart)),
+    );
+
+    let errors = [
+        queue.as_ref().err(),
+        lasers.as_ref().err(),
+        dmx.as_ref().err(),
+        uart.as_ref().err(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|e| e.to_string())
+    .collect::<Vec<_>>();
+
+    Json(json!({
+        "uptime_secs": state.started.elapsed().as_secs(),
+        "current_show": state.now_playing.get(),
+        "queue_length": queue.ok(),
+        "lasers": lasers.ok().as_ref().map(laser_json),
+        "dmx": dmx.ok().as_ref().map(dmx_json),
+        "uart": uart.ok().as_ref().map(uart_json),
+        "storage": state.structure.check(),
+        "restarts": state.restarts.report(RESTART_WINDOW),
+        "errors": errors,
+    }))
+}
+
+/// Everything the tasks have counted, with the show queue's length asked for
+/// as it's scraped. If the show worker doesn't answer, its last length stays.
+async fn metrics(State(state): State<HealthState>) -> Response {
+    let Some(metrics) = &state.metrics else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if let Ok(length) = within("the show worker", queue_length(&state.show_controls)).await {
+        metrics.set_queue_depth("shows", length);
+    }
+
+    ([(header::CONTENT_TYPE, TEXT_FORMAT)], metrics.encode()).into_response()
+}
+
+/// Give up on `query` after `QUERY_TIMEOUT`, naming what didn't answer
+pub(crate) async fn within<T>(
+    name: &str,
+    query: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    tokio::time::timeout(QUERY_TIMEOUT, query)
+        .await
+        .map_err(|_| Error::msg(format!("{} didn't answer in time", name)))?
+}
+
+async fn queue_length(show_controls: &TaskSender<ShowControl>) -> Result<usize, Error> {
+    let (reply, reply_rx) = SnapshotReply::new();
+    show_controls
+        .send(ShowControl::QueueSnapshot(reply))
+        .await
+        .map_err(|_| Error::msg("the show worker has stopped"))?;
+
+    reply_rx
+        .await
+        .map(|queue| queue.len())
+        .map_err(|_| Error::msg("the show worker dropped the query"))
+}
+
+pub(crate) async fn laser_stats(lasers: &TaskSender<LaserMessage>) -> Result<LaserStats, Error> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    lasers
+        .send(LaserMessage::Stats(reply_tx))
+        .await
+        .map_err(|_| Error::msg("the laser task has stopped"))?;
+
+    reply_rx
+        .await
+        .map_err(|_| Error::msg("the laser task dropped the query"))
+}
+
+async fn uart_stats(uart: &mpsc::Sender<UartMessage>) -> Result<UartStats, Error> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    uart.send(UartMessage::Stats(reply_tx))
+        .await
+        .map_err(|_| Error::msg("the UART task has stopped"))?;
+
+    reply_rx
+        .await
+        .map_err(|_| Error::msg("the UART task dropped the query"))
+}
+
+pub(crate) fn laser_json(stats: &LaserStats) -> Value {
+    json!({
+        "healthy": stats.healthy,
+        "sent": stats.sent,
+        "dropped": stats.dropped,
+        "failed": stats.failed,
+        "max_depth": stats.max_depth,
+        "latency_p50_ms": stats.latency.p50.as_millis() as u64,
+        "latency_p95_ms": stats.latency.p95.as_millis() as u64,
+        "latency_max_ms": stats.latency.max.as_millis() as u64,
+    })
+}
+
+fn dmx_json(snapshot: &DmxSnapshot) -> Value {
+    json!({
+        "healthy": snapshot.healthy,
+        "updates": snapshot.updates,
+        "rejected_updates": snapshot.rejected_updates,
+        "coalesced_sends": snapshot.coalesced_sends,
+        "last_sent": snapshot.last_sent.map(|time| time.to_rfc3339()),
+    })
+}
+
+fn uart_json(stats: &UartStats) -> Value {
+    let traffic = |traffic: &TrafficStats| {
+        json!({
+            "messages": traffic.messages,
+            "bytes": traffic.bytes,
+            "errors": traffic.errors,
+            "max_drain_ms": traffic.max_drain.as_millis() as u64,
+        })
+    };
+
+    json!({
+        "healthy": stats.healthy,
+        "laser": traffic(&stats.laser),
+        "dmx": traffic(&stats.dmx),
+        "write_errors": stats.write_errors,
+        "reopens": stats.reopens,
+        "dropped": stats.dropped,
+        "pending": stats.pending,
+        "skipped": stats.skipped,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Config, DmxSinkKind},
+        dmx::DmxState,
+        laser::LaserController,
+        show::prelude::{ShowChoice, ShowElement, ShowManager, ShowMap},
+        test_util::TempDir,
+        uart::{port::mock::MockPort, UartController},
+    };
+
+    /// The lasers, DMX, UART and show worker running as they do in `main`,
+    /// with the UART on a mock port
+    #[tokio::test]
+    async fn test_health_endpoints() {
+        let mut config = Config::default();
+        config.dmx.sink = Some(DmxSinkKind::Uart);
+        let cancel = CancellationToken::new();
+        let mut heartbeats = Heartbeats::default();
+
+        let (uart_tx, uart_rx) = mpsc::channel(100);
+        let mut uart = UartController::with_port(Box::new(MockPort::new(None)));
+        uart.heartbeat = heartbeats.add("UART");
+        tokio::spawn(uart.start(uart_rx, cancel.clone()));
+
+        let (laser_tx, laser_rx) = mpsc::channel(100);
+        let mut lasers = LaserController::init(&config);
+        lasers.heartbeat = heartbeats.add("lasers");
+        let (uart_tx_clone, cancel_clone) = (uart_tx.clone(), cancel.clone());
+        let lasers = tokio::spawn(async move {
+            lasers.start(laser_rx, uart_tx_clone, cancel_clone).await;
+        });
+
+        let (dmx_tx, dmx_rx) = mpsc::channel(100);
+        let mut dmx = DmxState::init(config.clone());
+        dmx.heartbeat = heartbeats.add("DMX");
+        tokio::spawn(dmx.start(dmx_rx, uart_tx.clone(), cancel.clone()));
+
+        let (message_tx, _message_rx) = mpsc::channel(100);
+        let (control_tx, control_rx) = mpsc::channel(10);
+        let (_queue_tx, queue_rx) = mpsc::channel(10);
+        let mut manager = ShowManager::new(ShowMap::new(), message_tx, &config);
+        manager.controls = Some(control_rx);
+        manager.heartbeat = heartbeats.add("show worker");
+        let now_playing = manager.now_playing.clone();
+        manager.start_show_worker(queue_rx, cancel.clone()).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (_uart_watch, uart_watch_rx) = watch::channel(uart_tx);
+        let state = HealthState {
+            heartbeats,
+            stale_after: Duration::from_secs(2),
+            now_playing: now_playing.clone(),
+            lasers: laser_tx.into(),
+            dmx: DmxHandle::new(dmx_tx),
+            uart: uart_watch_rx,
+            show_controls: control_tx.into(),
+            metrics: None,
+            structure: FileStructure::default(),
+            restarts: Restarts::default(),
+            started: Instant::now(),
+        };
+        tokio::spawn(serve(listener, state, cancel.clone()));
+
+        let get = |path: &str| {
+            let url = format!("{}{}", url, path);
+            async move {
+                let response = reqwest::get(url).await.unwrap();
+                (response.status(), response.json::<Value>().await.unwrap())
+            }
+        };
+
+        // Every task beats as soon as it starts
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let (code, body) = get("/healthz").await;
+        assert_eq!(code, reqwest::StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({ "healthy": true, "stale": [], "stopped": [], "restarts": {} })
+        );
+
+        now_playing.set(Some("graveyard".to_string()));
+        let (code, body) = get("/status").await;
+        assert_eq!(code, reqwest::StatusCode::OK);
+        assert_eq!(body["current_show"], "graveyard");
+        assert_eq!(body["queue_length"], 0);
+        assert_eq!(body["lasers"]["sent"], 0);
+        assert_eq!(body["dmx"]["healthy"], true);
+        assert_eq!(body["uart"]["healthy"], true);
+        assert_eq!(body["storage"]["folders"], json!([]));
+        assert_eq!(body["restarts"], json!({}));
+        assert_eq!(body["errors"], json!([]));
+
+        // A task that's gone stops beating and can't be asked
+        lasers.abort();
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        let (code, body) = get("/healthz").await;
+        assert_eq!(code, reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body,
+            json!({ "healthy": false, "stale": ["lasers"], "stopped": [], "restarts": {} })
+        );
+
+        let (_, body) = get("/status").await;
+        assert_eq!(body["lasers"], Value::Null);
+        assert_eq!(body["errors"], json!(["the laser task has stopped"]));
+
+        // Metrics weren't turned on
+        let response = reqwest::get(format!("{}/metrics", url)).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+        cancel.cancel();
+    }
+
+    /// A show played through the show worker is counted on `/metrics`
+    #[tokio::test]
+    async fn test_metrics() {
+        let dir = TempDir::new("metrics");
+        let show_dir = dir.join("graveyard");
+        std::fs::create_dir_all(&show_dir).unwrap();
+        std::fs::write(
+            show_dir.join("instructions-exported-1.json"),
+            r#"{ "0": { "turret-1": 1 }, "100": { "turret-1": 0 } }"#,
+        )
+        .unwrap();
+        let mut config = Config {
+            shows_dir: dir.to_path_buf(),
+            ..Default::default()
+        };
+        config.playlist.state_file = dir.join("show-history.json");
+        config.stats.data_dir = dir.join("data");
+        let cancel = CancellationToken::new();
+
+        let metrics = Metrics::new();
+        let (shows, _) = ShowManager::load_shows(&config);
+        let (message_tx, _message_rx) = mpsc::channel(100);
+        let (control_tx, control_rx) = mpsc::channel(10);
+        let (queue_tx, queue_rx) = mpsc::channel(10);
+        let mut manager = ShowManager::new(shows, message_tx, &config);
+        manager.controls = Some(control_rx);
+        manager.metrics = metrics.clone();
+        manager.start_show_worker(queue_rx, cancel.clone()).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/metrics", listener.local_addr().unwrap());
+        let (_uart_watch, uart_watch_rx) = watch::channel(mpsc::channel(1).0);
+        let state = HealthState {
+            heartbeats: Heartbeats::default(),
+            stale_after: Duration::from_secs(2),
+            now_playing: NowPlaying::default(),
+            lasers: TaskSender::closed(),
+            dmx: DmxHandle::new(mpsc::channel(1).0),
+            uart: uart_watch_rx,
+            show_controls: control_tx.into(),
+            metrics: Some(metrics),
+            structure: FileStructure::default(),
+            restarts: Restarts::default(),
+            started: Instant::now(),
+        };
+        tokio::spawn(serve(listener, state, cancel.clone()));
+        let scrape = || async { reqwest::get(&url).await.unwrap().text().await.unwrap() };
+
+        let body = scrape().await;
+        assert!(body.contains("halloween_frames_dispatched_total 0\n"));
+        assert!(body.contains("halloween_queue_depth{queue=\"shows\"} 0\n"));
+
+        queue_tx
+            .send(vec![ShowElement::PrepareShow(ShowChoice::Name(
+                "graveyard".to_string(),
+            ))])
+            .await
+            .unwrap();
+
+        // Both frames go out, then the show is over
+        let mut body = String::new();
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            body = scrape().await;
+            if body.contains("halloween_frames_dispatched_total 2\n")
+                && !body.contains("halloween_current_show{")
+            {
+                break;
+            }
+        }
+        assert!(
+            body.contains("halloween_frames_dispatched_total 2\n"),
+            "{}",
+            body
+        );
+        assert!(
+            body.contains("halloween_frames_skipped_total 0\n"),
+            "{}",
+            body
+        );
+        assert!(!body.contains("halloween_current_show{"), "{}", body);
+
+        cancel.cancel();
+    }
+
+    #[test]
+    fn test_stale() {
+        let mut heartbeats = Heartbeats::default();
+        let beating = heartbeats.add("beating");
+        heartbeats.add("never started");
+
+        beating.beat();
+        assert_eq!(heartbeats.stale(Duration::from_secs(5)), ["never started"]);
+        assert_eq!(
+            heartbeats.stale(Duration::ZERO),
+            ["beating", "never started"]
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use std::time::Duration;
+
+use anyhow::Error;
+use log::{debug, error, info};
+use tokio::{sync::mpsc, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::{InputAction, InputConfig},
+    show::prelude::{ShowChoice, ShowControl},
+    shutdown::recv_until,
+    InternalMessage, MessageKind,
+};
+
+/// Edges waiting to be handled. The interrupts drop edges rather than wait
+/// when it's full, which only happens if something's bouncing badly.
+const EDGE_QUEUE: usize = 64;
+
+/// What happened to an edge on an input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Triggered,
+    /// Too soon after the last edge, so it's contact bounce
+    Bounce,
+    /// The input triggered this long ago, which is too recent to go again
+    TooSoon(Duration),
+}
+
+/// Decides which edges on an input trigger its action
+#[derive(Debug, Clone)]
+pub struct InputFilter {
+    debounce: Duration,
+    min_interval: Duration,
+    /// Bounces keep coming until the contact settles, so each one holds off
+    /// the next
+    last_edge: Option<Instant>,
+    last_triggered: Option<Instant>,
+}
+
+impl InputFilter {
+    pub fn new(input: &InputConfig) -> Self {
+        InputFilter {
+            debounce: Duration::from_millis(input.debounce_ms),
+            min_interval: Duration::from_millis(input.min_interval_ms),
+            last_edge: None,
+            last_triggered: None,
+        }
+    }
+
+    pub fn edge(&mut self, at: Instant) -> Edge {
+        let since_edge = self
+            .last_edge
+            .map(|last| at.saturating_duration_since(last));
+        self.last_edge = Some(at);
+        if since_edge.is_some_and(|since| since < self.debounce) {
+            return Edge::Bounce;
+        }
+
+        if let Some(since) = self
+            .last_triggered
+            .map(|last| at.saturating_duration_since(last))
+            .filter(|since| *since < self.min_interval)
+        {
+            return Edge::TooSoon(since);
+        }
+
+        self.last_triggered = Some(at);
+        Edge::Triggered
+    }
+}
+
+impl InputAction {
+    /// What gets sent to the main queue
+    pub fn message(&self) -> InternalMessage {
+        match self {
+            InputAction::PrepareShow(name) => {
+                InternalMessage::ShowControl(ShowControl::Prepare(ShowChoice::Name(name.clone())))
+            }
+            InputAction::LightEffect { light_id, effect } => InternalMessage::LightEffect {
+                light_id: *light_id,
+                effect: effect.clone(),
+            },
+            InputAction::TurretFire { turret_id } => InternalMessage::TurretFire {
+                turret_id: *turret_id,
+            },
+        }
+    }
+}
+
+/// Watch every input, sending its action to the main queue whenever it's
+/// triggered
+pub async fn run(
+    inputs: Vec<InputConfig>,
+    message_queue: mpsc::Sender<MessageKind>,
+    cancel: CancellationToken,
+) -> Result<(), Error> {
+    let (edge_tx, edge_rx) = mpsc::channel(EDGE_QUEUE);
+    // Kept until the inputs stop being watched
+    let _listening = listen(&inputs, edge_tx)?;
+
+    handle_edges(&inputs, edge_rx, message_queue, cancel).await;
+    Ok(())
+}
+
+/// Turn the edges on each input, by its position in `inputs`, into actions
+async fn handle_edges(
+    inputs: &[InputConfig],
+    mut edges: mpsc::Receiver<usize>,
+    message_queue: mpsc::Sender<MessageKind>,
+    cancel: CancellationToken,
+) {
+    let mut filters = inputs.iter().map(InputFilter::new).collect::<Vec<_>>();
+
+    while let Some(index) = recv_until(&mut edges, &cancel).await {
+        let input = &inputs[index];
+        match filters[index].edge(Instant::now()) {
+            Edge::Triggered => info!("Input {}: triggered", input.name),
+            Edge::Bounce => continue,
+            Edge::TooSoon(since) => {
+                debug!(
+                    "Input {}: ignored, it triggered {}ms ago",
+                    input.name,
+                    since.as_millis()
+                );
+                continue;
+            }
+        }
+
+        if message_queue
+            .send(MessageKind::InternalMessage(input.action.message()))
+            .await
+            .is_err()
+        {
+            error!("The receiver task has stopped, not watching the inputs");
+            return;
+        }
+    }
+}
+
+/// Interrupt on each input's pin. The pins stop interrupting once they're
+/// dropped.
+#[cfg(feature = "pi")]
+fn listen(
+    inputs: &[InputConfig],
+    edges: mpsc::Sender<usize>,
+) -> Result<Vec<rppal::gpio::InputPin>, Error> {
+    use crate::config::InputEdge;
+    use rppal::gpio::{Gpio, Trigger};
+
+    let gpio = Gpio::new()?;
+    let mut pins = Vec::new();
+    for (index, input) in inputs.iter().enumerate() {
+        let number: pi_pinout::GpioPin = pi_pinout::PhysicalPin(input.pin).into();
+        let pin = gpio.get(number.0)?;
+        let (mut pin, trigger) = match input.edge {
+            InputEdge::Rising => (pin.into_input_pulldown(), Trigger::RisingEdge),
+            InputEdge::Falling => (pin.into_input_pullup(), Trigger::FallingEdge),
+            InputEdge::Both => (pin.into_input_pullup(), Trigger::Both),
+        };
+
+        let edges = edges.clone();
+        pin.set_async_interrupt(trigger, move |_| {
+            let _ = edges.try_send(index);
+        })?;
+        info!("Input {}: watching pin {}", input.name, number.0);
+        pins.push(pin);
+    }
+
+    Ok(pins)
+}
+
+/// Off the Pi, typing an input's name and pressing enter triggers it
+#[cfg(not(feature = "pi"))]
+fn listen(
+    inputs: &[InputConfig],
+    edges: mpsc::Sender<usize>,
+) -> Result<std::thread::JoinHandle<()>, Error> {
+    let names = inputs
+        .iter()
+        .map(|input| input.name.clone())
+        .collect::<Vec<_>>();
+    info!(
+        "Not on the Pi, type one of {:?} and press enter to trigger it",
+        names
+    );
+
+    // Its own thread, since a blocked read of stdin would hold up the runtime
+    // shutting down
+    Ok(std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else { return };
+            let Some(index) = names.iter().position(|name| *name == line.trim()) else {
+                continue;
+            };
+            if edges.blocking_send(index).is_err() {
+                return;
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::InputEdge;
+
+    use super::*;
+
+    fn input(action: InputAction) -> InputConfig {
+        InputConfig {
+            name: "big red button".to_string(),
+            pin: 11,
+            edge: InputEdge::Falling,
+            debounce_ms: 50,
+            min_interval_ms: 10_000,
+            action,
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut filter = InputFilter::new(&input(InputAction::TurretFire { turret_id: 1 }));
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        assert_eq!(filter.edge(at(0)), Edge::Triggered);
+        // Chatter carries on bouncing for as long as it keeps up
+        assert_eq!(filter.edge(at(20)), Edge::Bounce);
+        assert_eq!(filter.edge(at(60)), Edge::Bounce);
+        // Settled, but too soon to go again
+        assert_eq!(
+            filter.edge(at(5_000)),
+            Edge::TooSoon(Duration::from_secs(5))
+        );
+        assert_eq!(filter.edge(at(10_000)), Edge::Triggered);
+        assert_eq!(filter.edge(at(10_030)), Edge::Bounce);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handle_edges() {
+        let inputs = vec![
+            input(InputAction::PrepareShow("finale".to_string())),
+            InputConfig {
+                name: "motion".to_string(),
+                pin: 13,
+                edge: InputEdge::Rising,
+                min_interval_ms: 0,
+                ..input(InputAction::TurretFire { turret_id: 2 })
+            },
+        ];
+        let (edge_tx, edge_rx) = mpsc::channel(EDGE_QUEUE);
+        let (message_tx, mut messages) = mpsc::channel(10);
+        let task = tokio::spawn(async move {
+            handle_edges(&inputs, edge_rx, message_tx, CancellationToken::new()).await
+        });
+
+        // Pressed twice, then motion seen after the button's bounce
+        for (index, after_ms) in [(0, 0), (0, 10), (1, 0), (0, 100), (1, 100)] {
+            tokio::time::sleep(Duration::from_millis(after_ms)).await;
+            edge_tx.send(index).await.unwrap();
+        }
+        drop(edge_tx);
+        task.await.unwrap();
+
+        let mut sent = Vec::new();
+        while let Ok(MessageKind::InternalMessage(message)) = messages.try_recv() {
+            sent.push(message);
+        }
+        assert!(matches!(
+            sent.as_slice(),
+            [
+                InternalMessage::ShowControl(ShowControl::Prepare(ShowChoice::Name(name))),
+                InternalMessage::TurretFire { turret_id: 2 },
+                InternalMessage::TurretFire { turret_id: 2 },
+            ] if name == "finale"
+        ));
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+use crate::{config::ColourDepth, show::LaserDataFrame};
+
+use super::pack::PatternPack;
+
+/// Brightest level of a 3-bit channel. The Pico multiplies it by
+/// `COLOUR_MULTIPLIER` (146) onto its 1023 step PWM, so each level is a
+/// seventh of full brightness.
+pub const MAX_LEVEL: u8 = 7;
+
+/// Squeeze an 8-bit channel into the Pico's 3 bits
+pub fn quantize(value: u8, depth: ColourDepth) -> u8 {
+    match depth {
+        // What older shows were authored against
+        ColourDepth::Truncate => value >> 5,
+        ColourDepth::Round | ColourDepth::Dither => {
+            ((value as u16 * MAX_LEVEL as u16 + 127) / 255) as u8
+        }
+    }
+}
+
+/// Spread a 3-bit channel back out to 8 bits
+pub fn expand(level: u8) -> u8 {
+    (level.min(MAX_LEVEL) as u16 * 255 / MAX_LEVEL as u16) as u8
+}
+
+/// Turns draw instructions into patterns for the Pico. Dithering carries
+/// each channel's rounding error over to the same instruction on the same
+/// laser in the next frame, so a colour between two levels averages out to
+/// it over a few frames.
+#[derive(Debug, Default, Clone)]
+pub struct ColourQuantizer {
+    pub depth: ColourDepth,
+    /// Leftover error in 255ths of a level, by laser and instruction
+    errors: HashMap<(u8, usize), [i16; 3]>,
+}
+
+impl ColourQuantizer {
+    pub fn new(depth: ColourDepth) -> Self {
+        ColourQuantizer {
+            depth,
+            errors: HashMap::new(),
+        }
+    }
+
+    pub fn quantize(&mut self, laser_id: u8, frames: &[LaserDataFrame]) -> Vec<PatternPack> {
+        frames
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| {
+                let [r, g, b] = match self.depth {
+                    ColourDepth::Dither => {
+                        let errors = self.errors.entry((laser_id, i)).or_default();
+                        let mut levels = [0; 3];
+                        for (channel, value) in [frame.r, frame.g, frame.b].into_iter().enumerate()
+                        {
+                            let target = value as i16 * MAX_LEVEL as i16 + errors[channel];
+                            let level = ((target + 127).div_euclid(255)).clamp(0, MAX_LEVEL as i16);
+                            errors[channel] = target - level * 255;
+                            levels[channel] = level as u8;
+                        }
+                        levels
+                    }
+                    depth => [
+                        quantize(frame.r, depth),
+                        quantize(frame.g, depth),
+                        quantize(frame.b, depth),
+                    ],
+                };
+
+                PatternPack {
+                    pattern_id: frame.pattern_id.into(),
+                    red: r.into(),
+                    green: g.into(),
+                    blue: b.into(),
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grey(value: u8) -> LaserDataFrame {
+        LaserDataFrame {
+            pattern_id: 0,
+            r: value,
+            g: value,
+            b: value,
+        }
+    }
+
+    #[test]
+    fn test_quantize() {
+        assert_eq!(quantize(0, ColourDepth::Round), 0);
+        assert_eq!(quantize(18, ColourDepth::Round), 0);
+        assert_eq!(quantize(19, ColourDepth::Round), 1);
+        assert_eq!(quantize(128, ColourDepth::Round), 4);
+        assert_eq!(quantize(255, ColourDepth::Round), 7);
+
+        assert_eq!(quantize(31, ColourDepth::Truncate), 0);
+        assert_eq!(quantize(128, ColourDepth::Truncate), 4);
+        assert_eq!(quantize(255, ColourDepth::Truncate), 7);
+
+        // Every level survives being expanded and quantized again
+        for level in 0..=MAX_LEVEL {
+            assert_eq!(quantize(expand(level), ColourDepth::Round), level);
+        }
+    }
+
+    #[test]
+    fn test_dither_averages_out() {
+        // Half way between levels 3 and 4
+        let value = 127;
+        let mut quantizer = ColourQuantizer::new(ColourDepth::Dither);
+        let levels = (0..8)
+            .map(|_| u8::from(quantizer.quantize(1, &[grey(value)])[0].red))
+            .collect::<Vec<_>>();
+
+        assert_eq!(levels, vec![3, 4, 3, 4, 3, 4, 3, 4]);
+
+        // Other lasers keep their own error
+        let mut other = ColourQuantizer::new(ColourDepth::Dither);
+        other.quantize(2, &[grey(value)]);
+        assert_eq!(
+            u8::from(other.quantize(1, &[grey(value)])[0].red),
+            levels[0]
+        );
+    }
+}
@@ -1,80 +1,620 @@
-use std::fmt::{Debug, Display};
+use std::{
+    collections::VecDeque,
+    fmt::{Debug, Display},
+    time::Duration,
+};
 
-use self::pack::{HeaderPack, PatternPack};
+use self::pack::{
+    AccelerationConfigPack, HeaderPack, HomeConfigPack, PatternPack, SpeedConfigPack,
+};
+use packed_struct::PackedStruct;
 
-use crate::{laser::pack::CheckSum, show::LaserDataFrame, uart::UartMessage};
+use self::{
+    pattern::PatternLibrary,
+    simulator::{SimulatorSink, PREVIEW_DIR},
+    sink::{LaserSink, UartLaserSink},
+};
+use crate::{
+    config::{Config, LaserConfig, RunMode},
+    health::Heartbeat,
+    laser::pack::CheckSum,
+    metrics::Metrics,
+    safety::OutputsEnabled,
+    show::LaserDataFrame,
+    shutdown::recv_until,
+    uart::UartMessage,
+};
 
-use tokio::sync::mpsc;
+use anyhow::Error;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
 
+pub mod colour;
 pub mod pack;
+pub mod pattern;
+pub mod simulator;
+pub mod sink;
 
 type Frame = [u8; 4];
 
+/// The laser ID that every projector listens to
+pub const ALL_LASERS: u8 = 15;
+
+/// How many zeroed frames follow the header when nulling out the lasers
+pub const NULL_OUT_FRAMES: usize = 50;
+
+pub use common::SPEED_PROFILES;
+
+/// Estimate how long the galvos take to draw a pattern once, travelling
+/// between each point and back to the first at the speed profile's rate
+pub fn draw_time(points: &[(u16, u16)], speed_profile: u8) -> Duration {
+    let distance: f64 = points
+        .iter()
+        .zip(points.iter().cycle().skip(1))
+        .map(|((x1, y1), (x2, y2))| {
+            let dx = *x2 as f64 - *x1 as f64;
+            let dy = *y2 as f64 - *y1 as f64;
+            (dx * dx + dy * dy).sqrt()
+        })
+        .sum();
+
+    let steps_per_second = SPEED_PROFILES[speed_profile as usize & 0x7];
+    Duration::from_secs_f64(distance / steps_per_second as f64)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LaserStats {
+    /// Frames handed to the UART
+    pub sent: u64,
+    /// Frames thrown away because the queue was full
+    pub dropped: u64,
+    /// Frames that a sink couldn't take, like while the UART task is down
+    pub failed: u64,
+    /// Most frames that were ever waiting at once
+    pub max_depth: usize,
+    /// Whether the last frame made it to every sink
+    pub healthy: bool,
+    /// From when recent frames were made to when they were written
+    pub latency: LatencyStats,
+}
+
+/// Latency over the last `LATENCY_WINDOW` frames
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub samples: usize,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+impl LatencyStats {
+    pub fn from_samples(samples: &VecDeque<Duration>) -> Self {
+        let mut sorted = samples.iter().copied().collect::<Vec<_>>();
+        sorted.sort();
+
+        // Nearest rank
+        let percentile = |p: usize| match sorted.len() {
+            0 => Duration::ZERO,
+            len => sorted[(len * p).div_ceil(100) - 1],
+        };
+
+        LatencyStats {
+            samples: sorted.len(),
+            p50: percentile(50),
+            p95: percentile(95),
+            max: sorted.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// Frames kept for the latency percentiles
+pub const LATENCY_WINDOW: usize = 512;
+
+impl Default for LaserStats {
+    fn default() -> Self {
+        LaserStats {
+            sent: 0,
+            dropped: 0,
+            failed: 0,
+            max_depth: 0,
+            healthy: true,
+            latency: LatencyStats::default(),
+        }
+    }
+}
+
+/// A frame along with when it was made, so its latency can be measured once
+/// it's written. The timestamp never goes over the wire.
+#[derive(Debug, Clone)]
+pub struct TimedFrame {
+    pub pack: FrameSendPack,
+    pub created: Instant,
+}
+
+impl From<FrameSendPack> for TimedFrame {
+    fn from(pack: FrameSendPack) -> Self {
+        TimedFrame {
+            pack,
+            created: Instant::now(),
+        }
+    }
+}
+
+impl From<MessageSendPack> for TimedFrame {
+    fn from(message: MessageSendPack) -> Self {
+        FrameSendPack::from(message).into()
+    }
+}
+
+/// Starts a status packet saying a laser homed, followed by its ID
+pub const STATUS_HOMED: u8 = 0x01;
+/// Starts a status packet saying a laser couldn't home, followed by its ID
+pub const STATUS_HOME_FAILED: u8 = 0x02;
+
+/// What a laser's Pico said about homing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HomeStatus {
+    Homed { laser_id: u8 },
+    Failed { laser_id: u8 },
+}
+
+impl HomeStatus {
+    /// The homing report in a status packet's payload, if it is one
+    pub fn from_status(payload: &[u8]) -> Option<Self> {
+        match *payload {
+            [STATUS_HOMED, laser_id] => Some(HomeStatus::Homed { laser_id }),
+            [STATUS_HOME_FAILED, laser_id] => Some(HomeStatus::Failed { laser_id }),
+            _ => None,
+        }
+    }
+}
+
 pub enum LaserMessage {
-    Frame(FrameSendPack),
+    Frame(TimedFrame),
+    /// Frames from the same show tick, written to the UART together
+    FrameBatch(Vec<TimedFrame>),
+    /// Send a frame to one laser, whatever ID its header was built with
+    FrameTo {
+        laser_id: u8,
+        pack: FrameSendPack,
+    },
+    /// Home every laser
+    Home,
+    /// Turn every laser's output on or off
+    SetEnable(bool),
+    /// Write new settings to the projector with `projector_id`
+    Config {
+        acceleration: u32,
+        max_speed: u32,
+        min_speed: u16,
+        x_home: u16,
+        y_home: u16,
+        projector_id: u8,
+    },
+    /// Report the controller's counters
+    Stats(oneshot::Sender<LaserStats>),
+    /// The UART task was restarted, send to it from now on
+    Reconnect(mpsc::Sender<UartMessage>),
+    /// A show started playing, so a new round of dropped frames gets a warning
+    ShowStarted(String),
 }
 
-pub struct LaserController {}
+pub struct LaserController {
+    pub config: LaserConfig,
+    pub mode: RunMode,
+    /// Batches of frames waiting for the galvos to catch up, oldest first. A
+    /// lone frame is a batch of one.
+    pub queue: VecDeque<Vec<TimedFrame>>,
+    pub stats: LaserStats,
+    /// Latency of the most recent frames, cleared when a show starts
+    pub latencies: VecDeque<Duration>,
+    /// Whether this show has already warned about dropped frames
+    pub warned_drops: bool,
+    /// While the e-stop is tripped, only frames that turn the lasers off go
+    /// out
+    pub outputs_enabled: OutputsEnabled,
+    /// Beats while the loop is running, for the health checks
+    pub heartbeat: Heartbeat,
+    pub metrics: Metrics,
+    sinks: Vec<Box<dyn LaserSink>>,
+}
 
 impl LaserController {
-    pub fn init() -> Self {
-        Self {}
+    pub fn init(config: &Config) -> Self {
+        Self {
+            config: config.laser.clone(),
+            mode: config.mode,
+            queue: VecDeque::new(),
+            stats: LaserStats::default(),
+            latencies: VecDeque::new(),
+            warned_drops: false,
+            outputs_enabled: OutputsEnabled::default(),
+            heartbeat: Heartbeat::default(),
+            metrics: Metrics::default(),
+            sinks: Vec::new(),
+        }
+    }
+
+    /// Whether the lasers are simulated, on their own or along with
+    /// everything else
+    pub fn simulated(&self) -> bool {
+        self.config.simulate || self.mode.is_simulated()
+    }
+
+    /// The simulator replaces the UART when lasers are simulated
+    fn open_sinks(&self, uart_tx: mpsc::Sender<UartMessage>) -> Vec<Box<dyn LaserSink>> {
+        if self.simulated() {
+            info!("Simulating lasers, previews go to {}", PREVIEW_DIR);
+            let patterns = PatternLibrary::load().unwrap_or_else(|e| {
+                error!("Failed to load laser patterns: {}", e);
+                PatternLibrary::default()
+            });
+            return vec![Box::new(SimulatorSink::new(PREVIEW_DIR.into(), &patterns))];
+        }
+
+        vec![Box::new(UartLaserSink::new(&self.config, uart_tx))]
     }
 
     pub async fn start(
         &mut self,
         mut rx: mpsc::Receiver<LaserMessage>,
         uart_tx: mpsc::Sender<UartMessage>,
+        cancel: CancellationToken,
     ) {
-        while let Some(message) = rx.recv().await {
-            match message {
-                LaserMessage::Frame(frame) => {
-                    uart_tx
-                        .send(UartMessage::Laser(frame.into_bytes()))
-                        .await
-                        .unwrap();
+        self.sinks = self.open_sinks(uart_tx);
+        let mut next_send = Instant::now();
+        let mut heartbeat = Heartbeat::ticker();
+
+        loop {
+            tokio::select! {
+                message = recv_until(&mut rx, &cancel) => {
+                    let Some(message) = message else {
+                        break;
+                    };
+                    self.handle(message);
+                }
+                // Frames go out no faster than the galvos can draw them
+                _ = tokio::time::sleep_until(next_send), if !self.queue.is_empty() => {
+                    let batch = self.queue.pop_front().unwrap();
+                    self.metrics.set_queue_depth("lasers", self.queue.len());
+                    // The slowest head sets the pace
+                    let gap = batch
+                        .iter()
+                        .map(|frame| self.frame_gap(frame.pack.speed_profile()))
+                        .max()
+                        .unwrap_or_default();
+                    next_send = Instant::now() + gap;
+                    self.send(batch).await;
+                }
+                _ = heartbeat.tick() => self.heartbeat.beat(),
+            }
+        }
+
+        // Whatever's still queued goes out now, so a null out on the way
+        // down isn't lost
+        while let Some(batch) = self.queue.pop_front() {
+            self.send(batch).await;
+        }
+    }
+
+    fn handle(&mut self, message: LaserMessage) {
+        match message {
+            LaserMessage::Frame(frame) => self.enqueue(frame),
+            LaserMessage::FrameBatch(batch) => self.enqueue_batch(batch),
+            LaserMessage::FrameTo { laser_id, pack } => {
+                self.enqueue(pack.with_laser_id(laser_id).into())
+            }
+            LaserMessage::Home => self.enqueue(MessageSendPack::home_message().into()),
+            LaserMessage::SetEnable(enable) => {
+                self.enqueue(MessageSendPack::enable_message(enable).into())
+            }
+            LaserMessage::Config {
+                acceleration,
+                max_speed,
+                min_speed,
+                x_home,
+                y_home,
+                projector_id,
+            } => match FrameSendPack::config_message(
+                acceleration,
+                max_speed,
+                min_speed,
+                x_home,
+                y_home,
+                projector_id,
+                self.config.transfer_size,
+            ) {
+                Ok(frame) => self.enqueue(frame.into()),
+                Err(e) => error!("Invalid laser config: {}", e),
+            },
+            LaserMessage::Stats(reply) => {
+                self.stats.latency = LatencyStats::from_samples(&self.latencies);
+                let _ = reply.send(self.stats.clone());
+            }
+            LaserMessage::Reconnect(uart_tx) => {
+                info!("Reconnecting the lasers to the UART");
+                for sink in self.sinks.iter_mut() {
+                    sink.reconnect(uart_tx.clone());
+                }
+            }
+            LaserMessage::ShowStarted(name) => {
+                if !self.latencies.is_empty() {
+                    let latency = LatencyStats::from_samples(&self.latencies);
+                    info!(
+                        "Laser latency over {} frames: p50 {:?}, p95 {:?}, max {:?}",
+                        latency.samples, latency.p50, latency.p95, latency.max
+                    );
+                    self.latencies.clear();
+                }
+
+                self.warned_drops = false;
+                for sink in self.sinks.iter_mut() {
+                    sink.show_started(&name);
+                }
+            }
+        }
+    }
+
+    /// Queue a frame to be sent, dropping the oldest one if the queue is full
+    pub fn enqueue(&mut self, frame: TimedFrame) {
+        self.enqueue_batch(vec![frame]);
+    }
+
+    /// Queue frames to be sent together. Batches are dropped whole, and each
+    /// frame in one counts as dropped.
+    pub fn enqueue_batch(&mut self, batch: Vec<TimedFrame>) {
+        if batch.is_empty() {
+            return;
+        }
+        self.queue.push_back(batch);
+
+        if self.queue.len() > self.config.queue_depth {
+            let dropped = self.queue.pop_front().unwrap();
+            self.stats.dropped += dropped.len() as u64;
+            self.metrics.laser_drops.inc_by(dropped.len() as u64);
+
+            if !self.warned_drops {
+                warn!(
+                    "Dropping laser frames, more than {} are waiting",
+                    self.config.queue_depth
+                );
+                self.warned_drops = true;
+            }
+        }
+
+        self.stats.max_depth = self.stats.max_depth.max(self.queue.len());
+        self.metrics.set_queue_depth("lasers", self.queue.len());
+    }
+
+    /// How long the galvos need to draw a frame at this speed profile
+    pub fn frame_gap(&self, speed_profile: u8) -> Duration {
+        let steps_per_second = SPEED_PROFILES[speed_profile as usize & 0x7];
+        Duration::from_secs_f64(self.config.frame_gap_steps as f64 / steps_per_second as f64)
+    }
+
+    /// Errors are only logged when the lasers stop being healthy, so a dead
+    /// UART doesn't flood the log while frames keep being dropped
+    async fn send(&mut self, batch: Vec<TimedFrame>) {
+        let batch = match self.outputs_enabled.is_enabled() {
+            true => batch,
+            false => {
+                let (batch, blocked): (Vec<_>, Vec<_>) = batch
+                    .into_iter()
+                    .partition(|frame| !frame.pack.enables_output());
+                self.stats.dropped += blocked.len() as u64;
+                self.metrics.laser_drops.inc_by(blocked.len() as u64);
+                batch
+            }
+        };
+        if batch.is_empty() {
+            return;
+        }
+
+        let packs = batch
+            .iter()
+            .map(|frame| frame.pack.clone())
+            .collect::<Vec<_>>();
+
+        let mut error = None;
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.send_batch(&packs).await {
+                error = Some(e);
+            }
+        }
+
+        match error {
+            None => {
+                // Sinks return once the frames are written
+                for frame in &batch {
+                    if self.latencies.len() == LATENCY_WINDOW {
+                        self.latencies.pop_front();
+                    }
+                    self.latencies.push_back(frame.created.elapsed());
+                }
+
+                if !self.stats.healthy {
+                    info!("Laser frames are being sent again");
+                }
+                self.stats.healthy = true;
+                self.stats.sent += batch.len() as u64;
+            }
+            Some(e) => {
+                if self.stats.healthy {
+                    error!("Dropping laser frames until they can be sent: {}", e);
                 }
+                self.stats.healthy = false;
+                self.stats.failed += batch.len() as u64;
             }
         }
     }
 }
 
-#[derive(PartialEq, Clone, Debug)]
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct FrameSendPack {
     pub header: Frame,
-    pub draw_instruction: Frame,
+    pub draw_instructions: Vec<Frame>,
 }
 
 impl FrameSendPack {
-    pub fn into_bytes(self) -> Vec<u8> {
-        let mut bytes = Vec::new();
+    /// A configuration frame for the projector with `projector_id`. Every
+    /// value has to fit in its field of the Pico's config words.
+    pub fn config_message(
+        acceleration: u32,
+        max_speed: u32,
+        min_speed: u16,
+        x_home: u16,
+        y_home: u16,
+        projector_id: u8,
+        transfer_size: usize,
+    ) -> Result<Self, Error> {
+        let fields = [
+            ("acceleration", acceleration as u64, 0xFFFFF),
+            ("max_speed", max_speed as u64, 0x3FFFF),
+            ("min_speed", min_speed as u64, 0x1FFF),
+            ("x_home", x_home as u64, 0xFFF),
+            ("y_home", y_home as u64, 0xFFF),
+            ("projector_id", projector_id as u64, 0xF),
+            ("transfer_size", transfer_size as u64, 0x7FF),
+        ];
+        for (name, value, max) in fields {
+            if value > max {
+                return Err(Error::msg(format!(
+                    "{} {} is larger than the maximum of {}",
+                    name, value, max
+                )));
+            }
+        }
+
+        let draw_instructions = vec![
+            AccelerationConfigPack {
+                acceleration: acceleration.into(),
+                transfer_size: (transfer_size as u16).into(),
+                ..Default::default()
+            }
+            .checksum_pack(),
+            SpeedConfigPack {
+                max_speed: max_speed.into(),
+                min_speed: min_speed.into(),
+                ..Default::default()
+            }
+            .checksum_pack(),
+            HomeConfigPack {
+                x_home: x_home.into(),
+                y_home: y_home.into(),
+                projector_id: projector_id.into(),
+                ..Default::default()
+            }
+            .checksum_pack(),
+        ];
+
+        Ok(FrameSendPack {
+            header: HeaderPack {
+                laser_id: projector_id.into(),
+                point_count: (draw_instructions.len() as u8).into(),
+                configuration_mode: true,
+                ..Default::default()
+            }
+            .checksum_pack(),
+            draw_instructions,
+        })
+    }
+
+    /// Stop every laser drawing: a header with no points, followed by
+    /// `NULL_OUT_FRAMES` zeroed frames to flush out whatever the Pico was
+    /// part way through reading. The header doesn't enable output, so it
+    /// still goes out while the outputs are held off.
+    pub fn null_out() -> Self {
+        FrameSendPack {
+            header: HeaderPack {
+                laser_id: ALL_LASERS.into(),
+                point_count: 0.into(),
+                ..Default::default()
+            }
+            .checksum_pack(),
+            draw_instructions: vec![[0; 4]; NULL_OUT_FRAMES],
+        }
+    }
+
+    pub fn laser_id(&self) -> u8 {
+        self.header[0] >> 4
+    }
+
+    /// Whether this frame lights a laser up. Homing, config and disable
+    /// frames don't.
+    pub fn enables_output(&self) -> bool {
+        HeaderPack::unpack(&self.header)
+            .map(|header| header.enable && !header.home && !header.configuration_mode)
+            .unwrap_or(true)
+    }
+
+    pub fn speed_profile(&self) -> u8 {
+        (self.header[2] >> 4) & 0x7
+    }
+
+    /// Readdress the header to another laser, recalculating its checksum
+    pub fn with_laser_id(self, laser_id: u8) -> Self {
+        self.map_header(|header| header.laser_id = laser_id.into())
+    }
+
+    /// Tag the header with the sequence number the Pico acks with
+    pub fn with_sequence(self, sequence: u8) -> Self {
+        self.map_header(|header| header.sequence = sequence.into())
+    }
+
+    fn map_header(mut self, f: impl FnOnce(&mut HeaderPack)) -> Self {
+        let mut header = HeaderPack::unpack(&self.header).unwrap();
+        f(&mut header);
+        header.checksum = false;
+        self.header = header.checksum_pack();
+
+        self
+    }
+
+    /// The header followed by each draw instruction, padded with zeroed words
+    /// to `transfer_size` words. The Pico's DMA waits for a full transfer, so
+    /// anything shorter would stall it.
+    pub fn into_bytes(self, transfer_size: usize) -> Result<Vec<u8>, Error> {
+        if 1 + self.draw_instructions.len() > transfer_size {
+            return Err(Error::msg(format!(
+                "{} draw instructions don't fit in a transfer of {} words",
+                self.draw_instructions.len(),
+                transfer_size
+            )));
+        }
+
+        let mut bytes = Vec::with_capacity(4 * transfer_size);
 
         bytes.extend_from_slice(&self.header);
-        bytes.extend_from_slice(&self.draw_instruction);
+        for draw_instruction in &self.draw_instructions {
+            bytes.extend_from_slice(draw_instruction);
+        }
+        bytes.resize(4 * transfer_size, 0);
 
-        bytes
+        Ok(bytes)
     }
 }
 
 #[derive(PartialEq, Clone, Debug)]
 pub struct MessageSendPack {
     pub header: HeaderPack,
-    pub draw_instruction: PatternPack,
+    pub draw_instructions: Vec<PatternPack>,
 }
 
 impl Display for MessageSendPack {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut draw_instructions = String::new();
-        draw_instructions.push_str(&format!("{}\n", self.draw_instruction));
-
-        let laser = match self.header.laser_id.to_string().as_str() {
-            "15" => "all lasers".to_string(),
+        let laser = match u8::from(self.header.laser_id) {
+            ALL_LASERS => "all lasers".to_string(),
             id => format!("laser {}", id),
         };
 
         let task = match self.header.home {
             true => "a homing request".to_string(),
-            false => format!("{} draw instructions", 1),
+            false => format!("{} draw instructions", self.draw_instructions.len()),
         };
 
         write!(f, "Sending to {} with {}", laser, task,)
@@ -82,35 +622,847 @@ impl Display for MessageSendPack {
 }
 
 impl MessageSendPack {
-    pub fn new(header: HeaderPack, draw_instruction: LaserDataFrame) -> Self {
-        let draw_instruction = PatternPack::from(draw_instruction);
-
+    pub fn new(header: HeaderPack, draw_instructions: Vec<LaserDataFrame>) -> Self {
         MessageSendPack {
             header,
-            draw_instruction,
+            draw_instructions: draw_instructions
+                .into_iter()
+                .map(PatternPack::from)
+                .collect(),
         }
     }
+    /// Home every laser
     pub fn home_message() -> Self {
+        Self::home_message_to(ALL_LASERS)
+    }
+
+    /// Draw instructions for one laser at the show's speed profile
+    pub fn draw(laser_id: u8, draw_instructions: Vec<LaserDataFrame>) -> Self {
+        MessageSendPack::new(
+            HeaderPack {
+                laser_id: laser_id.into(),
+                home: false,
+                enable: true,
+                configuration_mode: false,
+                draw_boundary: false,
+                oneshot: false,
+                // speed_profile: laser.speed_profile.into(),
+                speed_profile: 1.into(),
+                ..Default::default()
+            },
+            draw_instructions,
+        )
+    }
+
+    /// Steps for checking a laser after setup: the boundary, the large square
+    /// in red, green, blue and white, then homing. Everything is built from
+    /// the Pico's own patterns and 3-bit colours, so nothing can be sent out
+    /// of range.
+    pub fn test_sequence(laser_id: u8) -> Vec<Self> {
+        let square = pattern::pattern_id("square_large").unwrap();
+        let colours = [(255, 0, 0), (0, 255, 0), (0, 0, 255), (255, 255, 255)];
+
+        let mut steps = vec![Self::boundary_message(laser_id)];
+        steps.extend(colours.into_iter().map(|(r, g, b)| {
+            Self::draw(
+                laser_id,
+                vec![LaserDataFrame {
+                    pattern_id: square,
+                    r,
+                    g,
+                    b,
+                }],
+            )
+        }));
+        steps.push(Self::home_message_to(laser_id));
+        steps
+    }
+
+    /// Trace the edges of the projection area, for lining a laser up
+    pub fn boundary_message(laser_id: u8) -> Self {
         MessageSendPack {
             header: HeaderPack {
-                laser_id: 15.into(),
+                laser_id: laser_id.into(),
+                enable: true,
+                draw_boundary: true,
+                ..Default::default()
+            },
+            draw_instructions: Vec::new(),
+        }
+    }
+
+    /// Turn every laser's output on or off
+    pub fn enable_message(enable: bool) -> Self {
+        MessageSendPack {
+            header: HeaderPack {
+                laser_id: ALL_LASERS.into(),
+                enable,
+                ..Default::default()
+            },
+            draw_instructions: Vec::new(),
+        }
+    }
+
+    /// Home a single laser, or all of them with `ALL_LASERS`
+    pub fn home_message_to(laser_id: u8) -> Self {
+        MessageSendPack {
+            header: HeaderPack {
+                laser_id: laser_id.into(),
                 home: true,
                 enable: true,
                 ..Default::default()
             },
-            draw_instruction: PatternPack::default(),
+            draw_instructions: Vec::new(),
         }
     }
 }
 
-/// Change from a MessageSendPack to a FrameSendPack
+/// Change from a MessageSendPack to a FrameSendPack. The header's point count
+/// is set from the number of draw instructions.
 impl From<MessageSendPack> for FrameSendPack {
     fn from(mut msg: MessageSendPack) -> FrameSendPack {
-        let pack = FrameSendPack {
+        msg.header.point_count = (msg.draw_instructions.len() as u8).into();
+
+        FrameSendPack {
             header: msg.header.checksum_pack(),
-            draw_instruction: msg.draw_instruction.checksum_pack(),
+            draw_instructions: msg
+                .draw_instructions
+                .iter_mut()
+                .map(|draw_instruction| draw_instruction.checksum_pack())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        health::HEARTBEAT_INTERVAL,
+        uart::{port::mock::MockPort, UartController},
+    };
+
+    // Masks from the Pico firmware's header word
+    const ID_MASK: u32 = 0xF0000000;
+    const COUNT_MASK: u32 = 0x0FF00000;
+    const HOME_MASK: u32 = 0x80000;
+    const ENABLE_MASK: u32 = 0x40000;
+    const SPEED_PROFILE_MASK: u32 = 0x7000;
+    const SEQUENCE_MASK: u32 = 0x1E;
+    const CONFIG_MASK: u32 = 0x20000;
+    const BOUNDARY_MASK: u32 = 0x10000;
+
+    // Masks from the configuration words
+    const ACCELERATION_MASK: u32 = 0xFFFFF000;
+    const TRANSFER_SIZE_MASK: u32 = 0x00000FFE;
+    const MAX_SPEED_MASK: u32 = 0xFFFFC000;
+    const MIN_SPEED_MASK: u32 = 0x00003FFE;
+    const X_HOME_MASK: u32 = 0xFFF00000;
+    const Y_HOME_MASK: u32 = 0x000FFF00;
+    const PROJECTOR_ID_MASK: u32 = 0x000000F0;
+
+    // Masks from the pattern selection word
+    const PATTERN_MASK: u32 = 0xFF000000;
+    const COLOUR_MASK: u32 = 0x00FF8000;
+
+    fn word(bytes: &[u8]) -> u32 {
+        u32::from_be_bytes(bytes.try_into().unwrap())
+    }
+
+    #[test]
+    fn test_simulated() {
+        assert!(!LaserController::init(&Config::default()).simulated());
+
+        // Either the lasers on their own or everything
+        let mut config = Config::default();
+        config.laser.simulate = true;
+        assert!(LaserController::init(&config).simulated());
+        let config = Config {
+            mode: RunMode::Simulate,
+            ..Default::default()
         };
+        assert!(LaserController::init(&config).simulated());
+    }
+
+    #[test]
+    fn test_home_status() {
+        assert_eq!(
+            HomeStatus::from_status(&[STATUS_HOMED, 3]),
+            Some(HomeStatus::Homed { laser_id: 3 })
+        );
+        assert_eq!(
+            HomeStatus::from_status(&[STATUS_HOME_FAILED, 1]),
+            Some(HomeStatus::Failed { laser_id: 1 })
+        );
+
+        // Other status packets, like a watchdog reset
+        assert_eq!(HomeStatus::from_status(&[STATUS_HOMED]), None);
+        assert_eq!(HomeStatus::from_status(&[0x03, 1]), None);
+    }
+
+    #[test]
+    fn test_multiple_draw_instructions() {
+        let pack: FrameSendPack = MessageSendPack::new(
+            HeaderPack {
+                laser_id: 3.into(),
+                enable: true,
+                speed_profile: 2.into(),
+                ..Default::default()
+            },
+            vec![
+                LaserDataFrame {
+                    pattern_id: 4,
+                    r: 255,
+                    g: 0,
+                    b: 0,
+                },
+                LaserDataFrame {
+                    pattern_id: 9,
+                    r: 0,
+                    g: 255,
+                    b: 0,
+                },
+                LaserDataFrame {
+                    pattern_id: 200,
+                    r: 0,
+                    g: 0,
+                    b: 255,
+                },
+            ],
+        )
+        .into();
+
+        let bytes = pack.into_bytes(4).unwrap();
+        assert_eq!(bytes.len(), 4 * 4);
+
+        let header = word(&bytes[0..4]);
+        assert_eq!((header & ID_MASK) >> 28, 3);
+        assert_eq!((header & COUNT_MASK) >> 20, 3);
+        assert_eq!(header & HOME_MASK, 0);
+        assert_eq!(header & ENABLE_MASK, ENABLE_MASK);
+        assert_eq!((header & SPEED_PROFILE_MASK) >> 12, 2);
+
+        let patterns = bytes[4..]
+            .chunks(4)
+            .map(|chunk| {
+                let instruction = word(chunk);
+                (
+                    (instruction & PATTERN_MASK) >> 24,
+                    (instruction & COLOUR_MASK) >> 15,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            patterns,
+            vec![(4, 0b111_000_000), (9, 0b000_111_000), (200, 0b000_000_111)]
+        );
+
+        // Every word carries even parity once the checksum bit is set
+        for chunk in bytes.chunks(4) {
+            assert_eq!(word(chunk).count_ones() % 2, 0);
+        }
+    }
+
+    #[test]
+    fn test_home_message() {
+        let pack: FrameSendPack = MessageSendPack::home_message().into();
+        assert!(pack.draw_instructions.is_empty());
+
+        let header = word(&pack.into_bytes(1).unwrap());
+        assert_eq!((header & ID_MASK) >> 28, 0xF);
+        assert_eq!(header & COUNT_MASK, 0);
+        assert_eq!(header & HOME_MASK, HOME_MASK);
+    }
+
+    #[test]
+    fn test_home_one_laser() {
+        let pack: FrameSendPack = MessageSendPack::home_message_to(2).into();
+
+        let header = word(&pack.into_bytes(1).unwrap());
+        assert_eq!((header & ID_MASK) >> 28, 2);
+        assert_eq!(header & HOME_MASK, HOME_MASK);
+    }
+
+    #[test]
+    fn test_with_laser_id() {
+        let pack: FrameSendPack = MessageSendPack::new(
+            HeaderPack {
+                laser_id: 1.into(),
+                enable: true,
+                ..Default::default()
+            },
+            vec![LaserDataFrame::default()],
+        )
+        .into();
+
+        let readdressed = pack.clone().with_laser_id(4);
+        assert_eq!(readdressed.draw_instructions, pack.draw_instructions);
+
+        let header = word(&readdressed.header);
+        assert_eq!((header & ID_MASK) >> 28, 4);
+        assert_eq!((header & COUNT_MASK) >> 20, 1);
+        assert_eq!(header & ENABLE_MASK, ENABLE_MASK);
+        assert_eq!(header.count_ones() % 2, 0);
+    }
+
+    #[test]
+    fn test_null_out() {
+        let pack = FrameSendPack::null_out();
+        assert_eq!(pack.draw_instructions.len(), NULL_OUT_FRAMES);
+        assert!(!pack.enables_output());
+
+        let bytes = pack
+            .into_bytes(LaserConfig::default().transfer_size)
+            .unwrap();
+        assert_eq!(bytes.len(), 4 * 51);
+
+        let header = word(&bytes[0..4]);
+        assert_eq!((header & ID_MASK) >> 28, 0xF);
+        assert_eq!(header & (COUNT_MASK | ENABLE_MASK | HOME_MASK), 0);
+        assert_eq!(header.count_ones() % 2, 0);
+
+        // Every word after the header is zeroed
+        assert!(bytes[4..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn test_enable_message() {
+        for enable in [true, false] {
+            let pack: FrameSendPack = MessageSendPack::enable_message(enable).into();
+
+            let header = word(&pack.into_bytes(1).unwrap());
+            assert_eq!((header & ID_MASK) >> 28, 0xF);
+            assert_eq!(header & HOME_MASK, 0);
+            assert_eq!(header & ENABLE_MASK != 0, enable);
+        }
+    }
+
+    #[test]
+    fn test_boundary_message() {
+        let pack: FrameSendPack = MessageSendPack::boundary_message(4).into();
+
+        let header = word(&pack.into_bytes(1).unwrap());
+        assert_eq!((header & ID_MASK) >> 28, 4);
+        assert_eq!(header & BOUNDARY_MASK, BOUNDARY_MASK);
+        assert_eq!(header & ENABLE_MASK, ENABLE_MASK);
+        assert_eq!(header & (HOME_MASK | CONFIG_MASK | COUNT_MASK), 0);
+        assert_eq!(header.count_ones() % 2, 0);
+    }
+
+    #[test]
+    fn test_test_sequence() {
+        let headers = MessageSendPack::test_sequence(3)
+            .into_iter()
+            .map(|message| {
+                let bytes = FrameSendPack::from(message).into_bytes(2).unwrap();
+                (word(&bytes[0..4]), word(&bytes[4..8]))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(headers.len(), 6);
+
+        for (header, _) in &headers {
+            assert_eq!((header & ID_MASK) >> 28, 3);
+            assert_eq!(header.count_ones() % 2, 0);
+        }
+        assert_eq!(headers[0].0 & BOUNDARY_MASK, BOUNDARY_MASK);
+        assert_eq!(headers[5].0 & HOME_MASK, HOME_MASK);
+
+        // Red, green, blue, then white, all on the large square
+        let colours = headers[1..5]
+            .iter()
+            .map(|(header, pattern)| {
+                assert_eq!((header & COUNT_MASK) >> 20, 1);
+                let pattern = PatternPack::unpack(&pattern.to_be_bytes()).unwrap();
+                assert_eq!(u8::from(pattern.pattern_id), 26);
+                (
+                    u8::from(pattern.red),
+                    u8::from(pattern.green),
+                    u8::from(pattern.blue),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(colours, vec![(7, 0, 0), (0, 7, 0), (0, 0, 7), (7, 7, 7)]);
+    }
+
+    #[test]
+    fn test_config_message() {
+        let pack = FrameSendPack::config_message(100_000, 40_000, 5_000, 580, 300, 3, 51).unwrap();
+        let bytes = pack.into_bytes(51).unwrap();
+
+        let header = word(&bytes[0..4]);
+        assert_eq!((header & ID_MASK) >> 28, 3);
+        assert_eq!((header & COUNT_MASK) >> 20, 3);
+        assert_eq!(header & CONFIG_MASK, CONFIG_MASK);
+        assert_eq!(header & HOME_MASK, 0);
+
+        let acceleration = word(&bytes[4..8]);
+        assert_eq!((acceleration & ACCELERATION_MASK) >> 12, 100_000);
+        assert_eq!((acceleration & TRANSFER_SIZE_MASK) >> 1, 51);
+
+        let speed = word(&bytes[8..12]);
+        assert_eq!((speed & MAX_SPEED_MASK) >> 14, 40_000);
+        assert_eq!((speed & MIN_SPEED_MASK) >> 1, 5_000);
+
+        let home = word(&bytes[12..16]);
+        assert_eq!((home & X_HOME_MASK) >> 20, 580);
+        assert_eq!((home & Y_HOME_MASK) >> 8, 300);
+        assert_eq!((home & PROJECTOR_ID_MASK) >> 4, 3);
+
+        for chunk in bytes[..16].chunks(4) {
+            assert_eq!(word(chunk).count_ones() % 2, 0);
+        }
+    }
+
+    #[test]
+    fn test_config_message_out_of_range() {
+        assert!(FrameSendPack::config_message(0x100000, 0, 0, 0, 0, 1, 51).is_err());
+        assert!(FrameSendPack::config_message(0, 0x40000, 0, 0, 0, 1, 51).is_err());
+        assert!(FrameSendPack::config_message(0, 0, 0x2000, 0, 0, 1, 51).is_err());
+        assert!(FrameSendPack::config_message(0, 0, 0, 0x1000, 0, 1, 51).is_err());
+        assert!(FrameSendPack::config_message(0, 0, 0, 0, 0, 16, 51).is_err());
+    }
+
+    fn paced_controller(queue_depth: usize) -> LaserController {
+        LaserController::init(&Config {
+            laser: LaserConfig {
+                frame_gap_steps: 50,
+                queue_depth,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_frame_gap_follows_speed_profile() {
+        let controller = paced_controller(16);
+        assert_eq!(controller.frame_gap(0), Duration::from_millis(100));
+        assert_eq!(controller.frame_gap(1), Duration::from_millis(50));
+        assert_eq!(controller.frame_gap(4), Duration::from_millis(10));
+
+        // Draw messages default to speed profile 1
+        assert_eq!(
+            FrameSendPack::from(MessageSendPack::draw(1, Vec::new())).speed_profile(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_draw_time() {
+        // A 300x400 right triangle is 1200 steps around
+        let triangle = [(0, 0), (300, 0), (0, 400)];
+        assert_eq!(draw_time(&triangle, 0), Duration::from_millis(2400));
+        assert_eq!(draw_time(&triangle, 4), Duration::from_millis(240));
+
+        // A single point doesn't move the galvos
+        assert_eq!(draw_time(&[(150, 150)], 0), Duration::ZERO);
+        assert_eq!(draw_time(&[], 0), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_drop_oldest_frames() {
+        let mut controller = paced_controller(2);
+        for laser_id in 1..=4 {
+            controller.enqueue(laser_frame(laser_id).into());
+        }
+
+        assert_eq!(
+            controller
+                .queue
+                .iter()
+                .map(|batch| batch[0].pack.laser_id())
+                .collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        assert_eq!(
+            controller.stats,
+            LaserStats {
+                sent: 0,
+                dropped: 2,
+                max_depth: 2,
+                ..Default::default()
+            }
+        );
+        assert!(controller.warned_drops);
+
+        controller.handle(LaserMessage::ShowStarted("test".to_string()));
+        assert!(!controller.warned_drops);
+    }
+
+    #[tokio::test]
+    async fn test_pace_frames() {
+        let (laser_tx, laser_rx) = mpsc::channel(10);
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        tokio::spawn(async move {
+            paced_controller(16)
+                .start(laser_rx, uart_tx, CancellationToken::new())
+                .await;
+        });
+
+        // Speed profile 1 needs 50ms a frame
+        let start = Instant::now();
+        for laser_id in 1..=3 {
+            laser_tx
+                .send(LaserMessage::Frame(
+                    MessageSendPack::draw(laser_id, Vec::new()).into(),
+                ))
+                .await
+                .unwrap();
+        }
+        for _ in 0..3 {
+            write_next(&mut uart_rx).await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        let (stats_tx, stats_rx) = oneshot::channel();
+        laser_tx.send(LaserMessage::Stats(stats_tx)).await.unwrap();
+        let stats = stats_rx.await.unwrap();
+        assert_eq!(stats.sent, 3);
+        assert_eq!(stats.dropped, 0);
+    }
+
+    /// Stand in for the UART task, writing the next laser frame
+    async fn write_next(uart_rx: &mut mpsc::Receiver<UartMessage>) {
+        let Some(UartMessage::Laser { written, .. }) = uart_rx.recv().await else {
+            panic!("Expected a laser frame");
+        };
+        let _ = written.send(());
+    }
+
+    async fn laser_stats(laser_tx: &mpsc::Sender<LaserMessage>) -> LaserStats {
+        let (stats_tx, stats_rx) = oneshot::channel();
+        laser_tx.send(LaserMessage::Stats(stats_tx)).await.unwrap();
+        stats_rx.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_survive_uart_stopping() {
+        let (laser_tx, laser_rx) = mpsc::channel(10);
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        let laser = tokio::spawn(async move {
+            paced_controller(16)
+                .start(laser_rx, uart_tx, CancellationToken::new())
+                .await;
+        });
+
+        laser_tx
+            .send(LaserMessage::Frame(laser_frame(1).into()))
+            .await
+            .unwrap();
+        write_next(&mut uart_rx).await;
+
+        // The UART task goes away mid-show, frames are dropped but the
+        // lasers keep taking messages
+        drop(uart_rx);
+        for laser_id in 2..=3 {
+            laser_tx
+                .send(LaserMessage::Frame(laser_frame(laser_id).into()))
+                .await
+                .unwrap();
+        }
+        // Speed profile 0 needs 100ms a frame
+        tokio::time::sleep(Duration::from_millis(250)).await;
+
+        let stats = laser_stats(&laser_tx).await;
+        assert!(!laser.is_finished());
+        assert!(!stats.healthy);
+        assert_eq!(stats.sent, 1);
+        assert_eq!(stats.failed, 2);
+
+        // A restarted UART picks up where it left off
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        laser_tx
+            .send(LaserMessage::Reconnect(uart_tx))
+            .await
+            .unwrap();
+        laser_tx
+            .send(LaserMessage::Frame(laser_frame(4).into()))
+            .await
+            .unwrap();
+        write_next(&mut uart_rx).await;
+
+        let stats = laser_stats(&laser_tx).await;
+        assert!(stats.healthy);
+        assert_eq!(stats.sent, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keep_running_while_uart_is_down() {
+        let (uart_tx, uart_rx) = mpsc::channel(10);
+        let uart = UartController::with_opener(Box::new(|| Err(Error::msg("unplugged"))));
+        tokio::spawn(uart.start(uart_rx, CancellationToken::new()));
+
+        let (laser_tx, laser_rx) = mpsc::channel(10);
+        let mut controller = paced_controller(16);
+        let heartbeat = controller.heartbeat.clone();
+        tokio::spawn(async move {
+            controller
+                .start(laser_rx, uart_tx, CancellationToken::new())
+                .await;
+        });
+
+        // The held frames time out instead of holding up the loop
+        for laser_id in 1..=3 {
+            laser_tx
+                .send(LaserMessage::Frame(laser_frame(laser_id).into()))
+                .await
+                .unwrap();
+        }
+        tokio::time::sleep(Duration::from_secs(3)).await;
+
+        let stats = tokio::time::timeout(Duration::from_secs(1), laser_stats(&laser_tx))
+            .await
+            .unwrap();
+        assert!(!stats.healthy);
+        assert_eq!(stats.failed, 3);
+        assert!(heartbeat.age().unwrap() <= HEARTBEAT_INTERVAL);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_measure_latency() {
+        let (laser_tx, laser_rx) = mpsc::channel(10);
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        tokio::spawn(async move {
+            paced_controller(16)
+                .start(laser_rx, uart_tx, CancellationToken::new())
+                .await;
+        });
+
+        // A UART that takes 30ms to write each frame
+        tokio::spawn(async move {
+            while let Some(UartMessage::Laser { written, .. }) = uart_rx.recv().await {
+                tokio::time::sleep(Duration::from_millis(30)).await;
+                let _ = written.send(());
+            }
+        });
+
+        // The second frame also waits out the first one's 100ms gap
+        laser_tx
+            .send(LaserMessage::Frame(laser_frame(1).into()))
+            .await
+            .unwrap();
+        laser_tx
+            .send(LaserMessage::Frame(laser_frame(2).into()))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let latency = laser_stats(&laser_tx).await.latency;
+        assert_eq!(latency.samples, 2);
+        assert_eq!(latency.p50, Duration::from_millis(30));
+        assert_eq!(latency.p95, Duration::from_millis(130));
+        assert_eq!(latency.max, Duration::from_millis(130));
+
+        // Each show starts over
+        laser_tx
+            .send(LaserMessage::ShowStarted("next".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            laser_stats(&laser_tx).await.latency,
+            LatencyStats::default()
+        );
+    }
+
+    #[test]
+    fn test_latency_percentiles() {
+        let samples = (1..=100).map(Duration::from_millis).collect();
+        let latency = LatencyStats::from_samples(&samples);
+        assert_eq!(latency.p50, Duration::from_millis(50));
+        assert_eq!(latency.p95, Duration::from_millis(95));
+        assert_eq!(latency.max, Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_batch_one_write() {
+        let (laser_tx, laser_rx) = mpsc::channel(10);
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        tokio::spawn(async move {
+            paced_controller(16)
+                .start(laser_rx, uart_tx, CancellationToken::new())
+                .await;
+        });
+
+        laser_tx
+            .send(LaserMessage::FrameBatch(
+                [2, 1, 3]
+                    .into_iter()
+                    .map(|laser_id| laser_frame(laser_id).into())
+                    .collect(),
+            ))
+            .await
+            .unwrap();
+
+        let Some(UartMessage::Laser { data, written }) = uart_rx.recv().await else {
+            panic!("Expected a laser frame");
+        };
+        written.send(()).unwrap();
+
+        // The frames go out back to back, in the order they were batched
+        let transfer_size = LaserConfig::default().transfer_size;
+        let mut expected = Vec::new();
+        for laser_id in [2, 1, 3] {
+            expected.extend(laser_frame(laser_id).into_bytes(transfer_size).unwrap());
+        }
+        assert_eq!(data, expected);
+
+        let stats = laser_stats(&laser_tx).await;
+        assert_eq!(stats.sent, 3);
+        assert_eq!(stats.latency.samples, 3);
+    }
+
+    #[tokio::test]
+    async fn test_estop_blocks_drawing() {
+        let mut laser = paced_controller(16);
+        laser.outputs_enabled.disable();
+
+        let (laser_tx, laser_rx) = mpsc::channel(10);
+        let (uart_tx, mut uart_rx) = mpsc::channel(10);
+        tokio::spawn(async move {
+            laser
+                .start(laser_rx, uart_tx, CancellationToken::new())
+                .await;
+        });
+
+        laser_tx
+            .send(LaserMessage::Frame(laser_frame(1).into()))
+            .await
+            .unwrap();
+        laser_tx.send(LaserMessage::SetEnable(false)).await.unwrap();
+
+        // Only turning the lasers off gets through
+        let Some(UartMessage::Laser { data, written }) = uart_rx.recv().await else {
+            panic!("Expected a laser frame");
+        };
+        written.send(()).unwrap();
+        let disable: FrameSendPack = MessageSendPack::enable_message(false).into();
+        assert_eq!(
+            data,
+            disable
+                .into_bytes(LaserConfig::default().transfer_size)
+                .unwrap()
+        );
+
+        let stats = laser_stats(&laser_tx).await;
+        assert_eq!(stats.dropped, 1);
+        assert_eq!(stats.sent, 1);
+    }
+
+    fn ack_sink(uart_tx: mpsc::Sender<UartMessage>) -> UartLaserSink {
+        UartLaserSink::new(
+            &LaserConfig {
+                ack: true,
+                ack_timeout_ms: 20,
+                ack_retries: 2,
+                ..Default::default()
+            },
+            uart_tx,
+        )
+    }
+
+    fn laser_frame(laser_id: u8) -> FrameSendPack {
+        MessageSendPack::new(
+            HeaderPack {
+                laser_id: laser_id.into(),
+                enable: true,
+                ..Default::default()
+            },
+            vec![LaserDataFrame::default()],
+        )
+        .into()
+    }
+
+    #[tokio::test]
+    async fn test_resend_dropped_acks() {
+        let port = MockPort::new(Some(3));
+        let writes = port.writes.clone();
+        let (uart_tx, uart_rx) = mpsc::channel(10);
+        tokio::spawn(
+            UartController::with_port(Box::new(port)).start(uart_rx, CancellationToken::new()),
+        );
+
+        let mut sink = ack_sink(uart_tx);
+        for _ in 0..6 {
+            sink.send_with_ack(laser_frame(2)).await.unwrap();
+        }
+
+        // The 3rd and 6th acks were lost, so two frames went out twice
+        let writes = writes.lock().unwrap();
+        assert_eq!(writes.len(), 8);
+
+        let sequences = writes
+            .iter()
+            .map(|data| (word(&data[0..4]) & SEQUENCE_MASK) >> 1)
+            .collect::<Vec<_>>();
+        assert_eq!(sequences, vec![0, 1, 2, 2, 3, 4, 4, 5]);
+        for data in writes.iter() {
+            assert_eq!(word(&data[0..4]).count_ones() % 2, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_give_up_without_acks() {
+        let port = MockPort::new(Some(1));
+        let writes = port.writes.clone();
+        let (uart_tx, uart_rx) = mpsc::channel(10);
+        tokio::spawn(
+            UartController::with_port(Box::new(port)).start(uart_rx, CancellationToken::new()),
+        );
+
+        let mut sink = ack_sink(uart_tx);
+        assert!(sink.send_with_ack(laser_frame(2)).await.is_err());
+        assert_eq!(writes.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_dmx_flows_while_waiting_for_ack() {
+        let port = MockPort::new(Some(1));
+        let writes = port.writes.clone();
+        let (uart_tx, uart_rx) = mpsc::channel(10);
+        tokio::spawn(
+            UartController::with_port(Box::new(port)).start(uart_rx, CancellationToken::new()),
+        );
+
+        let dmx_tx = uart_tx.clone();
+        let dmx = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            dmx_tx
+                .send(UartMessage::DMX(vec![0xA0, 1, 2]))
+                .await
+                .unwrap();
+        });
+
+        let mut sink = ack_sink(uart_tx);
+        let _ = sink.send_with_ack(laser_frame(2)).await;
+        dmx.await.unwrap();
+
+        // The DMX frame went out between the first laser frame and its resend
+        let writes = writes.lock().unwrap();
+        assert_eq!(writes[1], vec![0xA0, 1, 2]);
+    }
+
+    #[test]
+    fn test_pad_to_transfer_size() {
+        let pack: FrameSendPack = MessageSendPack::new(
+            HeaderPack::default(),
+            vec![
+                LaserDataFrame {
+                    pattern_id: 1,
+                    r: 7,
+                    g: 0,
+                    b: 0,
+                };
+                2
+            ],
+        )
+        .into();
+
+        let bytes = pack.clone().into_bytes(51).unwrap();
+        assert_eq!(bytes.len(), 51 * 4);
+        assert_eq!(&bytes[..12], &pack.clone().into_bytes(3).unwrap()[..]);
+        assert!(bytes[12..].iter().all(|byte| *byte == 0));
 
-        pack
+        // Too many instructions is an error, not a truncation
+        assert!(pack.into_bytes(2).is_err());
     }
 }
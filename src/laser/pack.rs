@@ -1,6 +1,8 @@
 use packed_struct::{prelude::*, types::bits::Bits};
 
-use crate::show::LaserDataFrame;
+use crate::{config::ColourDepth, show::LaserDataFrame};
+
+use super::colour::quantize;
 
 /// Trait to calculate checksum before packing the struct
 pub trait CheckSum {
@@ -29,6 +31,7 @@ pub trait CheckSum {
 //         | 0x00010000 = Draw Boundary
 //         | 0x00008000 = Oneshot
 //         | 0x00007000 = Speed Profile
+//         | 0x0000001E = Sequence number, echoed back in the Pico's ack
 //         | 0x00000001 = Checksum
 #[derive(PackedStruct, Default, Debug, PartialEq, Clone)]
 #[packed_struct(bit_numbering = "msb0")]
@@ -53,8 +56,10 @@ pub struct HeaderPack {
     // Should be in
     #[packed_field(bits = "17..=19")]
     pub speed_profile: Integer<u8, Bits<3>>,
-    #[packed_field(bits = "20..=30")]
-    pub _reserved: ReservedZero<packed_bits::Bits<11>>,
+    #[packed_field(bits = "20..=26")]
+    pub _reserved: ReservedZero<packed_bits::Bits<7>>,
+    #[packed_field(bits = "27..=30")]
+    pub sequence: Integer<u8, Bits<4>>,
     #[packed_field(bits = "31")]
     pub checksum: bool,
 }
@@ -97,18 +102,90 @@ impl CheckSum for PatternPack {
     }
 }
 
+/// Rounds each 8-bit channel to the nearest of the Pico's 3-bit levels. Use
+/// `ColourQuantizer` to truncate or dither instead.
 impl From<LaserDataFrame> for PatternPack {
     fn from(laser: LaserDataFrame) -> Self {
         PatternPack {
             pattern_id: laser.pattern_id.into(),
-            red: laser.r.into(),
-            green: laser.g.into(),
-            blue: laser.b.into(),
+            red: quantize(laser.r, ColourDepth::Round).into(),
+            green: quantize(laser.g, ColourDepth::Round).into(),
+            blue: quantize(laser.b, ColourDepth::Round).into(),
             ..Default::default()
         }
     }
 }
 
+// Configuration Mode, in the frames after a header with the configuration
+// flag set:
+// 1       | 0xFFFFF000 = Acceleration
+//         | 0x00000FFE = Transfer Size
+//         | 0x00000001 = Checksum
+// 2       | 0xFFFFC000 = Max Speed
+//         | 0x00003FFE = Min Speed
+//         | 0x00000001 = Checksum
+// 3       | 0xFFF00000 = X Home
+//         | 0x000FFF00 = Y Home
+//         | 0x000000F0 = Projector ID
+//         | 0x00000001 = Checksum
+#[derive(PackedStruct, Default, Debug, PartialEq, Clone)]
+#[packed_struct(bit_numbering = "msb0", endian = "msb")]
+pub struct AccelerationConfigPack {
+    #[packed_field(bits = "0..=19")]
+    pub acceleration: Integer<u32, Bits<20>>,
+    #[packed_field(bits = "20..=30")]
+    pub transfer_size: Integer<u16, Bits<11>>,
+    #[packed_field(bits = "31")]
+    pub checksum: bool,
+}
+
+impl CheckSum for AccelerationConfigPack {
+    fn checksum_pack(&mut self) -> [u8; 4] {
+        self.checksum = self.calculate_checksum(self.pack().unwrap());
+        self.pack().unwrap()
+    }
+}
+
+#[derive(PackedStruct, Default, Debug, PartialEq, Clone)]
+#[packed_struct(bit_numbering = "msb0", endian = "msb")]
+pub struct SpeedConfigPack {
+    #[packed_field(bits = "0..=17")]
+    pub max_speed: Integer<u32, Bits<18>>,
+    #[packed_field(bits = "18..=30")]
+    pub min_speed: Integer<u16, Bits<13>>,
+    #[packed_field(bits = "31")]
+    pub checksum: bool,
+}
+
+impl CheckSum for SpeedConfigPack {
+    fn checksum_pack(&mut self) -> [u8; 4] {
+        self.checksum = self.calculate_checksum(self.pack().unwrap());
+        self.pack().unwrap()
+    }
+}
+
+#[derive(PackedStruct, Default, Debug, PartialEq, Clone)]
+#[packed_struct(bit_numbering = "msb0", endian = "msb")]
+pub struct HomeConfigPack {
+    #[packed_field(bits = "0..=11")]
+    pub x_home: Integer<u16, Bits<12>>,
+    #[packed_field(bits = "12..=23")]
+    pub y_home: Integer<u16, Bits<12>>,
+    #[packed_field(bits = "24..=27")]
+    pub projector_id: Integer<u8, Bits<4>>,
+    #[packed_field(bits = "28..=30")]
+    pub _reserved: ReservedZero<packed_bits::Bits<3>>,
+    #[packed_field(bits = "31")]
+    pub checksum: bool,
+}
+
+impl CheckSum for HomeConfigPack {
+    fn checksum_pack(&mut self) -> [u8; 4] {
+        self.checksum = self.calculate_checksum(self.pack().unwrap());
+        self.pack().unwrap()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -261,8 +338,10 @@ mod tests {
 
     #[test]
     fn test_draw_pack() -> Result<(), PackingError> {
+        // Pattern 122 in 0xFF000000 and full red in 0x00E00000, with an even
+        // number of bits set so the checksum stays clear
         assert_eq!(
-            [0x3d, 0x17, 0x38, 0x00],
+            [0x7a, 0xe0, 0x00, 0x00],
             PatternPack {
                 pattern_id: 122.into(),
                 red: 7.into(),
@@ -275,4 +354,73 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_header_pack_round_trip() -> Result<(), PackingError> {
+        let mut header = HeaderPack {
+            laser_id: 0xF.into(),
+            point_count: 50.into(),
+            home: true,
+            enable: true,
+            configuration_mode: false,
+            draw_boundary: true,
+            oneshot: true,
+            speed_profile: 6.into(),
+            sequence: 9.into(),
+            ..HeaderPack::default()
+        };
+        let bytes = header.checksum_pack();
+
+        assert_eq!(HeaderPack::unpack(&bytes)?, header);
+        assert_eq!(HeaderPack::unpack(&bytes)?.pack()?, bytes);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pattern_pack_round_trip() -> Result<(), PackingError> {
+        let mut pattern = PatternPack {
+            pattern_id: 37.into(),
+            red: 5.into(),
+            green: 2.into(),
+            blue: 7.into(),
+            ..PatternPack::default()
+        };
+        let bytes = pattern.checksum_pack();
+
+        assert_eq!(PatternPack::unpack(&bytes)?, pattern);
+        assert_eq!(PatternPack::unpack(&bytes)?.pack()?, bytes);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_packs_round_trip() -> Result<(), PackingError> {
+        let mut acceleration = AccelerationConfigPack {
+            acceleration: 100_000.into(),
+            transfer_size: 51.into(),
+            ..Default::default()
+        };
+        let bytes = acceleration.checksum_pack();
+        assert_eq!(AccelerationConfigPack::unpack(&bytes)?, acceleration);
+
+        let mut speed = SpeedConfigPack {
+            max_speed: 40_000.into(),
+            min_speed: 5_000.into(),
+            ..Default::default()
+        };
+        let bytes = speed.checksum_pack();
+        assert_eq!(SpeedConfigPack::unpack(&bytes)?, speed);
+
+        let mut home = HomeConfigPack {
+            x_home: 580.into(),
+            y_home: 300.into(),
+            projector_id: 3.into(),
+            ..Default::default()
+        };
+        let bytes = home.checksum_pack();
+        assert_eq!(HomeConfigPack::unpack(&bytes)?, home);
+
+        Ok(())
+    }
 }
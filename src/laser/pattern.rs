@@ -0,0 +1,266 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Error;
+use rust_embed::RustEmbed;
+
+use crate::show::LaserDataFrame;
+
+use super::colour;
+
+/// Farthest a point can be from the origin, in galvo steps
+pub const MAX_X: u16 = 300;
+pub const MAX_Y: u16 = 300;
+
+/// Where patterns on disk are loaded from, next to the shows
+pub const PATTERN_DIR: &str = "shows/patterns";
+
+/// The Pico's pattern lookup array. A pattern's ID is its index here.
+pub const PATTERN_NAMES: [&str; 38] = [
+    "bat",
+    "bow",
+    "bow_slow",
+    "candy",
+    "circle",
+    "circle_slow",
+    "clockwise_spiral_slow",
+    "counterclockwise_spiral_slow",
+    "crescent",
+    "ghost",
+    "gravestone_cross",
+    "hexagon",
+    "hexagon_slow",
+    "horizontal_lines_left_to_right_slow",
+    "horizontal_lines_right_to_left_slow",
+    "lightning_bolt",
+    "octagon",
+    "octagon_slow",
+    "parallelogram",
+    "parallelogram_slow",
+    "pentagon",
+    "pentagon_slow",
+    "pentagram",
+    "pentagram_slow",
+    "pumpkin",
+    "septagon_slow",
+    "square_large",
+    "square_large_slow",
+    "square_small",
+    "square_small_slow",
+    "star",
+    "star_slow",
+    "triangle_large",
+    "triangle_large_slow",
+    "triangle_small",
+    "triangle_small_slow",
+    "vertical_lines_bottom_to_top_slow",
+    "vertical_lines_top_to_bottom_slow",
+];
+
+/// Look up a pattern's ID. Show files use dashes, the Pico uses underscores.
+pub fn pattern_id(name: &str) -> Option<u8> {
+    let name = name.replace('-', "_");
+    PATTERN_NAMES
+        .iter()
+        .position(|pattern| *pattern == name)
+        .map(|id| id as u8)
+}
+
+#[derive(RustEmbed)]
+#[folder = "src/laser/patterns"]
+struct PatternAsset;
+
+/// Named patterns, each a list of draw instructions ready to send to a laser.
+/// Pattern files are `<name>.json` with a `points` array of
+/// `[x, y, r, g, b]`, and the name has to be one of the Pico's patterns.
+#[derive(Debug, Default, Clone)]
+pub struct PatternLibrary {
+    pub patterns: HashMap<String, Vec<LaserDataFrame>>,
+    /// Each pattern's points by pattern ID, for previews
+    pub outlines: HashMap<u8, Vec<(u16, u16)>>,
+}
+
+impl PatternLibrary {
+    /// The embedded patterns, overridden by any in `shows/patterns`
+    pub fn load() -> Result<Self, Error> {
+        Self::load_from(Path::new(PATTERN_DIR))
+    }
+
+    /// The embedded patterns, overridden by any in `dir`. A missing directory
+    /// is fine.
+    pub fn load_from(dir: &Path) -> Result<Self, Error> {
+        let mut library = PatternLibrary::default();
+
+        for file in PatternAsset::iter() {
+            let contents = PatternAsset::get(&file).unwrap();
+            library.insert_file(&file, std::str::from_utf8(&contents.data)?)?;
+        }
+
+        if dir.is_dir() {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                    continue;
+                }
+                let file = path.file_name().unwrap().to_string_lossy().to_string();
+                library.insert_file(&file, &std::fs::read_to_string(&path)?)?;
+            }
+        }
+
+        Ok(library)
+    }
+
+    fn insert_file(&mut self, file: &str, contents: &str) -> Result<(), Error> {
+        let name = file.trim_end_matches(".json").replace('-', "_");
+        let points = Self::parse_points(&name, contents)
+            .map_err(|e| Error::msg(format!("Invalid pattern file {}: {}", file, e)))?;
+
+        let pattern_id = pattern_id(&name).unwrap();
+        self.outlines.insert(
+            pattern_id,
+            points.iter().map(|(x, y, _)| (*x, *y)).collect(),
+        );
+        self.patterns.insert(
+            name,
+            points.into_iter().map(|(_, _, frame)| frame).collect(),
+        );
+
+        Ok(())
+    }
+
+    /// Turn a pattern file into draw instructions, one for each point in its
+    /// colour. Pattern files use the Pico's 3-bit colours.
+    pub fn parse(name: &str, contents: &str) -> Result<Vec<LaserDataFrame>, Error> {
+        Ok(Self::parse_points(name, contents)?
+            .into_iter()
+            .map(|(_, _, frame)| frame)
+            .collect())
+    }
+
+    fn parse_points(name: &str, contents: &str) -> Result<Vec<(u16, u16, LaserDataFrame)>, Error> {
+        let pattern_id = pattern_id(name)
+            .ok_or_else(|| Error::msg(format!("{} isn't one of the Pico's patterns", name)))?;
+
+        let json: serde_json::Value = serde_json::from_str(contents)?;
+        let points = json["points"]
+            .as_array()
+            .ok_or_else(|| Error::msg("missing a points array"))?;
+
+        points
+            .iter()
+            .enumerate()
+            .map(|(i, point)| {
+                let values = point
+                    .as_array()
+                    .filter(|values| values.len() == 5)
+                    .and_then(|values| {
+                        values
+                            .iter()
+                            .map(|v| v.as_u64())
+                            .collect::<Option<Vec<_>>>()
+                    })
+                    .ok_or_else(|| Error::msg(format!("point {} isn't [x, y, r, g, b]", i)))?;
+
+                let (x, y) = (values[0], values[1]);
+                if x > MAX_X as u64 || y > MAX_Y as u64 {
+                    return Err(Error::msg(format!(
+                        "point {} ({}, {}) is outside of {}x{}",
+                        i, x, y, MAX_X, MAX_Y
+                    )));
+                }
+                if values[2..].iter().any(|colour| *colour > 7) {
+                    return Err(Error::msg(format!(
+                        "point {} has a colour channel above 7",
+                        i
+                    )));
+                }
+
+                Ok((
+                    x as u16,
+                    y as u16,
+                    LaserDataFrame {
+                        pattern_id,
+                        r: colour::expand(values[2] as u8),
+                        g: colour::expand(values[3] as u8),
+                        b: colour::expand(values[4] as u8),
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Vec<LaserDataFrame>> {
+        self.patterns.get(&name.replace('-', "_"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::laser::pack::PatternPack;
+
+    #[test]
+    fn test_load_fixture_pattern() {
+        let library = PatternLibrary::load_from(Path::new("tests/fixtures/patterns")).unwrap();
+
+        // Embedded patterns are still there alongside the ones on disk
+        assert!(library.get("bat").is_some());
+
+        let packs = library
+            .get("ghost")
+            .unwrap()
+            .iter()
+            .cloned()
+            .map(PatternPack::from)
+            .collect::<Vec<_>>();
+        let ghost = pattern_id("ghost").unwrap();
+        assert_eq!(
+            packs,
+            vec![
+                PatternPack {
+                    pattern_id: ghost.into(),
+                    red: 7.into(),
+                    green: 7.into(),
+                    blue: 7.into(),
+                    ..Default::default()
+                },
+                PatternPack {
+                    pattern_id: ghost.into(),
+                    red: 7.into(),
+                    green: 7.into(),
+                    blue: 7.into(),
+                    ..Default::default()
+                },
+                PatternPack {
+                    pattern_id: ghost.into(),
+                    red: 0.into(),
+                    green: 0.into(),
+                    blue: 7.into(),
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pattern_names() {
+        assert_eq!(pattern_id("bat"), Some(0));
+        assert_eq!(pattern_id("gravestone-cross"), Some(10));
+        assert_eq!(pattern_id("unicorn"), None);
+    }
+
+    #[test]
+    fn test_reject_invalid_patterns() {
+        assert!(PatternLibrary::parse("bat", r#"{ "points": [[301, 0, 7, 0, 0]] }"#).is_err());
+        assert!(PatternLibrary::parse("bat", r#"{ "points": [[0, 301, 7, 0, 0]] }"#).is_err());
+        assert!(PatternLibrary::parse("bat", r#"{ "points": [[0, 0, 8, 0, 0]] }"#).is_err());
+        assert!(PatternLibrary::parse("bat", r#"{ "points": [[0, 0, 7]] }"#).is_err());
+        assert!(PatternLibrary::parse("unicorn", r#"{ "points": [] }"#).is_err());
+
+        assert_eq!(
+            PatternLibrary::parse("bat", r#"{ "points": [[300, 300, 7, 0, 0]] }"#)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    path::PathBuf,
+};
+
+use anyhow::Error;
+use async_trait::async_trait;
+use packed_struct::PackedStruct;
+use tokio::time::Instant;
+
+use crate::show::MAX_LASERS;
+
+use super::{
+    colour,
+    pack::{HeaderPack, PatternPack},
+    pattern::{PatternLibrary, MAX_X, MAX_Y},
+    sink::LaserSink,
+    FrameSendPack, ALL_LASERS,
+};
+
+/// Where previews are written, one folder per show
+pub const PREVIEW_DIR: &str = "target/laser-preview";
+
+/// Drawn for patterns that don't have an outline in the library
+const UNKNOWN_OUTLINE: [(u16, u16); 4] = [(140, 140), (160, 140), (160, 160), (140, 160)];
+
+/// Stands in for the lasers, keeping what each one is drawing and rendering
+/// all of them to an SVG every time one changes
+pub struct SimulatorSink {
+    pub dir: PathBuf,
+    show: String,
+    started: Instant,
+    /// What each laser is drawing right now
+    lasers: BTreeMap<u8, Vec<PatternPack>>,
+    /// Pattern outlines by pattern ID
+    outlines: HashMap<u8, Vec<(u16, u16)>>,
+}
+
+impl SimulatorSink {
+    pub fn new(dir: PathBuf, patterns: &PatternLibrary) -> Self {
+        SimulatorSink {
+            dir,
+            show: "idle".to_string(),
+            started: Instant::now(),
+            lasers: BTreeMap::new(),
+            outlines: patterns.outlines.clone(),
+        }
+    }
+
+    /// Update what the addressed lasers are drawing
+    pub fn apply(&mut self, frame: &FrameSendPack) -> Result<(), Error> {
+        let header = HeaderPack::unpack(&frame.header)
+            .map_err(|e| Error::msg(format!("Invalid laser header: {:?}", e)))?;
+
+        // Config frames don't change what's drawn
+        if header.configuration_mode {
+            return Ok(());
+        }
+
+        let patterns = if header.enable && !header.home {
+            frame
+                .draw_instructions
+                .iter()
+                .map(PatternPack::unpack)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| Error::msg(format!("Invalid laser pattern: {:?}", e)))?
+        } else {
+            Vec::new()
+        };
+
+        let ids = match u8::from(header.laser_id) {
+            ALL_LASERS => (1..=MAX_LASERS as u8).collect(),
+            id => vec![id],
+        };
+        for id in ids {
+            self.lasers.insert(id, patterns.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Every laser's patterns, one path each, with y pointing up like the
+    /// galvos
+    pub fn render(&self) -> String {
+        let mut svg = String::new();
+        writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {MAX_X} {MAX_Y}" width="{}" height="{}">"#,
+            MAX_X * 2,
+            MAX_Y * 2
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            r#"<rect width="{MAX_X}" height="{MAX_Y}" fill="black"/>"#
+        )
+        .unwrap();
+
+        for (id, patterns) in &self.lasers {
+            writeln!(svg, r#"<g id="laser-{}">"#, id).unwrap();
+            for pattern in patterns {
+                let outline = self
+                    .outlines
+                    .get(&u8::from(pattern.pattern_id))
+                    .map(Vec::as_slice)
+                    .unwrap_or(&UNKNOWN_OUTLINE);
+
+                let d = outline
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (x, y))| {
+                        format!("{}{} {}", if i == 0 { "M" } else { "L" }, x, MAX_Y - y)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+
+                writeln!(
+                    svg,
+                    r##"<path d="{} Z" stroke="#{:02x}{:02x}{:02x}" fill="none"/>"##,
+                    d,
+                    colour::expand(pattern.red.into()),
+                    colour::expand(pattern.green.into()),
+                    colour::expand(pattern.blue.into()),
+                )
+                .unwrap();
+            }
+            writeln!(svg, "</g>").unwrap();
+        }
+
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+#[async_trait]
+impl LaserSink for SimulatorSink {
+    async fn send(&mut self, frame: &FrameSendPack) -> Result<(), Error> {
+        self.apply(frame)?;
+
+        let dir = self.dir.join(&self.show);
+        tokio::fs::create_dir_all(&dir).await?;
+        let path = dir.join(format!("{}.svg", self.started.elapsed().as_millis()));
+        tokio::fs::write(path, self.render()).await?;
+
+        Ok(())
+    }
+
+    fn show_started(&mut self, name: &str) {
+        self.show = name.to_string();
+        self.started = Instant::now();
+        self.lasers.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        laser::{colour::ColourQuantizer, MessageSendPack},
+        show::prelude::UnloadedShow,
+        test_util::TempDir,
+    };
+
+    #[tokio::test]
+    async fn test_render_fixture_show() {
+        let show = UnloadedShow::load_show_file(
+            Path::new("tests/fixtures/shows/laser-pattern/instructions.json"),
+            &Config::default(),
+        )
+        .unwrap();
+
+        let dir = TempDir::new("lasers");
+        let mut sink = SimulatorSink::new(dir.to_path_buf(), &PatternLibrary::load().unwrap());
+        sink.show_started("laser-pattern");
+
+        for message in show.frames[0].laser_messages(&mut ColourQuantizer::default()) {
+            sink.send(&message.into()).await.unwrap();
+        }
+
+        // The bat has four points, each drawn as its own path
+        let svg = sink.render();
+        assert_eq!(svg.matches("<path").count(), 4);
+        assert!(svg.contains(r#"<g id="laser-3">"#));
+        assert!(svg.contains(r##"stroke="#ff0000""##));
+
+        let written = std::fs::read_dir(dir.join("laser-pattern"))
+            .unwrap()
+            .count();
+        assert_eq!(written, 1);
+
+        // Turning the laser off clears it
+        for message in show.frames[1].laser_messages(&mut ColourQuantizer::default()) {
+            sink.send(&message.into()).await.unwrap();
+        }
+        sink.apply(&MessageSendPack::enable_message(false).into())
+            .unwrap();
+        assert_eq!(sink.render().matches("<path").count(), 0);
+    }
+}
@@ -0,0 +1,169 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+use async_trait::async_trait;
+use log::{debug, warn};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{config::LaserConfig, uart::UartMessage};
+
+use super::{FrameSendPack, ALL_LASERS};
+
+/// How long a frame can wait on the UART. While the device is gone frames
+/// are held rather than written, and the lasers can't wait on them forever.
+pub const WRITE_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Somewhere that laser frames can be sent
+#[async_trait]
+pub trait LaserSink: Send {
+    async fn send(&mut self, frame: &FrameSendPack) -> Result<(), Error>;
+
+    /// Send frames from the same show tick, one after the other
+    async fn send_batch(&mut self, frames: &[FrameSendPack]) -> Result<(), Error> {
+        for frame in frames {
+            self.send(frame).await?;
+        }
+        Ok(())
+    }
+
+    /// A show started playing
+    fn show_started(&mut self, _name: &str) {}
+
+    /// The UART task was restarted
+    fn reconnect(&mut self, _uart_tx: mpsc::Sender<UartMessage>) {}
+}
+
+/// Sends frames to the Picos over the UART, waiting for acks when they're
+/// turned on
+pub struct UartLaserSink {
+    config: LaserConfig,
+    uart_tx: mpsc::Sender<UartMessage>,
+    /// Sequence number for the next acked frame, wrapping at 4 bits
+    pub sequence: u8,
+}
+
+impl UartLaserSink {
+    pub fn new(config: &LaserConfig, uart_tx: mpsc::Sender<UartMessage>) -> Self {
+        UartLaserSink {
+            config: config.clone(),
+            uart_tx,
+            sequence: 0,
+        }
+    }
+
+    /// Send a frame and wait for the Pico to ack it, resending it up to
+    /// `ack_retries` times. The UART keeps handling DMX while this waits.
+    pub async fn send_with_ack(&mut self, frame: FrameSendPack) -> Result<(), Error> {
+        let sequence = self.sequence;
+        self.sequence = (self.sequence + 1) & 0xF;
+
+        let laser_id = frame.laser_id();
+        let data = frame
+            .with_sequence(sequence)
+            .into_bytes(self.config.transfer_size)?;
+        let timeout = Duration::from_millis(self.config.ack_timeout_ms);
+
+        for attempt in 0..=self.config.ack_retries {
+            let (ack_tx, ack_rx) = oneshot::channel();
+            self.uart_tx
+                .send(UartMessage::LaserWithAck {
+                    data: data.clone(),
+                    sequence,
+                    ack: ack_tx,
+                })
+                .await
+                .map_err(|_| Error::msg("the UART task has stopped"))?;
+
+            match tokio::time::timeout(timeout, ack_rx).await {
+                Ok(Ok(0)) => return Ok(()),
+                Ok(Ok(status)) => warn!(
+                    "Laser {} rejected frame {} with status {} (attempt {})",
+                    laser_id,
+                    sequence,
+                    status,
+                    attempt + 1
+                ),
+                _ => warn!(
+                    "No ack from laser {} for frame {} (attempt {})",
+                    laser_id,
+                    sequence,
+                    attempt + 1
+                ),
+            }
+        }
+
+        Err(Error::msg(format!(
+            "laser {} never acked frame {} after {} attempts",
+            laser_id,
+            sequence,
+            self.config.ack_retries + 1
+        )))
+    }
+
+    /// Hand bytes to the UART and wait up to `WRITE_TIMEOUT` for them to be
+    /// written. A held frame that times out is dropped by the UART once it's
+    /// back, since nobody is waiting on it.
+    async fn write(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        let (written_tx, written_rx) = oneshot::channel();
+        self.uart_tx
+            .send(UartMessage::Laser {
+                data,
+                written: written_tx,
+            })
+            .await
+            .map_err(|_| Error::msg("the UART task has stopped"))?;
+
+        match tokio::time::timeout(WRITE_TIMEOUT, written_rx).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err(Error::msg("the UART didn't write the frame")),
+            Err(_) => Err(Error::msg("the UART is holding frames, it's not open")),
+        }
+    }
+}
+
+#[async_trait]
+impl LaserSink for UartLaserSink {
+    async fn send(&mut self, frame: &FrameSendPack) -> Result<(), Error> {
+        // Every laser would answer a broadcast at once, so those aren't acked
+        if self.config.ack && frame.laser_id() != ALL_LASERS {
+            return self.send_with_ack(frame.clone()).await;
+        }
+
+        self.write(frame.clone().into_bytes(self.config.transfer_size)?)
+            .await
+    }
+
+    /// Without acks, a batch goes out as one buffer so the UART only drains
+    /// once for the whole tick. Both ways log how long the tick took, so
+    /// they can be compared.
+    async fn send_batch(&mut self, frames: &[FrameSendPack]) -> Result<(), Error> {
+        let started = Instant::now();
+        if self.config.ack {
+            for frame in frames {
+                self.send(frame).await?;
+            }
+            debug!(
+                "Sent {} laser frames one at a time in {:?}",
+                frames.len(),
+                started.elapsed()
+            );
+            return Ok(());
+        }
+
+        let mut data = Vec::new();
+        for frame in frames {
+            data.extend(frame.clone().into_bytes(self.config.transfer_size)?);
+        }
+        self.write(data).await?;
+        debug!(
+            "Sent {} laser frames as one write in {:?}",
+            frames.len(),
+            started.elapsed()
+        );
+        Ok(())
+    }
+
+    fn reconnect(&mut self, uart_tx: mpsc::Sender<UartMessage>) {
+        self.uart_tx = uart_tx;
+    }
+}
@@ -1,36 +1,143 @@
-use laser::FrameSendPack;
+use laser::TimedFrame;
+use lights::{LightEffect, LightState, LightTestReport};
 use prelude::LoadedSong;
-use show::prelude::DmxStateVarPosition;
+use show::prelude::{DmxStateVarPosition, InitReport, ShowControl};
+use std::time::Duration;
+use tokio::sync::oneshot;
 
 pub mod audio;
+pub mod cli;
 pub mod config;
+pub mod console;
+#[cfg(feature = "dashboard")]
+pub mod dashboard;
 pub mod dmx;
+pub mod health;
+pub mod inputs;
 pub mod laser;
 pub mod lights;
+pub mod logging;
+pub mod metrics;
+pub mod receiver;
+pub mod recorder;
+pub mod safety;
+pub mod schedule;
 pub mod show;
+pub mod shutdown;
+pub mod state;
 pub mod structure;
+pub mod supervisor;
+#[cfg(test)]
+mod test_util;
+pub mod turret;
 pub mod uart;
 
 pub mod prelude {
     pub use crate::{audio::*, config::*, laser::*, lights::*, show::*};
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub enum InternalMessage {
     /// Change a light over GPIO
     Light { light_id: u8, enable: bool },
+    /// Dim a light over GPIO, from 0 for off to 255 for fully on
+    LightLevel { light_id: u8, level: u8 },
+    /// Ask what every light is doing right now
+    LightQuery(oneshot::Sender<Vec<LightState>>),
+    /// Run an effect on a light until it's sent a level
+    LightEffect { light_id: u8, effect: LightEffect },
+    /// Turn every light off, replying once they are
+    AllLightsOff(oneshot::Sender<()>),
+    /// Step through every light, replying with how it went
+    LightTest(oneshot::Sender<LightTestReport>),
+    /// The outcome of a light test
+    LightTestReport(LightTestReport),
+    /// How initialising the hardware went
+    InitReport(InitReport),
+    /// Let outputs turn on again after the e-stop
+    OutputsRearm,
     /// Play an audio file
     Audio { audio_file_contents: LoadedSong },
     /// Stop audio playback
     AudioStop,
-    /// Direct projector frames
-    Laser(FrameSendPack),
+    /// Pause audio playback where it is
+    AudioPause,
+    /// Carry on playing paused audio
+    AudioResume,
+    /// Fade audio out over the duration, then stop it
+    AudioFadeOut(Duration),
+    /// Direct projector frames, stamped with when they were made
+    Laser(TimedFrame),
+    /// Every laser's frame for one show tick, sent to the UART together
+    LaserBatch(Vec<TimedFrame>),
+    /// Home every laser
+    LaserHome,
+    /// Something went wrong that someone needs to look at
+    Alert(String),
+    /// Turn every laser's output on or off
+    LaserEnable(bool),
+    /// A show started playing
+    ShowStarted(String),
+    /// How far along the show that's playing is, sent every second and when
+    /// it starts and stops
+    ShowProgress {
+        name: String,
+        elapsed_ms: u64,
+        total_ms: u64,
+        /// The last frame that was sent
+        frame_index: usize,
+    },
+    /// Pause, resume or skip the show that's playing
+    ShowControl(ShowControl),
+    /// Write new settings to one projector
+    LaserConfig {
+        acceleration: u32,
+        max_speed: u32,
+        min_speed: u16,
+        x_home: u16,
+        y_home: u16,
+        projector_id: u8,
+    },
     /// DMX data
     DmxUpdateState(Vec<DmxStateVarPosition>),
     /// DMX send request
     DmxSendRequest,
-    /// Zero out all DMX channels
+    /// Zero out all DMX channels, or the configured subset
     DmxZeroOut,
+    /// Apply the configured DMX blackout scene
+    DmxBlackout,
+    /// Fade the DMX to the blackout scene over a while
+    DmxFadeToBlackout(Duration),
+    /// Recall a named DMX scene from the config
+    DmxScene(String),
+    /// Fire an air cannon turret, unless it's locked out
+    TurretFire { turret_id: u8 },
+    /// Everything for a show frame has been sent, so the UART can write it
+    /// all at once
+    FrameFlush { frame_id: u64 },
+}
+
+impl InternalMessage {
+    /// Whether it's for the lasers or DMX, which the show manager sends on
+    /// their own lane rather than the main queue
+    pub fn is_frame_data(&self) -> bool {
+        matches!(
+            self,
+            InternalMessage::Laser(_)
+                | InternalMessage::LaserBatch(_)
+                | InternalMessage::LaserHome
+                | InternalMessage::LaserEnable(_)
+                | InternalMessage::LaserConfig { .. }
+                | InternalMessage::ShowStarted(_)
+                | InternalMessage::DmxUpdateState(_)
+                | InternalMessage::DmxSendRequest
+                | InternalMessage::DmxZeroOut
+                | InternalMessage::DmxBlackout
+                | InternalMessage::DmxFadeToBlackout(_)
+                | InternalMessage::DmxScene(_)
+                | InternalMessage::FrameFlush { .. }
+        )
+    }
 }
 
 // Add new enum for audio controller messages
@@ -38,10 +145,13 @@ pub enum InternalMessage {
 pub enum AudioMessage {
     Play(LoadedSong),
     Stop,
+    Pause,
+    Resume,
+    FadeOut(Duration),
 }
 
 /// Messages that should be processed in the queue
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub enum MessageKind {
     // ExternalMessage(PicoMessage),
     InternalMessage(InternalMessage),
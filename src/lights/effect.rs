@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Patterns that a light runs by itself instead of being written out frame by
+/// frame in a show
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LightEffect {
+    /// On for `duty` (0 to 1) of every `period_ms`, then off
+    Blink { period_ms: u64, duty: f64 },
+    /// Dip below full brightness at random, deeper with a higher `intensity`
+    /// (0 to 1). Only dims on PWM lights; a relay stays on up to 0.5.
+    FlickerCandle { intensity: f64 },
+}
+
+impl LightEffect {
+    /// The level for the `step`th change since the effect started, and how
+    /// long to hold it
+    pub fn step(&self, step: u64, rng: &mut impl Rng) -> (u8, Duration) {
+        match self {
+            LightEffect::Blink { period_ms, duty } => {
+                let period = (*period_ms).max(1) as f64;
+                let on = (period * duty.clamp(0.0, 1.0)).round() as u64;
+
+                match step % 2 {
+                    0 => (255, Duration::from_millis(on)),
+                    _ => (0, Duration::from_millis(period as u64 - on)),
+                }
+            }
+            LightEffect::FlickerCandle { intensity } => {
+                let dip = intensity.clamp(0.0, 1.0) * rng.gen::<f64>();
+                let level = (255.0 * (1.0 - dip)).round() as u8;
+
+                (level, Duration::from_millis(rng.gen_range(50..150)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+
+    use super::*;
+
+    #[test]
+    fn test_blink() {
+        let blink = LightEffect::Blink {
+            period_ms: 200,
+            duty: 0.25,
+        };
+        let mut rng = thread_rng();
+
+        assert_eq!(blink.step(0, &mut rng), (255, Duration::from_millis(50)));
+        assert_eq!(blink.step(1, &mut rng), (0, Duration::from_millis(150)));
+        assert_eq!(blink.step(2, &mut rng), (255, Duration::from_millis(50)));
+
+        // Always on never turns off
+        let on = LightEffect::Blink {
+            period_ms: 200,
+            duty: 1.0,
+        };
+        assert_eq!(on.step(1, &mut rng).1, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_flicker_candle() {
+        let candle = LightEffect::FlickerCandle { intensity: 0.4 };
+        let mut rng = thread_rng();
+
+        for step in 0..100 {
+            let (level, hold) = candle.step(step, &mut rng);
+            assert!(level >= 153);
+            assert!(hold >= Duration::from_millis(50) && hold < Duration::from_millis(150));
+        }
+    }
+}
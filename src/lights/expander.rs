@@ -0,0 +1,43 @@
+use anyhow::Error;
+use rppal::i2c::I2c;
+
+// Register addresses with IOCON.BANK left at 0, so A and B sit next to each
+// other and can be written together
+const IODIRA: u8 = 0x00;
+const OLATA: u8 = 0x14;
+
+/// An MCP23017 with all 16 pins as outputs
+pub struct Mcp23017 {
+    i2c: I2c,
+    /// What the output latches hold, GPA0 in bit 0 through GPB7 in bit 15
+    latch: u16,
+}
+
+impl Mcp23017 {
+    /// `off` is the latch with every light off. It's written before the
+    /// pins become outputs, so that nothing flashes on.
+    pub fn new(bus: u8, address: u16, off: u16) -> Result<Self, Error> {
+        let mut i2c = I2c::with_bus(bus)?;
+        i2c.set_slave_address(address)?;
+
+        let mut expander = Mcp23017 { i2c, latch: off };
+        expander.write_latch()?;
+        expander.i2c.block_write(IODIRA, &[0x00, 0x00])?;
+
+        Ok(expander)
+    }
+
+    pub fn set(&mut self, pin: u8, high: bool) -> Result<(), Error> {
+        if high {
+            self.latch |= 1 << pin;
+        } else {
+            self.latch &= !(1 << pin);
+        }
+        self.write_latch()
+    }
+
+    fn write_latch(&mut self) -> Result<(), Error> {
+        self.i2c.block_write(OLATA, &self.latch.to_le_bytes())?;
+        Ok(())
+    }
+}
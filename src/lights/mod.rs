@@ -1,25 +1,242 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
 use anyhow::Error;
-use log::{error, info};
-use tokio::sync::mpsc;
+use log::{error, info, warn};
+use rand::{rngs::StdRng, SeedableRng};
+use tokio::{sync::mpsc, task::JoinHandle};
 
 #[cfg(feature = "pi")]
 use rppal::gpio::{Gpio, OutputPin};
 
+#[cfg(feature = "pi")]
+use expander::Mcp23017;
+
 use crate::{
     config::{Config, Pin},
+    safety::OutputsEnabled,
     MessageKind,
 };
 
-#[allow(dead_code)]
+pub mod effect;
 #[cfg(feature = "pi")]
-pub struct LightController {
-    pins: Vec<OutputPin>,
+pub mod expander;
+mod simulator;
+
+pub use effect::LightEffect;
+
+/// What a light is doing, as it was last driven
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LightState {
+    /// From 0 for off to 255 for fully on. Relays are only ever 0 or 255.
+    pub level: u8,
+    /// When the level last changed, or `None` if it hasn't since startup
+    pub changed: Option<Instant>,
+    /// Switches on or off that were asked for but never made, because they
+    /// came faster than the light's minimum toggle interval
+    pub suppressed: u64,
+}
+
+impl LightState {
+    pub fn is_on(&self) -> bool {
+        self.level > 0
+    }
+}
+
+/// How often a dimmed light is switched, in Hz. Fast enough not to flicker,
+/// slow enough for rppal's software PWM to keep up.
+pub const PWM_FREQUENCY: f64 = 200.0;
+
+/// Where a light is wired
+#[cfg_attr(not(feature = "pi"), allow(dead_code))]
+enum Output {
+    #[cfg(feature = "pi")]
+    Gpio(OutputPin),
+    /// A pin on one of the expanders, by index
+    #[cfg(feature = "pi")]
+    Expander { expander: usize, pin: u8 },
+    /// Not on a Pi, or simulated, so only what the pin would be driven at is
+    /// kept, as in [`pin_duty`]
+    Stub { duty: f64 },
+    /// Its hardware failed to start
+    Disabled,
+}
+
+/// The pins and what was last written to them, shared with the effect tasks
+struct Outputs {
+    pins: Vec<Output>,
+    #[cfg(feature = "pi")]
+    expanders: Vec<Mcp23017>,
+    /// Whether each light can be dimmed, or is on a relay
+    pwm: Vec<bool>,
+    /// Whether each light is wired active low
+    inverted: Vec<bool>,
+    states: Vec<LightState>,
+    /// Bumped whenever a light's effect is replaced or cancelled, so an effect
+    /// on its way out can't write over whatever came after it
+    generations: Vec<u64>,
+    /// Lights can always be turned off, but only turned on while this is set
+    enabled: OutputsEnabled,
+    /// Shortest time between each light switching on or off
+    min_toggle: Vec<Duration>,
+    /// When each light last switched on or off
+    last_toggle: Vec<Option<tokio::time::Instant>>,
+    /// A level held back until the light's toggle interval is up
+    pending: Vec<Option<u8>>,
+    /// Draw the lights in the log whenever one changes
+    simulate: bool,
+}
+
+impl Outputs {
+    /// Write a level straight away, dropping anything held back
+    fn write(&mut self, index: usize, level: u8) -> Result<u8, Error> {
+        self.pending[index] = None;
+        let level = output_level(level, self.pwm[index]);
+        if level > 0 && !self.enabled.is_enabled() {
+            return Err(Error::msg(
+                "Lights can't turn on until the e-stop is re-armed",
+            ));
+        }
+
+        match &mut self.pins[index] {
+            Output::Disabled => {
+                return Err(Error::msg(format!(
+                    "Light {} is disabled, its hardware failed to start",
+                    index + 1
+                )))
+            }
+            Output::Stub { duty } => *duty = pin_duty(level, self.inverted[index]),
+            #[cfg(feature = "pi")]
+            output => drive(
+                output,
+                &mut self.expanders,
+                level,
+                self.pwm[index],
+                self.inverted[index],
+            )?,
+        }
+
+        let state = &mut self.states[index];
+        if state.is_on() != (level > 0) {
+            self.last_toggle[index] = Some(tokio::time::Instant::now());
+        }
+        if state.level != level {
+            state.level = level;
+            state.changed = Some(Instant::now());
+
+            if self.simulate {
+                info!("{}", simulator::render(&self.states));
+            }
+        }
+
+        Ok(level)
+    }
+
+    /// Write a level, holding back a switch on or off that comes too soon
+    /// after the last one. Only the latest held back level is kept, and a
+    /// request back to where the light already is cancels it. Also returns
+    /// when to [`Outputs::flush`] if a new switch was held back.
+    fn request(
+        &mut self,
+        index: usize,
+        level: u8,
+    ) -> Result<(u8, Option<tokio::time::Instant>), Error> {
+        let level = output_level(level, self.pwm[index]);
+        let toggles = self.states[index].is_on() != (level > 0);
+
+        if self.pending[index].is_some() {
+            if toggles {
+                self.pending[index] = Some(level);
+                return Ok((level, None));
+            }
+            // Neither the held back switch nor the one back are made
+            self.states[index].suppressed += 2;
+            self.write(index, level)?;
+            return Ok((level, None));
+        }
+
+        let ready = self.last_toggle[index].map(|last| last + self.min_toggle[index]);
+        match ready {
+            Some(ready) if toggles && tokio::time::Instant::now() < ready => {
+                self.pending[index] = Some(level);
+                Ok((level, Some(ready)))
+            }
+            _ => self.write(index, level).map(|level| (level, None)),
+        }
+    }
+
+    /// Make the switch that was held back, if it's still wanted
+    fn flush(&mut self, index: usize) -> Result<(), Error> {
+        if let Some(level) = self.pending[index] {
+            self.write(index, level)?;
+        }
+        Ok(())
+    }
+}
+
+/// Ask for a level, flushing it later if it's held back
+fn request(outputs: &Arc<Mutex<Outputs>>, index: usize, level: u8) -> Result<u8, Error> {
+    let (level, flush_at) = outputs.lock().unwrap().request(index, level)?;
+    if let Some(flush_at) = flush_at {
+        schedule_flush(outputs, index, flush_at);
+    }
+    Ok(level)
+}
+
+fn schedule_flush(outputs: &Arc<Mutex<Outputs>>, index: usize, at: tokio::time::Instant) {
+    let outputs = outputs.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep_until(at).await;
+        if let Err(e) = outputs.lock().unwrap().flush(index) {
+            error!(
+                "Light {}: couldn't make a held back switch: {}",
+                index + 1,
+                e
+            );
+        }
+    });
+}
+
+/// How a light test went
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LightTestReport {
+    pub configured: usize,
+    /// Lights whose hardware started
+    pub initialized: usize,
+    /// Names of the lights that couldn't be turned on
+    pub failed: Vec<String>,
+}
+
+impl fmt::Display for LightTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} lights initialized",
+            self.initialized, self.configured
+        )?;
+        if !self.failed.is_empty() {
+            write!(f, ", {} failed", self.failed.join(", "))?;
+        }
+        Ok(())
+    }
 }
 
-#[cfg(not(feature = "pi"))]
-#[allow(dead_code)]
 pub struct LightController {
-    pins: Vec<()>,
+    outputs: Arc<Mutex<Outputs>>,
+    /// Each light's ID from the config
+    ids: Vec<u8>,
+    /// Where each light ID is in the outputs
+    indices: HashMap<u8, usize>,
+    /// Each light's name from the config, for logs
+    names: Vec<String>,
+    /// How long the light test holds each step
+    test_step: Duration,
+    /// Running effects by light index
+    effects: HashMap<usize, JoinHandle<()>>,
 }
 
 impl LightController {
@@ -27,58 +244,718 @@ impl LightController {
         config: &Config,
         _message_queue: mpsc::Sender<MessageKind>,
     ) -> Result<Self, Error> {
-        #[allow(unused_mut)]
         let mut pins = Vec::new();
+        let mut pwm = Vec::new();
+        let mut inverted = Vec::new();
+        let mut min_toggle = Vec::new();
+        let stagger = Duration::from_millis(config.light.startup_stagger_ms);
+        let simulated = config.mode.is_simulated();
+        let mut ids = Vec::new();
+        let mut names = Vec::new();
+        #[cfg(feature = "pi")]
+        let mut expanders = Vec::new();
+        // Each expander's index, or `None` if it failed to start
+        #[cfg(feature = "pi")]
+        let mut expander_ids = HashMap::new();
+        // What each expander's latch holds with all of its lights off, so
+        // nothing flashes on while it starts. Unused pins are left high.
+        #[cfg(feature = "pi")]
+        let mut off_latches = HashMap::new();
+        #[cfg(feature = "pi")]
+        for light in &config.lights {
+            if let Pin::I2cExpander { bus, address, pin } = light.pin {
+                let latch = off_latches.entry((bus, address)).or_insert(0xFFFFu16);
+                if !light.inverted() {
+                    *latch &= !(1 << pin);
+                }
+            }
+        }
 
         for (i, light) in config.lights.iter().enumerate() {
-            // Turn this pin into a physical pin
-            let pin = match light.pin {
-                Pin::Physical(pin) => pin.into(),
-                Pin::Gpio(pin) => pin,
-                Pin::WiringPi(pin) => pin.into(),
+            if i > 0 && !stagger.is_zero() {
+                tokio::time::sleep(stagger).await;
+            }
+
+            ids.push(light.id);
+            names.push(light.name());
+
+            let gpio = match light.pin {
+                // Turn this pin into a physical pin
+                Pin::Physical(pin) => Some(pin.into()),
+                Pin::Gpio(pin) => Some(pin),
+                Pin::WiringPi(pin) => Some(pin.into()),
+                Pin::I2cExpander { bus, address, pin } => {
+                    info!(
+                        "Light {}: initializing on pin {} of the expander at {:#x} on bus {}",
+                        i, pin, address, bus
+                    );
+                    None
+                }
             };
 
-            info!("Light {}: initializing on pin {}", i, pin.0);
+            if let Some(pin) = gpio {
+                info!(
+                    "Light {}: initializing on pin {}{}",
+                    i,
+                    pin.0,
+                    if light.pwm { " with PWM" } else { "" }
+                );
+            }
 
-            // Only initialize GPIO if the Pi feature is enabled
+            // Expanders only switch their pins
+            if light.pwm && gpio.is_none() {
+                warn!(
+                    "Light {}: expander pins can't be dimmed, it'll be switched like a relay",
+                    i
+                );
+            }
+            pwm.push(light.pwm && gpio.is_some());
+            inverted.push(light.inverted());
+            min_toggle.push(Duration::from_millis(light.min_toggle_interval_ms));
+
+            // Only initialize GPIO if the Pi feature is enabled, and the
+            // lights aren't simulated
             #[cfg(feature = "pi")]
-            {
-                let mut pin = Gpio::new()?.get(pin.0).unwrap().into_output();
+            if !simulated {
+                let mut output = match (gpio, &light.pin) {
+                    // Start the pin off rather than wherever it was left, so
+                    // the relay doesn't click on before it's turned off
+                    (Some(pin), _) => match Gpio::new()?.get(pin.0) {
+                        Ok(pin) => Output::Gpio(match light.inverted() {
+                            true => pin.into_output_high(),
+                            false => pin.into_output_low(),
+                        }),
+                        // A bad or busy pin only takes out its own light
+                        Err(e) => {
+                            error!(
+                                "Light {}: GPIO {} couldn't be used, it's disabled: {}",
+                                i, pin.0, e
+                            );
+                            Output::Disabled
+                        }
+                    },
+                    (None, &Pin::I2cExpander { bus, address, pin }) => {
+                        // A missing expander only takes out its own lights
+                        let expander = *expander_ids.entry((bus, address)).or_insert_with(|| {
+                            match Mcp23017::new(bus, address, off_latches[&(bus, address)]) {
+                                Ok(expander) => {
+                                    expanders.push(expander);
+                                    Some(expanders.len() - 1)
+                                }
+                                Err(e) => {
+                                    error!(
+                                        "The expander at {:#x} on bus {} failed to start, its lights are disabled: {}",
+                                        address, bus, e
+                                    );
+                                    None
+                                }
+                            }
+                        });
+
+                        match expander {
+                            Some(expander) => Output::Expander { expander, pin },
+                            None => Output::Disabled,
+                        }
+                    }
+                    (None, _) => unreachable!(),
+                };
 
                 // Turn the light off
-                // Note; light values are inverted since the physical lights are inverted
-                pin.set_high();
+                if !matches!(output, Output::Disabled) {
+                    drive(&mut output, &mut expanders, 0, false, light.inverted())?;
+                }
 
                 // Add the pin to the list
-                pins.push(pin);
+                pins.push(output);
+                continue;
             }
+
+            // Keep a placeholder so the lights can still be addressed
+            pins.push(Output::Stub {
+                duty: pin_duty(0, light.inverted()),
+            });
         }
 
-        Ok(Self { pins })
+        Ok(Self {
+            outputs: Arc::new(Mutex::new(Outputs {
+                states: vec![LightState::default(); pins.len()],
+                generations: vec![0; pins.len()],
+                pins,
+                #[cfg(feature = "pi")]
+                expanders,
+                pwm,
+                inverted,
+                enabled: OutputsEnabled::default(),
+                last_toggle: vec![None; min_toggle.len()],
+                pending: vec![None; min_toggle.len()],
+                min_toggle,
+                simulate: config.light.simulate || simulated,
+            })),
+            indices: ids.iter().enumerate().map(|(i, id)| (*id, i)).collect(),
+            ids,
+            names,
+            test_step: Duration::from_millis(config.light.test_step_ms),
+            effects: HashMap::new(),
+        })
+    }
+
+    /// Where a light is in the outputs. Lights are looked up by their ID in
+    /// the config, not where they are in it.
+    fn index(&self, light_id: u8) -> Result<usize, Error> {
+        self.indices.get(&light_id).copied().ok_or_else(|| {
+            Error::msg(format!(
+                "Light {} isn't one of the {} configured lights",
+                light_id,
+                self.ids.len()
+            ))
+        })
     }
 
-    #[allow(dead_code, unused_variables)]
-    pub fn set_pin(&mut self, pin: u8, value: bool) {
-        let pin = pin - 1;
-        info!(
-            "Light {}: setting to {}. Len of pins: {}",
-            pin,
-            value,
-            self.pins.len()
+    /// Share the e-stop's flag
+    pub fn set_outputs_enabled(&mut self, enabled: OutputsEnabled) {
+        self.outputs.lock().unwrap().enabled = enabled;
+    }
+
+    /// Turn a light fully on or off
+    pub fn set_pin(&mut self, pin: u8, value: bool) -> Result<(), Error> {
+        self.set_level(pin, if value { 255 } else { 0 })
+    }
+
+    /// Dim a light, from 0 for off to 255 for fully on. Lights on relays
+    /// are switched on from half way up instead. This stops any effect the
+    /// light was running.
+    pub fn set_level(&mut self, light_id: u8, level: u8) -> Result<(), Error> {
+        let index = self.index(light_id)?;
+        let level = self.set_index(index, level)?;
+        info!("Light {}: setting to {}", light_id, level);
+
+        Ok(())
+    }
+
+    fn set_index(&mut self, index: usize, level: u8) -> Result<u8, Error> {
+        self.cancel_effect(index);
+        request(&self.outputs, index, level)
+    }
+
+    /// Run an effect on a light until it's given a level or another effect
+    pub fn set_effect(&mut self, light_id: u8, effect: LightEffect) -> Result<(), Error> {
+        let index = self.index(light_id)?;
+        self.cancel_effect(index);
+        info!("Light {}: starting {:?}", light_id, effect);
+
+        let generation = self.outputs.lock().unwrap().generations[index];
+        let outputs = self.outputs.clone();
+        let handle = tokio::spawn(async move {
+            let mut rng = StdRng::from_entropy();
+            for step in 0u64.. {
+                let (level, hold) = effect.step(step, &mut rng);
+                if hold.is_zero() {
+                    continue;
+                }
+
+                let requested = {
+                    let mut locked = outputs.lock().unwrap();
+                    if locked.generations[index] != generation {
+                        return;
+                    }
+                    locked.request(index, level)
+                };
+                match requested {
+                    Ok((_, Some(flush_at))) => schedule_flush(&outputs, index, flush_at),
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!("Light {}: stopping the effect: {}", light_id, e);
+                        return;
+                    }
+                }
+
+                tokio::time::sleep(hold).await;
+            }
+        });
+        self.effects.insert(index, handle);
+
+        Ok(())
+    }
+
+    fn cancel_effect(&mut self, index: usize) {
+        if let Some(handle) = self.effects.remove(&index) {
+            self.outputs.lock().unwrap().generations[index] += 1;
+            handle.abort();
+        }
+    }
+
+    /// Stop every effect and turn every light off, carrying on past any that
+    /// fail. This doesn't wait for toggle intervals.
+    pub fn all_off(&mut self) {
+        for index in 0..self.ids.len() {
+            self.cancel_effect(index);
+            if let Err(e) = self.outputs.lock().unwrap().write(index, 0) {
+                error!("Light {}: couldn't turn it off: {}", self.ids[index], e);
+            }
+        }
+    }
+
+    /// Turn each light on by itself for a step, then all of them together,
+    /// then none of them
+    pub async fn run_test(&mut self) -> LightTestReport {
+        info!("Starting the light test");
+        let mut failed = Vec::new();
+
+        for index in 0..self.ids.len() {
+            let name = self.names[index].clone();
+            info!("Light test: {} on", name);
+            if let Err(e) = self.set_index(index, 255) {
+                error!("Light test: {} failed: {}", name, e);
+                failed.push(name);
+                continue;
+            }
+
+            tokio::time::sleep(self.test_step).await;
+            info!("Light test: {} off", name);
+            let _ = self.set_index(index, 0);
+        }
+
+        info!("Light test: all on");
+        for index in 0..self.ids.len() {
+            let _ = self.set_index(index, 255);
+        }
+        tokio::time::sleep(self.test_step).await;
+
+        info!("Light test: all off");
+        self.all_off();
+
+        let initialized = self
+            .outputs
+            .lock()
+            .unwrap()
+            .pins
+            .iter()
+            .filter(|output| !matches!(output, Output::Disabled))
+            .count();
+
+        LightTestReport {
+            configured: self.ids.len(),
+            initialized,
+            failed,
+        }
+    }
+
+    /// Every light's state, in the order they're in the config
+    pub fn snapshot(&self) -> Vec<LightState> {
+        self.outputs.lock().unwrap().states.clone()
+    }
+}
+
+/// Don't leave anything running if the process exits or the task panics
+impl Drop for LightController {
+    fn drop(&mut self) {
+        self.all_off();
+    }
+}
+
+/// Drive a light at a level
+#[cfg(feature = "pi")]
+fn drive(
+    output: &mut Output,
+    expanders: &mut [Mcp23017],
+    level: u8,
+    pwm: bool,
+    inverted: bool,
+) -> Result<(), Error> {
+    match output {
+        Output::Gpio(pin) => match level {
+            0 | 255 => {
+                if pwm {
+                    pin.clear_pwm()?;
+                }
+                if pin_high(level, inverted) {
+                    pin.set_high();
+                } else {
+                    pin.set_low();
+                }
+            }
+            level => pin.set_pwm_frequency(PWM_FREQUENCY, pin_duty(level, inverted))?,
+        },
+        // Expander lights are never PWM, so they're already fully on or off
+        Output::Expander { expander, pin } => {
+            expanders[*expander].set(*pin, pin_high(level, inverted))?
+        }
+        Output::Disabled | Output::Stub { .. } => {}
+    }
+
+    Ok(())
+}
+
+/// How much of the time a light's pin is high at a level, from 0 to 1.
+/// Inverted lights are wired active low, so a high pin is off; this and
+/// [`pin_high`] are the only places that need to know.
+pub fn pin_duty(level: u8, inverted: bool) -> f64 {
+    let duty = level as f64 / 255.0;
+    if inverted {
+        1.0 - duty
+    } else {
+        duty
+    }
+}
+
+/// Whether a light that's fully on or off has its pin high
+#[cfg_attr(not(feature = "pi"), allow(dead_code))]
+fn pin_high(level: u8, inverted: bool) -> bool {
+    (level > 0) != inverted
+}
+
+/// The level a light can actually be driven at. Relays must never be PWMed,
+/// so they only ever get fully on or off.
+pub fn output_level(level: u8, pwm: bool) -> u8 {
+    match (pwm, level) {
+        (true, level) => level,
+        (false, 128..) => 255,
+        (false, _) => 0,
+    }
+}
+
+// The lights are only stubs off the Pi, and these check what they're set to
+#[cfg(all(test, not(feature = "pi")))]
+mod tests {
+    use super::*;
+    use crate::config::{Light, RunMode};
+
+    async fn controller(lights: u8) -> LightController {
+        let config = Config {
+            lights: (1..=lights)
+                .map(|id| Light {
+                    pin: Pin::Gpio(pi_pinout::GpioPin(id)),
+                    id,
+                    name: None,
+                    pwm: false,
+                    inverted: None,
+                    min_toggle_interval_ms: 0,
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let (tx, _rx) = mpsc::channel(1);
+        LightController::init(&config, tx).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_set_pin_bounds() {
+        let mut lights = controller(4).await;
+
+        assert!(lights.set_pin(0, true).is_err());
+        assert!(lights.set_pin(1, true).is_ok());
+        assert!(lights.set_pin(4, true).is_ok());
+        assert!(lights.set_pin(5, true).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lights_by_id() {
+        // Out of order, with IDs that don't match the keys
+        let config = Config::from_json(
+            r#"{
+                "light-1": { "protocol": "GPIO", "id": 3, "pin": 16 },
+                "light-2": { "protocol": "GPIO", "id": 1, "pin": 28 },
+                "light-3": { "protocol": "GPIO", "id": 7, "pin": 27 }
+            }"#,
+        )
+        .unwrap();
+        let (tx, _rx) = mpsc::channel(1);
+        let mut lights = LightController::init(&config, tx).await.unwrap();
+
+        // `light-7` in a show is the light with ID 7, wherever it is
+        lights.set_pin(7, true).unwrap();
+        let on = lights
+            .snapshot()
+            .iter()
+            .zip(&config.lights)
+            .filter(|(state, _)| state.is_on())
+            .map(|(_, light)| light.pin.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(on, vec![Pin::Physical(pi_pinout::PhysicalPin(27))]);
+
+        // Only configured IDs can be used
+        assert!(lights.set_pin(2, true).is_err());
+    }
+
+    #[test]
+    fn test_output_level() {
+        assert_eq!(output_level(102, true), 102);
+        assert_eq!(output_level(0, true), 0);
+
+        assert_eq!(output_level(102, false), 0);
+        assert_eq!(output_level(127, false), 0);
+        assert_eq!(output_level(128, false), 255);
+        assert_eq!(output_level(255, false), 255);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let mut lights = controller(3).await;
+        assert!(lights.snapshot().iter().all(|light| !light.is_on()));
+
+        lights.set_pin(1, true).unwrap();
+        lights.set_level(3, 200).unwrap();
+        // Relays only switch on from half way up
+        lights.set_level(2, 100).unwrap();
+
+        let snapshot = lights.snapshot();
+        assert_eq!(
+            snapshot.iter().map(|light| light.level).collect::<Vec<_>>(),
+            vec![255, 0, 255]
         );
-        // Note; light values are inverted since the physical lights are
-        // inverted
+        assert!(snapshot[0].changed.is_some());
+        assert!(snapshot[1].changed.is_none());
+
+        lights.set_pin(1, false).unwrap();
+        assert!(!lights.snapshot()[0].is_on());
+    }
 
-        // Make sure the pin input is not outside of the range of pins
-        if pin as usize > self.pins.len() {
-            error!("Light {}: pin {} is out of range", pin, pin);
-            return;
+    #[tokio::test]
+    async fn test_off_when_dropped() {
+        let mut lights = controller(2).await;
+        lights.set_pin(1, true).unwrap();
+        lights.set_pin(2, true).unwrap();
+
+        let outputs = lights.outputs.clone();
+        drop(lights);
+
+        assert!(outputs
+            .lock()
+            .unwrap()
+            .states
+            .iter()
+            .all(|light| !light.is_on()));
+    }
+
+    /// Whether the light was on at each of `samples`, `every` apart
+    async fn sample(lights: &LightController, every: u64, samples: usize) -> Vec<bool> {
+        let mut on = Vec::new();
+        for _ in 0..samples {
+            tokio::time::sleep(Duration::from_millis(every)).await;
+            on.push(lights.snapshot()[0].is_on());
         }
+        on
+    }
 
-        #[cfg(feature = "pi")]
-        match value {
-            true => self.pins[pin as usize].set_low(),
-            false => self.pins[pin as usize].set_high(),
+    #[tokio::test(start_paused = true)]
+    async fn test_blink_effect() {
+        let mut lights = controller(1).await;
+        lights
+            .set_effect(
+                1,
+                LightEffect::Blink {
+                    period_ms: 200,
+                    duty: 0.5,
+                },
+            )
+            .unwrap();
+
+        // Sample half way through each half period
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let on = sample(&lights, 100, 10).await;
+        let toggles = on.windows(2).filter(|pair| pair[0] != pair[1]).count();
+        assert_eq!(toggles, 9);
+
+        // A plain command takes the light back
+        lights.set_pin(1, true).unwrap();
+        assert!(sample(&lights, 100, 10).await.iter().all(|on| *on));
+
+        // And turning everything off stops effects too
+        lights
+            .set_effect(
+                1,
+                LightEffect::Blink {
+                    period_ms: 200,
+                    duty: 0.5,
+                },
+            )
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        lights.all_off();
+        assert!(sample(&lights, 100, 10).await.iter().all(|on| !on));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_light_test() {
+        let mut lights = controller(3).await;
+        let started = tokio::time::Instant::now();
+        let report = lights.run_test().await;
+
+        assert_eq!(
+            report,
+            LightTestReport {
+                configured: 3,
+                initialized: 3,
+                failed: Vec::new(),
+            }
+        );
+        assert_eq!(report.to_string(), "3 of 3 lights initialized");
+
+        // One step per light, then one with all of them on
+        assert_eq!(started.elapsed(), Duration::from_millis(4 * 1000));
+        assert!(lights.snapshot().iter().all(|light| !light.is_on()));
+        assert!(lights
+            .snapshot()
+            .iter()
+            .all(|light| light.changed.is_some()));
+    }
+
+    /// What each light's pin would be driven at
+    fn duties(outputs: &Mutex<Outputs>) -> Vec<f64> {
+        outputs
+            .lock()
+            .unwrap()
+            .pins
+            .iter()
+            .map(|output| match output {
+                Output::Stub { duty } => *duty,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_simulated() {
+        let config = Config {
+            lights: vec![Light {
+                pin: Pin::Gpio(pi_pinout::GpioPin(1)),
+                id: 1,
+                name: None,
+                pwm: false,
+                inverted: Some(false),
+                min_toggle_interval_ms: 0,
+            }],
+            mode: RunMode::Simulate,
+            ..Default::default()
+        };
+        let (tx, _rx) = mpsc::channel(1);
+        let mut lights = LightController::init(&config, tx).await.unwrap();
+
+        // Nothing's driven, and each change is drawn
+        lights.set_pin(1, true).unwrap();
+        assert_eq!(duties(&lights.outputs), vec![1.0]);
+        assert!(lights.outputs.lock().unwrap().simulate);
+    }
+
+    #[tokio::test]
+    async fn test_polarity() {
+        let light = |id, pwm, inverted| Light {
+            pin: Pin::Gpio(pi_pinout::GpioPin(id)),
+            id,
+            name: None,
+            pwm,
+            inverted,
+            min_toggle_interval_ms: 0,
+        };
+        let config = Config {
+            lights: vec![
+                light(1, false, None),
+                light(2, false, Some(false)),
+                light(3, true, Some(false)),
+                light(4, true, Some(true)),
+            ],
+            ..Default::default()
+        };
+        let (tx, _rx) = mpsc::channel(1);
+        let mut lights = LightController::init(&config, tx).await.unwrap();
+        let outputs = lights.outputs.clone();
+
+        // Everything starts off, whichever way it's wired
+        assert_eq!(duties(&outputs), vec![1.0, 0.0, 0.0, 1.0]);
+
+        lights.set_pin(1, true).unwrap();
+        lights.set_pin(2, true).unwrap();
+        lights.set_level(3, 102).unwrap();
+        lights.set_level(4, 102).unwrap();
+        assert_eq!(duties(&outputs), vec![0.0, 1.0, 0.4, 0.6]);
+        // The state tracks the level, not the pin
+        assert_eq!(
+            lights
+                .snapshot()
+                .iter()
+                .map(|light| light.level)
+                .collect::<Vec<_>>(),
+            vec![255, 255, 102, 102]
+        );
+
+        drop(lights);
+        assert_eq!(duties(&outputs), vec![1.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_min_toggle_interval() {
+        let config = Config {
+            lights: vec![Light {
+                pin: Pin::Gpio(pi_pinout::GpioPin(1)),
+                id: 1,
+                name: None,
+                pwm: false,
+                inverted: None,
+                min_toggle_interval_ms: 175,
+            }],
+            ..Default::default()
+        };
+        let (tx, _rx) = mpsc::channel(1);
+        let mut lights = LightController::init(&config, tx).await.unwrap();
+
+        // A show toggling every 40ms only gets a switch through every 200ms
+        for i in 0..20 {
+            lights.set_pin(1, i % 2 == 0).unwrap();
+            tokio::time::sleep(Duration::from_millis(40)).await;
         }
+        assert!(!lights.snapshot()[0].is_on());
+        assert_eq!(lights.snapshot()[0].suppressed, 16);
+
+        // The last switch, 200ms ago, was off, so this goes straight through
+        lights.set_pin(1, true).unwrap();
+        assert!(lights.snapshot()[0].is_on());
+
+        // But turning it back off waits out the interval
+        lights.set_pin(1, false).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(lights.snapshot()[0].is_on());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!lights.snapshot()[0].is_on());
+        assert_eq!(lights.snapshot()[0].suppressed, 16);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_startup_stagger() {
+        let mut config = Config {
+            lights: (1..=4)
+                .map(|id| Light {
+                    pin: Pin::Gpio(pi_pinout::GpioPin(id)),
+                    id,
+                    name: None,
+                    pwm: false,
+                    inverted: None,
+                    min_toggle_interval_ms: 0,
+                })
+                .collect(),
+            ..Default::default()
+        };
+        config.light.startup_stagger_ms = 50;
+
+        let started = tokio::time::Instant::now();
+        let (tx, _rx) = mpsc::channel(1);
+        LightController::init(&config, tx).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn test_estop() {
+        let mut lights = controller(2).await;
+        let enabled = OutputsEnabled::default();
+        lights.set_outputs_enabled(enabled.clone());
+        lights.set_pin(1, true).unwrap();
+
+        enabled.disable();
+        assert!(lights.set_pin(2, true).is_err());
+        // Turning things off still works
+        lights.set_pin(1, false).unwrap();
+        assert!(lights.snapshot().iter().all(|light| !light.is_on()));
+
+        enabled.rearm();
+        lights.set_pin(2, true).unwrap();
     }
 }
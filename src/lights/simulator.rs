@@ -0,0 +1,43 @@
+use std::fmt::Write;
+
+use super::LightState;
+
+/// Draw every light on one line, `■` in yellow as bright as the light for
+/// the ones that are on and `□` for the ones that are off
+pub fn render(states: &[LightState]) -> String {
+    let mut line = String::from("Lights ");
+
+    for state in states {
+        if state.is_on() {
+            // Dim lights still need to be visible against a dark terminal
+            let brightness = 55 + state.level as u16 * 200 / 255;
+            let _ = write!(line, "\x1b[38;2;{0};{0};0m■\x1b[0m", brightness);
+        } else {
+            line.push('□');
+        }
+    }
+
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn light(level: u8) -> LightState {
+        LightState {
+            level,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let line = render(&[light(255), light(0), light(102)]);
+
+        assert_eq!(
+            line,
+            "Lights \x1b[38;2;255;255;0m■\x1b[0m□\x1b[38;2;135;135;0m■\x1b[0m"
+        );
+    }
+}
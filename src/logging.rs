@@ -0,0 +1,230 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use anyhow::Error;
+use chrono::Local;
+use env_logger::{Builder, Target};
+use log::{LevelFilter, Record};
+use serde_json::{json, Value};
+
+use crate::config::{LogFileConfig, LogFormat, LoggingConfig};
+
+const LOG_FILE: &str = "rusty-halloween.log";
+
+/// Set up the global logger. Only `main` should call this, since there can
+/// only be one and it fails if anything else got there first.
+pub fn init(config: &LoggingConfig) -> Result<(), Error> {
+    let mut builder = builder(config);
+    if let Some(file) = &config.file {
+        builder.target(Target::Pipe(Box::new(Tee {
+            file: RotatingFile::open(file)?,
+        })));
+    }
+
+    builder
+        .try_init()
+        .map_err(|_| Error::msg("Logging was already set up by something else"))
+}
+
+/// The levels and format from the config, writing to stderr
+fn builder(config: &LoggingConfig) -> Builder {
+    // The config is checked when it's loaded
+    let level = |level: &str| level.parse().unwrap_or(LevelFilter::Info);
+
+    let mut builder = Builder::new();
+    builder.filter(None, level(&config.level));
+    for (module, module_level) in &config.levels {
+        builder.filter(Some(module), level(module_level));
+    }
+
+    match config.format {
+        LogFormat::Plain => builder.format(|buf, record| {
+            writeln!(
+                buf,
+                "{} [{}] ({}:{}) - {}",
+                Local::now().format("%Y-%m-%dT%H:%M:%S"),
+                record.level(),
+                record.file().unwrap_or("unknown"),
+                record.line().unwrap_or(0),
+                record.args()
+            )
+        }),
+        LogFormat::Json => builder.format(|buf, record| writeln!(buf, "{}", json_line(record))),
+    };
+
+    builder
+}
+
+/// One message as a JSON object, for log servers to pick apart
+fn json_line(record: &Record) -> Value {
+    json!({
+        "time": Local::now().to_rfc3339(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "file": record.file(),
+        "line": record.line(),
+        "message": record.args().to_string(),
+    })
+}
+
+/// Writes everything to stderr as well as the log files
+struct Tee {
+    file: RotatingFile,
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write_all(buf)?;
+        self.file.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()?;
+        self.file.flush()
+    }
+}
+
+/// Appends to the log file until the next write would take it past
+/// `max_bytes`, then moves each file along one and starts a new one
+struct RotatingFile {
+    config: LogFileConfig,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(config: &LogFileConfig) -> Result<Self, Error> {
+        fs::create_dir_all(&config.dir).map_err(|e| {
+            Error::msg(format!(
+                "Couldn't make the log folder {}: {}",
+                config.dir.display(),
+                e
+            ))
+        })?;
+
+        let file = open_append(&config.dir.join(LOG_FILE))?;
+        Ok(RotatingFile {
+            config: config.clone(),
+            size: file.metadata()?.len(),
+            file,
+        })
+    }
+
+    /// The current file for 0, then the full ones from newest to oldest
+    fn path(&self, n: usize) -> PathBuf {
+        match n {
+            0 => self.config.dir.join(LOG_FILE),
+            n => self.config.dir.join(format!("{}.{}", LOG_FILE, n)),
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        // Oldest first, so nothing is moved onto a file that's still needed
+        let _ = fs::remove_file(self.path(self.config.keep));
+        for n in (0..self.config.keep).rev() {
+            if self.path(n).exists() {
+                fs::rename(self.path(n), self.path(n + 1))?;
+            }
+        }
+
+        self.file = open_append(&self.path(0))?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.config.max_bytes {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use log::{Level, Log, Metadata};
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_levels() {
+        let mut config = LoggingConfig::default();
+        config
+            .levels
+            .insert("rusty_halloween::uart".to_string(), "debug".to_string());
+        let logger = builder(&config).build();
+
+        let enabled = |target: &str, level: Level| {
+            logger.enabled(&Metadata::builder().target(target).level(level).build())
+        };
+        assert!(enabled("rusty_halloween::show", Level::Info));
+        assert!(!enabled("rusty_halloween::show", Level::Debug));
+        assert!(enabled("rusty_halloween::uart", Level::Debug));
+        assert!(!enabled("symphonia_core::probe", Level::Error));
+    }
+
+    #[test]
+    fn test_json_line() {
+        let line = json_line(
+            &Record::builder()
+                .args(format_args!("Homing the projector"))
+                .level(Level::Warn)
+                .target("rusty_halloween::show")
+                .file(Some("src/show/show_manager.rs"))
+                .line(Some(12))
+                .build(),
+        );
+
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "rusty_halloween::show");
+        assert_eq!(line["file"], "src/show/show_manager.rs");
+        assert_eq!(line["line"], 12);
+        assert_eq!(line["message"], "Homing the projector");
+        assert!(line["time"].is_string());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = TempDir::new("logging");
+        let mut file = RotatingFile::open(&LogFileConfig {
+            dir: dir.to_path_buf(),
+            max_bytes: 16,
+            keep: 2,
+        })
+        .unwrap();
+
+        for line in [
+            "first line\n",
+            "second line\n",
+            "third line\n",
+            "fourth line\n",
+        ] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        // The oldest was deleted once there were more than two full files
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read(LOG_FILE), "fourth line\n");
+        assert_eq!(read("rusty-halloween.log.1"), "third line\n");
+        assert_eq!(read("rusty-halloween.log.2"), "second line\n");
+        assert!(!dir.join("rusty-halloween.log.3").exists());
+    }
+}
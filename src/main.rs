@@ -1,40 +1,77 @@
 use anyhow::Error;
-use chrono::Local;
-use env_logger::Builder;
-use log::{info, LevelFilter};
+use clap::Parser;
+use log::{error, info, warn};
 use rusty_halloween::{
     audio::Audio,
-    config::Config,
-    dmx::{DmxMessage, DmxState},
-    laser::{LaserController, LaserMessage},
+    cli::{self, Cli, Command},
+    config::{Config, LoggingConfig, Subsystem},
+    console,
+    dmx::{DmxHandle, DmxMessage, DmxState},
+    health::{self, HealthState, Heartbeats, NowPlaying},
+    inputs,
+    laser::{HomeStatus, LaserController, LaserMessage, ALL_LASERS},
     lights::LightController,
-    show::prelude::{ShowChoice, ShowElement, ShowManager},
-    uart::UartController,
-    AudioMessage, InternalMessage, MessageKind,
+    logging,
+    metrics::Metrics,
+    receiver::{Disabled, FrameRouter, Outlet, Receiver},
+    recorder::{self, MessageCopies, RecordedEntry, RecorderHandle},
+    safety::OutputsEnabled,
+    schedule::Scheduler,
+    show::prelude::{watch_shows, ShowChoice, ShowControl, ShowElement, ShowManager, WATCH_SETTLE},
+    shutdown::{Shutdown, Stage},
+    state::{ControllerState, StateStore, STATE_FILE},
+    structure::FileStructure,
+    supervisor::{Supervisor, TaskSender},
+    turret::TurretController,
+    uart::{UartController, UartEvent, UART_RESTART_MAX, UART_RESTART_MIN},
+    MessageKind,
+};
+use std::time::Instant;
+use tokio::{
+    net::TcpListener,
+    signal,
+    sync::{broadcast, mpsc, watch},
+    task::JoinHandle,
 };
-use std::io::Write;
-use tokio::{signal, sync::mpsc};
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    // Start logging
-    Builder::new()
-        .format(|buf, record| {
-            writeln!(
-                buf,
-                "{} [{}] ({}:{}) - {}",
-                Local::now().format("%Y-%m-%dT%H:%M:%S"),
-                record.level(),
-                record.file().unwrap_or("unknown"),
-                record.line().unwrap_or(0),
-                // record.module_path().unwrap_or("unknown"),
-                record.args()
-            )
-        })
-        .filter(None, LevelFilter::Info)
-        .filter(Some("symphonia_core::probe"), LevelFilter::Off)
-        .filter(Some("symphonia_bundle_mp3::demuxer"), LevelFilter::Off)
-        .init();
+    let cli = Cli::parse();
+
+    // Start logging, as the config says for the subcommands that read one
+    let logging = match cli.config() {
+        Some(config) => {
+            Config::load_logging(&config.config.to_string_lossy(), config.profile.as_deref())?
+        }
+        None => LoggingConfig::default(),
+    };
+    logging::init(&logging)?;
+
+    if cli.setup {
+        return cli::setup(&cli.run.config);
+    }
+
+    let (run, mode) = match cli.command {
+        None => (cli.run, Mode::Shows),
+        Some(Command::Run(run)) => (run, Mode::Shows),
+        Some(Command::Play { show, run }) => (run, Mode::Play(show)),
+        Some(Command::Replay { path }) => (cli.run, Mode::Replay(recorder::read_recording(&path)?)),
+        Some(Command::LightTest { config }) => return cli::light_test(&config.load()?).await,
+        Some(Command::LaserTest {
+            laser_id,
+            boundary,
+            config,
+        }) => {
+            let config = config.load()?;
+            return match boundary {
+                true => cli::boundary_check(&config, laser_id).await,
+                false => cli::laser_test(&config, laser_id).await,
+            };
+        }
+        Some(command) => return command.run(),
+    };
+
+    let started = tokio::time::Instant::now();
 
     info!("Starting Tokio console...");
     #[cfg(not(feature = "pi"))]
@@ -42,172 +79,520 @@ async fn main() -> Result<(), Error> {
 
     // Load the config file
     info!("Loading config...");
-    let config = Config::load_from_json("src/show/assets/2024/hardware.json")?;
-
-    // // Set up the local audio storage
-    // info!("Starting audio system...");
-    // FileStructure::verify();
+    let config = run.load_config()?;
+
+    // Make the folders that are missing, and make sure there's room in them.
+    // Problems are only warned about, since the shows can still play.
+    info!("Checking folders...");
+    let structure = FileStructure::from_config(&config, &run.config.config);
+    let report = structure.verify();
+    match report.is_ok() {
+        true => info!("Folders are ready:\n{}", report),
+        false => warn!("Some folders have problems:\n{}", report),
+    }
 
     // Message queue
-    let (message_queue_tx, mut message_queue_rx) = mpsc::channel(100);
+    let (message_queue_tx, message_queue_rx) = mpsc::channel(100);
+
+    // The show manager's laser and DMX messages, kept off the message queue so
+    // a show's frames never hold up anything else. Roomy enough for a few
+    // seconds of frames.
+    let (frames_tx, frames_rx) = mpsc::channel(1000);
+
+    // Nothing is copied to the recorder unless it's turned on
+    let recorder = config.recording.enabled.then(|| {
+        info!("Recording to {}...", config.recording.dir.display());
+        RecorderHandle::spawn(&config.recording)
+    });
+
+    // Every message is copied here for anything watching
+    let copies = MessageCopies::default();
+
+    // Shared by everything that can turn on, so the e-stop reaches all of it
+    let outputs_enabled = OutputsEnabled::default();
+
+    // Every task is stopped through this on the way out
+    let mut shutdown = Shutdown::default();
+
+    // The tasks the health checks look for
+    let mut heartbeats = Heartbeats::default();
+
+    // Counted into by each controller, served on `/metrics`
+    let metrics = Metrics::new();
+
+    // Where the last run left off, kept up to date by everything with a part
+    // of it and saved every so often on top
+    let state_path = config.stats.data_dir.join(STATE_FILE);
+    let restored = ControllerState::load(&state_path);
+    let saved_state = StateStore::new(state_path, restored.clone().unwrap_or_default());
+    let state_saver = tokio::spawn(saved_state.clone().run(shutdown.token(Stage::Shows)));
+    shutdown.track(Stage::Shows, "state", state_saver);
+
+    // Anything turned off isn't started, and what's sent to it is dropped.
+    // Everything below that can be turned off goes by this.
+    let subsystems = config.subsystems();
+    let disabled = Subsystem::ALL
+        .into_iter()
+        .filter(|subsystem| !subsystems.contains(subsystem))
+        .map(Subsystem::name)
+        .collect::<Vec<_>>();
+    if !disabled.is_empty() {
+        info!("Not starting {}", disabled.join(", "));
+    }
 
     // Initialize the lights
-    let mut light_controller = {
-        info!("Starting lights...");
-        let tx_clone = message_queue_tx.clone();
-        #[allow(unused_variables, unused_mut)]
-        LightController::init(&config, tx_clone).await?
+    let light_controller = match subsystems.contains(&Subsystem::Lights) {
+        true => {
+            info!("Starting lights...");
+            let tx_clone = message_queue_tx.clone();
+            let mut light_controller = LightController::init(&config, tx_clone).await?;
+            light_controller.set_outputs_enabled(outputs_enabled.clone());
+            Some(light_controller)
+        }
+        false => None,
     };
 
-    // Initialize UART controller
-    let uart_tx = {
+    // Initialize UART controller. Its events outlive it so that subscribers
+    // keep hearing from the Picos across restarts.
+    let (uart_events, _) = broadcast::channel(64);
+    let uart_heartbeat = heartbeats.add("UART");
+    let (uart_tx, uart_handle) = {
         let (uart_tx, uart_rx) = mpsc::channel(100);
-        let uart_controller = UartController::init().await.unwrap();
-        tokio::spawn(async move {
-            uart_controller.start(uart_rx).await;
+        let mut uart_controller = UartController::init(&config.uart, config.mode).await;
+        uart_controller.events = uart_events.clone();
+        uart_controller.heartbeat = uart_heartbeat.clone();
+        uart_controller.metrics = metrics.clone();
+        let cancel = shutdown.token(Stage::Uart);
+        let uart_handle = tokio::spawn(async move {
+            uart_controller.start(uart_rx, cancel).await;
         });
 
-        uart_tx
+        (uart_tx, uart_handle)
     };
+    // The UART task is replaced when it restarts, so anything started later
+    // takes whichever one's running
+    let (uart_watch_tx, uart_watch_rx) = watch::channel(uart_tx);
+
+    // The lasers, DMX, turrets and show worker are started again if they
+    // fall over
+    let supervisor = Supervisor::default();
+
+    // Homing reports go on to the shows, which wait on them after homing.
+    // Each start of the show worker takes a fresh channel.
+    let (home_status_senders, home_status_tx) = watch::channel(mpsc::channel(16).0);
+    let mut laser_status_rx = uart_events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match laser_status_rx.recv().await {
+                Ok(UartEvent::LaserStatus(payload)) => {
+                    info!("Laser status: {:02X?}", payload);
+                    if let Some(status) = HomeStatus::from_status(&payload) {
+                        // Nobody waits on reports while the shows aren't homing
+                        let _ = home_status_tx.borrow().try_send(status);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Missed {} laser statuses", missed)
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
 
     // Initialize the projector
-    info!("Starting laser...");
     let tx_clone = message_queue_tx.clone();
-    let (laser_tx, laser_rx) = mpsc::channel(100);
-    let mut laser_controller = LaserController::init();
-    let uart_tx_clone = uart_tx.clone();
-    tokio::spawn(async move {
-        laser_controller.start(laser_rx, uart_tx_clone).await;
-    });
+    let laser_tx = match subsystems.contains(&Subsystem::Lasers) {
+        true => {
+            info!("Starting laser...");
+            let (config, outputs_enabled) = (config.clone(), outputs_enabled.clone());
+            let (heartbeat, uart) = (heartbeats.add("lasers"), uart_watch_rx.clone());
+            let metrics = metrics.clone();
+            let cancel = shutdown.token(Stage::Outputs);
+            let (laser_tx, lasers) =
+                supervisor.spawn("lasers", 100, cancel, move |laser_rx, cancel| {
+                    let mut laser_controller = LaserController::init(&config);
+                    laser_controller.outputs_enabled = outputs_enabled.clone();
+                    laser_controller.heartbeat = heartbeat.clone();
+                    laser_controller.metrics = metrics.clone();
+                    let uart_tx = uart.borrow().clone();
+                    async move {
+                        laser_controller.start(laser_rx, uart_tx, cancel).await;
+                    }
+                });
+            shutdown.track(Stage::Outputs, "lasers", lasers);
+            laser_tx
+        }
+        false => TaskSender::closed(),
+    };
 
     // Initialize the audio
-    info!("Starting audio...");
     #[cfg(feature = "audio")]
-    let audio_tx = {
-        let (audio_tx, audio_rx) = mpsc::channel(100);
-        let audio_controller = Audio::new()?;
-        tokio::spawn(async move {
-            audio_controller.start(audio_rx).await;
-        });
-        audio_tx
+    let audio_tx = match subsystems.contains(&Subsystem::Audio) {
+        true => {
+            info!("Starting audio...");
+            let (audio_tx, audio_rx) = mpsc::channel(100);
+            let mut audio_controller = Audio::new()?;
+            audio_controller.metrics = metrics.clone();
+            let (tx_clone, cancel) = (message_queue_tx.clone(), shutdown.token(Stage::Outputs));
+            let audio = tokio::spawn(async move {
+                audio_controller.start(audio_rx, tx_clone, cancel).await;
+            });
+            shutdown.track(Stage::Outputs, "audio", audio);
+            Some(audio_tx)
+        }
+        false => None,
     };
+    #[cfg(not(feature = "audio"))]
+    let audio_tx = None;
 
     // Initialize DMX
-    info!("Starting DMX...");
-    let (dmx_tx, dmx_rx) = mpsc::channel(100);
-    let dmx_state = DmxState::init(config.clone());
-    let uart_tx_clone = uart_tx.clone();
-    tokio::spawn(async move {
-        dmx_state.start(dmx_rx, uart_tx_clone).await;
-    });
-
-    let handle = tokio::spawn(async move {
-        info!("Starting the reciever thread");
-
-        while let Some(message) = message_queue_rx.recv().await {
-            // TODO: Catch errors to not crash the thread
-
-            // Handle the message
-            match message {
-                MessageKind::InternalMessage(internal_message) => match internal_message {
-                    InternalMessage::Audio {
-                        audio_file_contents,
-                    } => {
-                        if cfg!(feature = "audio") {
-                            audio_tx
-                                .send(AudioMessage::Play(audio_file_contents))
-                                .await
-                                .unwrap();
-                        }
-                    }
-                    InternalMessage::AudioStop => {
-                        if cfg!(feature = "audio") {
-                            audio_tx.send(AudioMessage::Stop).await.unwrap();
-                        }
-                    }
-                    InternalMessage::Light {
-                        light_id: _light_id,
-                        enable: _enable,
-                    } => {
-                        info!("Light command received");
-                        #[cfg(feature = "pi")]
-                        light_controller.set_pin(_light_id, _enable);
-                    }
-                    #[allow(unused_variables)]
-                    InternalMessage::Laser(frame_send_pack) => {
-                        info!("Projector command received");
-                        {
-                            laser_tx
-                                .send(LaserMessage::Frame(frame_send_pack))
-                                .await
-                                .unwrap();
-                        }
-                    }
-                    InternalMessage::DmxUpdateState(dmx_state_var_positions) => {
-                        info!("DMX data received");
-                        dmx_tx
-                            .send(DmxMessage::UpdateState(dmx_state_var_positions))
-                            .await
-                            .unwrap();
-                    }
-                    InternalMessage::DmxSendRequest | InternalMessage::DmxZeroOut => {
-                        info!("DMX request received");
-                        dmx_tx.send(DmxMessage::Send).await.unwrap();
+    let dmx_tx = match subsystems.contains(&Subsystem::Dmx) {
+        true => {
+            info!("Starting DMX...");
+            let (config, outputs_enabled) = (config.clone(), outputs_enabled.clone());
+            let (heartbeat, uart) = (heartbeats.add("DMX"), uart_watch_rx.clone());
+            let metrics = metrics.clone();
+            let saved_state = saved_state.clone();
+            let cancel = shutdown.token(Stage::Outputs);
+            let (dmx_tx, dmx) = supervisor.spawn("DMX", 100, cancel, move |dmx_rx, cancel| {
+                let mut dmx_state = DmxState::init(config.clone());
+                dmx_state.outputs_enabled = outputs_enabled.clone();
+                dmx_state.heartbeat = heartbeat.clone();
+                dmx_state.metrics = metrics.clone();
+                // Overrides are held across restarts, of the task or the
+                // controller
+                dmx_state.resume(saved_state.clone());
+                let uart_tx = uart.borrow().clone();
+                dmx_state.start(dmx_rx, uart_tx, cancel)
+            });
+            shutdown.track(Stage::Outputs, "DMX", dmx);
+            dmx_tx
+        }
+        false => TaskSender::closed(),
+    };
+    let dmx_shutdown_tx = subsystems.contains(&Subsystem::Dmx).then(|| dmx_tx.clone());
+
+    // Initialize the turrets. They're only checked here, restarts that can't
+    // set them up again just stop.
+    info!("Starting turrets...");
+    let (turret_tx, turrets) = {
+        let mut first = Some(TurretController::init(&config)?);
+        let (config, outputs_enabled) = (config.clone(), outputs_enabled.clone());
+        let cancel = shutdown.token(Stage::Outputs);
+        supervisor.spawn("turrets", 100, cancel, move |turret_rx, cancel| {
+            let turret_controller = first
+                .take()
+                .map(Ok)
+                .unwrap_or_else(|| TurretController::init(&config));
+            let outputs_enabled = outputs_enabled.clone();
+            async move {
+                match turret_controller {
+                    Ok(mut turret_controller) => {
+                        turret_controller.outputs_enabled = outputs_enabled;
+                        turret_controller.start(turret_rx, cancel).await;
                     }
-                },
+                    Err(e) => error!("Couldn't set the turrets up again: {}", e),
+                }
+            }
+        })
+    };
+    shutdown.track(Stage::Outputs, "turrets", turrets);
+
+    if config.schedule.enabled {
+        let mut scheduler = Scheduler::new(&config.schedule)?;
+        scheduler.resume(saved_state.clone());
+        let schedule =
+            tokio::spawn(scheduler.run(message_queue_tx.clone(), shutdown.token(Stage::Shows)));
+        shutdown.track(Stage::Shows, "schedule", schedule);
+    }
+
+    // Shows copied in while running are picked up without a restart
+    if let Err(e) = watch_shows(&config.shows_dir, WATCH_SETTLE, message_queue_tx.clone()) {
+        warn!(
+            "Couldn't watch {} for new shows: {}",
+            config.shows_dir.display(),
+            e
+        );
+    }
+
+    if config.console.enabled {
+        let (console_config, tx_clone) = (config.console.clone(), message_queue_tx.clone());
+        let cancel = shutdown.token(Stage::Shows);
+        let console = tokio::spawn(async move {
+            if let Err(e) = console::run(&console_config, tx_clone, cancel).await {
+                error!("The console stopped: {}", e);
+            }
+        });
+        shutdown.track(Stage::Shows, "console", console);
+    }
+
+    if !config.inputs.is_empty() {
+        let (inputs, tx_clone) = (config.inputs.clone(), message_queue_tx.clone());
+        let cancel = shutdown.token(Stage::Shows);
+        let watcher = tokio::spawn(async move {
+            if let Err(e) = inputs::run(inputs, tx_clone, cancel).await {
+                error!("Couldn't watch the inputs: {}", e);
             }
+        });
+        shutdown.track(Stage::Shows, "inputs", watcher);
+    }
+
+    // Watch the e-stop once everything it turns off is running
+    if let Some(pin) = config.safety.estop_pin {
+        #[cfg(feature = "pi")]
+        {
+            let (outputs_enabled, tx_clone) = (outputs_enabled.clone(), message_queue_tx.clone());
+            tokio::spawn(async move {
+                if let Err(e) =
+                    rusty_halloween::safety::watch_estop(pin, outputs_enabled, tx_clone).await
+                {
+                    error!("Couldn't watch the e-stop: {}", e);
+                }
+            });
         }
-    });
-
-    // Get the shows on disk
-    info!("Starting shows...");
-    let tx_clone = message_queue_tx.clone();
-    let shows = ShowManager::load_shows(tx_clone, &config);
-
-    // Start playing the first show
-    let tx_clone = message_queue_tx.clone();
-    let manager = ShowManager::new(shows, tx_clone);
-
-    let (show_worker_channel_tx, show_worker_channel_rx) = mpsc::channel(100);
-
-    info!("Starting show worker...");
+        #[cfg(not(feature = "pi"))]
+        warn!("The e-stop on pin {} only works on the Pi", pin);
+    }
+
+    // Restart the UART if its task stops, and point the lasers, DMX and
+    // health checks at the new one. The lasers and DMX drop frames in the
+    // meantime.
+    let (laser_reconnect_tx, dmx_reconnect_tx) = (laser_tx.clone(), dmx_tx.clone());
+    let (uart_config, run_mode) = (config.uart.clone(), config.mode);
+    let uart_metrics = metrics.clone();
+    let cancel = shutdown.token(Stage::Uart);
+    let uart = tokio::spawn(async move {
+        let mut uart_handle = uart_handle;
+        let mut backoff = UART_RESTART_MIN;
+        loop {
+            let started = Instant::now();
+            let result = uart_handle.await;
+            // It's meant to stop on the way out
+            if cancel.is_cancelled() {
+                break;
+            }
+            match result {
+                Ok(()) => error!("The UART task stopped"),
+                Err(e) => error!("The UART task stopped: {}", e),
+            }
 
-    let worker_handle = tokio::spawn(async move {
-        manager.start_show_worker(show_worker_channel_rx).await;
-    });
+            // Only keep backing off if it keeps falling over
+            if started.elapsed() > UART_RESTART_MAX {
+                backoff = UART_RESTART_MIN;
+            }
 
-    info!("Starting queue worker...");
-
-    let queue_handle = tokio::spawn(async move {
-        // Send startup command
-        show_worker_channel_tx
-            .send(vec![ShowElement::Idle { time: 5 }])
-            .await
-            .unwrap();
-
-        // Send first show
-        show_worker_channel_tx
-            .send(vec![
-                // ShowElement::LightTest,
-                ShowElement::RunInit,
-                ShowElement::Home,
-                ShowElement::PrepareShow(ShowChoice::Random { last_song: None }),
-            ])
-            .await
-            .unwrap();
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = cancel.cancelled() => break,
+            }
+            backoff = (backoff * 2).min(UART_RESTART_MAX);
+
+            info!("Restarting the UART");
+            let mut uart_controller = UartController::init(&uart_config, run_mode).await;
+            uart_controller.events = uart_events.clone();
+            uart_controller.heartbeat = uart_heartbeat.clone();
+            uart_controller.metrics = uart_metrics.clone();
+            let (uart_tx, uart_rx) = mpsc::channel(100);
+            uart_watch_tx.send_replace(uart_tx.clone());
+            let _ = laser_reconnect_tx
+                .send(LaserMessage::Reconnect(uart_tx.clone()))
+                .await;
+            let _ = dmx_reconnect_tx.send(DmxMessage::Reconnect(uart_tx)).await;
+            let cancel = cancel.clone();
+            uart_handle = tokio::spawn(async move {
+                uart_controller.start(uart_rx, cancel).await;
+            });
+        }
     });
+    shutdown.track(Stage::Uart, "UART", uart);
+
+    // For asking the lasers and DMX how they're doing
+    let (query_laser_tx, dmx_handle) = (laser_tx.clone(), DmxHandle::new(dmx_tx.clone()));
+    let restarts = supervisor.restarts.clone();
+
+    let mut frames = FrameRouter::new(laser_tx.clone(), dmx_tx.clone(), recorder.clone());
+    frames.copies = copies.clone();
+    frames.disable(&config);
+    let frames = tokio::spawn(frames.run(frames_rx, shutdown.token(Stage::Frames)));
+    shutdown.track(Stage::Frames, "frames", frames);
+
+    // Every start of the show worker shares these, so the health checks and
+    // the dashboard follow it across restarts
+    let now_playing = NowPlaying::default();
+    // A replay plays without the show worker
+    let show_heartbeat = (!matches!(mode, Mode::Replay(_))).then(|| heartbeats.add("show worker"));
+    let mut new_manager = {
+        let (config, message_queue_tx) = (config.clone(), message_queue_tx.clone());
+        let (metrics, now_playing) = (metrics.clone(), now_playing.clone());
+        move |controls| {
+            let (home_status_tx, home_status_rx) = mpsc::channel(16);
+            home_status_senders.send_replace(home_status_tx);
+            let mut manager = show_manager(
+                &config,
+                &message_queue_tx,
+                frames_tx.clone(),
+                controls,
+                home_status_rx,
+            );
+            if let Some(heartbeat) = &show_heartbeat {
+                manager.heartbeat = heartbeat.clone();
+            }
+            manager.metrics = metrics.clone();
+            manager.now_playing = now_playing.clone();
+            manager
+        }
+    };
 
-    info!("Joining...");
-
-    // let _ = tokio::join!(handle, worker_handle, queue_handle);
-
-    match signal::ctrl_c().await {
-        Ok(()) => {}
-        Err(err) => {
-            eprintln!("Unable to listen for shutdown signal: {}", err);
-            // we also shut down in case of error
+    // Controls for the show worker, which follow it across restarts
+    let (show_control_tx, play) = match &mode {
+        Mode::Shows => {
+            let show_control_tx = start_shows(
+                &config,
+                new_manager,
+                restored,
+                saved_state.clone(),
+                &supervisor,
+                &mut shutdown,
+            );
+            (show_control_tx, None)
+        }
+        // Playing one show doesn't change where the shows carry on from, and
+        // isn't restarted once it's played
+        Mode::Play(_) => {
+            let (show_control_tx, show_control_rx) = mpsc::channel(10);
+            let manager = new_manager(show_control_rx);
+            (show_control_tx.into(), Some(manager))
         }
+        Mode::Replay(_) => (TaskSender::closed(), None),
+    };
+    let health_show_controls = show_control_tx.clone();
+
+    let mut receiver_frames = FrameRouter::new(laser_tx, dmx_tx, None);
+    receiver_frames.disable(&config);
+    let receiver = Receiver {
+        lights: light_controller,
+        lights_disabled: Disabled::new("lights"),
+        audio: match subsystems.contains(&Subsystem::Audio) {
+            true => audio_tx.map(|tx| Outlet::new("audio", tx)),
+            false => Some(Outlet::disabled("audio")),
+        },
+        frames: receiver_frames,
+        turrets: Outlet::new("turrets", turret_tx),
+        show_controls: match mode {
+            Mode::Replay(_) => Outlet::disabled("shows"),
+            _ => Outlet::new("shows", show_control_tx),
+        },
+        outputs_enabled,
+        recorder,
+        copies: copies.clone(),
     };
+    let receiver = tokio::spawn(receiver.run(message_queue_rx, shutdown.token(Stage::Receiver)));
+    shutdown.track(Stage::Receiver, "receiver", receiver);
+
+    if let Some(health) = &config.health {
+        let listener = TcpListener::bind(health.bind).await.map_err(|e| {
+            Error::msg(format!(
+                "Couldn't listen for health checks on {}: {}",
+                health.bind, e
+            ))
+        })?;
+        info!("Answering health checks on {}...", health.bind);
+
+        let state = HealthState {
+            heartbeats,
+            stale_after: std::time::Duration::from_millis(health.stale_ms),
+            now_playing: now_playing.clone(),
+            lasers: query_laser_tx.clone(),
+            dmx: dmx_handle.clone(),
+            uart: uart_watch_rx,
+            show_controls: health_show_controls,
+            metrics: health.metrics.then_some(metrics),
+            structure,
+            restarts,
+            started,
+        };
+        let server = tokio::spawn(health::serve(listener, state, shutdown.token(Stage::Shows)));
+        shutdown.track(Stage::Shows, "health server", server);
+    }
+
+    if let Some(dashboard) = config
+        .dashboard
+        .as_ref()
+        .filter(|_| subsystems.contains(&Subsystem::Dashboard))
+    {
+        #[cfg(feature = "dashboard")]
+        {
+            let listener = TcpListener::bind(dashboard.bind).await.map_err(|e| {
+                Error::msg(format!(
+                    "Couldn't serve the dashboard on {}: {}",
+                    dashboard.bind, e
+                ))
+            })?;
+            info!("Serving the dashboard on http://{}...", dashboard.bind);
+
+            let server = rusty_halloween::dashboard::Dashboard::new(
+                message_queue_tx.clone(),
+                query_laser_tx,
+                dmx_handle,
+                now_playing,
+                config.stats.clone(),
+            )
+            .serve(listener, copies.subscribe(), shutdown.token(Stage::Shows));
+            shutdown.track(Stage::Shows, "dashboard", tokio::spawn(server));
+        }
+        #[cfg(not(feature = "dashboard"))]
+        warn!(
+            "The dashboard on {} needs a build with the dashboard feature",
+            dashboard.bind
+        );
+    }
+    match mode {
+        // Bench testing a recording stands in for the shows
+        Mode::Replay(recording) => {
+            info!("Replaying {} recorded messages...", recording.len());
+            tokio::select! {
+                result = recorder::replay(recording, message_queue_tx.clone()) => match result {
+                    Ok(()) => info!("Replay finished"),
+                    Err(e) => error!("Replay failed: {}", e),
+                },
+                _ = signal::ctrl_c() => info!("Replay stopped"),
+            }
+        }
+        Mode::Play(name) => {
+            let manager = play.expect("a manager is made to play a show");
+            let mut worker = play_show(manager, &name, &shutdown).await?;
+            tokio::select! {
+                _ = &mut worker => info!("Finished playing {}", name),
+                _ = signal::ctrl_c() => info!("Stopped playing {}", name),
+            }
+            shutdown.track(Stage::Shows, "show worker", worker);
+        }
+        Mode::Shows => {
+            info!("Joining...");
+
+            // let _ = tokio::join!(handle, worker_handle, queue_handle);
+
+            match signal::ctrl_c().await {
+                Ok(()) => {}
+                Err(err) => {
+                    eprintln!("Unable to listen for shutdown signal: {}", err);
+                    // we also shut down in case of error
+                }
+            };
+        }
+    }
+
+    // Relays and fixtures stay wherever the last frame left them otherwise
+    info!("Shutting down...");
+    if let Err(e) = shutdown
+        .run(
+            &message_queue_tx,
+            dmx_shutdown_tx.map(|tx| tx.current()).as_ref(),
+        )
+        .await
+    {
+        error!("Shutdown wasn't clean: {}", e);
+    }
 
     // let _tx_clone = message_queue_tx.clone();
 
@@ -236,3 +621,143 @@ async fn main() -> Result<(), Error> {
 
     Ok(())
 }
+
+/// What the controller does once it's running
+enum Mode {
+    /// Play shows until it's stopped
+    Shows,
+    /// Play one show, then stop
+    Play(String),
+    /// Send the messages from a recording instead of playing shows
+    Replay(Vec<RecordedEntry>),
+}
+
+/// Load the shows and set up a manager to play them
+fn show_manager(
+    config: &Config,
+    message_queue_tx: &mpsc::Sender<MessageKind>,
+    frames_tx: mpsc::Sender<MessageKind>,
+    controls: mpsc::Receiver<ShowControl>,
+    home_status: mpsc::Receiver<HomeStatus>,
+) -> ShowManager {
+    // Get the shows on disk
+    info!("Starting shows...");
+    let (shows, discovery) = ShowManager::load_shows(config);
+    info!(
+        "Found {} shows, skipped {}",
+        discovery.loaded.len(),
+        discovery.skipped.len()
+    );
+
+    let tx_clone = message_queue_tx.clone();
+    let mut manager = ShowManager::new(shows, tx_clone, config);
+    manager.frames = frames_tx;
+    manager.controls = Some(controls);
+    // Simulated lasers never report homing
+    if !(config.laser.simulate || config.mode.is_simulated()) {
+        manager.home_status = Some(home_status);
+    }
+    manager
+}
+
+/// Initialise the hardware and play `name`. The worker stops once it's
+/// played.
+async fn play_show(
+    mut manager: ShowManager,
+    name: &str,
+    shutdown: &Shutdown,
+) -> Result<JoinHandle<()>, Error> {
+    if !manager.has_show(name) {
+        return Err(Error::msg(format!("There's no show called {}", name)));
+    }
+
+    // Nothing plays after it, even when the schedule says the shows are off
+    manager.stopped = false;
+    manager.closing = true;
+    manager.run_once = true;
+
+    let (show_worker_channel_tx, show_worker_channel_rx) = mpsc::channel(1);
+    show_worker_channel_tx
+        .send(vec![
+            ShowElement::RunInit,
+            ShowElement::PrepareShow(ShowChoice::Name(name.to_string())),
+            ShowElement::NextShow,
+        ])
+        .await
+        .unwrap();
+
+    info!("Playing {}...", name);
+    Ok(manager
+        .start_show_worker(show_worker_channel_rx, shutdown.token(Stage::Shows))
+        .await)
+}
+
+/// Start the show worker and the first show. The worker is started again if
+/// it falls over, with the shows loaded again, carrying on from the saved
+/// state. Returns its controls, which follow it across restarts.
+fn start_shows(
+    config: &Config,
+    mut new_manager: impl FnMut(mpsc::Receiver<ShowControl>) -> ShowManager + Send + 'static,
+    mut restored: Option<ControllerState>,
+    saved_state: StateStore,
+    supervisor: &Supervisor,
+    shutdown: &mut Shutdown,
+) -> TaskSender<ShowControl> {
+    let test_lasers = config.laser.test_on_startup;
+    let mut first_start = true;
+
+    info!("Starting show worker...");
+    let cancel = shutdown.token(Stage::Shows);
+    let (show_control_tx, worker) =
+        supervisor.spawn("show worker", 10, cancel, move |controls, cancel| {
+            let mut manager = new_manager(controls);
+            // Where the last run left off the first time, and where this run
+            // got to after that
+            let first = std::mem::take(&mut first_start);
+            match first {
+                true => {
+                    if let Some(state) = restored.take() {
+                        manager.resume(&state);
+                    }
+                }
+                false => manager.resume(&saved_state.get()),
+            }
+            manager.saved_state = Some(saved_state.clone());
+
+            // Only the first start idles and tests the lasers
+            let mut startup = Vec::new();
+            if first {
+                startup.push(ShowElement::Idle { time: 5 });
+                if test_lasers {
+                    startup.push(ShowElement::LaserTest {
+                        laser_id: ALL_LASERS,
+                    });
+                }
+            }
+            // Stopped shows are started by the schedule or by hand
+            if !manager.stopped {
+                startup.extend([
+                    // ShowElement::LightTest,
+                    ShowElement::RunInit,
+                    ShowElement::Transition {
+                        next: manager.next_choice(),
+                    },
+                ]);
+            }
+            let (startup_tx, startup_rx) = mpsc::channel(1);
+            let _ = startup_tx.try_send(startup);
+
+            async move {
+                let worker = manager.start_show_worker(startup_rx, cancel).await;
+                // Pass a panic on, so the supervisor says why it stopped
+                if let Err(e) = worker.await {
+                    if e.is_panic() {
+                        std::panic::resume_unwind(e.into_panic());
+                    }
+                }
+            }
+        });
+    shutdown.track(Stage::Shows, "show worker", worker);
+
+    show_control_tx
+}
@@ -0,0 +1,137 @@
+use prometheus::{
+    core::Collector, Encoder, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+/// Counters and gauges from every subsystem, served on `/metrics`. Clones
+/// share the same registry, so each controller counts into its own clone.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    /// Show frames sent on time
+    pub frames_dispatched: IntCounter,
+    /// Show frames skipped for being too late
+    pub frames_skipped: IntCounter,
+    /// Bytes written to the UART, by `kind` of traffic
+    pub uart_bytes: IntCounterVec,
+    /// DMX send requests folded into another send
+    pub dmx_coalesced_sends: IntCounter,
+    /// Laser frames dropped, from a full queue or the e-stop
+    pub laser_drops: IntCounter,
+    /// Songs that couldn't be played
+    pub audio_failures: IntCounter,
+    /// What's waiting in each `queue`
+    pub queue_depth: IntGaugeVec,
+    /// 1 for the `show` that's playing
+    pub current_show: IntGaugeVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("halloween".to_string()), None).expect("the prefix is valid");
+
+        let counter = |name: &str, help: &str| {
+            register(
+                &registry,
+                IntCounter::new(name, help).expect("the counter is valid"),
+            )
+        };
+        let frames_dispatched = counter("frames_dispatched_total", "Show frames sent on time");
+        let frames_skipped = counter(
+            "frames_skipped_total",
+            "Show frames skipped for being too late",
+        );
+        let dmx_coalesced_sends = counter(
+            "dmx_coalesced_sends_total",
+            "DMX send requests folded into another send",
+        );
+        let laser_drops = counter("laser_drops_total", "Laser frames dropped");
+        let audio_failures = counter("audio_failures_total", "Songs that couldn't be played");
+
+        let uart_bytes = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new("uart_bytes_total", "Bytes written to the UART"),
+                &["kind"],
+            )
+            .expect("the counter is valid"),
+        );
+        let gauge = |name: &str, help: &str, label: &str| {
+            register(
+                &registry,
+                IntGaugeVec::new(Opts::new(name, help), &[label]).expect("the gauge is valid"),
+            )
+        };
+        let queue_depth = gauge("queue_depth", "What's waiting in each queue", "queue");
+        let current_show = gauge("current_show", "1 for the show that's playing", "show");
+
+        Metrics {
+            registry,
+            frames_dispatched,
+            frames_skipped,
+            uart_bytes,
+            dmx_coalesced_sends,
+            laser_drops,
+            audio_failures,
+            queue_depth,
+            current_show,
+        }
+    }
+
+    pub fn set_queue_depth(&self, queue: &str, depth: usize) {
+        self.queue_depth
+            .with_label_values(&[queue])
+            .set(depth as i64);
+    }
+
+    /// Only the show that's playing has a series, so nothing is left behind
+    /// from the last one
+    pub fn set_current_show(&self, show: Option<&str>) {
+        self.current_show.reset();
+        if let Some(show) = show {
+            self.current_show.with_label_values(&[show]).set(1);
+        }
+    }
+
+    /// Everything in the Prometheus text format
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("writing to a Vec can't fail");
+        String::from_utf8(buffer).expect("the text format is UTF-8")
+    }
+}
+
+fn register<C: Collector + Clone + 'static>(registry: &Registry, collector: C) -> C {
+    registry
+        .register(Box::new(collector.clone()))
+        .expect("each metric is registered once");
+    collector
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let metrics = Metrics::new();
+        metrics.clone().frames_dispatched.inc_by(3);
+        metrics.uart_bytes.with_label_values(&["dmx"]).inc_by(513);
+        metrics.set_current_show(Some("graveyard"));
+        metrics.set_current_show(Some("bats"));
+
+        let text = metrics.encode();
+        assert!(text.contains("halloween_frames_dispatched_total 3\n"));
+        assert!(text.contains("halloween_uart_bytes_total{kind=\"dmx\"} 513\n"));
+        assert!(text.contains("halloween_current_show{show=\"bats\"} 1\n"));
+        assert!(!text.contains("graveyard"));
+    }
+}
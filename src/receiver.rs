@@ -0,0 +1,735 @@
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::{Config, Subsystem},
+    dmx::DmxMessage,
+    laser::LaserMessage,
+    lights::LightController,
+    recorder::{MessageCopies, RecorderHandle},
+    safety::OutputsEnabled,
+    show::prelude::ShowControl,
+    shutdown::recv_until,
+    supervisor::TaskSender,
+    turret::TurretMessage,
+    AudioMessage, InternalMessage, MessageKind,
+};
+
+/// How long a destination can keep failing before it's reported as degraded
+pub const DEGRADED_AFTER: Duration = Duration::from_secs(5);
+
+/// How long a message that has to get through waits for room
+pub const SEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often messages for a subsystem that's turned off are warned about
+pub const DISABLED_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What happens to a message when its destination can't take it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wait up to `SEND_TIMEOUT` for room, for messages that mustn't be lost
+    Wait,
+    /// Drop it straight away, for messages that are stale by the time
+    /// there's room, like show frames
+    Drop,
+}
+
+/// Drops the messages for a subsystem that's turned off. Shows can still
+/// use it, so dropping them isn't an error, and it's only warned about every
+/// `DISABLED_WARNING_INTERVAL` so it doesn't drown out the rest of the log.
+#[derive(Debug)]
+pub struct Disabled {
+    name: &'static str,
+    dropped: u64,
+    /// Dropped since the last warning
+    unwarned: u64,
+    warned_at: Option<Instant>,
+}
+
+impl Disabled {
+    pub fn new(name: &'static str) -> Self {
+        Disabled {
+            name,
+            dropped: 0,
+            unwarned: 0,
+            warned_at: None,
+        }
+    }
+
+    pub fn drop_message(&mut self) {
+        self.dropped += 1;
+        self.unwarned += 1;
+
+        let now = Instant::now();
+        if self
+            .warned_at
+            .is_some_and(|at| now - at < DISABLED_WARNING_INTERVAL)
+        {
+            return;
+        }
+        warn!(
+            "The {} are turned off, dropped {} messages for them",
+            self.name, self.unwarned
+        );
+        self.warned_at = Some(now);
+        self.unwarned = 0;
+    }
+
+    /// Every message that's been dropped
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/// One of the tasks the receiver hands messages to. Failed sends are counted
+/// and logged rather than taking the receiver down with them.
+pub struct Outlet<T> {
+    name: &'static str,
+    tx: TaskSender<T>,
+    failures: u64,
+    /// When the current run of failures started
+    failing_since: Option<Instant>,
+    degraded: bool,
+    /// The task is turned off, so nothing is sent to it
+    disabled: Option<Disabled>,
+}
+
+impl<T> Outlet<T> {
+    pub fn new(name: &'static str, tx: impl Into<TaskSender<T>>) -> Self {
+        Outlet {
+            name,
+            tx: tx.into(),
+            failures: 0,
+            failing_since: None,
+            degraded: false,
+            disabled: None,
+        }
+    }
+
+    /// For a task that's turned off, which drops everything sent to it
+    pub fn disabled(name: &'static str) -> Self {
+        Outlet {
+            disabled: Some(Disabled::new(name)),
+            ..Outlet::new(name, TaskSender::closed())
+        }
+    }
+
+    /// Messages dropped because the task is turned off
+    pub fn dropped(&self) -> u64 {
+        self.disabled.as_ref().map_or(0, Disabled::dropped)
+    }
+
+    /// Every message that's failed to send
+    pub fn failures(&self) -> u64 {
+        self.failures
+    }
+
+    /// Whether it's been failing for longer than `DEGRADED_AFTER`
+    pub fn degraded(&self) -> bool {
+        self.degraded
+    }
+
+    /// Send a message, returning an alert the first time the destination
+    /// has been failing for longer than `DEGRADED_AFTER`
+    pub async fn send(&mut self, message: T, overflow: Overflow) -> Option<String> {
+        if let Some(disabled) = &mut self.disabled {
+            disabled.drop_message();
+            return None;
+        }
+
+        let result = match overflow {
+            Overflow::Wait => match tokio::time::timeout(SEND_TIMEOUT, self.tx.send(message)).await
+            {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(_)) => Err("it has stopped"),
+                Err(_) => Err("it's been full too long"),
+            },
+            Overflow::Drop => match self.tx.try_send(message) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => Err("it's full"),
+                Err(TrySendError::Closed(_)) => Err("it has stopped"),
+            },
+        };
+
+        let now = Instant::now();
+        match result {
+            Ok(()) => {
+                if self.failing_since.take().is_some() {
+                    info!("Sending to the {} works again", self.name);
+                    self.degraded = false;
+                }
+                None
+            }
+            Err(reason) => {
+                self.failures += 1;
+                let since = *self.failing_since.get_or_insert_with(|| {
+                    warn!("Couldn't send to the {}, {}", self.name, reason);
+                    now
+                });
+                debug!("Dropped a message for the {}, {}", self.name, reason);
+
+                if self.degraded || now - since <= DEGRADED_AFTER {
+                    return None;
+                }
+                self.degraded = true;
+                Some(format!(
+                    "the {} have been failing for {}s, {} messages lost so far ({})",
+                    self.name,
+                    (now - since).as_secs(),
+                    self.failures,
+                    reason
+                ))
+            }
+        }
+    }
+}
+
+/// Hands the lasers and DMX their messages. The show manager's frames come
+/// to one of these on their own lane, so a busy show never holds up the
+/// lights or the audio, and the receiver has another for everything else that
+/// sends them.
+pub struct FrameRouter {
+    pub lasers: Outlet<LaserMessage>,
+    pub dmx: Outlet<DmxMessage>,
+    pub recorder: Option<RecorderHandle>,
+    /// Where every message on the lane is copied for anything watching
+    pub copies: MessageCopies,
+}
+
+impl FrameRouter {
+    pub fn new(
+        lasers: impl Into<TaskSender<LaserMessage>>,
+        dmx: impl Into<TaskSender<DmxMessage>>,
+        recorder: Option<RecorderHandle>,
+    ) -> Self {
+        FrameRouter {
+            lasers: Outlet::new("lasers", lasers),
+            dmx: Outlet::new("DMX", dmx),
+            recorder,
+            copies: MessageCopies::default(),
+        }
+    }
+
+    /// Drop the messages for the lasers or DMX if they're turned off
+    pub fn disable(&mut self, config: &Config) {
+        if !config.enabled(Subsystem::Lasers) {
+            self.lasers = Outlet::disabled("lasers");
+        }
+        if !config.enabled(Subsystem::Dmx) {
+            self.dmx = Outlet::disabled("DMX");
+        }
+    }
+
+    pub async fn run(mut self, mut rx: mpsc::Receiver<MessageKind>, cancel: CancellationToken) {
+        info!("Starting the frames lane");
+
+        while let Some(message) = recv_until(&mut rx, &cancel).await {
+            let MessageKind::InternalMessage(message) = message;
+            if let Some(recorder) = &self.recorder {
+                recorder.record(&message);
+            }
+            self.copies.copy(&message);
+
+            if let Some(alert) = self.route(message).await {
+                error!("Alert: {}", alert);
+            }
+        }
+    }
+
+    /// Send on a laser or DMX message, returning an alert if its destination
+    /// has been failing for a while
+    pub async fn route(&mut self, message: InternalMessage) -> Option<String> {
+        match message {
+            // A frame that can't go out now would only be late
+            InternalMessage::Laser(frame_send_pack) => {
+                info!("Projector command received");
+                self.lasers
+                    .send(LaserMessage::Frame(frame_send_pack), Overflow::Drop)
+                    .await
+            }
+            InternalMessage::LaserBatch(batch) => {
+                info!("Projector batch of {} received", batch.len());
+                self.lasers
+                    .send(LaserMessage::FrameBatch(batch), Overflow::Drop)
+                    .await
+            }
+            InternalMessage::LaserHome => {
+                info!("Laser home received");
+                self.lasers.send(LaserMessage::Home, Overflow::Wait).await
+            }
+            InternalMessage::LaserEnable(enable) => {
+                info!("Laser enable {} received", enable);
+                self.lasers
+                    .send(LaserMessage::SetEnable(enable), Overflow::Wait)
+                    .await
+            }
+            InternalMessage::LaserConfig {
+                acceleration,
+                max_speed,
+                min_speed,
+                x_home,
+                y_home,
+                projector_id,
+            } => {
+                info!("Laser config for projector {} received", projector_id);
+                let config = LaserMessage::Config {
+                    acceleration,
+                    max_speed,
+                    min_speed,
+                    x_home,
+                    y_home,
+                    projector_id,
+                };
+                self.lasers.send(config, Overflow::Wait).await
+            }
+            InternalMessage::ShowStarted(name) => {
+                self.lasers
+                    .send(LaserMessage::ShowStarted(name), Overflow::Drop)
+                    .await
+            }
+            InternalMessage::DmxUpdateState(dmx_state_var_positions) => {
+                info!("DMX data received");
+                self.dmx
+                    .send(
+                        DmxMessage::UpdateState(dmx_state_var_positions),
+                        Overflow::Wait,
+                    )
+                    .await
+            }
+            InternalMessage::DmxSendRequest => {
+                info!("DMX request received");
+                self.dmx.send(DmxMessage::Send, Overflow::Wait).await
+            }
+            InternalMessage::DmxZeroOut => {
+                info!("DMX zero out received");
+                self.dmx.send(DmxMessage::ZeroOut, Overflow::Wait).await
+            }
+            InternalMessage::DmxBlackout => {
+                info!("DMX blackout received");
+                self.dmx.send(DmxMessage::Blackout, Overflow::Wait).await
+            }
+            InternalMessage::DmxFadeToBlackout(duration) => {
+                info!("DMX fade to blackout received");
+                self.dmx
+                    .send(DmxMessage::FadeToBlackout(duration), Overflow::Wait)
+                    .await
+            }
+            InternalMessage::DmxScene(name) => {
+                info!("DMX scene {} received", name);
+                self.dmx
+                    .send(DmxMessage::RecallScene(name), Overflow::Wait)
+                    .await
+            }
+            // Through the DMX task so the frame's DMX goes out ahead of it
+            InternalMessage::FrameFlush { frame_id } => {
+                self.dmx
+                    .send(DmxMessage::Flush { frame_id }, Overflow::Wait)
+                    .await
+            }
+            message => {
+                warn!("Not a laser or DMX message: {:?}", message);
+                None
+            }
+        }
+    }
+}
+
+/// Takes every message off the main queue and hands it to whatever it's for
+pub struct Receiver {
+    /// `None` when they're turned off
+    pub lights: Option<LightController>,
+    /// For the messages for the lights while they're turned off
+    pub lights_disabled: Disabled,
+    pub audio: Option<Outlet<AudioMessage>>,
+    /// For the laser and DMX messages that don't come on the frames lane
+    pub frames: FrameRouter,
+    pub turrets: Outlet<TurretMessage>,
+    pub show_controls: Outlet<ShowControl>,
+    pub outputs_enabled: OutputsEnabled,
+    pub recorder: Option<RecorderHandle>,
+    /// Where every message is copied for anything watching
+    pub copies: MessageCopies,
+}
+
+impl Receiver {
+    pub async fn run(mut self, mut rx: mpsc::Receiver<MessageKind>, cancel: CancellationToken) {
+        info!("Starting the reciever thread");
+
+        while let Some(message) = recv_until(&mut rx, &cancel).await {
+            let MessageKind::InternalMessage(message) = message;
+            if let Some(recorder) = &self.recorder {
+                recorder.record(&message);
+            }
+            self.copies.copy(&message);
+
+            self.handle(message).await;
+        }
+    }
+
+    async fn handle(&mut self, message: InternalMessage) {
+        let alert = match message {
+            InternalMessage::Audio {
+                audio_file_contents,
+            } => {
+                self.send_audio(AudioMessage::Play(audio_file_contents))
+                    .await
+            }
+            InternalMessage::AudioStop => self.send_audio(AudioMessage::Stop).await,
+            InternalMessage::AudioPause => self.send_audio(AudioMessage::Pause).await,
+            InternalMessage::AudioResume => self.send_audio(AudioMessage::Resume).await,
+            InternalMessage::AudioFadeOut(duration) => {
+                self.send_audio(AudioMessage::FadeOut(duration)).await
+            }
+            InternalMessage::Light { light_id, enable } => {
+                info!("Light command received");
+                if let Some(Err(e)) = self.lights().map(|lights| lights.set_pin(light_id, enable)) {
+                    error!("{}", e);
+                }
+                None
+            }
+            InternalMessage::LightLevel { light_id, level } => {
+                info!("Light level command received");
+                if let Some(Err(e)) = self
+                    .lights()
+                    .map(|lights| lights.set_level(light_id, level))
+                {
+                    error!("{}", e);
+                }
+                None
+            }
+            InternalMessage::LightEffect { light_id, effect } => {
+                info!("Light effect command received");
+                if let Some(Err(e)) = self
+                    .lights()
+                    .map(|lights| lights.set_effect(light_id, effect))
+                {
+                    error!("{}", e);
+                }
+                None
+            }
+            InternalMessage::LightQuery(reply) => {
+                // Whoever asked may have given up waiting
+                let _ = reply.send(
+                    self.lights
+                        .as_ref()
+                        .map(LightController::snapshot)
+                        .unwrap_or_default(),
+                );
+                None
+            }
+            InternalMessage::LightTest(done) => {
+                // Without lights there's no report to wait on
+                if let Some(lights) = self.lights() {
+                    let _ = done.send(lights.run_test().await);
+                }
+                None
+            }
+            InternalMessage::OutputsRearm => {
+                self.outputs_enabled.rearm();
+                None
+            }
+            InternalMessage::LightTestReport(report) => {
+                info!("Light test finished: {}", report);
+                None
+            }
+            InternalMessage::InitReport(report) => {
+                match report.failed {
+                    None => info!("{}", report),
+                    Some(_) => error!("{}", report),
+                }
+                None
+            }
+            InternalMessage::AllLightsOff(done) => {
+                info!("All lights off received");
+                // They're already off if they're turned off
+                if let Some(lights) = self.lights.as_mut() {
+                    lights.all_off();
+                }
+                let _ = done.send(());
+                None
+            }
+            InternalMessage::Alert(alert) => {
+                error!("Alert: {}", alert);
+                None
+            }
+            InternalMessage::ShowProgress {
+                name,
+                elapsed_ms,
+                total_ms,
+                ..
+            } => {
+                // Only the recorder needs these
+                info!("{} is at {}/{}ms", name, elapsed_ms, total_ms);
+                None
+            }
+            // Never hold up the queue on the show manager
+            InternalMessage::ShowControl(control) => {
+                self.show_controls.send(control, Overflow::Drop).await
+            }
+            // A shot that can't fire now would go off late
+            InternalMessage::TurretFire { turret_id } => {
+                info!("Turret {} fire received", turret_id);
+                self.turrets
+                    .send(TurretMessage::Fire(turret_id), Overflow::Drop)
+                    .await
+            }
+            // Everything else is for the lasers or DMX
+            message => self.frames.route(message).await,
+        };
+
+        // Reported like any other alert
+        if let Some(alert) = alert {
+            error!("Alert: {}", alert);
+        }
+    }
+
+    /// The lights, or `None` after dropping a message for them because
+    /// they're turned off
+    fn lights(&mut self) -> Option<&mut LightController> {
+        if self.lights.is_none() {
+            self.lights_disabled.drop_message();
+        }
+        self.lights.as_mut()
+    }
+
+    async fn send_audio(&mut self, message: AudioMessage) -> Option<String> {
+        match self.audio.as_mut() {
+            Some(audio) => audio.send(message, Overflow::Wait).await,
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::{config::Config, laser::FrameSendPack};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_outlet_degrades_and_recovers() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut lasers = Outlet::new("lasers", tx);
+
+        assert_eq!(lasers.send(1, Overflow::Drop).await, None);
+        assert_eq!(lasers.send(2, Overflow::Drop).await, None);
+        assert_eq!(lasers.failures(), 1);
+
+        // Only reported once it's been failing for a while, and only once
+        tokio::time::advance(DEGRADED_AFTER + Duration::from_secs(1)).await;
+        let alert = lasers.send(3, Overflow::Drop).await.unwrap();
+        assert_eq!(
+            alert,
+            "the lasers have been failing for 6s, 2 messages lost so far (it's full)"
+        );
+        assert!(lasers.degraded());
+        assert_eq!(lasers.send(4, Overflow::Wait).await, None);
+        assert_eq!(lasers.failures(), 3);
+
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(lasers.send(5, Overflow::Drop).await, None);
+        assert!(!lasers.degraded());
+        assert_eq!(rx.recv().await, Some(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_disabled_subsystems() {
+        let config = Config::from_json(
+            r#"{
+                "audio": { "enabled": false },
+                "dmx": { "enabled": false },
+                "light": { "enabled": false }
+            }"#,
+        )
+        .unwrap();
+        let (laser_tx, mut laser_rx) = mpsc::channel(100);
+        let (turret_tx, _) = mpsc::channel(100);
+        let (show_control_tx, _) = mpsc::channel(10);
+        let mut frames = FrameRouter::new(laser_tx, TaskSender::closed(), None);
+        frames.disable(&config);
+        let mut receiver = Receiver {
+            lights: None,
+            lights_disabled: Disabled::new("lights"),
+            audio: Some(Outlet::disabled("audio")),
+            frames,
+            turrets: Outlet::new("turrets", turret_tx),
+            show_controls: Outlet::new("shows", show_control_tx),
+            outputs_enabled: OutputsEnabled::default(),
+            recorder: None,
+            copies: MessageCopies::default(),
+        };
+
+        // What a show sends, none of which fails it
+        for message in [
+            InternalMessage::AudioStop,
+            InternalMessage::Light {
+                light_id: 1,
+                enable: true,
+            },
+            InternalMessage::DmxUpdateState(vec![(1, 255)]),
+            InternalMessage::DmxSendRequest,
+            InternalMessage::LaserHome,
+        ] {
+            receiver.handle(message).await;
+        }
+        assert_eq!(receiver.audio.as_ref().unwrap().dropped(), 1);
+        assert_eq!(receiver.lights_disabled.dropped(), 1);
+        assert_eq!(receiver.frames.dmx.dropped(), 2);
+        assert_eq!(receiver.frames.dmx.failures(), 0);
+        assert!(matches!(laser_rx.try_recv(), Ok(LaserMessage::Home)));
+
+        // Turning the lights off at the end of a show still finishes
+        let (done_tx, done_rx) = oneshot::channel();
+        receiver
+            .handle(InternalMessage::AllLightsOff(done_tx))
+            .await;
+        assert_eq!(done_rx.await, Ok(()));
+
+        // Warned about once, then again after a while with what was dropped
+        let dmx = receiver.frames.dmx.disabled.as_ref().unwrap();
+        assert_eq!(dmx.unwarned, 1);
+        tokio::time::advance(DISABLED_WARNING_INTERVAL).await;
+        receiver.handle(InternalMessage::DmxSendRequest).await;
+        let dmx = receiver.frames.dmx.disabled.as_ref().unwrap();
+        assert_eq!((dmx.dropped, dmx.unwarned), (3, 0));
+    }
+
+    #[tokio::test]
+    async fn test_receiver_survives_closed_destinations() {
+        let (message_tx, message_rx) = mpsc::channel(100);
+        let (laser_tx, _) = mpsc::channel(100);
+        let (dmx_tx, _) = mpsc::channel(100);
+        let (turret_tx, _) = mpsc::channel(100);
+        let (show_control_tx, mut show_controls) = mpsc::channel(10);
+        let receiver = Receiver {
+            lights: Some(
+                LightController::init(&Config::default(), message_tx.clone())
+                    .await
+                    .unwrap(),
+            ),
+            lights_disabled: Disabled::new("lights"),
+            audio: None,
+            frames: FrameRouter::new(laser_tx, dmx_tx, None),
+            turrets: Outlet::new("turrets", turret_tx),
+            show_controls: Outlet::new("shows", show_control_tx),
+            outputs_enabled: OutputsEnabled::default(),
+            recorder: None,
+            copies: MessageCopies::default(),
+        };
+        let cancel = CancellationToken::new();
+        let receiver = tokio::spawn(receiver.run(message_rx, cancel.clone()));
+
+        for message in [
+            InternalMessage::Laser(FrameSendPack::null_out().into()),
+            InternalMessage::LaserHome,
+            InternalMessage::DmxUpdateState(vec![(1, 255)]),
+            InternalMessage::DmxSendRequest,
+            InternalMessage::TurretFire { turret_id: 1 },
+            InternalMessage::ShowControl(ShowControl::Skip),
+        ] {
+            message_tx
+                .send(MessageKind::InternalMessage(message))
+                .await
+                .unwrap();
+        }
+
+        // Still handling messages after every send above failed
+        assert_eq!(show_controls.recv().await, Some(ShowControl::Skip));
+        let (reply_tx, reply_rx) = oneshot::channel();
+        message_tx
+            .send(MessageKind::InternalMessage(InternalMessage::LightQuery(
+                reply_tx,
+            )))
+            .await
+            .unwrap();
+        assert!(reply_rx.await.unwrap().is_empty());
+
+        cancel.cancel();
+        receiver.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_lights_keep_up_with_frames() {
+        let (message_tx, message_rx) = mpsc::channel(100);
+        let (frames_tx, frames_rx) = mpsc::channel(1000);
+        let (laser_tx, mut laser_rx) = mpsc::channel(1000);
+        let (dmx_tx, _dmx_rx) = mpsc::channel(100);
+        let (turret_tx, _turret_rx) = mpsc::channel(100);
+        let (show_control_tx, _show_controls) = mpsc::channel(10);
+
+        // Lasers that take a while over each frame, so they back up
+        let lasers = tokio::spawn(async move {
+            while laser_rx.recv().await.is_some() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        });
+
+        // A show's worth of frames in flight before anything else is sent
+        for _ in 0..1000 {
+            frames_tx
+                .try_send(MessageKind::InternalMessage(InternalMessage::Laser(
+                    FrameSendPack::null_out().into(),
+                )))
+                .unwrap();
+        }
+
+        let cancel = CancellationToken::new();
+        let laser_queue = laser_tx.clone();
+        let frames = FrameRouter::new(laser_tx.clone(), dmx_tx.clone(), None);
+        let frames = tokio::spawn(frames.run(frames_rx, cancel.clone()));
+        let receiver = Receiver {
+            lights: Some(
+                LightController::init(&Config::default(), message_tx.clone())
+                    .await
+                    .unwrap(),
+            ),
+            lights_disabled: Disabled::new("lights"),
+            audio: None,
+            frames: FrameRouter::new(laser_tx, dmx_tx, None),
+            turrets: Outlet::new("turrets", turret_tx),
+            show_controls: Outlet::new("shows", show_control_tx),
+            outputs_enabled: OutputsEnabled::default(),
+            recorder: None,
+            copies: MessageCopies::default(),
+        };
+        let receiver = tokio::spawn(receiver.run(message_rx, cancel.clone()));
+
+        for _ in 0..10 {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            for message in [
+                InternalMessage::Light {
+                    light_id: 1,
+                    enable: true,
+                },
+                InternalMessage::LightQuery(reply_tx),
+            ] {
+                message_tx
+                    .send(MessageKind::InternalMessage(message))
+                    .await
+                    .unwrap();
+            }
+            reply_rx.await.unwrap();
+            // The lasers take at least a millisecond a frame, so the lights
+            // answered while most of the show's frames were still queued
+            let in_flight = frames_tx.max_capacity() - frames_tx.capacity()
+                + laser_queue.max_capacity()
+                - laser_queue.capacity();
+            assert!(in_flight > 500);
+        }
+        // The frames were still on their way to the lasers the whole time
+        assert!(laser_queue.capacity() < 900);
+
+        drop((frames_tx, laser_queue));
+        cancel.cancel();
+        frames.await.unwrap();
+        receiver.await.unwrap();
+        lasers.abort();
+    }
+}
@@ -0,0 +1,558 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Error;
+use chrono::{DateTime, Local};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::{
+    config::RecordingConfig, laser::FrameSendPack, lights::LightEffect,
+    show::prelude::DmxStateVarPosition, InternalMessage, MessageKind,
+};
+
+/// How many messages can wait to be written before new ones are dropped
+const RECORDER_QUEUE: usize = 1000;
+
+/// How many copies a watcher can fall behind by before it misses some
+const COPIES_QUEUE: usize = 256;
+
+/// The commands in an `InternalMessage`, without anything that can't be
+/// written down like reply channels or audio
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RecordedMessage {
+    Light {
+        light_id: u8,
+        enable: bool,
+    },
+    LightLevel {
+        light_id: u8,
+        level: u8,
+    },
+    LightEffect {
+        light_id: u8,
+        effect: LightEffect,
+    },
+    AllLightsOff,
+    OutputsRearm,
+    /// Only the song's name is kept, so it isn't replayed
+    Audio {
+        name: String,
+    },
+    AudioStop,
+    AudioPause,
+    AudioResume,
+    AudioFadeOut(Duration),
+    Laser(FrameSendPack),
+    LaserBatch(Vec<FrameSendPack>),
+    LaserHome,
+    LaserEnable(bool),
+    ShowStarted(String),
+    ShowProgress {
+        name: String,
+        elapsed_ms: u64,
+        total_ms: u64,
+        frame_index: usize,
+    },
+    LaserConfig {
+        acceleration: u32,
+        max_speed: u32,
+        min_speed: u16,
+        x_home: u16,
+        y_home: u16,
+        projector_id: u8,
+    },
+    DmxUpdateState(Vec<DmxStateVarPosition>),
+    DmxSendRequest,
+    DmxZeroOut,
+    DmxBlackout,
+    DmxFadeToBlackout(Duration),
+    DmxScene(String),
+    TurretFire {
+        turret_id: u8,
+    },
+    FrameFlush {
+        frame_id: u64,
+    },
+}
+
+impl RecordedMessage {
+    /// What's worth recording from a message, if anything. Queries and
+    /// light tests only matter to whoever asked.
+    pub fn from_message(message: &InternalMessage) -> Option<Self> {
+        Some(match message {
+            InternalMessage::Light { light_id, enable } => RecordedMessage::Light {
+                light_id: *light_id,
+                enable: *enable,
+            },
+            InternalMessage::LightLevel { light_id, level } => RecordedMessage::LightLevel {
+                light_id: *light_id,
+                level: *level,
+            },
+            InternalMessage::LightEffect { light_id, effect } => RecordedMessage::LightEffect {
+                light_id: *light_id,
+                effect: effect.clone(),
+            },
+            InternalMessage::AllLightsOff(_) => RecordedMessage::AllLightsOff,
+            InternalMessage::OutputsRearm => RecordedMessage::OutputsRearm,
+            InternalMessage::Audio {
+                audio_file_contents,
+            } => RecordedMessage::Audio {
+                name: audio_file_contents.name.clone(),
+            },
+            InternalMessage::AudioStop => RecordedMessage::AudioStop,
+            InternalMessage::AudioPause => RecordedMessage::AudioPause,
+            InternalMessage::AudioResume => RecordedMessage::AudioResume,
+            InternalMessage::AudioFadeOut(fade) => RecordedMessage::AudioFadeOut(*fade),
+            InternalMessage::Laser(frame) => RecordedMessage::Laser(frame.pack.clone()),
+            InternalMessage::LaserBatch(batch) => {
+                RecordedMessage::LaserBatch(batch.iter().map(|frame| frame.pack.clone()).collect())
+            }
+            InternalMessage::LaserHome => RecordedMessage::LaserHome,
+            InternalMessage::LaserEnable(enable) => RecordedMessage::LaserEnable(*enable),
+            InternalMessage::ShowStarted(name) => RecordedMessage::ShowStarted(name.clone()),
+            InternalMessage::ShowProgress {
+                name,
+                elapsed_ms,
+                total_ms,
+                frame_index,
+            } => RecordedMessage::ShowProgress {
+                name: name.clone(),
+                elapsed_ms: *elapsed_ms,
+                total_ms: *total_ms,
+                frame_index: *frame_index,
+            },
+            InternalMessage::LaserConfig {
+                acceleration,
+                max_speed,
+                min_speed,
+                x_home,
+                y_home,
+                projector_id,
+            } => RecordedMessage::LaserConfig {
+                acceleration: *acceleration,
+                max_speed: *max_speed,
+                min_speed: *min_speed,
+                x_home: *x_home,
+                y_home: *y_home,
+                projector_id: *projector_id,
+            },
+            InternalMessage::DmxUpdateState(state) => {
+                RecordedMessage::DmxUpdateState(state.clone())
+            }
+            InternalMessage::DmxSendRequest => RecordedMessage::DmxSendRequest,
+            InternalMessage::DmxZeroOut => RecordedMessage::DmxZeroOut,
+            InternalMessage::DmxBlackout => RecordedMessage::DmxBlackout,
+            InternalMessage::DmxFadeToBlackout(fade) => RecordedMessage::DmxFadeToBlackout(*fade),
+            InternalMessage::DmxScene(name) => RecordedMessage::DmxScene(name.clone()),
+            InternalMessage::TurretFire { turret_id } => RecordedMessage::TurretFire {
+                turret_id: *turret_id,
+            },
+            InternalMessage::FrameFlush { frame_id } => RecordedMessage::FrameFlush {
+                frame_id: *frame_id,
+            },
+            // Controls only matter to the show that was playing, and alerts
+            // to whoever is watching
+            InternalMessage::Alert(_)
+            | InternalMessage::LightQuery(_)
+            | InternalMessage::LightTest(_)
+            | InternalMessage::LightTestReport(_)
+            | InternalMessage::InitReport(_)
+            | InternalMessage::ShowControl(_) => return None,
+        })
+    }
+
+    /// The message to send again, or `None` for audio
+    pub fn into_message(self) -> Option<InternalMessage> {
+        Some(match self {
+            RecordedMessage::Light { light_id, enable } => {
+                InternalMessage::Light { light_id, enable }
+            }
+            RecordedMessage::LightLevel { light_id, level } => {
+                InternalMessage::LightLevel { light_id, level }
+            }
+            RecordedMessage::LightEffect { light_id, effect } => {
+                InternalMessage::LightEffect { light_id, effect }
+            }
+            // Nothing waits for the lights to go off
+            RecordedMessage::AllLightsOff => InternalMessage::AllLightsOff(oneshot::channel().0),
+            RecordedMessage::OutputsRearm => InternalMessage::OutputsRearm,
+            RecordedMessage::Audio { .. } => return None,
+            RecordedMessage::AudioStop => InternalMessage::AudioStop,
+            RecordedMessage::AudioPause => InternalMessage::AudioPause,
+            RecordedMessage::AudioResume => InternalMessage::AudioResume,
+            RecordedMessage::AudioFadeOut(fade) => InternalMessage::AudioFadeOut(fade),
+            RecordedMessage::Laser(pack) => InternalMessage::Laser(pack.into()),
+            RecordedMessage::LaserBatch(batch) => {
+                InternalMessage::LaserBatch(batch.into_iter().map(Into::into).collect())
+            }
+            RecordedMessage::LaserHome => InternalMessage::LaserHome,
+            RecordedMessage::LaserEnable(enable) => InternalMessage::LaserEnable(enable),
+            RecordedMessage::ShowStarted(name) => InternalMessage::ShowStarted(name),
+            RecordedMessage::ShowProgress {
+                name,
+                elapsed_ms,
+                total_ms,
+                frame_index,
+            } => InternalMessage::ShowProgress {
+                name,
+                elapsed_ms,
+                total_ms,
+                frame_index,
+            },
+            RecordedMessage::LaserConfig {
+                acceleration,
+                max_speed,
+                min_speed,
+                x_home,
+                y_home,
+                projector_id,
+            } => InternalMessage::LaserConfig {
+                acceleration,
+                max_speed,
+                min_speed,
+                x_home,
+                y_home,
+                projector_id,
+            },
+            RecordedMessage::DmxUpdateState(state) => InternalMessage::DmxUpdateState(state),
+            RecordedMessage::DmxSendRequest => InternalMessage::DmxSendRequest,
+            RecordedMessage::DmxZeroOut => InternalMessage::DmxZeroOut,
+            RecordedMessage::DmxBlackout => InternalMessage::DmxBlackout,
+            RecordedMessage::DmxFadeToBlackout(fade) => InternalMessage::DmxFadeToBlackout(fade),
+            RecordedMessage::DmxScene(name) => InternalMessage::DmxScene(name),
+            RecordedMessage::TurretFire { turret_id } => InternalMessage::TurretFire { turret_id },
+            RecordedMessage::FrameFlush { frame_id } => InternalMessage::FrameFlush { frame_id },
+        })
+    }
+}
+
+/// A line in a recording
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedEntry {
+    pub time: DateTime<Local>,
+    pub message: RecordedMessage,
+}
+
+/// Hands messages to the recorder task without ever waiting on it
+#[derive(Clone)]
+pub struct RecorderHandle {
+    tx: mpsc::Sender<RecordedEntry>,
+}
+
+impl RecorderHandle {
+    /// Start a recorder task writing under `config.dir`
+    pub fn spawn(config: &RecordingConfig) -> Self {
+        let (tx, rx) = mpsc::channel(RECORDER_QUEUE);
+        tokio::spawn(Recorder::new(config).start(rx));
+        RecorderHandle { tx }
+    }
+
+    pub fn record(&self, message: &InternalMessage) {
+        let Some(message) = RecordedMessage::from_message(message) else {
+            return;
+        };
+
+        let entry = RecordedEntry {
+            time: Local::now(),
+            message,
+        };
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(entry) {
+            warn!("The recorder is falling behind, dropping a message");
+        }
+    }
+}
+
+/// Copies of every message, for anything watching like the dashboard. A
+/// watcher that falls behind misses messages rather than holding up the
+/// queue. Clones share the same watchers.
+#[derive(Clone)]
+pub struct MessageCopies(broadcast::Sender<RecordedEntry>);
+
+impl Default for MessageCopies {
+    fn default() -> Self {
+        MessageCopies(broadcast::channel(COPIES_QUEUE).0)
+    }
+}
+
+impl MessageCopies {
+    pub fn subscribe(&self) -> broadcast::Receiver<RecordedEntry> {
+        self.0.subscribe()
+    }
+
+    pub fn copy(&self, message: &InternalMessage) {
+        // Nothing is copied while nobody's watching
+        if self.0.receiver_count() == 0 {
+            return;
+        }
+
+        if let Some(message) = RecordedMessage::from_message(message) {
+            let _ = self.0.send(RecordedEntry {
+                time: Local::now(),
+                message,
+            });
+        }
+    }
+}
+
+/// Writes every message to a JSONL file, starting a new one for each show
+/// and keeping only the latest `config.keep`
+pub struct Recorder {
+    dir: PathBuf,
+    keep: usize,
+    file: Option<File>,
+}
+
+impl Recorder {
+    pub fn new(config: &RecordingConfig) -> Self {
+        Recorder {
+            dir: config.dir.clone(),
+            keep: config.keep,
+            file: None,
+        }
+    }
+
+    pub async fn start(mut self, mut rx: mpsc::Receiver<RecordedEntry>) {
+        while let Some(entry) = rx.recv().await {
+            if let Err(e) = self.write(&entry) {
+                error!("Failed to record a message: {}", e);
+            }
+        }
+    }
+
+    pub fn write(&mut self, entry: &RecordedEntry) -> Result<(), Error> {
+        if let RecordedMessage::ShowStarted(name) = &entry.message {
+            self.rotate(&entry.time, name)?;
+        }
+        // Anything sent before the first show
+        if self.file.is_none() {
+            self.rotate(&entry.time, "startup")?;
+        }
+
+        if let Some(file) = &mut self.file {
+            writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Start a new file, deleting the oldest ones past `keep`
+    fn rotate(&mut self, time: &DateTime<Local>, name: &str) -> Result<(), Error> {
+        fs::create_dir_all(&self.dir)?;
+
+        // Show names come from directories, but keep them safe for a file
+        // name anyway
+        let name = name
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect::<String>();
+        let path = self
+            .dir
+            .join(format!("{}-{}.jsonl", time.format("%Y%m%d-%H%M%S"), name));
+        info!("Recording to {}", path.display());
+        self.file = Some(OpenOptions::new().create(true).append(true).open(&path)?);
+
+        // The timestamp at the front sorts them oldest first
+        let mut recordings = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "jsonl")
+            })
+            .collect::<Vec<_>>();
+        recordings.sort();
+        let excess = recordings.len().saturating_sub(self.keep.max(1));
+        for old in &recordings[..excess] {
+            fs::remove_file(old)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Read every entry in a recording
+pub fn read_recording(path: &Path) -> Result<Vec<RecordedEntry>, Error> {
+    let mut entries = Vec::new();
+
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(serde_json::from_str(&line)?);
+    }
+
+    Ok(entries)
+}
+
+/// Send a recording's messages again, as far apart as they were recorded
+pub async fn replay(
+    entries: Vec<RecordedEntry>,
+    message_queue: mpsc::Sender<MessageKind>,
+) -> Result<(), Error> {
+    let Some(first) = entries.first().map(|entry| entry.time) else {
+        return Ok(());
+    };
+    // Measured from the start so a slow send doesn't push everything after it
+    let start = tokio::time::Instant::now();
+
+    for entry in entries {
+        let offset = (entry.time - first).to_std().unwrap_or_default();
+        tokio::time::sleep_until(start + offset).await;
+
+        match entry.message.into_message() {
+            Some(message) => message_queue
+                .send(MessageKind::InternalMessage(message))
+                .await
+                .map_err(|_| Error::msg("the receiver task has stopped"))?,
+            None => info!("Skipping recorded audio"),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::{laser::MessageSendPack, test_util::TempDir};
+
+    fn entry(millis: i64, message: RecordedMessage) -> RecordedEntry {
+        RecordedEntry {
+            time: Utc
+                .timestamp_millis_opt(1_700_000_000_000 + millis)
+                .unwrap()
+                .into(),
+            message,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let frame: FrameSendPack = MessageSendPack::enable_message(true).into();
+        let messages = vec![
+            InternalMessage::LightLevel {
+                light_id: 3,
+                level: 102,
+            },
+            InternalMessage::LightEffect {
+                light_id: 1,
+                effect: LightEffect::Blink {
+                    period_ms: 200,
+                    duty: 0.5,
+                },
+            },
+            InternalMessage::LaserBatch(vec![frame.clone().into()]),
+            InternalMessage::DmxUpdateState(vec![(40, 255)]),
+        ];
+
+        for message in messages {
+            let recorded = RecordedMessage::from_message(&message).unwrap();
+            let line = serde_json::to_string(&recorded).unwrap();
+            let read: RecordedMessage = serde_json::from_str(&line).unwrap();
+            assert_eq!(read, recorded);
+
+            let replayed = read.into_message().unwrap();
+            assert_eq!(RecordedMessage::from_message(&replayed).unwrap(), recorded);
+        }
+
+        // Replies can't be recorded
+        let (tx, _rx) = oneshot::channel();
+        assert_eq!(
+            RecordedMessage::from_message(&InternalMessage::LightQuery(tx)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_file_per_show() {
+        let dir = TempDir::new("recordings");
+        let mut recorder = Recorder::new(&RecordingConfig {
+            enabled: true,
+            dir: dir.to_path_buf(),
+            keep: 2,
+        });
+
+        recorder
+            .write(&entry(0, RecordedMessage::LaserHome))
+            .unwrap();
+        for (i, show) in ["spooky", "graveyard/intro"].iter().enumerate() {
+            let start = (i as i64 + 1) * 60_000;
+            recorder
+                .write(&entry(
+                    start,
+                    RecordedMessage::ShowStarted(show.to_string()),
+                ))
+                .unwrap();
+            recorder
+                .write(&entry(
+                    start + 500,
+                    RecordedMessage::Light {
+                        light_id: 1,
+                        enable: true,
+                    },
+                ))
+                .unwrap();
+        }
+
+        // Only the latest two are kept, so the startup file is gone
+        let mut files = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files.len(), 2);
+        let name = files[1].file_name().unwrap().to_string_lossy().to_string();
+        assert!(name.ends_with("-graveyard_intro.jsonl"), "{}", name);
+
+        let entries = read_recording(&files[1]).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0].message,
+            RecordedMessage::ShowStarted("graveyard/intro".to_string())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_replay_timing() {
+        let entries = vec![
+            entry(0, RecordedMessage::DmxBlackout),
+            entry(
+                250,
+                RecordedMessage::Audio {
+                    name: "spooky".to_string(),
+                },
+            ),
+            entry(1000, RecordedMessage::LaserHome),
+        ];
+        let (tx, mut rx) = mpsc::channel(10);
+        let start = tokio::time::Instant::now();
+        tokio::spawn(replay(entries, tx));
+
+        let Some(MessageKind::InternalMessage(InternalMessage::DmxBlackout)) = rx.recv().await
+        else {
+            panic!("Expected the blackout first");
+        };
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // Audio is skipped, but the timing after it holds
+        let Some(MessageKind::InternalMessage(InternalMessage::LaserHome)) = rx.recv().await else {
+            panic!("Expected a laser home");
+        };
+        assert_eq!(start.elapsed(), Duration::from_millis(1000));
+        assert!(rx.recv().await.is_none());
+    }
+}
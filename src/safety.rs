@@ -0,0 +1,138 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+#[cfg(feature = "pi")]
+use anyhow::Error;
+use log::{error, info};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{InternalMessage, MessageKind};
+
+/// How often the e-stop is checked
+pub const ESTOP_POLL: Duration = Duration::from_millis(20);
+
+/// Whether anything is allowed to turn on. The e-stop clears it, and it stays
+/// cleared until it's re-armed. Clones share the same flag, so the lights,
+/// DMX and lasers all see the same thing.
+#[derive(Debug, Clone)]
+pub struct OutputsEnabled(Arc<AtomicBool>);
+
+impl Default for OutputsEnabled {
+    fn default() -> Self {
+        OutputsEnabled(Arc::new(AtomicBool::new(true)))
+    }
+}
+
+impl OutputsEnabled {
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Turn outputs off, returning whether they were on
+    pub fn disable(&self) -> bool {
+        self.0.swap(false, Ordering::SeqCst)
+    }
+
+    /// Let outputs turn on again
+    pub fn rearm(&self) {
+        if !self.0.swap(true, Ordering::SeqCst) {
+            info!("Outputs re-armed");
+        }
+    }
+}
+
+/// Turn everything off and keep it off. Only the first trip sends anything.
+pub async fn trip(outputs: &OutputsEnabled, message_queue: &mpsc::Sender<MessageKind>) {
+    if !outputs.disable() {
+        return;
+    }
+    error!("E-stop pressed, every output is off until it's re-armed");
+
+    // Nothing waits for the lights, the flag already keeps them off
+    let (done, _) = oneshot::channel();
+    for message in [
+        InternalMessage::AllLightsOff(done),
+        InternalMessage::DmxZeroOut,
+        InternalMessage::LaserEnable(false),
+    ] {
+        if message_queue
+            .send(MessageKind::InternalMessage(message))
+            .await
+            .is_err()
+        {
+            error!("The receiver task has stopped, outputs rely on the flag");
+            return;
+        }
+    }
+}
+
+/// Watch the e-stop on physical pin `pin`. It's wired to ground, so pressing
+/// it pulls the pin low.
+#[cfg(feature = "pi")]
+pub async fn watch_estop(
+    pin: u8,
+    outputs: OutputsEnabled,
+    message_queue: mpsc::Sender<MessageKind>,
+) -> Result<(), Error> {
+    let gpio: pi_pinout::GpioPin = pi_pinout::PhysicalPin(pin).into();
+    let pin = rppal::gpio::Gpio::new()?.get(gpio.0)?.into_input_pullup();
+    info!("Watching the e-stop on pin {}", gpio.0);
+
+    loop {
+        if pin.is_low() {
+            trip(&outputs, &message_queue).await;
+        }
+        tokio::time::sleep(ESTOP_POLL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outputs_enabled() {
+        let outputs = OutputsEnabled::default();
+        let shared = outputs.clone();
+        assert!(shared.is_enabled());
+
+        assert!(outputs.disable());
+        assert!(!shared.is_enabled());
+        // Tripping again doesn't change anything
+        assert!(!outputs.disable());
+
+        shared.rearm();
+        assert!(outputs.is_enabled());
+    }
+
+    #[tokio::test]
+    async fn test_trip() {
+        let outputs = OutputsEnabled::default();
+        let (tx, mut rx) = mpsc::channel(10);
+
+        trip(&outputs, &tx).await;
+        assert!(!outputs.is_enabled());
+
+        let mut messages = Vec::new();
+        while let Ok(MessageKind::InternalMessage(message)) = rx.try_recv() {
+            messages.push(message);
+        }
+        assert!(matches!(
+            messages.as_slice(),
+            [
+                InternalMessage::AllLightsOff(_),
+                InternalMessage::DmxZeroOut,
+                InternalMessage::LaserEnable(false)
+            ]
+        ));
+
+        // Only the first press turns things off
+        trip(&outputs, &tx).await;
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,292 @@
+use std::time::Duration;
+
+use anyhow::Error;
+use chrono::{Datelike, FixedOffset, Local, NaiveDateTime, NaiveTime, Utc};
+use log::{error, info};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::ScheduleConfig, show::prelude::ShowControl, state::StateStore, InternalMessage,
+    MessageKind,
+};
+
+/// How often the clock is checked. The schedule only acts when the hours open
+/// or close, so a clock that jumps is picked up on the next check.
+pub const SCHEDULE_POLL: Duration = Duration::from_secs(15);
+
+/// Starts and stops shows as the operating hours open and close
+pub struct Scheduler {
+    config: ScheduleConfig,
+    timezone: Option<FixedOffset>,
+    /// Whether the hours were open at the last check, unknown before the
+    /// first one
+    open: Option<bool>,
+    /// The block the last check was in, if any
+    block: Option<usize>,
+    /// Where whether the hours were open is kept across restarts
+    state: Option<StateStore>,
+}
+
+impl Scheduler {
+    pub fn new(config: &ScheduleConfig) -> Result<Self, Error> {
+        let timezone = match &config.timezone {
+            Some(timezone) => Some(timezone.parse().map_err(|_| {
+                Error::msg(format!("{:?} isn't a UTC offset like -04:00", timezone))
+            })?),
+            None => None,
+        };
+
+        Ok(Scheduler {
+            config: config.clone(),
+            timezone,
+            open: None,
+            block: None,
+            state: None,
+        })
+    }
+
+    /// The time on the schedule's clock
+    pub fn now(&self) -> NaiveDateTime {
+        match self.timezone {
+            Some(timezone) => Utc::now().with_timezone(&timezone).naive_local(),
+            None => Local::now().naive_local(),
+        }
+    }
+
+    /// Carry on from whether the hours were open before a restart, so
+    /// starting or stopping by hand still sticks, and keep `state` up to date
+    pub fn resume(&mut self, state: StateStore) {
+        self.open = state.get().schedule_open;
+        self.state = Some(state);
+    }
+
+    /// Whether the display should be running at `now`
+    pub fn is_open(&self, now: NaiveDateTime) -> bool {
+        let (start, end, time) = (self.config.start, self.config.end, now.time());
+        let runs_on = |day| self.config.days.contains(&day);
+        let today = now.date().weekday();
+
+        if start < end {
+            runs_on(today) && within(start, end, time)
+        } else {
+            // The night runs past midnight
+            (runs_on(today) && start <= time) || (runs_on(today.pred()) && time < end)
+        }
+    }
+
+    /// What to send to the shows at `now`, if anything. Nothing is sent
+    /// between the hours opening and closing, so starting or stopping by
+    /// hand sticks until the next change. Shows start off stopped, so
+    /// nothing is sent if the first check is outside of the hours. With a
+    /// closing show, closing fades into it instead of stopping straight away.
+    pub fn update(&mut self, now: NaiveDateTime) -> Option<ShowControl> {
+        let open = self.is_open(now);
+        let control = match (self.open, open) {
+            (Some(true), true) | (None | Some(false), false) => None,
+            (_, true) => Some(ShowControl::Start),
+            (Some(true), false) => Some(match &self.config.closing_show {
+                Some(name) => ShowControl::Close(name.clone()),
+                None => ShowControl::Stop,
+            }),
+        };
+        self.open = Some(open);
+        if let Some(state) = &self.state {
+            state.update(|state| state.schedule_open = Some(open));
+        }
+
+        control
+    }
+
+    /// Which tags to pick shows by at `now`, if they've changed since the
+    /// last check. Leaving a block picks from all of the shows again.
+    pub fn update_tags(&mut self, now: NaiveDateTime) -> Option<ShowControl> {
+        let block = self
+            .config
+            .blocks
+            .iter()
+            .position(|block| within(block.start, block.end, now.time()));
+        if block == self.block {
+            return None;
+        }
+        self.block = block;
+
+        Some(match block.map(|block| &self.config.blocks[block]) {
+            Some(block) => ShowControl::Tags {
+                include: block.include.clone(),
+                exclude: block.exclude.clone(),
+            },
+            None => ShowControl::Tags {
+                include: Vec::new(),
+                exclude: Vec::new(),
+            },
+        })
+    }
+
+    pub async fn run(
+        mut self,
+        message_queue: mpsc::Sender<MessageKind>,
+        cancel: CancellationToken,
+    ) {
+        info!(
+            "Running shows from {} to {} on {:?}",
+            self.config.start, self.config.end, self.config.days
+        );
+
+        let mut poll = tokio::time::interval(SCHEDULE_POLL);
+        loop {
+            tokio::select! {
+                _ = poll.tick() => {}
+                _ = cancel.cancelled() => return,
+            }
+
+            let now = self.now();
+            // Tags first, so a show started by the hours opening uses them
+            let controls = [self.update_tags(now), self.update(now)];
+            for control in controls.into_iter().flatten() {
+                info!("The schedule is sending {:?}", control);
+                if message_queue
+                    .send(MessageKind::InternalMessage(InternalMessage::ShowControl(
+                        control,
+                    )))
+                    .await
+                    .is_err()
+                {
+                    error!("The message queue has stopped");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Whether `time` is between `start` and `end`, which is before `start` if
+/// the window runs past midnight
+fn within(start: NaiveTime, end: NaiveTime, time: NaiveTime) -> bool {
+    match start < end {
+        true => start <= time && time < end,
+        false => start <= time || time < end,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, Weekday};
+
+    use crate::config::ScheduleBlock;
+
+    use super::*;
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        // October 2023 starts on a Sunday
+        NaiveDate::from_ymd_opt(2023, 10, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    fn scheduled(start: u32, end: u32, days: Vec<Weekday>) -> Scheduler {
+        Scheduler::new(&ScheduleConfig {
+            enabled: true,
+            start: NaiveTime::from_hms_opt(start, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(end, 0, 0).unwrap(),
+            days,
+            timezone: None,
+            closing_show: None,
+            blocks: Vec::new(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_evening_hours() {
+        let mut scheduler = scheduled(18, 22, vec![Weekday::Fri, Weekday::Sat]);
+
+        // Friday the 6th
+        assert_eq!(scheduler.update(at(6, 17, 59)), None);
+        assert_eq!(scheduler.update(at(6, 18, 0)), Some(ShowControl::Start));
+        assert_eq!(scheduler.update(at(6, 21, 59)), None);
+        assert_eq!(scheduler.update(at(6, 22, 0)), Some(ShowControl::Stop));
+        assert_eq!(scheduler.update(at(6, 23, 0)), None);
+
+        // Not on Sunday
+        assert!(scheduler.is_open(at(7, 19, 0)));
+        assert!(!scheduler.is_open(at(8, 19, 0)));
+    }
+
+    #[test]
+    fn test_past_midnight() {
+        let scheduler = scheduled(20, 1, vec![Weekday::Sat]);
+
+        // Saturday night into Sunday morning, but not Sunday night
+        assert!(!scheduler.is_open(at(7, 19, 59)));
+        assert!(scheduler.is_open(at(7, 23, 59)));
+        assert!(scheduler.is_open(at(8, 0, 0)));
+        assert!(scheduler.is_open(at(8, 0, 59)));
+        assert!(!scheduler.is_open(at(8, 1, 0)));
+        assert!(!scheduler.is_open(at(8, 20, 0)));
+
+        // Saturday just after midnight belongs to Friday
+        assert!(!scheduler.is_open(at(7, 0, 30)));
+    }
+
+    #[test]
+    fn test_starting_up_and_clock_jumps() {
+        let mut scheduler = scheduled(18, 22, vec![Weekday::Fri]);
+
+        // Starting in the middle of the hours starts the shows
+        assert_eq!(scheduler.update(at(6, 20, 0)), Some(ShowControl::Start));
+
+        // The clock jumping back before the start stops them, and they start
+        // again once it catches up
+        assert_eq!(scheduler.update(at(6, 17, 0)), Some(ShowControl::Stop));
+        assert_eq!(scheduler.update(at(6, 18, 0)), Some(ShowControl::Start));
+
+        // Starting outside of the hours leaves them stopped
+        let mut scheduler = scheduled(18, 22, vec![Weekday::Fri]);
+        assert_eq!(scheduler.update(at(6, 23, 0)), None);
+    }
+
+    #[test]
+    fn test_closing_show() {
+        let mut scheduler = scheduled(18, 22, vec![Weekday::Fri]);
+        scheduler.config.closing_show = Some("goodnight".to_string());
+
+        assert_eq!(scheduler.update(at(6, 18, 0)), Some(ShowControl::Start));
+        assert_eq!(
+            scheduler.update(at(6, 22, 0)),
+            Some(ShowControl::Close("goodnight".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_tag_blocks() {
+        let mut scheduler = scheduled(18, 1, vec![Weekday::Fri]);
+        let block = |start, end, include: &[&str], exclude: &[&str]| ScheduleBlock {
+            start: NaiveTime::from_hms_opt(start, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(end, 0, 0).unwrap(),
+            include: include.iter().map(|tag| tag.to_string()).collect(),
+            exclude: exclude.iter().map(|tag| tag.to_string()).collect(),
+        };
+        scheduler.config.blocks = vec![block(18, 20, &["kids"], &[]), block(22, 1, &[], &["kids"])];
+        let tags = |include: &[&str], exclude: &[&str]| {
+            Some(ShowControl::Tags {
+                include: include.iter().map(|tag| tag.to_string()).collect(),
+                exclude: exclude.iter().map(|tag| tag.to_string()).collect(),
+            })
+        };
+
+        // Nothing is sent before the first block
+        assert_eq!(scheduler.update_tags(at(6, 17, 0)), None);
+        assert_eq!(scheduler.update_tags(at(6, 18, 0)), tags(&["kids"], &[]));
+        assert_eq!(scheduler.update_tags(at(6, 19, 59)), None);
+
+        // Between the blocks any show can play
+        assert_eq!(scheduler.update_tags(at(6, 20, 0)), tags(&[], &[]));
+        assert_eq!(scheduler.update_tags(at(6, 22, 0)), tags(&[], &["kids"]));
+
+        // The late block runs past midnight
+        assert_eq!(scheduler.update_tags(at(7, 0, 30)), None);
+        assert_eq!(scheduler.update_tags(at(7, 1, 0)), tags(&[], &[]));
+    }
+}
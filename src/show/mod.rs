@@ -1,17 +1,29 @@
 use rust_embed::RustEmbed;
+use serde::{Deserialize, Serialize};
 
+mod playlist;
 mod show;
+mod show_file;
 mod show_manager;
+mod show_server;
+mod stats;
+mod validate;
+mod watcher;
 
 pub mod prelude {
-    pub use crate::show::{show::*, show_manager::*};
+    pub use crate::show::{
+        playlist::*, show::*, show_file::*, show_manager::*, show_server::*, stats::*, validate::*,
+        watcher::*,
+    };
 }
 
 #[derive(RustEmbed)]
 #[folder = "src/show/assets"]
 struct ShowAsset;
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+/// One draw instruction, with 8-bit colour channels that are brought down to
+/// the Pico's 3 bits when they're packed
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct LaserDataFrame {
     pub pattern_id: u8,
     pub r: u8,
@@ -19,7 +31,7 @@ pub struct LaserDataFrame {
     pub b: u8,
 }
 
-pub const MAX_LIGHTS: usize = 7;
+pub const MAX_LIGHTS: usize = 16;
 pub const MAX_LASERS: usize = 5;
 pub const MAX_PROJECTORS: usize = 1;
 pub const MAX_TURRETS: usize = 4;
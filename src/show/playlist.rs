@@ -0,0 +1,311 @@
+use std::{collections::VecDeque, fs, io::ErrorKind};
+
+use log::warn;
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::config::PlaylistConfig;
+
+use super::prelude::ShowName;
+
+/// What's kept in the state file
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct PlaylistState {
+    /// The last shows played, oldest first
+    history: VecDeque<ShowName>,
+    /// Where the next show in order is
+    position: usize,
+}
+
+/// Picks the shows to play, remembering the last few so they aren't
+/// repeated too soon
+pub struct Playlist {
+    config: PlaylistConfig,
+    state: PlaylistState,
+}
+
+impl Playlist {
+    /// Pick up where the state file left off, or start afresh without one
+    pub fn load(config: &PlaylistConfig) -> Self {
+        let state = match fs::read_to_string(&config.state_file) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                warn!(
+                    "Ignoring the show history in {}: {}",
+                    config.state_file.display(),
+                    e
+                );
+                PlaylistState::default()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => PlaylistState::default(),
+            Err(e) => {
+                warn!(
+                    "Couldn't read the show history from {}: {}",
+                    config.state_file.display(),
+                    e
+                );
+                PlaylistState::default()
+            }
+        };
+
+        Playlist {
+            config: config.clone(),
+            state,
+        }
+    }
+
+    /// Where the next show in order is
+    pub fn position(&self) -> usize {
+        self.state.position
+    }
+
+    /// Carry on from `position` in the order
+    pub fn resume(&mut self, position: usize) {
+        self.state.position = position;
+    }
+
+    pub fn ordered(&self) -> bool {
+        self.config.ordered
+    }
+
+    /// Pick one of `available` at random, weighted by the playlist. Anything
+    /// in the last `min_gap` shows or matching `last_song` is skipped, unless
+    /// that leaves nothing, in which case the one played longest ago is
+    /// picked.
+    pub fn choose_random(
+        &self,
+        available: &[ShowName],
+        last_song: Option<&ShowName>,
+        rng: &mut impl Rng,
+    ) -> Option<ShowName> {
+        let candidates = match self.config.shows.is_empty() {
+            true => available.iter().map(|name| (name, 1)).collect::<Vec<_>>(),
+            false => self
+                .config
+                .shows
+                .iter()
+                .filter(|entry| available.contains(&entry.name))
+                .map(|entry| (&entry.name, entry.weight))
+                .collect(),
+        };
+
+        let recent = self.recent();
+        let fresh = candidates
+            .iter()
+            .filter(|(name, _)| !recent.contains(name) && Some(*name) != last_song)
+            .collect::<Vec<_>>();
+
+        let picked = match fresh.choose_weighted(rng, |(_, weight)| *weight) {
+            Ok((name, _)) => *name,
+            Err(_) => {
+                candidates
+                    .iter()
+                    // Never the same show twice in a row if there's another
+                    .filter(|(name, _)| candidates.len() == 1 || Some(*name) != last_song)
+                    .min_by_key(|(name, _)| {
+                        self.state
+                            .history
+                            .iter()
+                            .rposition(|played| played == *name)
+                    })?
+                    .0
+            }
+        };
+
+        Some(picked.clone())
+    }
+
+    /// The next show in the playlist's order that's in `available`, going
+    /// back to the start after the last one
+    pub fn choose_next(&mut self, available: &[ShowName]) -> Option<ShowName> {
+        let len = self.config.shows.len();
+        let index = (0..len)
+            .map(|offset| (self.state.position + offset) % len)
+            .find(|index| available.contains(&self.config.shows[*index].name))?;
+
+        self.state.position = (index + 1) % len;
+        self.save();
+        Some(self.config.shows[index].name.clone())
+    }
+
+    /// Remember that a show started playing
+    pub fn played(&mut self, name: &str) {
+        self.state.history.push_back(name.to_string());
+        while self.state.history.len() > self.config.min_gap.max(1) {
+            self.state.history.pop_front();
+        }
+        self.save();
+    }
+
+    /// Shows that are too recent to be picked at random
+    fn recent(&self) -> Vec<&ShowName> {
+        let skip = self.state.history.len().saturating_sub(self.config.min_gap);
+        self.state.history.iter().skip(skip).collect()
+    }
+
+    fn save(&self) {
+        let result = serde_json::to_string(&self.state)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| Ok(fs::write(&self.config.state_file, contents)?));
+        if let Err(e) = result {
+            warn!(
+                "Couldn't save the show history to {}: {}",
+                self.config.state_file.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{config::PlaylistEntry, test_util::TempDir};
+
+    fn names(names: &[&str]) -> Vec<ShowName> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    /// A playlist with no history, saving it in `dir`
+    fn playlist(dir: &Path, shows: &[(&str, u32)], min_gap: usize) -> Playlist {
+        let state_file = dir.join("show-history.json");
+        let _ = fs::remove_file(&state_file);
+
+        Playlist::load(&PlaylistConfig {
+            shows: shows
+                .iter()
+                .map(|(name, weight)| PlaylistEntry {
+                    name: name.to_string(),
+                    weight: *weight,
+                })
+                .collect(),
+            ordered: false,
+            min_gap,
+            state_file,
+            seed: None,
+        })
+    }
+
+    #[test]
+    fn test_weights() {
+        let dir = TempDir::new("playlist-weights");
+        let playlist = playlist(&dir, &[("a", 1), ("b", 3), ("missing", 100)], 0);
+        let available = names(&["a", "b", "unlisted"]);
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut picked_b = 0;
+        for _ in 0..4000 {
+            match playlist
+                .choose_random(&available, None, &mut rng)
+                .as_deref()
+            {
+                Some("b") => picked_b += 1,
+                Some("a") => {}
+                other => panic!("Picked {:?}", other),
+            }
+        }
+
+        // About three quarters of the picks
+        assert!((2800..3200).contains(&picked_b), "{}", picked_b);
+    }
+
+    #[test]
+    fn test_min_gap() {
+        let dir = TempDir::new("playlist-gap");
+        let mut playlist = playlist(&dir, &[], 2);
+        let available = names(&["a", "b", "c", "d"]);
+
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut played = Vec::new();
+        for _ in 0..100 {
+            let name = playlist.choose_random(&available, None, &mut rng).unwrap();
+            playlist.played(&name);
+            played.push(name);
+        }
+        for window in played.windows(3) {
+            assert!(
+                window[2] != window[0] && window[2] != window[1],
+                "{:?}",
+                window
+            );
+        }
+
+        // Only shows inside the gap are left, so the oldest one goes
+        let available = names(&["c", "d"]);
+        playlist.played("c");
+        playlist.played("d");
+        assert_eq!(
+            playlist.choose_random(&available, None, &mut rng),
+            Some("c".to_string())
+        );
+    }
+
+    #[test]
+    fn test_history_survives_restart() {
+        let dir = TempDir::new("playlist-restart");
+        let mut playlist = playlist(&dir, &[("a", 1), ("b", 1), ("c", 1)], 1);
+        assert_eq!(
+            playlist.choose_next(&names(&["a", "c"])),
+            Some("a".to_string())
+        );
+        assert_eq!(
+            playlist.choose_next(&names(&["a", "c"])),
+            Some("c".to_string())
+        );
+        playlist.played("c");
+
+        let mut reloaded = Playlist::load(&playlist.config);
+        assert_eq!(reloaded.state, playlist.state);
+
+        // Loops back around, and won't pick the last show at random
+        assert_eq!(
+            reloaded.choose_next(&names(&["a", "c"])),
+            Some("a".to_string())
+        );
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..20 {
+            assert_eq!(
+                reloaded.choose_random(&names(&["a", "c"]), None, &mut rng),
+                Some("a".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_no_immediate_repeat() {
+        let dir = TempDir::new("playlist-repeat");
+        let mut rng = StdRng::seed_from_u64(4);
+
+        // Nothing more is added to the history, so only `last_song` keeps
+        // the same show from coming up again
+        for (shows, min_gap) in [(&["a", "b"][..], 0), (&["a", "b", "c"], 5)] {
+            let mut playlist = playlist(&dir, &[], min_gap);
+            let available = names(shows);
+            if min_gap > 0 {
+                // Everything is too recent, so it falls back to the oldest
+                for name in &available {
+                    playlist.played(name);
+                }
+            }
+
+            let mut last_song = None;
+            for _ in 0..1000 {
+                let picked = playlist
+                    .choose_random(&available, last_song.as_ref(), &mut rng)
+                    .unwrap();
+                assert_ne!(Some(&picked), last_song.as_ref());
+                last_song = Some(picked);
+            }
+        }
+
+        // With only one show, it has to repeat
+        let playlist = playlist(&dir, &[], 1);
+        assert_eq!(
+            playlist.choose_random(&names(&["a"]), Some(&"a".to_string()), &mut rng),
+            Some("a".to_string())
+        );
+    }
+}
@@ -1,20 +1,27 @@
-use std::path::Path;
+use std::{collections::HashSet, path::Path, time::Duration};
 
-use log::info;
+use anyhow::Error;
+use common::{SerializableFrame, SerializableLaser, SerializablePoint, SerializableShow};
+use log::{info, warn};
 
 use crate::{
     audio::Audio,
     config::Config,
+    laser::{colour::ColourQuantizer, draw_time, pattern::pattern_id, MessageSendPack},
     prelude::{LoadedSong, LoadingSong},
+    InternalMessage,
 };
 
-use super::{LaserDataFrame, MAX_LASERS, MAX_LIGHTS, MAX_PROJECTORS, MAX_TURRETS};
+use super::{
+    show_file::{show_version, LaserV2, ProjectorV2, ShowFileV2, TurretV2, SHOW_FORMAT_VERSION},
+    LaserDataFrame, MAX_LASERS, MAX_LIGHTS, MAX_PROJECTORS, MAX_TURRETS,
+};
 
 pub type DmxStateData = u8;
-pub type DmxStateIndex = u8;
+pub type DmxStateIndex = u16;
 pub type DmxStateVarPosition = (DmxStateIndex, DmxStateData);
 
-/// A show contains a song and a list of frames. The song won't be loaded in
+/// A show contains a song, if it has one, and a list of frames. The song won't be loaded in
 /// until it is the next one up. This is to save memory. A show should be
 /// clonable from the show dictionary with ease.
 #[derive(Clone, Debug)]
@@ -24,23 +31,43 @@ pub enum Show {}
 pub struct UnloadedShow {
     pub name: String,
     pub frames: Vec<Frame>,
+    /// Play again from the start until another show is queued
+    pub looping: bool,
+    /// The show to play straight after this one
+    pub next: Option<String>,
+    /// What kind of show it is, for picking shows by tag
+    pub tags: Vec<String>,
 }
 
 /// Turn an unloaded show into a loaded show. This will be async because it
 /// needs to load the song from disk.
 impl UnloadedShow {
-    pub async fn load_show(self) -> LoadingShow {
+    pub async fn load_show(self, shows_dir: &Path) -> LoadingShow {
         // Load the song
         info!("Name is {}", self.name);
-        let song = match Audio::get_sound(&self.name) {
-            Ok(song) => song,
-            Err(e) => panic!("Error loading song: {}", e),
+        let has_song =
+            Audio::song_path(shows_dir, &self.name).exists() || Audio::is_embedded(&self.name);
+        let song = match has_song.then(|| Audio::get_sound(&self.name, shows_dir)) {
+            Some(Ok(song)) => Some(song),
+            Some(Err(e)) => {
+                warn!(
+                    "Couldn't load the song for {}, playing without it: {}",
+                    self.name, e
+                );
+                None
+            }
+            None => {
+                info!("{} has no song, playing it without audio", self.name);
+                None
+            }
         };
 
         LoadingShow {
             song,
             name: self.name,
             frames: self.frames,
+            looping: self.looping,
+            next: self.next,
         }
     }
 }
@@ -49,49 +76,190 @@ impl UnloadedShow {
 /// not be ready to play yet.
 #[derive(Clone, Debug)]
 pub struct LoadingShow {
-    pub song: LoadingSong,
+    /// Shows without a song play off their frames alone
+    pub song: Option<LoadingSong>,
     pub name: String,
     pub frames: Vec<Frame>,
+    pub looping: bool,
+    pub next: Option<String>,
 }
 
 impl LoadingShow {
+    /// Give up on the show, stopping its song from loading
+    pub fn cancel(self) {
+        info!("Cancelled loading {}", self.name);
+        if let Some(song) = &self.song {
+            song.cancel();
+        }
+    }
+
+    /// Whether the song has loaded, or has failed to
     pub fn is_ready(&self) -> bool {
-        self.song.stream.lock().unwrap().is_some()
+        self.song.as_ref().is_none_or(|song| {
+            song.stream.lock().unwrap().is_some() || song.failed.lock().unwrap().is_some()
+        })
     }
 
-    pub fn get_loaded_show(self) -> Result<LoadedShow, ()> {
+    pub fn get_loaded_show(self) -> Result<LoadedShow, Error> {
         // Verify that the song is loaded
-        match self.song.stream.lock().unwrap().clone() {
-            Some(stream) => Ok(LoadedShow {
-                song: LoadedSong {
-                    name: self.song.name,
+        let song = match self.song {
+            Some(song) => {
+                if let Some(e) = song.failed.lock().unwrap().clone() {
+                    return Err(Error::msg(format!("its song couldn't be loaded: {}", e)));
+                }
+                let Some(stream) = song.stream.lock().unwrap().clone() else {
+                    // It isn't wanted once it's given up on
+                    song.cancel();
+                    return Err(Error::msg("its song hasn't loaded yet"));
+                };
+                Some(LoadedSong {
+                    name: song.name,
                     stream,
-                },
-                name: self.name,
-                frames: self.frames,
-            }),
-            None => Err(()),
-        }
+                })
+            }
+            None => None,
+        };
+
+        Ok(LoadedShow {
+            song,
+            name: self.name,
+            frames: self.frames,
+            looping: self.looping,
+            next: self.next,
+        })
     }
 }
 
 // This is clone because the song is behind an Arc
 #[derive(Debug)]
 pub struct LoadedShow {
-    pub song: LoadedSong,
+    pub song: Option<LoadedSong>,
     pub name: String,
     pub frames: Vec<Frame>,
+    pub looping: bool,
+    pub next: Option<String>,
 }
 
 /// A frame consists of a timestamp since the beginning of this show, a list of
 /// commands for the lights, and a list of commands for the lasers.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Frame {
     pub timestamp: u64,
-    pub lights: Vec<Option<bool>>,
+    pub lights: Vec<Option<u8>>,
     pub lasers: Vec<Option<Laser>>,
     pub projectors: Vec<Option<Projector>>,
     pub turrets: Vec<Option<Turret>>,
+    /// Turrets to fire over GPIO, by ID
+    pub turret_fires: Vec<u8>,
+    /// Named DMX scene from the config to recall before the device updates
+    pub dmx_scene: Option<String>,
+    /// Raw DMX channel values, applied after the scene and the devices
+    pub dmx: Vec<DmxStateVarPosition>,
+}
+
+impl Frame {
+    /// A message for each active laser, addressed to that laser. Lasers in the
+    /// instruction file start at 1, which matches their ID on the bus.
+    pub fn laser_messages(&self, colours: &mut ColourQuantizer) -> Vec<MessageSendPack> {
+        self.lasers
+            .iter()
+            .enumerate()
+            .filter_map(|(index, laser)| {
+                let laser = laser.as_ref()?;
+
+                let laser_id = index as u8 + 1;
+                let mut message = MessageSendPack::draw(laser_id, Vec::new());
+                message.draw_instructions = colours.quantize(laser_id, &laser.draw_instructions);
+                message.header.oneshot = laser.oneshot;
+                message.header.speed_profile = laser.speed_profile().into();
+                Some(message)
+            })
+            .collect()
+    }
+
+    /// The DMX messages for this frame, in the order they're applied. The
+    /// scene goes first so that device values land on top of it, then the
+    /// devices, then raw channel values so they win over the devices. The last
+    /// message asks for everything to be sent.
+    pub fn dmx_messages(&self) -> Vec<InternalMessage> {
+        let mut messages = Vec::new();
+
+        if let Some(scene) = &self.dmx_scene {
+            messages.push(InternalMessage::DmxScene(scene.clone()));
+        }
+
+        for projector in self.projectors.iter().flatten() {
+            messages.push(InternalMessage::DmxUpdateState(vec![
+                projector.state,
+                projector.gallery,
+                projector.pattern,
+                projector.colour,
+            ]));
+        }
+
+        for turret in self.turrets.iter().flatten() {
+            messages.push(InternalMessage::DmxUpdateState(vec![
+                turret.state,
+                turret.pan,
+                turret.tilt,
+            ]));
+        }
+
+        if !self.dmx.is_empty() {
+            messages.push(InternalMessage::DmxUpdateState(self.dmx.clone()));
+        }
+
+        messages.push(InternalMessage::DmxSendRequest);
+
+        messages
+    }
+
+    /// The frame for the show-server. Projectors, turrets and raw channels are
+    /// flattened into channel values in the order `dmx_messages` applies them.
+    pub fn to_serializable(&self) -> SerializableFrame {
+        let mut dmx = Vec::new();
+        for projector in self.projectors.iter().flatten() {
+            dmx.extend([
+                projector.state,
+                projector.gallery,
+                projector.pattern,
+                projector.colour,
+            ]);
+        }
+        for turret in self.turrets.iter().flatten() {
+            dmx.extend([turret.state, turret.pan, turret.tilt]);
+        }
+        dmx.extend(&self.dmx);
+
+        SerializableFrame {
+            timestamp: self.timestamp,
+            lights: self.lights.clone(),
+            lasers: self
+                .lasers
+                .iter()
+                .map(|laser| {
+                    laser.as_ref().map(|laser| SerializableLaser {
+                        home: laser.home,
+                        enable: laser.enable,
+                        oneshot: laser.oneshot,
+                        speed_profile: laser.speed_profile(),
+                        draw_instructions: laser
+                            .draw_instructions
+                            .iter()
+                            .map(|point| SerializablePoint {
+                                pattern_id: point.pattern_id,
+                                r: point.r,
+                                g: point.g,
+                                b: point.b,
+                            })
+                            .collect(),
+                    })
+                })
+                .collect(),
+            dmx_scene: self.dmx_scene.clone(),
+            dmx,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -101,19 +269,55 @@ pub struct DmxState {
     pub values: Vec<u8>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Laser {
     // Laser conf
     pub home: bool,
     pub point_count: u8,
-    pub speed_profile: u8,
+    /// Only set when the show picks one, or `auto_speed` raises it
+    pub speed_profile: Option<u8>,
     pub enable: bool,
+    /// Draw the pattern once instead of repeating it until it's replaced.
+    /// Homing a laser clears this, so it's never set alongside `home`.
+    pub oneshot: bool,
+    /// Raise the speed profile until the pattern can be drawn before the next
+    /// frame
+    pub auto_speed: bool,
     // Laser
     pub hex: [u8; 3],
     pub value: u8,
+    /// The library pattern this laser draws, when the show names one
+    pub pattern: Option<String>,
+    /// What's sent to the laser, either the pattern's instructions or a
+    /// single one built from `value` and `hex`
+    pub draw_instructions: Vec<LaserDataFrame>,
+    /// Where the galvos travel, for estimating how long a pattern takes to
+    /// draw
+    pub points: Vec<(u16, u16)>,
 }
 
-#[derive(Clone, Debug)]
+/// What lasers draw at unless the show picks a speed profile
+pub const DEFAULT_SPEED_PROFILE: u8 = 1;
+
+impl Laser {
+    /// The speed profile that's sent
+    pub fn speed_profile(&self) -> u8 {
+        self.speed_profile.unwrap_or(DEFAULT_SPEED_PROFILE)
+    }
+}
+
+/// A laser that's estimated to still be drawing when the next frame starts
+#[derive(Debug, Clone, PartialEq)]
+pub struct DrawOverrun {
+    pub timestamp: u64,
+    /// Numbered from 1, like in show files
+    pub laser: usize,
+    pub speed_profile: u8,
+    pub draw_time: Duration,
+    pub gap: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Projector {
     pub state: DmxStateVarPosition,
     pub gallery: DmxStateVarPosition,
@@ -121,7 +325,7 @@ pub struct Projector {
     pub colour: DmxStateVarPosition,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Turret {
     pub state: DmxStateVarPosition,
     pub pan: DmxStateVarPosition,
@@ -129,15 +333,26 @@ pub struct Turret {
 }
 
 impl UnloadedShow {
-    pub fn load_show_file(show_file_path: &Path, config: &Config) -> Self {
+    /// The show as it's uploaded to the show-server
+    pub fn to_serializable(&self) -> SerializableShow {
+        SerializableShow {
+            name: self.name.clone(),
+            frames: self.frames.iter().map(Frame::to_serializable).collect(),
+        }
+    }
+
+    pub fn load_show_file(show_file_path: &Path, config: &Config) -> Result<Self, Error> {
         // The show name is in shows/<show_name>/instructions.json, extract it
         let show_name = show_file_path
             .parent()
-            .unwrap()
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap();
+            .and_then(Path::file_name)
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| {
+                Error::msg(format!(
+                    "{} isn't in a show folder",
+                    show_file_path.display()
+                ))
+            })?;
 
         // // Load the hardware configuration
         // let hardware_config = std::fs::read_to_string("src/show/assets/2024/hardware.json")
@@ -146,46 +361,186 @@ impl UnloadedShow {
         //     serde_json::from_str(&hardware_config).expect("Failed to parse hardware config");
 
         // Load the show file
-        let show_file = std::fs::read_to_string(show_file_path).unwrap();
-        let show_json: serde_json::Value = serde_json::from_str(&show_file).unwrap();
+        let show_file = std::fs::read_to_string(show_file_path)?;
+        let mut show = match show_version(&show_file) {
+            1 => Self::parse_v1(show_name, &show_file, config)?,
+            SHOW_FORMAT_VERSION => {
+                let show_file: ShowFileV2 = serde_json::from_str(&show_file)?;
+                UnloadedShow {
+                    name: show_name.to_string(),
+                    looping: show_file.looping,
+                    next: show_file.next.clone(),
+                    tags: show_file.tags.clone(),
+                    frames: show_file.into_frames(config)?,
+                }
+            }
+            version => {
+                return Err(Error::msg(format!(
+                    "Show format version {} isn't supported",
+                    version
+                )))
+            }
+        };
+
+        // Sort frames by timestamp
+        show.frames.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+
+        for overrun in show.schedule_lasers() {
+            warn!(
+                "Laser {} in show {} takes {:?} to draw at {}ms (speed profile {}), but the next frame is {:?} later",
+                overrun.laser,
+                show.name,
+                overrun.draw_time,
+                overrun.timestamp,
+                overrun.speed_profile,
+                overrun.gap
+            );
+        }
+
+        Ok(show)
+    }
+
+    /// The first format, an object of frames keyed by their timestamp, with a
+    /// key for each device
+    fn parse_v1(show_name: &str, show_file: &str, config: &Config) -> Result<Self, Error> {
+        let show_json: serde_json::Value = serde_json::from_str(show_file)?;
 
         let mut frames = Vec::new();
+        // Only warn about each one once
+        let mut unknown_lights = HashSet::new();
+        // Only loaded once a frame asks for a pattern by name
+        let mut patterns = None;
 
         // Process each timestamp frame
-        for (timestamp, frame) in show_json.as_object().unwrap() {
-            if timestamp == "song" {
+        let show_json = show_json
+            .as_object()
+            .ok_or_else(|| Error::msg("A show has to be an object of frames"))?;
+        let looping = match show_json.get("loop") {
+            Some(looping) => looping
+                .as_bool()
+                .ok_or_else(|| Error::msg("loop has to be true or false"))?,
+            None => false,
+        };
+        let next = match show_json.get("next") {
+            Some(next) => Some(
+                next.as_str()
+                    .ok_or_else(|| Error::msg("next has to be a show name"))?
+                    .to_string(),
+            ),
+            None => None,
+        };
+        let tags = match show_json.get("tags") {
+            Some(tags) => tags
+                .as_array()
+                .and_then(|tags| {
+                    tags.iter()
+                        .map(|tag| tag.as_str().map(String::from))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(|| Error::msg("tags has to be a list of names"))?,
+            None => Vec::new(),
+        };
+        for (timestamp, frame) in show_json {
+            if matches!(
+                timestamp.as_str(),
+                "song" | "version" | "loop" | "next" | "tags"
+            ) {
                 continue;
             }
 
-            let timestamp: u64 = timestamp.parse().unwrap();
-            let frame = frame.as_object().unwrap();
+            let timestamp: u64 = timestamp
+                .parse()
+                .map_err(|_| Error::msg(format!("Invalid timestamp: {}", timestamp)))?;
+            let frame = frame.as_object().ok_or_else(|| {
+                Error::msg(format!("The frame at {}ms isn't an object", timestamp))
+            })?;
 
             let mut lights = vec![None; MAX_LIGHTS];
             let mut lasers = vec![None; MAX_LASERS];
             let mut projectors = vec![None; MAX_PROJECTORS];
             let mut turrets = vec![None; MAX_TURRETS];
+            let mut turret_fires = Vec::new();
+            let mut dmx_scene = None;
+            let mut dmx = Vec::new();
+            // Applied once the frame's own lights are known
+            let mut groups = Vec::new();
 
             // Process each device in the frame
             for (device_name, device_state) in frame {
-                if let Some(light_num) = device_name.strip_prefix("light-") {
+                if device_name == "dmx-scene" {
+                    let scene = device_state
+                        .as_str()
+                        .ok_or_else(|| Error::msg("dmx-scene has to be a scene name"))?;
+                    if !config.dmx.scenes.contains_key(scene) {
+                        return Err(Error::msg(format!("Unknown DMX scene: {}", scene)));
+                    }
+                    dmx_scene = Some(scene.to_string());
+                } else if device_name == "dmx" {
+                    // Keys are either a DMX address or a device variable like
+                    // `lp-1.pattern`
+                    let channels = device_state
+                        .as_object()
+                        .ok_or_else(|| Error::msg("dmx has to map channels to values"))?;
+                    for (channel, value) in channels {
+                        let index = match channel.split_once('.') {
+                            Some((device, var)) => {
+                                config.get_dmx_state_var_position(device, var)?
+                            }
+                            None => channel.parse::<DmxStateIndex>().map_err(|_| {
+                                Error::msg(format!("Invalid DMX channel: {}", channel))
+                            })?,
+                        };
+                        dmx.push((index, value.as_u64().unwrap_or(0) as DmxStateData));
+                    }
+                } else if let Some(light_num) = device_name.strip_prefix("light-") {
+                    // Lights are addressed by their ID in the config
                     if let Ok(index) = light_num.parse::<usize>() {
-                        if index <= MAX_LIGHTS {
-                            let value = device_state.as_f64().unwrap_or(0.0) > 0.0;
-                            lights[index - 1] = Some(value);
+                        if !config.lights.iter().any(|light| light.id as usize == index)
+                            && unknown_lights.insert(index)
+                        {
+                            warn!("{} isn't in the config", device_name);
+                        }
+                        if (1..=MAX_LIGHTS).contains(&index) {
+                            lights[index - 1] = Some(light_level(device_state));
                         }
                     }
+                } else if let Some(group) = device_name.strip_prefix("group-") {
+                    let members = config
+                        .light
+                        .groups
+                        .get(group)
+                        .ok_or_else(|| Error::msg(format!("Unknown light group: {}", group)))?;
+                    groups.push((device_name.clone(), members, light_level(device_state)));
                 } else if let Some(laser_num) = device_name.strip_prefix("laser-") {
                     if let Ok(index) = laser_num.parse::<usize>() {
-                        if index <= MAX_LASERS {
+                        if (1..=MAX_LASERS).contains(&index) {
                             let laser = if device_state.is_number() {
                                 // Reset command
-                                Some(Laser {
-                                    home: false,
-                                    point_count: 0,
-                                    speed_profile: 0,
-                                    enable: true,
-                                    hex: [0, 0, 0],
-                                    value: 0,
+                                Some(LaserV2::default())
+                            } else if let Some(name) =
+                                device_state.get("pattern").and_then(|v| v.as_str())
+                            {
+                                // A pattern from the library
+                                let config = device_state.get("config");
+                                Some(LaserV2 {
+                                    home: config
+                                        .and_then(|c| c.get("home"))
+                                        .and_then(|v| v.as_bool())
+                                        .unwrap_or(false),
+                                    speed_profile: config
+                                        .and_then(|c| c.get("speed-profile"))
+                                        .and_then(|v| v.as_u64())
+                                        .map(|v| v as u8),
+                                    oneshot: config
+                                        .and_then(|c| c.get("oneshot"))
+                                        .and_then(|v| v.as_bool())
+                                        .unwrap_or(false),
+                                    auto_speed: config
+                                        .and_then(|c| c.get("auto_speed"))
+                                        .and_then(|v| v.as_bool())
+                                        .unwrap_or(false),
+                                    pattern: Some(name.to_string()),
+                                    ..Default::default()
                                 })
                             } else {
                                 // Full laser configuration
@@ -202,11 +557,34 @@ impl UnloadedShow {
                                         let speed_profile = config
                                             .get("speed-profile")
                                             .and_then(|v| v.as_u64())
-                                            .unwrap_or(0)
-                                            as u8;
+                                            .map(|v| v as u8);
 
-                                        let point_count =
-                                            points.unwrap().as_array().unwrap().len() as u8;
+                                        let oneshot = config
+                                            .get("oneshot")
+                                            .and_then(|v| v.as_bool())
+                                            .unwrap_or(false);
+
+                                        // Only the position of each point is used
+                                        let points = points
+                                            .and_then(|points| points.as_array())
+                                            .ok_or_else(|| {
+                                                Error::msg(format!(
+                                                    "{} needs a list of points",
+                                                    device_name
+                                                ))
+                                            })?
+                                            .iter()
+                                            .map(|point| {
+                                                let coord = |i: usize| {
+                                                    point
+                                                        .get(i)
+                                                        .and_then(|v| v.as_u64())
+                                                        .unwrap_or(0)
+                                                        as u16
+                                                };
+                                                (coord(0), coord(1))
+                                            })
+                                            .collect::<Vec<_>>();
 
                                         let hex_str = device_state
                                             .get("hex")
@@ -214,146 +592,110 @@ impl UnloadedShow {
                                             .unwrap_or("000");
 
                                         let hex: [u8; 3] = {
-                                            let mut values = hex_str
+                                            let values = hex_str
                                                 .chars()
                                                 .map(|c| match c {
-                                                    'F' | 'f' => 7,
-                                                    '0' => 0,
-                                                    _ => panic!("Invalid hex value: {}", c),
+                                                    'F' | 'f' => Ok(7),
+                                                    '0' => Ok(0),
+                                                    _ => Err(Error::msg(format!(
+                                                        "Invalid hex value: {}",
+                                                        c
+                                                    ))),
                                                 })
-                                                .collect::<Vec<u8>>();
+                                                .collect::<Result<Vec<u8>, _>>()?;
 
                                             // Check that exactly one value is 7
                                             if values.iter().filter(|&&x| x == 7).count() != 1 {
-                                                panic!("Exactly one color channel must be set to F in hex value: {}", hex_str);
+                                                return Err(Error::msg(format!("Exactly one color channel must be set to F in hex value: {}", hex_str)));
                                             }
 
-                                            values.try_into().unwrap()
+                                            values.try_into().map_err(|_| {
+                                                Error::msg(format!(
+                                                    "Hex value {} has to have 3 channels",
+                                                    hex_str
+                                                ))
+                                            })?
                                         };
 
-                                        let value_lookup = [
-                                            "bat",
-                                            "bow",
-                                            "bow_slow",
-                                            "candy",
-                                            "circle",
-                                            "circle_slow",
-                                            "clockwise_spiral_slow",
-                                            "counterclockwise_spiral_slow",
-                                            "crescent",
-                                            "ghost",
-                                            "gravestone_cross",
-                                            "hexagon",
-                                            "hexagon_slow",
-                                            "horizontal_lines_left_to_right_slow",
-                                            "horizontal_lines_right_to_left_slow",
-                                            "lightning_bolt",
-                                            "octagon",
-                                            "octagon_slow",
-                                            "parallelogram",
-                                            "parallelogram_slow",
-                                            "pentagon",
-                                            "pentagon_slow",
-                                            "pentagram",
-                                            "pentagram_slow",
-                                            "pumpkin",
-                                            "septagon_slow",
-                                            "square_large",
-                                            "square_large_slow",
-                                            "square_small",
-                                            "square_small_slow",
-                                            "star",
-                                            "star_slow",
-                                            "triangle_large",
-                                            "triangle_large_slow",
-                                            "triangle_small",
-                                            "triangle_small_slow",
-                                            "vertical_lines_bottom_to_top_slow",
-                                            "vertical_lines_top_to_bottom_slow",
-                                        ];
-
-                                        let value_config = device_state.get("value").unwrap();
-                                        let value = value_lookup
-                                            .iter()
-                                            .position(|&v| {
-                                                v == value_config
-                                                    .as_str()
-                                                    .unwrap()
-                                                    .replace("-", "_")
-                                            })
-                                            .unwrap()
-                                            as u8;
+                                        let value = device_state
+                                            .get("value")
+                                            .and_then(|value| value.as_str())
+                                            .and_then(pattern_id)
+                                            .ok_or_else(|| {
+                                                Error::msg(format!(
+                                                    "{} needs a known pattern as its value",
+                                                    device_name
+                                                ))
+                                            })?;
 
-                                        Some(Laser {
+                                        Some(LaserV2 {
                                             home,
-                                            point_count,
                                             speed_profile,
-                                            enable: true,
-                                            hex,
+                                            oneshot,
+                                            auto_speed: config
+                                                .get("auto_speed")
+                                                .and_then(|v| v.as_bool())
+                                                .unwrap_or(false),
                                             value,
+                                            colour: hex,
+                                            points,
+                                            ..Default::default()
                                         })
                                     }
                                     _ => None,
                                 }
                             };
-                            lasers[index - 1] = laser;
+                            lasers[index - 1] = laser
+                                .map(|laser| laser.into_laser(&mut patterns))
+                                .transpose()?;
                         }
                     }
                 } else if let Some(projector_num) = device_name.strip_prefix("lp-") {
                     if let Ok(index) = projector_num.parse::<usize>() {
-                        if index <= MAX_PROJECTORS {
-                            let projector = Projector {
-                                state: (
-                                    config.get_dmx_state_var_position(device_name, "state"),
-                                    device_state["state"].as_u64().unwrap_or(0) as u8,
-                                ),
-                                gallery: (
-                                    config.get_dmx_state_var_position(device_name, "gallery"),
-                                    device_state["gallery"].as_u64().unwrap_or(0) as u8,
-                                ),
-                                pattern: (
-                                    config.get_dmx_state_var_position(device_name, "pattern"),
-                                    device_state["pattern"].as_u64().unwrap_or(0) as u8,
-                                ),
-                                colour: (
-                                    config.get_dmx_state_var_position(device_name, "colour"),
-                                    device_state["colour"].as_u64().unwrap_or(0) as u8,
-                                ),
-                            };
+                        if (1..=MAX_PROJECTORS).contains(&index) {
+                            let projector = ProjectorV2 {
+                                state: device_state["state"].as_u64().unwrap_or(0) as u8,
+                                gallery: device_state["gallery"].as_u64().unwrap_or(0) as u8,
+                                pattern: device_state["pattern"].as_u64().unwrap_or(0) as u8,
+                                colour: device_state["colour"].as_u64().unwrap_or(0) as u8,
+                            }
+                            .into_projector(device_name, config)?;
                             projectors[index - 1] = Some(projector);
                         }
                     }
                 } else if let Some(turret_num) = device_name.strip_prefix("turret-") {
                     if let Ok(index) = turret_num.parse::<usize>() {
-                        if index <= MAX_TURRETS {
-                            let turret = Turret {
-                                state: (
-                                    config.get_dmx_state_var_position(device_name, "state"),
-                                    device_state["state"].as_u64().unwrap_or(0) as u8,
-                                ),
-                                pan: (
-                                    config.get_dmx_state_var_position(device_name, "pan"),
-                                    device_state["pan"].as_u64().unwrap_or(0) as u8,
-                                ),
-                                tilt: (
-                                    config.get_dmx_state_var_position(device_name, "tilt"),
-                                    device_state["tilt"].as_u64().unwrap_or(0) as u8,
-                                ),
-                            };
+                        if device_state.is_number() {
+                            // Air cannons are just fired
+                            if device_state.as_f64().unwrap_or(0.0) > 0.0 {
+                                turret_fires.push(index as u8);
+                            }
+                        } else if (1..=MAX_TURRETS).contains(&index) {
+                            let turret = TurretV2 {
+                                state: device_state["state"].as_u64().unwrap_or(0) as u8,
+                                pan: device_state["pan"].as_u64().unwrap_or(0) as u8,
+                                tilt: device_state["tilt"].as_u64().unwrap_or(0) as u8,
+                            }
+                            .into_turret(device_name, config)?;
                             turrets[index - 1] = Some(turret);
                         }
                     }
                 } else {
-                    panic!("Unknown device: {}", device_name);
+                    return Err(Error::msg(format!("Unknown device: {}", device_name)));
                 }
             }
 
+            apply_light_groups(&mut lights, groups, timestamp);
+
             let frame = Frame {
                 timestamp,
                 lights,
                 lasers,
                 projectors,
                 turrets,
+                turret_fires,
+                dmx_scene,
+                dmx,
             };
 
             // dbg!("Frame: {:?}", &frame);
@@ -361,13 +703,64 @@ impl UnloadedShow {
             frames.push(frame);
         }
 
-        // Sort frames by timestamp
-        frames.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
-
-        UnloadedShow {
+        Ok(UnloadedShow {
             name: show_name.to_string(),
             frames,
+            looping,
+            next,
+            tags,
+        })
+    }
+
+    /// Check that each laser can finish drawing before the next frame. Lasers
+    /// with `auto_speed` set have their speed profile raised until they fit,
+    /// and any that still don't are returned.
+    pub fn schedule_lasers(&mut self) -> Vec<DrawOverrun> {
+        let mut overruns = Vec::new();
+
+        for i in 1..self.frames.len() {
+            let gap =
+                Duration::from_millis(self.frames[i].timestamp - self.frames[i - 1].timestamp);
+            let frame = &mut self.frames[i - 1];
+
+            for (index, laser) in frame.lasers.iter_mut().enumerate() {
+                let Some(laser) = laser else {
+                    continue;
+                };
+
+                // Profile 7 is no faster than 6
+                if laser.auto_speed {
+                    let starting_profile = laser.speed_profile();
+                    let mut speed_profile = starting_profile;
+                    while speed_profile < 6 && draw_time(&laser.points, speed_profile) > gap {
+                        speed_profile += 1;
+                    }
+                    if speed_profile != starting_profile {
+                        info!(
+                            "Raised laser {} at {}ms from speed profile {} to {}",
+                            index + 1,
+                            frame.timestamp,
+                            starting_profile,
+                            speed_profile
+                        );
+                        laser.speed_profile = Some(speed_profile);
+                    }
+                }
+
+                let estimate = draw_time(&laser.points, laser.speed_profile());
+                if estimate > gap {
+                    overruns.push(DrawOverrun {
+                        timestamp: frame.timestamp,
+                        laser: index + 1,
+                        speed_profile: laser.speed_profile(),
+                        draw_time: estimate,
+                        gap,
+                    });
+                }
+            }
         }
+
+        overruns
     }
 
     // Update row_flashing to include empty DMX states
@@ -378,15 +771,18 @@ impl UnloadedShow {
                 lights: (0..MAX_LIGHTS)
                     .map(|light| {
                         if i as usize % MAX_LIGHTS == light {
-                            Some(true)
+                            Some(255)
                         } else {
-                            Some(false)
+                            Some(0)
                         }
                     })
                     .collect(),
                 lasers: (0..MAX_LASERS).map(|_| None).collect(),
                 projectors: (0..MAX_PROJECTORS).map(|_| None).collect(),
                 turrets: (0..MAX_TURRETS).map(|_| None).collect(),
+                turret_fires: Vec::new(),
+                dmx_scene: None,
+                dmx: Vec::new(),
             })
             .collect::<Vec<Frame>>()
     }
@@ -403,3 +799,524 @@ impl Default for LaserDataFrame {
         }
     }
 }
+
+/// Set the lights in each group to the group's level. A light set on its own
+/// wins over its group.
+pub(super) fn apply_light_groups(
+    lights: &mut [Option<u8>],
+    groups: Vec<(String, &Vec<u8>, u8)>,
+    timestamp: u64,
+) {
+    let set = lights.iter().map(Option::is_some).collect::<Vec<_>>();
+    for (group, members, level) in groups {
+        for id in members {
+            let index = *id as usize - 1;
+            if set[index] {
+                warn!(
+                    "light-{} is set at {}ms, so {} doesn't change it",
+                    id, timestamp, group
+                );
+            } else {
+                lights[index] = Some(level);
+            }
+        }
+    }
+}
+
+/// A light's level in a show file, written from 0.0 for off to 1.0 for on
+fn light_level(state: &serde_json::Value) -> u8 {
+    let value = state.as_f64().unwrap_or(0.0).clamp(0.0, 1.0);
+    (value * 255.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{
+        config, laser::pack::PatternPack, show::show_manager::ShowManager, test_util::TempDir,
+    };
+
+    fn scene_config() -> Config {
+        let mut config = Config::default();
+        config
+            .dmx
+            .scenes
+            .insert("flash".to_string(), BTreeMap::from([(1, 255)]));
+        config
+    }
+
+    #[test]
+    fn test_laser_messages_addressed_per_laser() {
+        let mut frame = UnloadedShow::row_flashing().remove(0);
+        frame.lasers[1] = Some(Laser {
+            home: false,
+            point_count: 1,
+            speed_profile: None,
+            enable: true,
+            oneshot: false,
+            auto_speed: false,
+            hex: [7, 0, 3],
+            value: 12,
+            pattern: None,
+            draw_instructions: vec![LaserDataFrame {
+                pattern_id: 12,
+                r: 255,
+                g: 0,
+                b: 109,
+            }],
+            points: vec![(0, 0)],
+        });
+
+        let messages = frame.laser_messages(&mut ColourQuantizer::default());
+        assert_eq!(messages.len(), 1);
+        assert_eq!(u8::from(messages[0].header.laser_id), 2);
+        // Left unset, it's drawn at the default
+        assert_eq!(u8::from(messages[0].header.speed_profile), 1);
+        assert_eq!(
+            messages[0].draw_instructions,
+            vec![PatternPack {
+                pattern_id: 12.into(),
+                red: 7.into(),
+                green: 0.into(),
+                blue: 3.into(),
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_load_laser_pattern() {
+        let show = UnloadedShow::load_show_file(
+            Path::new("tests/fixtures/shows/laser-pattern/instructions.json"),
+            &Config::default(),
+        )
+        .unwrap();
+
+        let laser = show.frames[0].lasers[2].as_ref().unwrap();
+        assert_eq!(laser.pattern.as_deref(), Some("bat"));
+        assert_eq!(laser.point_count, 4);
+
+        let messages = show.frames[0].laser_messages(&mut ColourQuantizer::default());
+        assert_eq!(messages.len(), 1);
+        assert_eq!(u8::from(messages[0].header.laser_id), 3);
+        assert_eq!(messages[0].draw_instructions.len(), 4);
+    }
+
+    #[test]
+    fn test_load_laser_oneshot() {
+        let show = UnloadedShow::load_show_file(
+            Path::new("tests/fixtures/shows/laser-oneshot/instructions.json"),
+            &Config::default(),
+        )
+        .unwrap();
+
+        let lasers = &show.frames[0].lasers;
+        assert!(lasers[0].as_ref().unwrap().oneshot);
+        assert!(lasers[1].as_ref().unwrap().oneshot);
+        assert!(!lasers[2].as_ref().unwrap().oneshot);
+        // Homing clears oneshot
+        let homed = lasers[3].as_ref().unwrap();
+        assert!(homed.home);
+        assert!(!homed.oneshot);
+
+        let oneshot = show.frames[0]
+            .laser_messages(&mut ColourQuantizer::default())
+            .iter()
+            .map(|message| message.header.oneshot)
+            .collect::<Vec<_>>();
+        assert_eq!(oneshot, vec![true, true, false, false]);
+    }
+
+    #[test]
+    fn test_save_laser_round_trip() {
+        let show = UnloadedShow::load_show_file(
+            Path::new("tests/fixtures/shows/laser-oneshot/instructions.json"),
+            &Config::default(),
+        )
+        .unwrap();
+
+        let dir = TempDir::new("oneshot");
+        let path = dir.join("laser-oneshot").join("instructions.json");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(
+            &path,
+            ShowManager::save_show(show.clone(), &Config::default()),
+        )
+        .unwrap();
+
+        let saved = UnloadedShow::load_show_file(&path, &Config::default()).unwrap();
+
+        assert_eq!(saved.frames.len(), show.frames.len());
+        for (saved, frame) in saved.frames.iter().zip(&show.frames) {
+            for (saved, laser) in saved.lasers.iter().zip(&frame.lasers) {
+                let (Some(saved), Some(laser)) = (saved, laser) else {
+                    assert_eq!(saved.is_some(), laser.is_some());
+                    continue;
+                };
+                assert_eq!(saved.home, laser.home);
+                assert_eq!(saved.oneshot, laser.oneshot);
+                assert_eq!(saved.speed_profile, laser.speed_profile);
+                assert_eq!(saved.point_count, laser.point_count);
+                assert_eq!(saved.pattern, laser.pattern);
+                assert_eq!(saved.draw_instructions, laser.draw_instructions);
+                assert_eq!(saved.points, laser.points);
+            }
+        }
+    }
+
+    fn laser_drawing(points: Vec<(u16, u16)>, auto_speed: bool) -> Option<Laser> {
+        Some(Laser {
+            home: false,
+            point_count: points.len() as u8,
+            speed_profile: Some(0),
+            enable: true,
+            oneshot: false,
+            auto_speed,
+            hex: [7, 0, 0],
+            value: 0,
+            pattern: None,
+            draw_instructions: vec![LaserDataFrame::default(); points.len()],
+            points,
+        })
+    }
+
+    #[test]
+    fn test_schedule_lasers() {
+        let mut frames = UnloadedShow::row_flashing();
+        frames.truncate(2);
+        frames[1].timestamp = 100;
+
+        // 1200 steps takes 2.4s at speed profile 0, but only 80ms at 6
+        let triangle = vec![(0, 0), (300, 0), (0, 400)];
+        frames[0].lasers[0] = laser_drawing(triangle.clone(), false);
+        frames[0].lasers[1] = laser_drawing(triangle, true);
+        frames[0].lasers[2] = laser_drawing(vec![(0, 0), (10, 0)], true);
+        // Nothing comes after the last frame
+        frames[1].lasers[0] = laser_drawing(vec![(0, 0), (300, 300)], false);
+
+        let mut show = UnloadedShow {
+            name: "schedule".to_string(),
+            frames,
+            looping: false,
+            next: None,
+            tags: Vec::new(),
+        };
+        let overruns = show.schedule_lasers();
+
+        assert_eq!(
+            overruns,
+            vec![DrawOverrun {
+                timestamp: 0,
+                laser: 1,
+                speed_profile: 0,
+                draw_time: Duration::from_millis(2400),
+                gap: Duration::from_millis(100),
+            }]
+        );
+
+        let lasers = &show.frames[0].lasers;
+        assert_eq!(lasers[1].as_ref().unwrap().speed_profile, Some(6));
+        // Already fits, so it's left alone
+        assert_eq!(lasers[2].as_ref().unwrap().speed_profile, Some(0));
+        assert_eq!(
+            u8::from(
+                show.frames[0].laser_messages(&mut ColourQuantizer::default())[1]
+                    .header
+                    .speed_profile
+            ),
+            6
+        );
+    }
+
+    #[test]
+    fn test_load_dmx_scene() {
+        let show = UnloadedShow::load_show_file(
+            Path::new("tests/fixtures/shows/dmx-scene/instructions.json"),
+            &scene_config(),
+        )
+        .unwrap();
+
+        assert_eq!(show.name, "dmx-scene");
+        assert_eq!(show.frames[0].dmx_scene.as_deref(), Some("flash"));
+        assert_eq!(show.frames[1].dmx_scene, None);
+    }
+
+    #[test]
+    fn test_load_unknown_dmx_scene() {
+        let error = UnloadedShow::load_show_file(
+            Path::new("tests/fixtures/shows/dmx-scene/instructions.json"),
+            &Config::default(),
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "Unknown DMX scene: flash");
+    }
+
+    fn dmx_config() -> Config {
+        Config {
+            projectors: vec![config::Projector {
+                id: 20,
+                format: vec!["state".into(), "gallery".into(), "pattern".into()],
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_load_dmx_channels() {
+        let show = UnloadedShow::load_show_file(
+            Path::new("tests/fixtures/shows/dmx/instructions.json"),
+            &dmx_config(),
+        )
+        .unwrap();
+
+        assert_eq!(show.frames[0].dmx, vec![(1, 255), (22, 5)]);
+        assert_eq!(show.frames[1].dmx, vec![(1, 0), (310, 128)]);
+        assert!(show.frames[2].dmx.is_empty());
+    }
+
+    #[test]
+    fn test_load_light_levels() {
+        let show = UnloadedShow::load_show_file(
+            Path::new("tests/fixtures/shows/dmx/instructions.json"),
+            &dmx_config(),
+        )
+        .unwrap();
+
+        assert_eq!(show.frames[2].lights[0], Some(255));
+        assert_eq!(show.frames[2].lights[1], Some(102));
+        assert_eq!(show.frames[2].lights[2], None);
+    }
+
+    #[test]
+    fn test_save_dmx_round_trip() {
+        let config = dmx_config();
+        let show = UnloadedShow::load_show_file(
+            Path::new("tests/fixtures/shows/dmx/instructions.json"),
+            &config,
+        )
+        .unwrap();
+
+        let dir = TempDir::new("dmx-show");
+        let path = dir.join("dmx").join("instructions.json");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, ShowManager::save_show(show.clone(), &config)).unwrap();
+
+        let saved = UnloadedShow::load_show_file(&path, &config).unwrap();
+
+        assert_eq!(saved.frames.len(), show.frames.len());
+        for (saved, frame) in saved.frames.iter().zip(&show.frames) {
+            let mut saved_dmx = saved.dmx.clone();
+            let mut dmx = frame.dmx.clone();
+            saved_dmx.sort();
+            dmx.sort();
+
+            assert_eq!(saved.timestamp, frame.timestamp);
+            assert_eq!(saved_dmx, dmx);
+            assert_eq!(saved.lights, frame.lights);
+        }
+    }
+
+    #[test]
+    fn test_load_turret_fires() {
+        let show = UnloadedShow::load_show_file(
+            Path::new("tests/fixtures/shows/turret/instructions.json"),
+            &Config::default(),
+        )
+        .unwrap();
+
+        // Nothing fires at 0
+        assert_eq!(show.frames[0].turret_fires, vec![1]);
+        assert_eq!(show.frames[1].turret_fires, vec![2]);
+        assert!(show.frames[0].turrets.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn test_load_loop_and_next() {
+        let show = UnloadedShow::load_show_file(
+            Path::new("tests/fixtures/shows/looping/instructions.json"),
+            &Config::default(),
+        )
+        .unwrap();
+        assert!(show.looping);
+        assert_eq!(show.next.as_deref(), Some("finale"));
+        assert_eq!(show.tags, vec!["kids".to_string()]);
+        assert_eq!(show.frames.len(), 2);
+
+        // They're kept when the show is written back out
+        let saved: serde_json::Value =
+            serde_json::from_str(&ShowManager::save_show(show, &Config::default())).unwrap();
+        assert_eq!(saved["loop"], true);
+        assert_eq!(saved["next"], "finale");
+        assert_eq!(saved["tags"], serde_json::json!(["kids"]));
+
+        // Shows without them play once
+        let show = UnloadedShow::load_show_file(
+            Path::new("tests/fixtures/shows/turret/instructions.json"),
+            &Config::default(),
+        )
+        .unwrap();
+        assert!(!show.looping);
+        assert!(show.next.is_none());
+    }
+
+    fn group_config() -> Config {
+        Config {
+            lights: (1..=4)
+                .map(|id| config::Light {
+                    pin: config::Pin::Gpio(pi_pinout::GpioPin(id)),
+                    id,
+                    name: None,
+                    pwm: true,
+                    inverted: None,
+                    min_toggle_interval_ms: 0,
+                })
+                .collect(),
+            light: config::LightConfig {
+                groups: BTreeMap::from([("pumpkin".to_string(), vec![1, 2, 3])]),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_load_light_groups() {
+        let show = UnloadedShow::load_show_file(
+            Path::new("tests/fixtures/shows/light-groups/instructions.json"),
+            &group_config(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            show.frames[0].lights[..4],
+            [Some(255), Some(255), Some(255), None]
+        );
+        // The light set on its own keeps its level
+        assert_eq!(
+            show.frames[1].lights[..4],
+            [Some(102), Some(0), Some(102), None]
+        );
+    }
+
+    #[test]
+    fn test_save_light_groups_round_trip() {
+        let config = group_config();
+        let show = UnloadedShow::load_show_file(
+            Path::new("tests/fixtures/shows/light-groups/instructions.json"),
+            &config,
+        )
+        .unwrap();
+
+        let dir = TempDir::new("groups");
+        let path = dir.join("light-groups").join("instructions.json");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let saved_json = ShowManager::save_show(show.clone(), &config);
+        std::fs::write(&path, &saved_json).unwrap();
+
+        let saved = UnloadedShow::load_show_file(&path, &config).unwrap();
+
+        for (saved, frame) in saved.frames.iter().zip(&show.frames) {
+            assert_eq!(saved.lights, frame.lights);
+        }
+
+        // Only frames where the whole group agrees are collapsed
+        let json: serde_json::Value = serde_json::from_str(&saved_json).unwrap();
+        assert_eq!(
+            json["frames"][2]["groups"],
+            serde_json::json!({ "pumpkin": 255 })
+        );
+        assert_eq!(
+            json["frames"][2]["lights"],
+            serde_json::json!([null, null, null, 128])
+        );
+        assert!(json["frames"][1].get("groups").is_none());
+        assert_eq!(
+            json["frames"][1]["lights"],
+            serde_json::json!([102, 0, 102])
+        );
+    }
+
+    #[test]
+    fn test_v2_round_trip() {
+        for (name, config) in [
+            ("laser-oneshot", Config::default()),
+            ("laser-pattern", Config::default()),
+            ("dmx", dmx_config()),
+            ("dmx-scene", scene_config()),
+            ("light-groups", group_config()),
+            ("looping", Config::default()),
+            ("turret", Config::default()),
+        ] {
+            let path = format!("tests/fixtures/shows/{}/instructions.json", name);
+            let show = UnloadedShow::load_show_file(Path::new(&path), &config).unwrap();
+
+            let dir = TempDir::new("v2");
+            let path = dir.join(name).join("instructions.json");
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let saved_json = ShowManager::save_show(show.clone(), &config);
+            std::fs::write(&path, &saved_json).unwrap();
+
+            let saved = UnloadedShow::load_show_file(&path, &config).unwrap();
+
+            assert_eq!(show_version(&saved_json), SHOW_FORMAT_VERSION, "{}", name);
+            assert_eq!(saved.frames, show.frames, "{}", name);
+            assert_eq!(saved.looping, show.looping, "{}", name);
+            assert_eq!(saved.next, show.next, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_load_v2() {
+        let show = UnloadedShow::load_show_file(
+            Path::new("tests/fixtures/shows/v2/instructions.json"),
+            &dmx_config(),
+        )
+        .unwrap();
+        assert!(show.looping);
+        assert_eq!(show.frames.len(), 2);
+
+        let frame = &show.frames[0];
+        assert_eq!(frame.lights[..4], [Some(255), None, Some(102), None]);
+        assert_eq!(frame.lights.len(), MAX_LIGHTS);
+        assert_eq!(frame.dmx, vec![(1, 255)]);
+
+        let pattern = frame.lasers[0].as_ref().unwrap();
+        assert_eq!(pattern.pattern.as_deref(), Some("bat"));
+        assert!(pattern.oneshot);
+        assert!(!pattern.draw_instructions.is_empty());
+
+        let drawing = frame.lasers[1].as_ref().unwrap();
+        assert_eq!(drawing.speed_profile, Some(2));
+        assert_eq!(drawing.point_count, 2);
+        assert_eq!(
+            drawing.draw_instructions,
+            vec![LaserDataFrame {
+                pattern_id: 4,
+                r: 0,
+                g: crate::laser::colour::expand(7),
+                b: 0,
+            }]
+        );
+
+        // An empty laser is a reset
+        let frame = &show.frames[1];
+        assert_eq!(
+            frame.lasers[0].as_ref().unwrap().draw_instructions,
+            vec![LaserDataFrame::default()]
+        );
+        assert_eq!(frame.turret_fires, vec![2]);
+    }
+
+    #[test]
+    fn test_load_unsupported_version() {
+        let dir = TempDir::new("v3");
+        let path = dir.join("future").join("instructions.json");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, r#"{ "version": 3, "frames": [] }"#).unwrap();
+
+        let error = UnloadedShow::load_show_file(&path, &Config::default()).unwrap_err();
+        assert_eq!(error.to_string(), "Show format version 3 isn't supported");
+    }
+}
@@ -0,0 +1,734 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::File,
+    io::BufReader,
+    path::Path,
+};
+
+use anyhow::Error;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    laser::{
+        colour,
+        pattern::{pattern_id, PatternLibrary},
+    },
+};
+
+use super::{
+    show::{
+        apply_light_groups, DmxStateData, DmxStateVarPosition, Frame, Laser, Projector, Turret,
+        UnloadedShow,
+    },
+    LaserDataFrame, MAX_LASERS, MAX_LIGHTS, MAX_PROJECTORS, MAX_TURRETS,
+};
+
+/// The version `save_show` writes
+pub const SHOW_FORMAT_VERSION: u32 = 2;
+
+/// The version of a show file. Files from before versions were written are 1.
+#[derive(Deserialize)]
+struct ShowVersion {
+    #[serde(default = "first_version")]
+    version: u32,
+}
+
+fn first_version() -> u32 {
+    1
+}
+
+/// Which format a show file is in. Anything that isn't an object with a
+/// version is left for the first format's loader to complain about.
+pub fn show_version(contents: &str) -> u32 {
+    serde_json::from_str::<ShowVersion>(contents).map_or(1, |show| show.version)
+}
+
+/// Just the tags from a show file of either format
+#[derive(Deserialize)]
+struct ShowTags {
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+/// The tags in the show file at `path`. The frames are skipped over without
+/// being kept, and a file that can't be read has none, since it's reported
+/// once the show is parsed.
+pub fn show_tags(path: &Path) -> Vec<String> {
+    File::open(path)
+        .ok()
+        .and_then(|file| serde_json::from_reader::<_, ShowTags>(BufReader::new(file)).ok())
+        .map(|show| show.tags)
+        .unwrap_or_default()
+}
+
+/// A show file with a `"version": 2` header. Frames are a list, and each
+/// device type is a list indexed by ID from 1, where `null` leaves that
+/// device as it is.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShowFileV2 {
+    pub version: u32,
+    /// Play again from the start until another show is queued
+    #[serde(rename = "loop", default, skip_serializing_if = "is_false")]
+    pub looping: bool,
+    /// The show to play straight after this one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+    /// What kind of show it is, for picking shows by tag
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    pub frames: Vec<FrameV2>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FrameV2 {
+    pub timestamp: u64,
+    /// Levels from 0 to 255
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lights: Vec<Option<u8>>,
+    /// Levels for light groups from the config. A light set in `lights` keeps
+    /// its own level.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub groups: BTreeMap<String, u8>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lasers: Vec<Option<LaserV2>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub projectors: Vec<Option<ProjectorV2>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub turrets: Vec<Option<TurretV2>>,
+    /// Turrets to fire over GPIO, by ID
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub turret_fires: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dmx_scene: Option<String>,
+    /// `[channel, value]` pairs, applied after the scene and the devices
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dmx: Vec<DmxStateVarPosition>,
+}
+
+/// A laser with everything left out is a reset
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LaserV2 {
+    #[serde(skip_serializing_if = "is_false")]
+    pub home: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed_profile: Option<u8>,
+    #[serde(skip_serializing_if = "is_true")]
+    pub enable: bool,
+    #[serde(skip_serializing_if = "is_false")]
+    pub oneshot: bool,
+    #[serde(skip_serializing_if = "is_false")]
+    pub auto_speed: bool,
+    /// A pattern from the library, which is drawn instead of the points
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    /// The pattern ID the points are drawn with
+    #[serde(skip_serializing_if = "is_zero")]
+    pub value: u8,
+    /// Red, green and blue from 0 to 7
+    #[serde(skip_serializing_if = "is_black")]
+    pub colour: [u8; 3],
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub points: Vec<(u16, u16)>,
+    /// Only written when it isn't the number of points, or of the pattern's
+    /// instructions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub point_count: Option<u8>,
+    /// Only written when they aren't the single instruction `value` and
+    /// `colour` make
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub draw_instructions: Option<Vec<LaserDataFrame>>,
+}
+
+impl Default for LaserV2 {
+    fn default() -> Self {
+        LaserV2 {
+            home: false,
+            speed_profile: None,
+            enable: true,
+            oneshot: false,
+            auto_speed: false,
+            pattern: None,
+            value: 0,
+            colour: [0, 0, 0],
+            points: Vec::new(),
+            point_count: None,
+            draw_instructions: None,
+        }
+    }
+}
+
+/// Channels come from the projector's entry in the config
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProjectorV2 {
+    pub state: DmxStateData,
+    pub gallery: DmxStateData,
+    pub pattern: DmxStateData,
+    pub colour: DmxStateData,
+}
+
+/// Channels come from the turret's entry in the config
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TurretV2 {
+    pub state: DmxStateData,
+    pub pan: DmxStateData,
+    pub tilt: DmxStateData,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+fn is_true(value: &bool) -> bool {
+    *value
+}
+
+fn is_zero(value: &u8) -> bool {
+    *value == 0
+}
+
+fn is_black(colour: &[u8; 3]) -> bool {
+    *colour == [0, 0, 0]
+}
+
+impl ShowFileV2 {
+    /// Lights are written as their group wherever every light in a group
+    /// from `config` is at the same level
+    pub fn from_show(show: &UnloadedShow, config: &Config) -> Self {
+        let frames = show
+            .frames
+            .iter()
+            .map(|frame| {
+                let mut lights = frame.lights.clone();
+                let mut groups = BTreeMap::new();
+                for (group, members) in &config.light.groups {
+                    let levels = members
+                        .iter()
+                        .map(|id| lights.get(*id as usize - 1).copied().flatten())
+                        .collect::<Vec<_>>();
+                    // A light already written as part of another group is
+                    // `None` by now, so it's never in two
+                    let Some(Some(level)) = levels.first().copied() else {
+                        continue;
+                    };
+                    if levels.iter().all(|other| *other == Some(level)) {
+                        groups.insert(group.clone(), level);
+                        for id in members {
+                            lights[*id as usize - 1] = None;
+                        }
+                    }
+                }
+
+                FrameV2 {
+                    timestamp: frame.timestamp,
+                    lights: trimmed(lights),
+                    groups,
+                    lasers: trimmed(
+                        frame
+                            .lasers
+                            .iter()
+                            .map(|laser| laser.as_ref().map(LaserV2::from))
+                            .collect(),
+                    ),
+                    projectors: trimmed(
+                        frame
+                            .projectors
+                            .iter()
+                            .map(|projector| projector.as_ref().map(ProjectorV2::from))
+                            .collect(),
+                    ),
+                    turrets: trimmed(
+                        frame
+                            .turrets
+                            .iter()
+                            .map(|turret| turret.as_ref().map(TurretV2::from))
+                            .collect(),
+                    ),
+                    turret_fires: frame.turret_fires.clone(),
+                    dmx_scene: frame.dmx_scene.clone(),
+                    dmx: frame.dmx.clone(),
+                }
+            })
+            .collect();
+
+        ShowFileV2 {
+            version: SHOW_FORMAT_VERSION,
+            looping: show.looping,
+            next: show.next.clone(),
+            tags: show.tags.clone(),
+            frames,
+        }
+    }
+
+    /// The frames in the order they're written
+    pub fn into_frames(self, config: &Config) -> Result<Vec<Frame>, Error> {
+        // Only warn about each one once
+        let unknown_lights = self
+            .frames
+            .iter()
+            .flat_map(|frame| frame.lights.iter().enumerate())
+            .filter(|(_, level)| level.is_some())
+            .map(|(index, _)| index + 1)
+            .filter(|id| !config.lights.iter().any(|light| light.id as usize == *id))
+            .collect::<HashSet<_>>();
+        for id in unknown_lights {
+            warn!("light-{} isn't in the config", id);
+        }
+
+        // Only loaded once a frame asks for a pattern by name
+        let mut patterns = None;
+        self.frames
+            .into_iter()
+            .map(|frame| frame.into_frame(config, &mut patterns))
+            .collect()
+    }
+}
+
+impl FrameV2 {
+    fn into_frame(
+        self,
+        config: &Config,
+        patterns: &mut Option<PatternLibrary>,
+    ) -> Result<Frame, Error> {
+        let timestamp = self.timestamp;
+
+        let mut lights = padded(self.lights, MAX_LIGHTS, "lights", timestamp)?;
+        let groups = self
+            .groups
+            .iter()
+            .map(|(group, level)| {
+                let members = config
+                    .light
+                    .groups
+                    .get(group)
+                    .ok_or_else(|| Error::msg(format!("Unknown light group: {}", group)))?;
+                Ok((format!("group-{}", group), members, *level))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        apply_light_groups(&mut lights, groups, timestamp);
+
+        let lasers = padded(self.lasers, MAX_LASERS, "lasers", timestamp)?
+            .into_iter()
+            .map(|laser| laser.map(|laser| laser.into_laser(patterns)).transpose())
+            .collect::<Result<Vec<_>, _>>()?;
+        let projectors = padded(self.projectors, MAX_PROJECTORS, "projectors", timestamp)?
+            .into_iter()
+            .enumerate()
+            .map(|(index, projector)| {
+                projector
+                    .map(|projector| projector.into_projector(&format!("lp-{}", index + 1), config))
+                    .transpose()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let turrets = padded(self.turrets, MAX_TURRETS, "turrets", timestamp)?
+            .into_iter()
+            .enumerate()
+            .map(|(index, turret)| {
+                turret
+                    .map(|turret| turret.into_turret(&format!("turret-{}", index + 1), config))
+                    .transpose()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if let Some(scene) = &self.dmx_scene {
+            if !config.dmx.scenes.contains_key(scene) {
+                return Err(Error::msg(format!("Unknown DMX scene: {}", scene)));
+            }
+        }
+
+        Ok(Frame {
+            timestamp,
+            lights,
+            lasers,
+            projectors,
+            turrets,
+            turret_fires: self.turret_fires,
+            dmx_scene: self.dmx_scene,
+            dmx: self.dmx,
+        })
+    }
+}
+
+impl LaserV2 {
+    /// Library patterns are looked up in `patterns`, which is loaded the first
+    /// time one is needed
+    pub fn into_laser(self, patterns: &mut Option<PatternLibrary>) -> Result<Laser, Error> {
+        // Homing clears oneshot
+        let oneshot = self.oneshot && !self.home;
+        if self.colour.iter().any(|channel| *channel > 7) {
+            return Err(Error::msg(format!(
+                "Laser colour {:?} has to be from 0 to 7 on each channel",
+                self.colour
+            )));
+        }
+
+        match self.pattern {
+            Some(name) => {
+                if patterns.is_none() {
+                    *patterns = Some(PatternLibrary::load()?);
+                }
+                let library = patterns.as_ref().unwrap();
+                let draw_instructions = library
+                    .get(&name)
+                    .ok_or_else(|| Error::msg(format!("Unknown laser pattern: {}", name)))?
+                    .clone();
+                let value = pattern_id(&name)
+                    .ok_or_else(|| Error::msg(format!("Unknown laser pattern: {}", name)))?;
+
+                Ok(Laser {
+                    home: self.home,
+                    point_count: self.point_count.unwrap_or(draw_instructions.len() as u8),
+                    speed_profile: self.speed_profile,
+                    enable: self.enable,
+                    oneshot,
+                    auto_speed: self.auto_speed,
+                    hex: self.colour,
+                    value,
+                    pattern: Some(name),
+                    draw_instructions,
+                    points: library.outlines.get(&value).cloned().unwrap_or_default(),
+                })
+            }
+            None => Ok(Laser {
+                home: self.home,
+                point_count: self.point_count.unwrap_or(self.points.len() as u8),
+                speed_profile: self.speed_profile,
+                enable: self.enable,
+                oneshot,
+                auto_speed: self.auto_speed,
+                hex: self.colour,
+                value: self.value,
+                pattern: None,
+                draw_instructions: self
+                    .draw_instructions
+                    .unwrap_or_else(|| vec![drawn_instruction(self.value, self.colour)]),
+                points: self.points,
+            }),
+        }
+    }
+}
+
+/// Points drawn with `value` in one colour
+fn drawn_instruction(value: u8, hex: [u8; 3]) -> LaserDataFrame {
+    LaserDataFrame {
+        pattern_id: value,
+        r: colour::expand(hex[0]),
+        g: colour::expand(hex[1]),
+        b: colour::expand(hex[2]),
+    }
+}
+
+impl From<&Laser> for LaserV2 {
+    fn from(laser: &Laser) -> Self {
+        let settings = LaserV2 {
+            home: laser.home,
+            speed_profile: laser.speed_profile,
+            enable: laser.enable,
+            oneshot: laser.oneshot,
+            auto_speed: laser.auto_speed,
+            colour: laser.hex,
+            ..Default::default()
+        };
+
+        match &laser.pattern {
+            // The instructions, points and value come from the library
+            Some(pattern) => LaserV2 {
+                pattern: Some(pattern.clone()),
+                point_count: Some(laser.point_count)
+                    .filter(|count| *count as usize != laser.draw_instructions.len()),
+                ..settings
+            },
+            None => LaserV2 {
+                value: laser.value,
+                points: laser.points.clone(),
+                point_count: Some(laser.point_count)
+                    .filter(|count| *count as usize != laser.points.len()),
+                draw_instructions: Some(laser.draw_instructions.clone())
+                    .filter(|draw| *draw != [drawn_instruction(laser.value, laser.hex)]),
+                ..settings
+            },
+        }
+    }
+}
+
+impl ProjectorV2 {
+    pub fn into_projector(self, device: &str, config: &Config) -> Result<Projector, Error> {
+        let channel = |var: &str| config.get_dmx_state_var_position(device, var);
+        Ok(Projector {
+            state: (channel("state")?, self.state),
+            gallery: (channel("gallery")?, self.gallery),
+            pattern: (channel("pattern")?, self.pattern),
+            colour: (channel("colour")?, self.colour),
+        })
+    }
+}
+
+impl From<&Projector> for ProjectorV2 {
+    fn from(projector: &Projector) -> Self {
+        ProjectorV2 {
+            state: projector.state.1,
+            gallery: projector.gallery.1,
+            pattern: projector.pattern.1,
+            colour: projector.colour.1,
+        }
+    }
+}
+
+impl TurretV2 {
+    pub fn into_turret(self, device: &str, config: &Config) -> Result<Turret, Error> {
+        let channel = |var: &str| config.get_dmx_state_var_position(device, var);
+        Ok(Turret {
+            state: (channel("state")?, self.state),
+            pan: (channel("pan")?, self.pan),
+            tilt: (channel("tilt")?, self.tilt),
+        })
+    }
+}
+
+impl From<&Turret> for TurretV2 {
+    fn from(turret: &Turret) -> Self {
+        TurretV2 {
+            state: turret.state.1,
+            pan: turret.pan.1,
+            tilt: turret.tilt.1,
+        }
+    }
+}
+
+/// Devices after the last one that's set are left out
+fn trimmed<T>(mut devices: Vec<Option<T>>) -> Vec<Option<T>> {
+    while matches!(devices.last(), Some(None)) {
+        devices.pop();
+    }
+    devices
+}
+
+/// Filled out to one entry for each device there can be
+fn padded<T>(
+    mut devices: Vec<Option<T>>,
+    count: usize,
+    kind: &str,
+    timestamp: u64,
+) -> Result<Vec<Option<T>>, Error> {
+    if devices.len() > count {
+        return Err(Error::msg(format!(
+            "The frame at {}ms has {} {}, but there can only be {}",
+            timestamp,
+            devices.len(),
+            kind,
+            count
+        )));
+    }
+    devices.resize_with(count, || None);
+    Ok(devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        config, laser::pattern::PATTERN_NAMES, show::prelude::ShowManager, test_util::TempDir,
+    };
+
+    fn device_config() -> Config {
+        let mut config = Config {
+            projectors: vec![config::Projector {
+                id: 20,
+                format: ["state", "gallery", "pattern", "colour"]
+                    .map(String::from)
+                    .to_vec(),
+            }],
+            turrets: (0..MAX_TURRETS as u16)
+                .map(|i| config::Turret {
+                    id: 30 + i * 3,
+                    format: ["state", "pan", "tilt"].map(String::from).to_vec(),
+                })
+                .collect(),
+            light: config::LightConfig {
+                groups: BTreeMap::from([
+                    ("pumpkin".to_string(), vec![1, 2, 3]),
+                    ("porch".to_string(), vec![5, 6]),
+                ]),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        config
+            .dmx
+            .scenes
+            .insert("flash".to_string(), BTreeMap::from([(1, 255)]));
+        config
+    }
+
+    fn random_laser(rng: &mut StdRng, patterns: &mut Option<PatternLibrary>) -> Laser {
+        let home = rng.gen_bool(0.2);
+        let settings = LaserV2 {
+            home,
+            speed_profile: rng.gen_bool(0.5).then(|| rng.gen_range(0..8)),
+            enable: rng.gen_bool(0.8),
+            // Homing clears oneshot, so they're never both set
+            oneshot: rng.gen_bool(0.3) && !home,
+            auto_speed: rng.gen_bool(0.3),
+            colour: [
+                rng.gen_range(0..8),
+                rng.gen_range(0..8),
+                rng.gen_range(0..8),
+            ],
+            ..Default::default()
+        };
+
+        if rng.gen_bool(0.3) {
+            let mut names = patterns
+                .as_ref()
+                .unwrap()
+                .patterns
+                .keys()
+                .filter(|name| pattern_id(name).is_some())
+                .cloned()
+                .collect::<Vec<_>>();
+            names.sort();
+            let pattern = names.choose(rng).cloned();
+            return LaserV2 {
+                pattern,
+                ..settings
+            }
+            .into_laser(patterns)
+            .unwrap();
+        }
+
+        let points = (0..rng.gen_range(0..6))
+            .map(|_| (rng.gen_range(0..=300), rng.gen_range(0..=300)))
+            .collect();
+        let value = rng.gen_range(0..PATTERN_NAMES.len() as u8);
+        let mut laser = LaserV2 {
+            value,
+            points,
+            ..settings
+        }
+        .into_laser(patterns)
+        .unwrap();
+
+        // Lasers built in code don't have to agree with their own fields
+        if rng.gen_bool(0.3) {
+            laser.point_count = rng.gen();
+        }
+        if rng.gen_bool(0.3) {
+            laser.draw_instructions = (0..rng.gen_range(0..4))
+                .map(|_| LaserDataFrame {
+                    pattern_id: rng.gen(),
+                    r: rng.gen(),
+                    g: rng.gen(),
+                    b: rng.gen(),
+                })
+                .collect();
+        }
+        laser
+    }
+
+    fn random_frame(
+        rng: &mut StdRng,
+        timestamp: u64,
+        config: &Config,
+        patterns: &mut Option<PatternLibrary>,
+    ) -> Frame {
+        let mut lights = (0..MAX_LIGHTS)
+            .map(|_| rng.gen_bool(0.7).then(|| rng.gen()))
+            .collect::<Vec<_>>();
+        // Sometimes a whole group agrees, so it's written as the group
+        if rng.gen_bool(0.4) {
+            let level = Some(rng.gen());
+            lights[..3].fill(level);
+        }
+
+        Frame {
+            timestamp,
+            lights,
+            lasers: (0..MAX_LASERS)
+                .map(|_| rng.gen_bool(0.6).then(|| random_laser(rng, patterns)))
+                .collect(),
+            projectors: (0..MAX_PROJECTORS)
+                .map(|i| {
+                    rng.gen_bool(0.5).then(|| {
+                        let projector = ProjectorV2 {
+                            state: rng.gen(),
+                            gallery: rng.gen(),
+                            pattern: rng.gen(),
+                            colour: rng.gen(),
+                        };
+                        let device = format!("lp-{}", i + 1);
+                        projector.into_projector(&device, config).unwrap()
+                    })
+                })
+                .collect(),
+            turrets: (0..MAX_TURRETS)
+                .map(|i| {
+                    rng.gen_bool(0.5).then(|| {
+                        let turret = TurretV2 {
+                            state: rng.gen(),
+                            pan: rng.gen(),
+                            tilt: rng.gen(),
+                        };
+                        let device = format!("turret-{}", i + 1);
+                        turret.into_turret(&device, config).unwrap()
+                    })
+                })
+                .collect(),
+            turret_fires: (0..rng.gen_range(0..3))
+                .map(|_| rng.gen_range(1..=4))
+                .collect(),
+            dmx_scene: rng.gen_bool(0.3).then(|| "flash".to_string()),
+            dmx: (0..rng.gen_range(0..4))
+                .map(|_| (rng.gen_range(1..=512), rng.gen()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_random_shows_round_trip() {
+        let config = device_config();
+        let mut patterns = Some(PatternLibrary::load().unwrap());
+        let dir = TempDir::new("random");
+        let path = dir.join("random").join("instructions.json");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+        for seed in 0..50 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut timestamp = 0;
+            let mut frames = Vec::new();
+            for _ in 0..rng.gen_range(1..6) {
+                frames.push(random_frame(&mut rng, timestamp, &config, &mut patterns));
+                timestamp += rng.gen_range(1..2000);
+            }
+            let mut show = UnloadedShow {
+                name: "random".to_string(),
+                frames,
+                looping: rng.gen(),
+                next: rng.gen_bool(0.5).then(|| "finale".to_string()),
+                tags: ["kids", "finale", "strobe"]
+                    .into_iter()
+                    .filter(|_| rng.gen_bool(0.5))
+                    .map(String::from)
+                    .collect(),
+            };
+            // Loading raises the speed of lasers that can't keep up, so a
+            // show that was loaded already has
+            show.schedule_lasers();
+
+            std::fs::write(&path, ShowManager::save_show(show.clone(), &config)).unwrap();
+            let saved = UnloadedShow::load_show_file(&path, &config).unwrap();
+
+            assert_eq!(saved.frames, show.frames, "seed {}", seed);
+            assert_eq!(saved.looping, show.looping, "seed {}", seed);
+            assert_eq!(saved.next, show.next, "seed {}", seed);
+            assert_eq!(saved.tags, show.tags, "seed {}", seed);
+        }
+    }
+}
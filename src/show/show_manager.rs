@@ -1,28 +1,43 @@
+use anyhow::Error;
+use chrono::Local;
+
 use crate::{
     config::Config,
-    laser::pack::PatternPack,
-    prelude::{pack::HeaderPack, MessageSendPack},
-    show::{LaserDataFrame, MAX_LIGHTS},
+    health::{Heartbeat, NowPlaying},
+    laser::{colour::ColourQuantizer, pattern::PatternLibrary, HomeStatus, ALL_LASERS},
+    metrics::Metrics,
+    prelude::{FrameSendPack, MessageSendPack},
+    show::MAX_LASERS,
+    shutdown::recv_until,
+    state::{ControllerState, StateStore},
     InternalMessage, MessageKind,
 };
-use log::{error, info};
+use log::{error, info, warn};
 
-use rand::seq::IteratorRandom;
+use rand::{rngs::StdRng, seq::IteratorRandom, SeedableRng};
+use serde::Serialize;
 use std::{
-    cmp::max,
-    collections::{HashMap, VecDeque},
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
     sync::Arc,
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 use tokio::{
-    sync::{mpsc, Mutex},
-    time::{sleep, Instant},
+    sync::{mpsc, oneshot, Mutex},
+    task::JoinHandle,
+    time::{sleep, sleep_until, Instant},
 };
+use tokio_util::sync::CancellationToken;
 
-use super::prelude::{LoadedShow, LoadingShow, UnloadedShow};
+use super::prelude::{
+    show_tags, validate_show, LoadedShow, LoadingShow, PlayOutcome, Playlist, ShowFileV2, ShowPlay,
+    ShowServer, ShowStats, UnloadedShow,
+};
 
 pub type ShowName = String;
-pub type ShowMap = HashMap<ShowName, UnloadedShow>;
+pub type ShowMap = HashMap<ShowName, IndexedShow>;
 
 /// The ShowManager oversees everything relating to how shows are running. It
 /// takes requests to queue shows, as well as stop and play them. It sends
@@ -41,6 +56,9 @@ pub struct ShowManager {
     /// The next show that is going to be played. This gives a staging zone to
     /// load the song before it will start being played.
     pub next_show: Option<LoadingShow>,
+    /// The next show was picked at random or from the playlist rather than
+    /// asked for by name, so asking for a show by name replaces it
+    pub next_show_picked: bool,
     /// This stores what is going to happen next. It is a list of instructions
     /// on how to run a show.
     ///
@@ -50,6 +68,61 @@ pub struct ShowManager {
     pub start_time: Option<Instant>,
     pub shows: ShowMap,
     pub message_queue: mpsc::Sender<MessageKind>,
+    /// Where the laser and DMX messages go, so a show's frames never hold up
+    /// the main queue. The main queue unless it's given its own.
+    pub frames: mpsc::Sender<MessageKind>,
+    /// Named laser patterns for `ShowElement::LaserPattern`
+    pub patterns: PatternLibrary,
+    /// Seconds to idle after a boundary check
+    pub boundary_check_time: u64,
+    /// Seconds homing is given to finish, or to be reported finished
+    pub home_wait: u64,
+    /// Times homing is tried again after it's reported failed
+    pub home_retries: u32,
+    /// Homing reports from the Picos. Without them, homing is given
+    /// `home_wait` and assumed to have worked.
+    pub home_status: Option<mpsc::Receiver<HomeStatus>>,
+    /// How long each step of a laser test is held
+    pub laser_test_step: Duration,
+    /// Brings show colours down to what the lasers can draw, keeping the
+    /// error between frames when dithering
+    pub colours: ColourQuantizer,
+    /// Finish each frame with a flush so the UART writes it all at once
+    pub frame_barrier: bool,
+    /// How fast shows play, for rehearsing them quickly. Anything but 1 plays
+    /// them without audio.
+    pub time_scale: f32,
+    /// Pause, resume and skip the show that's playing, or start and stop
+    /// shows altogether
+    pub controls: Option<mpsc::Receiver<ShowControl>>,
+    /// Shows don't start on their own until they're started again
+    pub stopped: bool,
+    /// The next show to start is the last one before stopping
+    pub closing: bool,
+    /// Stop the worker once everything it was sent has been done and nothing
+    /// is playing, rather than waiting for more
+    pub run_once: bool,
+    /// Which show is picked next, and which were played recently
+    pub playlist: Playlist,
+    /// Every show that's played and how it ended, kept across runs
+    pub stats: ShowStats,
+    /// Plays each show along with this one, if there's one set up
+    pub show_server: Option<ShowServer>,
+    /// Which shows can be picked at random, set by the schedule
+    pub tags: TagFilter,
+    /// Picks the random shows, from `seed`
+    pub rng: StdRng,
+    pub seed: u64,
+    /// What the shows are loaded with, kept for rescanning them
+    pub config: Config,
+    /// Beats while the worker is running, for the health checks
+    pub heartbeat: Heartbeat,
+    /// The name of `current_show`, for the status page
+    pub now_playing: NowPlaying,
+    pub metrics: Metrics,
+    /// Where the last show, the playlist's place and whether the shows are
+    /// stopped are kept across restarts
+    pub saved_state: Option<StateStore>,
     // pub dmx_sender: mpsc::Sender<DmxMessageSendPack>,
 }
 
@@ -78,41 +151,531 @@ pub enum ShowElement {
     /// until the new song is loaded
     NextShow,
     // Disable {duration: Duration},
-    // Send header with 0 frames, then 50 frames of 00000000, wait 3 seconds
-    // before homing again
+    /// Send every laser a header with 0 frames, then 50 frames of 00000000,
+    /// and idle for 3 seconds before homing again
     NullOut,
-    // BoundaryCheck,
+    /// Trace the projection area on a laser, then idle so it can be checked
+    BoundaryCheck {
+        laser_id: u8,
+    },
+    /// Run a laser through its boundary, each colour, then homing, holding
+    /// each for a couple of seconds. `ALL_LASERS` tests each one in turn.
+    LaserTest {
+        laser_id: u8,
+    },
+    /// Draw a pattern from the library on one laser
+    LaserPattern {
+        laser_id: u8,
+        name: String,
+    },
     Idle {
         time: u64,
     },
+    /// Fade the DMX to blackout and the audio out over `transition_ms`,
+    /// null out the lasers, then play `next` straight away
     Transition {
-        show_id: usize,
+        next: ShowChoice,
     },
     LightTest,
+    /// Bring the hardware up: configure the lasers, home them, set the DMX
+    /// startup values and flash each light once
     RunInit,
 }
 
+impl ShowElement {
+    /// What the element does, said plainly for the console
+    pub fn describe(&self) -> String {
+        match self {
+            ShowElement::Home => "Home the lasers".to_string(),
+            ShowElement::PrepareShow(choice) => format!("Prepare {}", choice.describe()),
+            ShowElement::NextShow => "Play the next show".to_string(),
+            ShowElement::NullOut => "Null out the lasers".to_string(),
+            ShowElement::BoundaryCheck { laser_id } => {
+                format!("Draw the boundary on laser {}", laser_id)
+            }
+            ShowElement::LaserTest { laser_id } => format!("Test laser {}", laser_id),
+            ShowElement::LaserPattern { laser_id, name } => {
+                format!("Draw {} on laser {}", name, laser_id)
+            }
+            ShowElement::Idle { time } => format!("Idle for {}s", time),
+            ShowElement::Transition { next } => format!("Transition into {}", next.describe()),
+            ShowElement::LightTest => "Test the lights".to_string(),
+            ShowElement::RunInit => "Initialise the hardware".to_string(),
+        }
+    }
+}
+
+/// One element of the show queue, as `ShowControl::QueueSnapshot` sends it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ShowElementSummary {
+    /// Where it is in the queue, for `ShowControl::RemoveAt`
+    pub index: usize,
+    pub description: String,
+}
+
+/// Where a `ShowControl::QueueSnapshot` is answered. Controls can be cloned,
+/// so the sender is shared and whichever copy is answered first uses it.
 #[derive(Debug, Clone)]
+pub struct SnapshotReply(Arc<std::sync::Mutex<Option<oneshot::Sender<Vec<ShowElementSummary>>>>>);
+
+impl SnapshotReply {
+    pub fn new() -> (Self, oneshot::Receiver<Vec<ShowElementSummary>>) {
+        let (tx, rx) = oneshot::channel();
+        (SnapshotReply(Arc::new(std::sync::Mutex::new(Some(tx)))), rx)
+    }
+
+    pub(crate) fn send(&self, summary: Vec<ShowElementSummary>) {
+        if let Some(tx) = self.0.lock().unwrap().take() {
+            // Nothing to do if whoever asked stopped waiting
+            let _ = tx.send(summary);
+        }
+    }
+}
+
+impl PartialEq for SnapshotReply {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SnapshotReply {}
+
+/// Controls for the show that's playing
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShowControl {
+    /// Stop sending frames and pause the audio
+    Pause,
+    /// Carry on from where it was paused
+    Resume,
+    /// Stop the outputs and start the next show
+    Skip,
+    /// Something the show needs went wrong part way through. It's stopped
+    /// like a skip, nulling out the lasers so they don't keep drawing the
+    /// last pattern.
+    Fail(String),
+    /// Get the display going and keep playing shows
+    Start,
+    /// Stop the outputs and don't play anything until started again
+    Stop,
+    /// Look for shows that were added, changed or removed on disk
+    Rescan,
+    /// Transition into the named show, then stop once it's played
+    Close(ShowName),
+    /// Drop the show that's loading, if there is one, so another can be
+    /// prepared
+    CancelPrepare,
+    /// Load a show to play next, in place of anything that's loading
+    Prepare(ShowChoice),
+    /// Send back what's in the show queue, in order
+    QueueSnapshot(SnapshotReply),
+    /// Drop everything in the show queue
+    ClearQueue,
+    /// Drop one element of the show queue, counting from 0
+    RemoveAt(usize),
+    /// Initialise the hardware again, after the show that's playing
+    Init,
+    /// Test a laser after the show that's playing, or only trace its
+    /// boundary with `boundary`. `ALL_LASERS` tests each one in turn.
+    LaserTest { laser_id: u8, boundary: bool },
+    /// Play shows this many times faster, without audio unless it's 1. The
+    /// show that's playing carries on from where it's up to.
+    SetTimeScale(f32),
+    /// Only pick shows at random with one of `include` and none of `exclude`
+    /// from now on. Both empty picks from all of them again.
+    Tags {
+        include: Vec<String>,
+        exclude: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ShowChoice {
+    /// A show by its key, or by its folder name to pick any of its
+    /// instruction files
     Name(ShowName),
     Random {
         // Option to not choose the last song
         last_song: Option<ShowName>,
     },
+    /// A random show with at least one of `include`, or any show if it's
+    /// empty, and none of `exclude`
+    RandomTagged {
+        include: Vec<String>,
+        exclude: Vec<String>,
+    },
+    /// The next show in the playlist's order
+    Playlist,
+}
+
+impl ShowChoice {
+    fn describe(&self) -> String {
+        match self {
+            ShowChoice::Name(name) => name.clone(),
+            ShowChoice::Random { .. } => "a random show".to_string(),
+            ShowChoice::RandomTagged { include, exclude } => {
+                format!("a random show tagged {:?}, not {:?}", include, exclude)
+            }
+            ShowChoice::Playlist => "the next show in the playlist".to_string(),
+        }
+    }
+}
+
+/// Which tags a randomly picked show needs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TagFilter {
+    /// A show needs one of these, unless it's empty
+    pub include: Vec<String>,
+    /// A show can't have any of these
+    pub exclude: Vec<String>,
+}
+
+impl TagFilter {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn matches(&self, tags: &[String]) -> bool {
+        (self.include.is_empty() || tags.iter().any(|tag| self.include.contains(tag)))
+            && !tags.iter().any(|tag| self.exclude.contains(tag))
+    }
+}
+
+/// How long the audio fades out for when a show is skipped
+const SKIP_FADE: Duration = Duration::from_secs(2);
+
+/// How far behind its time a frame can be and still be sent. Anything later
+/// is skipped so the frames after it aren't sent in a burst.
+const LATE_FRAME: Duration = Duration::from_millis(20);
+
+/// How often the show that's playing reports how far along it is
+const PROGRESS_INTERVAL: u64 = 1000;
+
+/// Where the show that's playing is up to
+#[derive(Debug, Clone)]
+pub struct ShowProgress {
+    pub name: ShowName,
+    /// The last frame or the end of the song, whichever is later. The show
+    /// holds its last frame until the song ends.
+    pub total_ms: u64,
+    /// The last frame that was sent
+    pub frame_index: usize,
+    /// When the next report is due, in time since the show started
+    next_report_ms: u64,
+    /// How fast the show is playing. Times are all in the show's own time,
+    /// so this turns them into real time.
+    time_scale: f32,
+}
+
+impl ShowProgress {
+    pub fn new(show: &LoadedShow, time_scale: f32) -> Self {
+        let last_frame = show.frames.last().map_or(0, |frame| frame.timestamp);
+        let song = show
+            .song
+            .as_ref()
+            .map_or(0, |song| song.stream.duration().as_millis() as u64);
+
+        ShowProgress {
+            name: show.name.clone(),
+            total_ms: last_frame.max(song),
+            frame_index: 0,
+            next_report_ms: PROGRESS_INTERVAL,
+            time_scale,
+        }
+    }
+
+    /// How long after the start `ms` into the show is due
+    fn due_after(&self, ms: u64) -> Duration {
+        scale_duration(Duration::from_millis(ms), 1.0 / self.time_scale)
+    }
+
+    /// The report for a show that started at `start_time`
+    fn message(&self, start_time: Instant) -> InternalMessage {
+        InternalMessage::ShowProgress {
+            name: self.name.clone(),
+            elapsed_ms: scale_duration(start_time.elapsed(), self.time_scale).as_millis() as u64,
+            total_ms: self.total_ms,
+            frame_index: self.frame_index,
+        }
+    }
+}
+
+/// The script that sets up the Pi's serial port for the lasers
+const INIT_SCRIPT: &str = "/home/pi/Halloween/uart/init_serial.sh";
+
+/// How initialising the hardware went
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InitReport {
+    /// The steps that finished, in order
+    pub done: Vec<&'static str>,
+    /// The step that failed and why. Nothing after it was run.
+    pub failed: Option<(&'static str, String)>,
+}
+
+impl InitReport {
+    fn fail(mut self, step: &'static str, reason: impl fmt::Display) -> Self {
+        error!("Init: {} failed: {}", step, reason);
+        self.failed = Some((step, reason.to_string()));
+        self
+    }
+}
+
+impl fmt::Display for InitReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.failed {
+            None => write!(f, "Init finished: {}", self.done.join(", ")),
+            Some((step, reason)) => write!(f, "Init failed at {}, {}", step, reason),
+        }
+    }
 }
 
-const HOME_SLEEP_TIME: u64 = 15;
+/// How far behind the music the frames of a show ran
+#[derive(Debug, Default)]
+pub struct FrameTiming {
+    pub sent: u64,
+    pub skipped: u64,
+    /// The latest any frame was reached, sent or not
+    pub max_lateness: Duration,
+}
+
+impl FrameTiming {
+    /// Whether a frame due at `due` should still be sent at `now`
+    fn on_time(&mut self, due: Instant, now: Instant) -> bool {
+        let lateness = now.saturating_duration_since(due);
+        self.max_lateness = self.max_lateness.max(lateness);
+
+        if lateness > LATE_FRAME {
+            self.skipped += 1;
+            false
+        } else {
+            self.sent += 1;
+            true
+        }
+    }
+}
 
 impl ShowManager {
-    pub fn new(shows: ShowMap, sender: mpsc::Sender<MessageKind>) -> Self {
+    pub fn new(shows: ShowMap, sender: mpsc::Sender<MessageKind>, config: &Config) -> Self {
+        let seed = config.playlist.seed.unwrap_or_else(rand::random);
+        info!("Picking shows with seed {}", seed);
+
         Self {
             current_show: None,
             next_show: None,
+            next_show_picked: false,
             last_show_name: None,
             start_time: None,
+            frames: sender.clone(),
             message_queue: sender,
             shows,
             show_queue: Vec::new(),
+            patterns: PatternLibrary::load().unwrap_or_else(|e| {
+                error!("Failed to load laser patterns: {}", e);
+                PatternLibrary::default()
+            }),
+            boundary_check_time: config.laser.boundary_check_secs,
+            home_wait: config.laser.home_wait_secs,
+            home_retries: config.laser.home_retries,
+            home_status: None,
+            laser_test_step: Duration::from_secs(config.laser.test_step_secs),
+            colours: ColourQuantizer::new(config.laser.colour_depth),
+            frame_barrier: config.uart.frame_barrier,
+            time_scale: config.time_scale,
+            controls: None,
+            // The schedule starts them when the hours open
+            stopped: config.schedule.enabled,
+            closing: false,
+            run_once: false,
+            playlist: Playlist::load(&config.playlist),
+            stats: ShowStats::load(&config.stats),
+            show_server: config
+                .show_server
+                .as_ref()
+                .filter(|show_server| show_server.enabled)
+                .map(ShowServer::new),
+            tags: TagFilter::default(),
+            rng: StdRng::seed_from_u64(seed),
+            seed,
+            config: config.clone(),
+            heartbeat: Heartbeat::default(),
+            now_playing: NowPlaying::default(),
+            metrics: Metrics::default(),
+            saved_state: None,
+        }
+    }
+
+    /// Carry on from where a run before a restart left off
+    pub fn resume(&mut self, state: &ControllerState) {
+        if let Some(name) = &state.last_show {
+            info!("Carrying on after {}", name);
+        }
+        self.last_show_name = state.last_show.clone();
+        self.playlist.resume(state.playlist_position);
+        self.stopped = state.shows_stopped;
+    }
+
+    /// Keep the saved state up to date, which saves it if anything changed
+    fn remember(&self) {
+        if let Some(saved_state) = &self.saved_state {
+            saved_state.update(|state| {
+                state.last_show = self.last_show_name.clone();
+                state.playlist_position = self.playlist.position();
+                state.shows_stopped = self.stopped;
+            });
+        }
+    }
+
+    /// Give the show-server a show as it's being prepared here
+    fn upload_show(&self, show: &UnloadedShow) {
+        if let Some(show_server) = &self.show_server {
+            show_server.upload(show);
+        }
+    }
+
+    /// The frames lane for the lasers and DMX, the main queue for the rest
+    fn lane(&self, message: &InternalMessage) -> &mpsc::Sender<MessageKind> {
+        match message.is_frame_data() {
+            true => &self.frames,
+            false => &self.message_queue,
+        }
+    }
+
+    /// What to play when nothing in particular was asked for
+    pub fn next_choice(&self) -> ShowChoice {
+        match self.playlist.ordered() {
+            true => ShowChoice::Playlist,
+            false if !self.tags.is_empty() => ShowChoice::RandomTagged {
+                include: self.tags.include.clone(),
+                exclude: self.tags.exclude.clone(),
+            },
+            false => ShowChoice::Random {
+                last_song: self.last_show_name.clone(),
+            },
+        }
+    }
+
+    /// Every show's name, once each
+    fn show_names(&self) -> Vec<ShowName> {
+        self.tagged_show_names(&TagFilter::default())
+    }
+
+    /// The name of every show with a set of instructions that `filter` lets
+    /// through, once each
+    fn tagged_show_names(&self, filter: &TagFilter) -> Vec<ShowName> {
+        let names = self
+            .shows
+            .values()
+            .filter(|show| filter.matches(&show.tags))
+            .map(|show| show.name.clone())
+            .collect::<BTreeSet<_>>();
+        names.into_iter().collect()
+    }
+
+    /// Whether there's a show under `name`, as a key or a folder name
+    pub fn has_show(&self, name: &str) -> bool {
+        self.shows.contains_key(name) || self.shows.values().any(|show| show.name == name)
+    }
+
+    /// A show can have more than one set of instructions, so pick one
+    fn show_variant(&mut self, name: &str) -> Option<UnloadedShow> {
+        self.tagged_show_variant(name, &TagFilter::default())
+    }
+
+    fn tagged_show_variant(&mut self, name: &str, filter: &TagFilter) -> Option<UnloadedShow> {
+        let key = self
+            .shows
+            .iter()
+            .filter(|(_, show)| show.name == name && filter.matches(&show.tags))
+            .map(|(key, _)| key.clone())
+            .choose(&mut self.rng)?;
+        self.parse_show(&key)
+    }
+
+    /// Pick a show at random from the ones `filter` lets through, or from
+    /// all of them if there aren't any
+    fn pick_random(
+        &mut self,
+        last_song: Option<ShowName>,
+        filter: TagFilter,
+    ) -> Option<UnloadedShow> {
+        // A show may have started since this was queued
+        let last_song = self.last_show_name.clone().or(last_song);
+
+        let mut filter = filter;
+        let mut names = self.tagged_show_names(&filter);
+        if names.is_empty() && !filter.is_empty() {
+            warn!("No shows match {:?}, picking from all of them", filter);
+            filter = TagFilter::default();
+            names = self.show_names();
+        }
+
+        let picked = self
+            .playlist
+            .choose_random(&names, last_song.as_ref(), &mut self.rng)?;
+        let show = self.tagged_show_variant(&picked, &filter)?;
+        info!("Picked {} at random (seed {})", show.name, self.seed);
+        Some(show)
+    }
+
+    /// The show `choice` picks, or `None` with the reason logged. Picking
+    /// from the playlist moves it on to the show after.
+    fn choose_show(&mut self, choice: ShowChoice) -> Option<UnloadedShow> {
+        match choice {
+            ShowChoice::Name(show_name) => {
+                let show = self
+                    .parse_show(&show_name)
+                    .or_else(|| self.show_variant(&show_name));
+                if show.is_none() {
+                    error!("Show {} not found", show_name);
+                }
+                show
+            }
+            ShowChoice::Random { last_song } => {
+                let show = self.pick_random(last_song, TagFilter::default());
+                if show.is_none() {
+                    error!("There are no shows to play");
+                }
+                show
+            }
+            ShowChoice::RandomTagged { include, exclude } => {
+                let show = self.pick_random(None, TagFilter { include, exclude });
+                if show.is_none() {
+                    error!("There are no shows to play");
+                }
+                show
+            }
+            ShowChoice::Playlist => {
+                let picked = self.playlist.choose_next(&self.show_names());
+                let show = picked.and_then(|name| self.show_variant(&name));
+                if show.is_none() {
+                    error!("None of the shows in the playlist are loaded");
+                }
+                show
+            }
+        }
+    }
+
+    /// The show under `key`, parsed the first time it's asked for. One that
+    /// can't be parsed is dropped so it isn't picked again.
+    fn parse_show(&mut self, key: &str) -> Option<UnloadedShow> {
+        let show = self.shows.get_mut(key)?;
+        let parsed = show.parsed.is_some();
+        match show.parse(&self.config) {
+            Ok(show) => {
+                if let Some(next) = show.next.as_ref().filter(|_| !parsed) {
+                    if !self.has_show(next) {
+                        warn!(
+                            "{} plays {} next, but there's no show by that name",
+                            key, next
+                        );
+                    }
+                }
+                Some(show)
+            }
+            Err(e) => {
+                warn!("Skipping {}: {}", show.path.display(), e);
+                self.shows.remove(key);
+                None
+            }
         }
     }
 
@@ -129,63 +692,19 @@ impl ShowManager {
     //     }
     // }
 
-    pub fn save_show(show: UnloadedShow) -> String {
-        let mut file_json = json::JsonValue::new_object();
-
-        for frame in show.frames {
-            let timestamp = frame.timestamp.to_string();
-            file_json[&timestamp] = json::JsonValue::new_object();
-
-            for (i, light) in frame.lights.iter().enumerate() {
-                let light_name = format!("light-{}", i);
-                if let Some(light) = light {
-                    file_json[&timestamp][&light_name] = match light {
-                        true => 1.0.into(),
-                        false => 0.0.into(),
-                    };
-                }
-            }
-
-            // TODO: Fix this if we need to save the laser data
-            unimplemented!();
-            // for (i, laser) in frame.lasers.iter().enumerate() {
-            //     let laser_name = format!("laser-{}", i);
-            //     let laser_config_name = format!("laser-{}-config", i);
-            //     if let Some(laser) = laser {
-            //         file_json[&timestamp][&laser_config_name] = json::JsonValue::new_object();
-            //         file_json[&timestamp][&laser_config_name]["home"] =
-            //             json::JsonValue::Boolean(laser.home);
-            //         file_json[&timestamp][&laser_config_name]["speed-profile"] =
-            //             json::JsonValue::Number(laser.speed_profile.into());
-
-            //         file_json[&timestamp][&laser_name] = json::JsonValue::new_array();
-
-            //         for laser_frame in &laser.data_frame {
-            //             file_json[&timestamp][&laser_name]
-            //                 .push(json::JsonValue::new_array())
-            //                 .unwrap();
-            //             let last_index = file_json[&timestamp][&laser_name].len() - 1;
-            //             file_json[&timestamp][&laser_name][last_index]
-            //                 .push(laser_frame.x_pos)
-            //                 .unwrap();
-            //             file_json[&timestamp][&laser_name][last_index]
-            //                 .push(laser_frame.y_pos)
-            //                 .unwrap();
-            //             file_json[&timestamp][&laser_name][last_index]
-            //                 .push(laser_frame.r)
-            //                 .unwrap();
-            //             file_json[&timestamp][&laser_name][last_index]
-            //                 .push(laser_frame.g)
-            //                 .unwrap();
-            //             file_json[&timestamp][&laser_name][last_index]
-            //                 .push(laser_frame.b)
-            //                 .unwrap();
-            //         }
-            //     }
-            // }
-        }
-
-        file_json.pretty(4)
+    /// Write a show back out in the latest format. Lights are written as
+    /// their group wherever every light in a group from `config` is at the
+    /// same level.
+    pub fn save_show(show: UnloadedShow, config: &Config) -> String {
+        let show_file = ShowFileV2::from_show(&show, config);
+
+        let mut output = Vec::new();
+        let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
+        let mut serializer = serde_json::Serializer::with_formatter(&mut output, formatter);
+        show_file
+            .serialize(&mut serializer)
+            .expect("a show can always be written as JSON");
+        String::from_utf8(output).expect("serde_json writes UTF-8")
     }
 
     // pub fn load_show_file(
@@ -203,15 +722,22 @@ impl ShowManager {
 
     /// This function starts a thread that will manage the show. It will keep a
     /// list of upcoming shows to play, and it will send messages to the other
-    /// worker threads for the projector, lights, and audio.
-    pub async fn start_show_worker(self, mut receiver: mpsc::Receiver<Vec<ShowElement>>) {
+    /// worker threads for the projector, lights, and audio. The worker stops
+    /// wherever it is when `cancel` fires, leaving the outputs to be turned
+    /// off by whoever cancelled it.
+    pub async fn start_show_worker(
+        self,
+        mut receiver: mpsc::Receiver<Vec<ShowElement>>,
+        cancel: CancellationToken,
+    ) -> JoinHandle<()> {
         let show_job_queue: Arc<Mutex<VecDeque<ShowElement>>> =
             Arc::new(Mutex::new(VecDeque::new()));
 
         // Start a thread to add jobs to the queue
         let show_job_queue_clone = show_job_queue.clone();
-        let _queue_handle = tokio::spawn(async move {
-            while let Some(show_job_list) = receiver.recv().await {
+        let queue_cancel = cancel.clone();
+        let queue_handle = tokio::spawn(async move {
+            while let Some(show_job_list) = recv_until(&mut receiver, &queue_cancel).await {
                 let mut show_job_queue = show_job_queue_clone.lock().await;
                 show_job_queue.extend(show_job_list);
             }
@@ -219,86 +745,202 @@ impl ShowManager {
 
         // Start the show worker thread
         let show_job_queue_clone = show_job_queue.clone();
-        let _worker_handle =
-            tokio::spawn(async move { show_task_loop(self, show_job_queue_clone).await });
+        // It waits a long time between shows on purpose, so it beats for as
+        // long as it's running rather than from its loop
+        let heartbeat = self.heartbeat.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = heartbeat.beat_while(show_task_loop(self, show_job_queue_clone, queue_handle)) => {}
+                _ = cancel.cancelled() => info!("The show worker stopped"),
+            }
+        })
     }
 
-    pub fn load_shows(_message_queue: mpsc::Sender<MessageKind>, config: &Config) -> ShowMap {
+    /// Find every show under `config.shows_dir`. Only the files are looked
+    /// at, the frames are parsed once a show is first prepared. A show that
+    /// can't be played is logged and skipped rather than stopping the rest.
+    pub fn load_shows(config: &Config) -> (ShowMap, ShowDiscovery) {
+        let started = std::time::Instant::now();
+        let mut shows = ShowMap::new();
+        let mut discovery = ShowDiscovery::default();
+
         // Find all folders in the shows folder
-        let shows = std::fs::read_dir("shows").unwrap();
+        let show_dirs = match show_dirs(&config.shows_dir) {
+            Ok(show_dirs) => show_dirs,
+            Err(e) => {
+                error!(
+                    "Couldn't read the shows folder {}: {}",
+                    config.shows_dir.display(),
+                    e
+                );
+                return (shows, discovery);
+            }
+        };
 
-        let names = shows
-            .into_iter()
-            .map(|show| {
-                show.unwrap()
-                    .path()
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .to_string()
-            })
-            .collect::<Vec<String>>();
+        // For each one, load the show and song
+        for show_dir in show_dirs {
+            let Some(name) = show_dir.file_name().and_then(|name| name.to_str()) else {
+                discovery.skip(&show_dir, "the folder name isn't valid UTF-8".to_string());
+                continue;
+            };
 
-        info!("Found shows: {:?}", names);
+            let instruction_files = match instruction_files(&show_dir) {
+                Ok(files) if files.is_empty() => {
+                    discovery.skip(&show_dir, "there are no instruction files".to_string());
+                    continue;
+                }
+                Ok(files) => files,
+                Err(e) => {
+                    discovery.skip(&show_dir, e.to_string());
+                    continue;
+                }
+            };
+
+            // Create a show for each instruction file
+            for path in instruction_files {
+                let modified = match path.metadata() {
+                    Ok(metadata) => metadata.modified().ok(),
+                    Err(e) => {
+                        discovery.skip(&path, e.to_string());
+                        continue;
+                    }
+                };
+                let file_name = path.file_name().unwrap().to_string_lossy();
+                let unique_name = format!("{}-{}", name, file_name);
+                discovery.loaded.push(unique_name.clone());
+                shows.insert(
+                    unique_name,
+                    IndexedShow {
+                        name: name.to_string(),
+                        tags: show_tags(&path),
+                        path,
+                        modified,
+                        parsed: None,
+                    },
+                );
+            }
+        }
 
-        // For each one, load the show and song
-        let shows = names
-            .iter()
-            .flat_map(|name| {
-                let show_dir = format!("shows/{}", name);
-
-                // Get all instruction files (starting with 'instructions-')
-                let instruction_files = std::fs::read_dir(&show_dir)
-                    .unwrap()
-                    .filter_map(Result::ok)
-                    .filter(|entry| {
-                        entry
-                            .file_name()
-                            .to_str()
-                            .map(|s| s.starts_with("instructions-exported"))
-                            .unwrap_or(false)
-                    })
-                    .collect::<Vec<_>>();
-
-                // Find the first MP3 file (if any)
-                let _song_file_name = std::fs::read_dir(&show_dir)
-                    .unwrap()
-                    .filter_map(Result::ok)
-                    .find(|entry| {
-                        entry.path().extension().and_then(|ext| ext.to_str()) == Some("mp3")
-                    })
-                    .and_then(|entry| {
-                        entry
-                            .path()
-                            .file_stem()
-                            .and_then(|s| s.to_str())
-                            .map(String::from)
-                    });
+        info!(
+            "Found shows in {:?}: {:?}",
+            started.elapsed(),
+            discovery.loaded
+        );
 
-                // Create a show for each instruction file
-                instruction_files
-                    .into_iter()
-                    .filter_map(|entry| {
-                        let path = entry.path();
-                        let show = UnloadedShow::load_show_file(&path, &config);
-                        let unique_name = format!("{}-{}", name, entry.file_name().to_str()?);
-                        Some((unique_name, show))
-                    })
-                    .collect::<ShowMap>()
-            })
-            .collect::<ShowMap>();
+        (shows, discovery)
+    }
+}
+
+/// Every show folder in `shows_dir`, in order
+pub fn show_dirs(shows_dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut show_dirs = std::fs::read_dir(shows_dir)?
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry.path()),
+            Err(e) => {
+                warn!("Couldn't read an entry in the shows folder: {}", e);
+                None
+            }
+        })
+        .filter(|path| path.is_dir())
+        // Laser patterns live alongside the shows
+        .filter(|path| !path.ends_with("patterns"))
+        .collect::<Vec<_>>();
+    show_dirs.sort();
+
+    Ok(show_dirs)
+}
+
+/// The instruction files in a show folder (starting with
+/// 'instructions-exported'), in order
+pub fn instruction_files(show_dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = std::fs::read_dir(show_dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("instructions-exported"))
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+
+    Ok(files)
+}
+
+/// An instruction file in a show folder. Its frames aren't read until the
+/// show is first prepared, then they're kept.
+#[derive(Clone, Debug)]
+pub struct IndexedShow {
+    /// The show's folder, which its song is named after
+    pub name: String,
+    /// Read when the show is found, for picking shows by tag
+    pub tags: Vec<String>,
+    pub path: PathBuf,
+    /// When the file was last changed, so a rescan can keep what's parsed
+    pub modified: Option<SystemTime>,
+    pub parsed: Option<UnloadedShow>,
+}
+
+impl IndexedShow {
+    /// Parse the show the first time, warning about anything that loads but
+    /// looks wrong. This runs in the show worker, so a file that panics the
+    /// parser fails like one that doesn't parse rather than taking it down.
+    pub fn parse(&mut self, config: &Config) -> Result<UnloadedShow, Error> {
+        if let Some(show) = &self.parsed {
+            return Ok(show.clone());
+        }
+
+        let started = std::time::Instant::now();
+        let (show, issues) =
+            panic::catch_unwind(AssertUnwindSafe(|| validate_show(&self.path, config)))
+                .map_err(|_| Error::msg("parsing it panicked"))?;
+        let Some(show) = show else {
+            let reasons = issues.iter().map(ToString::to_string).collect::<Vec<_>>();
+            return Err(Error::msg(reasons.join(", ")));
+        };
+        for issue in issues {
+            warn!("{}:{}", self.path.display(), issue);
+        }
+        info!("Parsed {} in {:?}", self.path.display(), started.elapsed());
+
+        self.parsed = Some(show.clone());
+        Ok(show)
+    }
+}
 
-        shows
+/// What `ShowManager::load_shows` found
+#[derive(Debug, Default)]
+pub struct ShowDiscovery {
+    /// Every show that was found
+    pub loaded: Vec<ShowName>,
+    /// Show folders and instruction files that were left out, with why
+    pub skipped: Vec<(PathBuf, String)>,
+}
+
+impl ShowDiscovery {
+    fn skip(&mut self, path: &Path, reason: String) {
+        warn!("Skipping {}: {}", path.display(), reason);
+        self.skipped.push((path.to_path_buf(), reason));
     }
 }
 
 async fn show_task_loop(
     mut show_manager: ShowManager,
     show_job_queue_clone: Arc<Mutex<VecDeque<ShowElement>>>,
+    queue_handle: JoinHandle<()>,
 ) {
     let mut now: Option<Instant> = None;
     loop {
+        // Controls that came in between shows
+        while let Some(control) = show_manager
+            .controls
+            .as_mut()
+            .and_then(|controls| controls.try_recv().ok())
+        {
+            idle_control(&mut show_manager, &show_job_queue_clone, control).await;
+        }
+        show_manager.remember();
+
         // Get the next element in the queue
         let mut show_job_queue = show_job_queue_clone.lock().await;
         let next_show_element = show_job_queue.pop_front().to_owned();
@@ -319,9 +961,18 @@ async fn show_task_loop(
         if next_show_element.is_none() {
             drop(show_job_queue);
 
+            // Once nothing more can be sent, there's nothing left to do
+            if show_manager.run_once
+                && queue_handle.is_finished()
+                && show_manager.current_show.is_none()
+            {
+                info!("Played everything queued, stopping the show worker");
+                return;
+            }
+
             // If we don't have a next song loaded, then we should see if we
             // should queue a random one up
-            if show_manager.next_show.is_none() {
+            if show_manager.next_show.is_none() && !show_manager.stopped {
                 // If 5 seconds has elapsed, add a random show to the queue
                 if now.is_none() {
                     now = Some(Instant::now());
@@ -331,9 +982,7 @@ async fn show_task_loop(
                     show_job_queue_clone
                         .lock()
                         .await
-                        .push_back(ShowElement::PrepareShow(ShowChoice::Random {
-                            last_song: show_manager.last_show_name.clone(),
-                        }));
+                        .push_back(ShowElement::PrepareShow(show_manager.next_choice()));
 
                     // // If there isn't a current show, then we should start the
                     // // next show right away
@@ -344,11 +993,12 @@ async fn show_task_loop(
                     //         .push_back(ShowElement::NextShow);
                     // }
                 }
-
-                // Either way, it's fine to sleep for a bit
-                sleep(Duration::from_millis(100)).await;
-                continue;
             }
+
+            // Either way, it's fine to sleep for a bit. This also keeps the
+            // loop from spinning while shows are stopped.
+            sleep(Duration::from_millis(100)).await;
+            continue;
         } else {
             // Print the details about the current show manager
             info!(
@@ -374,37 +1024,18 @@ async fn show_task_loop(
 
             match next_show_element.unwrap() {
                 ShowElement::Home => {
-                    // Send a home command
-                    info!("Homing the projector");
-                    show_manager
-                        .message_queue
-                        .send(MessageKind::InternalMessage(InternalMessage::Laser(
-                            MessageSendPack {
-                                header: HeaderPack {
-                                    laser_id: 15.into(),
-                                    home: true,
-                                    enable: true,
-                                    configuration_mode: false,
-                                    draw_boundary: false,
-                                    oneshot: false,
-                                    speed_profile: 0.into(),
-                                    ..Default::default()
-                                },
-                                draw_instruction: PatternPack::default(),
-                            }
-                            .into(),
-                        )))
-                        .await
-                        .unwrap();
-
-                    // Add a 15 second idle command to the beginning of the
-                    // queue
-                    show_job_queue_clone
-                        .lock()
-                        .await
-                        .push_front(ShowElement::Idle {
-                            time: HOME_SLEEP_TIME,
-                        });
+                    // Nothing else can go ahead on lasers that aren't homed
+                    if let Err(e) = home_lasers(&mut show_manager, &show_job_queue_clone).await {
+                        error!("Stopping shows: {}", e);
+                        show_manager
+                            .message_queue
+                            .send(MessageKind::InternalMessage(InternalMessage::Alert(
+                                format!("Stopped the shows, {}", e),
+                            )))
+                            .await
+                            .unwrap();
+                        stop_shows(&mut show_manager, &show_job_queue_clone).await;
+                    }
                 }
                 ShowElement::PrepareShow(choice) => {
                     info!("Preparing a show");
@@ -412,71 +1043,28 @@ async fn show_task_loop(
                     // if isn't, then there is another show loading, and we
                     // might OOM
                     if show_manager.next_show.is_some() {
-                        error!("There is already a show loading");
-                        continue;
-                    }
-
-                    match choice {
-                        ShowChoice::Name(show_name) => {
-                            // Set the show from the name
-                            let unloaded_show = match show_manager.shows.get(&show_name) {
-                                Some(show) => show.clone(),
-                                None => {
-                                    error!("Show {} not found", show_name);
-                                    continue;
-                                }
-                            };
-
-                            // Turn it into a loading show
-                            let loading_show = unloaded_show.load_show().await;
-
-                            // Set the next show
-                            show_manager.next_show = Some(loading_show);
+                        // A show asked for by name wins over a picked one
+                        if matches!(choice, ShowChoice::Name(_)) && show_manager.next_show_picked {
+                            cancel_prepare(&mut show_manager.next_show);
+                        } else {
+                            error!("There is already a show loading");
+                            continue;
                         }
-                        ShowChoice::Random { last_song } => {
-                            // Pick a random show from the show manager list
-                            let unloaded_show = {
-                                for show in show_manager.shows.values() {
-                                    dbg!(&show.name);
-                                }
+                    }
+                    show_manager.next_show_picked = !matches!(choice, ShowChoice::Name(_));
 
-                                let mut attempts = 0;
-                                const MAX_ATTEMPTS: u8 = 5;
-
-                                loop {
-                                    let unloaded_show = match show_manager
-                                        .shows
-                                        .values()
-                                        .choose(&mut rand::thread_rng())
-                                    {
-                                        Some(show) => show.clone(),
-                                        None => {
-                                            error!("There are no shows to play");
-                                            continue;
-                                        }
-                                    };
-
-                                    match last_song {
-                                        Some(ref last_song) => {
-                                            if &unloaded_show.name != last_song
-                                                || attempts >= MAX_ATTEMPTS
-                                            {
-                                                break unloaded_show;
-                                            }
-                                            attempts += 1;
-                                        }
-                                        None => break unloaded_show,
-                                    }
-                                }
-                            };
+                    let Some(unloaded_show) = show_manager.choose_show(choice) else {
+                        continue;
+                    };
+                    show_manager.upload_show(&unloaded_show);
 
-                            // Turn it into a loading show
-                            let loading_show = unloaded_show.load_show().await;
+                    // Turn it into a loading show
+                    let loading_show = unloaded_show
+                        .load_show(&show_manager.config.shows_dir)
+                        .await;
 
-                            // Set the next show
-                            show_manager.next_show = Some(loading_show);
-                        }
-                    }
+                    // Set the next show
+                    show_manager.next_show = Some(loading_show);
 
                     // If nothing is currently playing, then prepare a NextShow
                     // command
@@ -504,6 +1092,12 @@ async fn show_task_loop(
                         continue;
                     }
 
+                    // Keep it loaded for when shows are started again
+                    if show_manager.stopped {
+                        info!("Shows are stopped, not starting the next one");
+                        continue;
+                    }
+
                     let next_show = show_manager.next_show.take().unwrap();
 
                     // Load the song. If there is no song loaded, wait on it
@@ -516,254 +1110,452 @@ async fn show_task_loop(
                             break;
                         }
 
-                        // if timer.elapsed().as_secs() > TIMEOUT {
-                        //     error!("There was no next show loaded in time");
-                        //     break;
-                        // }
+                        if timer.elapsed().as_secs() > TIMEOUT {
+                            error!("There was no next show loaded in time");
+                            break;
+                        }
 
                         sleep(Duration::from_millis(100)).await;
                     }
 
                     // Turn this into a loaded show
+                    let name = next_show.name.clone();
                     let loaded_show = match next_show.get_loaded_show() {
                         Ok(show) => show,
-                        Err(_) => {
-                            error!("The next show is not ready to play");
+                        Err(e) => {
+                            error!("{} can't be played: {}", name, e);
+                            show_manager.stats.record(ShowPlay {
+                                name,
+                                started: Local::now(),
+                                duration_ms: 0,
+                                outcome: PlayOutcome::Errored,
+                                max_lateness_ms: 0,
+                            });
                             continue;
                         }
                     };
 
                     // Set the current show
+                    show_manager.now_playing.set(Some(loaded_show.name.clone()));
+                    show_manager
+                        .metrics
+                        .set_current_show(Some(&loaded_show.name));
                     show_manager.current_show = Some(loaded_show);
 
                     // Set the last song for future reference
                     show_manager.last_show_name =
                         Some(show_manager.current_show.as_ref().unwrap().name.clone());
+                    show_manager
+                        .playlist
+                        .played(show_manager.last_show_name.as_ref().unwrap());
+
+                    // Nothing starts on its own after the closing show
+                    if show_manager.closing {
+                        info!("Playing the closing show, stopping after it");
+                        show_manager.closing = false;
+                        show_manager.stopped = true;
+                    }
+                    show_manager.remember();
 
                     // Get the show
                     let current_show = show_manager.current_show.as_ref().unwrap();
 
-                    // Get the runtime of the show
-                    let runtime = current_show.frames.last().unwrap().timestamp;
+                    // Get the runtime of the show. One without frames or a
+                    // song is over as soon as it starts.
+                    let runtime = current_show
+                        .frames
+                        .last()
+                        .map_or(0, |frame| frame.timestamp);
+
+                    // A looping show starts again from here each time
+                    let started = (Local::now(), Instant::now());
+                    let mut max_lateness = Duration::ZERO;
+                    let mut looped = false;
+                    let mut ended_by;
+                    loop {
+                        ended_by = None;
 
-                    // Start the song
-                    let song = current_show.song.clone();
-                    show_manager
-                        .message_queue
-                        .try_send(MessageKind::InternalMessage(InternalMessage::Audio {
-                            audio_file_contents: song,
-                        }))
-                        .unwrap();
+                        // Start the song. It can't be sped up, so a rehearsal
+                        // plays without it.
+                        if show_manager.time_scale == 1.0 {
+                            if let Some(song) = current_show.song.clone() {
+                                show_manager
+                                    .message_queue
+                                    .try_send(MessageKind::InternalMessage(
+                                        InternalMessage::Audio {
+                                            audio_file_contents: song,
+                                        },
+                                    ))
+                                    .unwrap();
+                            }
+                            if let Some(show_server) = &show_manager.show_server {
+                                show_server.start();
+                            }
+                        } else {
+                            info!(
+                                "Playing {} at {}x without audio",
+                                current_show.name, show_manager.time_scale
+                            );
+                        }
 
-                    // Set the timer. This should be in sync with when the audio starts.
-                    show_manager.start_time = Some(Instant::now());
+                        if !looped {
+                            show_manager
+                                .frames
+                                .send(MessageKind::InternalMessage(InternalMessage::ShowStarted(
+                                    current_show.name.clone(),
+                                )))
+                                .await
+                                .unwrap();
+                        }
 
-                    let mut frames_iter = current_show.frames.iter();
+                        // Set the timer. This should be in sync with when the audio starts.
+                        show_manager.start_time = Some(Instant::now());
 
-                    // Every 5 seconds while the show is running, we want to
-                    // print how much longer is in the show.
-                    let mut timer = Instant::now();
+                        let mut progress = ShowProgress::new(current_show, show_manager.time_scale);
+                        show_manager
+                            .message_queue
+                            .send(MessageKind::InternalMessage(
+                                progress.message(show_manager.start_time.unwrap()),
+                            ))
+                            .await
+                            .unwrap();
 
-                    loop {
-                        // Get the next frame
-                        let curr_frame = match frames_iter.next() {
-                            Some(frame) => frame,
-                            None => break,
-                        };
-
-                        // Sleep until the current frame is ready
-                        let curr_time =
-                            show_manager.start_time.unwrap().elapsed().as_millis() as i64;
-                        let sleep_time = max(curr_frame.timestamp as i64 - curr_time, 0);
-                        sleep(Duration::from_millis(sleep_time as u64)).await;
-
-                        // Print the amount of time remaining in the show
-                        let time_remaining = runtime - curr_frame.timestamp;
-
-                        if timer.elapsed().as_secs() > 5 {
-                            timer = Instant::now();
-                            info!("{} seconds remaining", time_remaining / 1000);
-                        }
+                        let mut frames_iter = current_show.frames.iter().enumerate();
 
-                        // Execute the current frame
+                        // Every 5 seconds while the show is running, we want to
+                        // print how much longer is in the show.
+                        let mut timer = Instant::now();
+                        let mut timing = FrameTiming::default();
 
-                        // Send all the lights data
-                        for (light_number, light) in curr_frame.lights.iter().enumerate() {
-                            // We add one to the light number here to account
-                            // for lasers in the instruction file starting at 1
-                            let light_number = light_number + 1;
+                        loop {
+                            // Get the next frame
+                            let (frame_id, curr_frame) = match frames_iter.next() {
+                                Some(frame) => frame,
+                                None => break,
+                            };
 
-                            if let Some(light) = light {
-                                show_manager
-                                    .message_queue
-                                    .send(MessageKind::InternalMessage(InternalMessage::Light {
-                                        light_id: light_number as u8,
-                                        enable: *light,
-                                    }))
-                                    .await
-                                    .unwrap();
+                            // Sleep until the current frame is ready
+                            ended_by = wait_for_frame(
+                                show_manager.start_time.as_mut().unwrap(),
+                                &mut show_manager.controls,
+                                &show_manager.message_queue,
+                                &mut show_manager.shows,
+                                &show_manager.config,
+                                &mut show_manager.tags,
+                                &mut show_manager.next_show,
+                                &show_job_queue_clone,
+                                &mut progress,
+                                curr_frame.timestamp,
+                            )
+                            .await;
+                            if ended_by.is_some() {
+                                break;
                             }
-                        }
 
-                        // Send all the lasers data
-                        for (laser_number, laser) in curr_frame.lasers.iter().enumerate() {
-                            // We add one to the laser number here to account
-                            // for lasers in the instruction file starting at 1
-                            let laser_number = laser_number + 1;
+                            // Drop a frame that's too late rather than sending
+                            // it in a burst with the ones after it
+                            let due = *show_manager.start_time.as_ref().unwrap()
+                                + progress.due_after(curr_frame.timestamp);
+                            if !timing.on_time(due, Instant::now()) {
+                                show_manager.metrics.frames_skipped.inc();
+                                continue;
+                            }
+                            show_manager.metrics.frames_dispatched.inc();
+                            progress.frame_index = frame_id;
+
+                            // Print the amount of time remaining in the show
+                            let time_remaining = runtime - curr_frame.timestamp;
 
-                            if let Some(laser) = laser {
-                                show_manager
-                                    .message_queue
-                                    .send(MessageKind::InternalMessage(InternalMessage::Laser(
-                                        MessageSendPack::new(
-                                            HeaderPack {
-                                                laser_id: (laser_number as u8).into(),
-                                                point_count: laser.point_count.into(),
-                                                home: false,
-                                                enable: true,
-                                                configuration_mode: false,
-                                                draw_boundary: false,
-                                                oneshot: false,
-                                                // speed_profile: laser.speed_profile.into(),
-                                                speed_profile: 1.into(),
-                                                ..Default::default()
-                                            },
-                                            LaserDataFrame {
-                                                pattern_id: laser.value,
-                                                r: laser.hex[0],
-                                                g: laser.hex[1],
-                                                b: laser.hex[2],
-                                            },
-                                        )
-                                        .into(),
-                                    )))
-                                    .await
-                                    .unwrap();
+                            if timer.elapsed().as_secs() > 5 {
+                                timer = Instant::now();
+                                info!("{} seconds remaining", time_remaining / 1000);
+                            }
 
-                                // Print the point count, pattern id, and hex values
-                                info!(
-                                    "Laser {} has {} points, pattern {}, hex {:?}",
-                                    laser_number,
-                                    laser.point_count,
-                                    laser.value,
-                                    laser.hex
-                                );
+                            // Execute the current frame
+
+                            // Send all the lights data
+                            for (light_number, light) in curr_frame.lights.iter().enumerate() {
+                                // We add one to the light number here to account
+                                // for lasers in the instruction file starting at 1
+                                let light_number = light_number + 1;
+
+                                if let Some(level) = light {
+                                    show_manager
+                                        .message_queue
+                                        .send(MessageKind::InternalMessage(
+                                            InternalMessage::LightLevel {
+                                                light_id: light_number as u8,
+                                                level: *level,
+                                            },
+                                        ))
+                                        .await
+                                        .unwrap();
+                                }
                             }
-                        }
 
-                        // Go through all the DMX devices and send the data.
-                        // Start with the projectors
-                        for projector in curr_frame.projectors.iter() {
-                            if let Some(projector) = projector {
+                            for turret_id in &curr_frame.turret_fires {
                                 show_manager
                                     .message_queue
                                     .send(MessageKind::InternalMessage(
-                                        InternalMessage::DmxUpdateState(vec![
-                                            projector.state,
-                                            projector.gallery,
-                                            projector.pattern,
-                                            projector.colour,
-                                        ]),
+                                        InternalMessage::TurretFire {
+                                            turret_id: *turret_id,
+                                        },
                                     ))
                                     .await
                                     .unwrap();
                             }
-                        }
 
-                        // Send all the turrets data
-                        for turret in curr_frame.turrets.iter() {
-                            if let Some(turret) = turret {
+                            // Send all the lasers data, each addressed to its
+                            // own laser, in one batch so they go out together
+                            let batch = curr_frame
+                                .laser_messages(&mut show_manager.colours)
+                                .into_iter()
+                                .map(|message| {
+                                    info!("{}", message);
+                                    message.into()
+                                })
+                                .collect::<Vec<_>>();
+                            if !batch.is_empty() {
                                 show_manager
-                                    .message_queue
+                                    .frames
                                     .send(MessageKind::InternalMessage(
-                                        InternalMessage::DmxUpdateState(vec![
-                                            turret.state,
-                                            turret.pan,
-                                            turret.tilt,
-                                        ]),
+                                        InternalMessage::LaserBatch(batch),
                                     ))
                                     .await
                                     .unwrap();
                             }
-                        }
 
-                        // Now that a frame is done sending everything send all
-                        // of the DMX data
-                        show_manager
-                            .message_queue
+                            // Send the frame's DMX data, finishing with a send
+                            // request
+                            for message in curr_frame.dmx_messages() {
+                                show_manager
+                                    .frames
+                                    .send(MessageKind::InternalMessage(message))
+                                    .await
+                                    .unwrap();
+                            }
+
+                            if show_manager.frame_barrier {
+                                show_manager
+                                    .frames
+                                    .send(MessageKind::InternalMessage(
+                                        InternalMessage::FrameFlush {
+                                            frame_id: frame_id as u64,
+                                        },
+                                    ))
+                                    .await
+                                    .unwrap();
+                            }
+                        }
+
+                        // A song that runs past the last frame plays out,
+                        // holding whatever the last frame left on
+                        let song_end = progress.total_ms;
+                        if ended_by.is_none() && song_end > runtime {
+                            info!(
+                                "Holding the last frame until the song ends in {}ms",
+                                song_end - runtime
+                            );
+                            ended_by = wait_for_frame(
+                                show_manager.start_time.as_mut().unwrap(),
+                                &mut show_manager.controls,
+                                &show_manager.message_queue,
+                                &mut show_manager.shows,
+                                &show_manager.config,
+                                &mut show_manager.tags,
+                                &mut show_manager.next_show,
+                                &show_job_queue_clone,
+                                &mut progress,
+                                song_end,
+                            )
+                            .await;
+                        }
+
+                        info!(
+                            "Sent {} frames, skipped {} late ones, at most {:?} behind",
+                            timing.sent, timing.skipped, timing.max_lateness
+                        );
+                        max_lateness = max_lateness.max(timing.max_lateness);
+                        // A time scale set part way through carries on
+                        show_manager.time_scale = progress.time_scale;
+                        show_manager
+                            .message_queue
                             .send(MessageKind::InternalMessage(
-                                InternalMessage::DmxSendRequest,
+                                progress.message(show_manager.start_time.unwrap()),
                             ))
                             .await
                             .unwrap();
-                    }
 
-                    info!("Finished playing the show");
+                        // Play it again unless another show is waiting, or
+                        // it's the last one before stopping
+                        if ended_by.is_none()
+                            && current_show.looping
+                            && !show_manager.stopped
+                            && !show_waiting(&show_manager, &show_job_queue_clone).await
+                        {
+                            info!("Looping {}", current_show.name);
+                            looped = true;
+                            continue;
+                        }
+                        break;
+                    }
 
-                    // Send stop command to audio
-                    show_manager
-                        .message_queue
-                        .send(MessageKind::InternalMessage(InternalMessage::AudioStop))
-                        .await
-                        .unwrap();
+                    // Stop the audio, fading it if the show was cut short
+                    match ended_by {
+                        // The transition fades everything out itself
+                        Some(ShowControl::Close(_)) => {}
+                        Some(_) => {
+                            stop_outputs(&show_manager, InternalMessage::AudioFadeOut(SKIP_FADE))
+                                .await
+                        }
+                        None => {
+                            info!("Finished playing the show");
+                            stop_outputs(&show_manager, InternalMessage::AudioStop).await;
+                        }
+                    }
 
-                    // Zero out DMX channels
-                    show_manager
-                        .message_queue
-                        .send(MessageKind::InternalMessage(InternalMessage::DmxZeroOut))
-                        .await
-                        .unwrap();
+                    show_manager.stats.record(ShowPlay {
+                        name: current_show.name.clone(),
+                        started: started.0,
+                        duration_ms: started.1.elapsed().as_millis() as u64,
+                        outcome: match ended_by {
+                            None => PlayOutcome::Completed,
+                            Some(ShowControl::Skip) => PlayOutcome::Skipped,
+                            Some(ShowControl::Fail(_)) => PlayOutcome::Errored,
+                            Some(_) => PlayOutcome::Stopped,
+                        },
+                        max_lateness_ms: max_lateness.as_millis() as u64,
+                    });
 
-                    // Turn off all lights (MAX_LIGHTS is defined in show/mod.rs)
-                    for light_id in 1..=MAX_LIGHTS {
+                    if let Some(ShowControl::Fail(reason)) = &ended_by {
                         show_manager
                             .message_queue
-                            .send(MessageKind::InternalMessage(InternalMessage::Light {
-                                light_id: light_id as u8,
-                                enable: false,
-                            }))
+                            .send(MessageKind::InternalMessage(InternalMessage::Alert(
+                                format!("{} failed, {}", current_show.name, reason),
+                            )))
                             .await
                             .unwrap();
                     }
 
                     // Remove the current song from the ShowManager
-                    show_manager.current_show = None;
+                    let next = show_manager.current_show.take().and_then(|show| show.next);
+                    show_manager.now_playing.set(None);
+                    show_manager.metrics.set_current_show(None);
 
-                    // Now that this show is done, try loading the next show in
-                    // the queue
                     let mut show_job_queue = show_job_queue_clone.lock().await;
-                    show_job_queue.push_back(ShowElement::RunInit);
-                    show_job_queue.push_back(ShowElement::Home);
-                    show_job_queue.push_back(ShowElement::NextShow);
+                    match ended_by {
+                        // Null out the lasers and go straight to the next
+                        // show, picking one if there isn't one loading
+                        Some(ShowControl::Skip | ShowControl::Fail(_)) => {
+                            show_job_queue.push_front(match show_manager.next_show {
+                                Some(_) => ShowElement::NextShow,
+                                None => ShowElement::PrepareShow(show_manager.next_choice()),
+                            });
+                            show_job_queue.push_front(ShowElement::NullOut);
+                        }
+                        Some(ShowControl::Close(name)) => {
+                            drop(show_job_queue);
+                            close_shows(&mut show_manager, &show_job_queue_clone, name).await;
+                        }
+                        Some(_) => {
+                            drop(show_job_queue);
+                            stop_shows(&mut show_manager, &show_job_queue_clone).await;
+                        }
+                        // That was the closing show
+                        None if show_manager.stopped => {
+                            drop(show_job_queue);
+                            stop_shows(&mut show_manager, &show_job_queue_clone).await;
+                        }
+                        // Now that this show is done, try loading the next
+                        // show in the queue
+                        None => match next {
+                            // Chained shows play straight after each other,
+                            // in place of anything that was loading
+                            Some(next) => {
+                                info!("Playing {} next", next);
+                                show_manager.next_show = None;
+                                show_job_queue.push_front(ShowElement::NextShow);
+                                show_job_queue
+                                    .push_front(ShowElement::PrepareShow(ShowChoice::Name(next)));
+                            }
+                            None => {
+                                show_job_queue.push_back(ShowElement::Home);
+                                show_job_queue.push_back(ShowElement::NextShow);
+                            }
+                        },
+                    }
+                }
+                ShowElement::BoundaryCheck { laser_id } => {
+                    info!("Drawing the boundary on laser {}", laser_id);
+                    show_manager
+                        .frames
+                        .send(MessageKind::InternalMessage(InternalMessage::Laser(
+                            MessageSendPack::boundary_message(laser_id).into(),
+                        )))
+                        .await
+                        .unwrap();
+
+                    show_job_queue_clone
+                        .lock()
+                        .await
+                        .push_front(ShowElement::Idle {
+                            time: show_manager.boundary_check_time,
+                        });
+                }
+                ShowElement::LaserTest { laser_id } => {
+                    let laser_ids = match laser_id {
+                        ALL_LASERS => (1..=MAX_LASERS as u8).collect(),
+                        id => vec![id],
+                    };
+
+                    for laser_id in laser_ids {
+                        info!("Testing laser {}", laser_id);
+                        for step in MessageSendPack::test_sequence(laser_id) {
+                            show_manager
+                                .frames
+                                .send(MessageKind::InternalMessage(InternalMessage::Laser(
+                                    step.into(),
+                                )))
+                                .await
+                                .unwrap();
+
+                            sleep(show_manager.laser_test_step).await;
+                        }
+                    }
+                }
+                ShowElement::LaserPattern { laser_id, name } => {
+                    let Some(draw_instructions) = show_manager.patterns.get(&name) else {
+                        error!("Unknown laser pattern: {}", name);
+                        continue;
+                    };
+
+                    info!("Drawing {} on laser {}", name, laser_id);
+                    show_manager
+                        .frames
+                        .send(MessageKind::InternalMessage(InternalMessage::Laser(
+                            MessageSendPack::draw(laser_id, draw_instructions.clone()).into(),
+                        )))
+                        .await
+                        .unwrap();
                 }
                 ShowElement::NullOut => {
-                    info!("Nulling out the projector");
+                    info!("Nulling out the lasers");
 
-                    // Send a null out command
                     show_manager
-                        .message_queue
+                        .frames
                         .send(MessageKind::InternalMessage(InternalMessage::Laser(
-                            MessageSendPack {
-                                header: HeaderPack {
-                                    laser_id: 16.into(),
-                                    point_count: 0.into(),
-                                    home: false,
-                                    enable: true,
-                                    configuration_mode: false,
-                                    draw_boundary: false,
-                                    oneshot: false,
-                                    speed_profile: 0.into(),
-                                    ..Default::default()
-                                },
-                                draw_instruction: PatternPack::default(),
-                            }
-                            .into(),
+                            FrameSendPack::null_out().into(),
                         )))
                         .await
                         .unwrap();
 
-                    // Sleep for 3 seconds
-                    sleep(Duration::from_secs(3)).await;
+                    // Let the galvos settle before homing them again. Once
+                    // the shows are stopped, starting them homes the lasers.
+                    if !show_manager.stopped {
+                        let mut show_job_queue = show_job_queue_clone.lock().await;
+                        show_job_queue.push_front(ShowElement::Home);
+                        show_job_queue.push_front(ShowElement::Idle { time: 3 });
+                    }
                 }
                 ShowElement::Idle { time } => {
                     // Sleep for the given time. Print once a second that we're
@@ -784,51 +1576,2106 @@ async fn show_task_loop(
                         info!("{} seconds remaining", time_remaining);
                     }
                 }
-                ShowElement::Transition { show_id: _ } => todo!(),
-                ShowElement::LightTest => {
-                    info!("Starting the light test");
-
-                    // Turn on all the lights one at a time. Leave each one on
-                    // for 1 second, then turn it off
-                    for i in 0..=8 {
+                ShowElement::Transition { next } => {
+                    let fade = Duration::from_millis(show_manager.config.transition_ms);
+                    info!("Transitioning to {:?} over {:?}", next, fade);
+
+                    for message in [
+                        InternalMessage::AudioFadeOut(fade),
+                        InternalMessage::DmxFadeToBlackout(fade),
+                        InternalMessage::Laser(FrameSendPack::null_out().into()),
+                    ] {
                         show_manager
-                            .message_queue
-                            .send(MessageKind::InternalMessage(InternalMessage::Light {
-                                light_id: i,
-                                enable: true,
-                            }))
+                            .lane(&message)
+                            .send(MessageKind::InternalMessage(message))
                             .await
                             .unwrap();
+                    }
 
-                        // Sleep for 1 second
-                        sleep(Duration::from_secs(1)).await;
+                    sleep(fade).await;
 
-                        // Turn the light off
-                        show_manager
+                    // Hold the blackout and make sure the lights are off
+                    // before the next show starts
+                    show_manager
+                        .frames
+                        .send(MessageKind::InternalMessage(InternalMessage::DmxBlackout))
+                        .await
+                        .unwrap();
+                    let (done, _) = oneshot::channel();
+                    show_manager
+                        .message_queue
+                        .send(MessageKind::InternalMessage(InternalMessage::AllLightsOff(
+                            done,
+                        )))
+                        .await
+                        .unwrap();
+
+                    // Whatever was loading gives way to the show asked for
+                    show_manager.next_show = None;
+                    let mut show_job_queue = show_job_queue_clone.lock().await;
+                    show_job_queue.push_front(ShowElement::NextShow);
+                    show_job_queue.push_front(ShowElement::PrepareShow(next));
+                }
+                ShowElement::LightTest => {
+                    // Lights are driven from the receiver task, so it runs
+                    // the test and this waits on it
+                    let (done_tx, done_rx) = oneshot::channel();
+                    show_manager
+                        .message_queue
+                        .send(MessageKind::InternalMessage(InternalMessage::LightTest(
+                            done_tx,
+                        )))
+                        .await
+                        .unwrap();
+
+                    match done_rx.await {
+                        Ok(report) => show_manager
                             .message_queue
-                            .send(MessageKind::InternalMessage(InternalMessage::Light {
-                                light_id: i,
-                                enable: false,
-                            }))
+                            .send(MessageKind::InternalMessage(
+                                InternalMessage::LightTestReport(report),
+                            ))
                             .await
-                            .unwrap();
+                            .unwrap(),
+                        Err(_) => error!("The light test never finished"),
                     }
                 }
                 ShowElement::RunInit => {
-                    // Return if we're not on the pi
-                    if !cfg!(feature = "pi") {
-                        info!("Not on the pi, skipping init");
-                        continue;
+                    let report = run_init(&mut show_manager, &show_job_queue_clone).await;
+                    let failed = report.failed.is_some();
+                    show_manager
+                        .message_queue
+                        .send(MessageKind::InternalMessage(InternalMessage::InitReport(
+                            report,
+                        )))
+                        .await
+                        .unwrap();
+
+                    // Nothing can go ahead on hardware that didn't come up
+                    if failed {
+                        stop_shows(&mut show_manager, &show_job_queue_clone).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Handle a control that came in while no show was playing
+async fn idle_control(
+    show_manager: &mut ShowManager,
+    show_job_queue: &Arc<Mutex<VecDeque<ShowElement>>>,
+    control: ShowControl,
+) {
+    match control {
+        ShowControl::Start if show_manager.stopped => {
+            info!("Starting shows");
+            show_manager.stopped = false;
+            let mut show_job_queue = show_job_queue.lock().await;
+            show_job_queue.push_back(ShowElement::RunInit);
+            show_job_queue.push_back(match show_manager.next_show {
+                Some(_) => ShowElement::NextShow,
+                None => ShowElement::PrepareShow(show_manager.next_choice()),
+            });
+        }
+        ShowControl::Stop if !show_manager.stopped => {
+            info!("Stopping shows");
+            stop_outputs(show_manager, InternalMessage::AudioStop).await;
+            stop_shows(show_manager, show_job_queue).await;
+        }
+        ShowControl::Close(name) if !show_manager.stopped => {
+            close_shows(show_manager, show_job_queue, name).await;
+        }
+        ShowControl::Start | ShowControl::Stop | ShowControl::Close(_) => {
+            info!("Ignoring {:?}, shows are already in that state", control)
+        }
+        ShowControl::Rescan => rescan_shows(&mut show_manager.shows, &show_manager.config),
+        ShowControl::Init => queue_init(show_job_queue).await,
+        ShowControl::LaserTest { laser_id, boundary } => {
+            queue_laser_test(show_job_queue, laser_id, boundary).await
+        }
+        ShowControl::SetTimeScale(scale) => match valid_time_scale(scale) {
+            true => {
+                info!("Playing shows at {}x", scale);
+                show_manager.time_scale = scale;
+            }
+            false => warn!("Ignoring a time scale of {}", scale),
+        },
+        ShowControl::CancelPrepare => cancel_prepare(&mut show_manager.next_show),
+        ShowControl::Prepare(choice) if !show_manager.stopped => {
+            prepare_next(&mut show_manager.next_show, show_job_queue, choice).await
+        }
+        ShowControl::Prepare(_) => info!("Shows are stopped, ignoring {:?}", control),
+        ShowControl::QueueSnapshot(_) | ShowControl::ClearQueue | ShowControl::RemoveAt(_) => {
+            queue_control(show_job_queue, &control).await
+        }
+        ShowControl::Tags { include, exclude } => {
+            set_tags(&mut show_manager.tags, TagFilter { include, exclude })
+        }
+        control => info!("No show is playing, ignoring {:?}", control),
+    }
+}
+
+/// Turn the lights and DMX off, stopping the audio with `audio_stop`
+async fn stop_outputs(show_manager: &ShowManager, audio_stop: InternalMessage) {
+    show_manager
+        .message_queue
+        .send(MessageKind::InternalMessage(audio_stop))
+        .await
+        .unwrap();
+
+    // Zero out DMX channels
+    show_manager
+        .frames
+        .send(MessageKind::InternalMessage(InternalMessage::DmxZeroOut))
+        .await
+        .unwrap();
+
+    // Turn off all the configured lights, nothing needs to wait for it
+    let (done, _) = oneshot::channel();
+    show_manager
+        .message_queue
+        .send(MessageKind::InternalMessage(InternalMessage::AllLightsOff(
+            done,
+        )))
+        .await
+        .unwrap();
+}
+
+/// Bring the hardware up from scratch: set up the serial port on the Pi,
+/// send each laser its config, home them, put the DMX at its startup values
+/// and flash each light once. Stops at the first step that fails.
+async fn run_init(
+    show_manager: &mut ShowManager,
+    show_job_queue: &Arc<Mutex<VecDeque<ShowElement>>>,
+) -> InitReport {
+    let mut report = InitReport::default();
+
+    if cfg!(feature = "pi") {
+        info!("Init: setting up the serial port");
+        match std::process::Command::new("sh").arg(INIT_SCRIPT).status() {
+            Ok(status) if status.success() => report.done.push("serial"),
+            Ok(status) => {
+                return report.fail("serial", format!("the init script exited with {}", status))
+            }
+            Err(e) => return report.fail("serial", e),
+        }
+    }
+
+    info!(
+        "Init: configuring {} lasers",
+        show_manager.config.lasers.len()
+    );
+    let laser = &show_manager.config.laser;
+    for projector_id in show_manager.config.lasers.iter().map(|laser| laser.id) {
+        // Checked here too, so a value that doesn't fit fails the init
+        // instead of only being logged by the laser controller
+        if let Err(e) = FrameSendPack::config_message(
+            laser.acceleration,
+            laser.max_speed,
+            laser.min_speed,
+            laser.x_home,
+            laser.y_home,
+            projector_id,
+            laser.transfer_size,
+        ) {
+            return report.fail("laser config", e);
+        }
+
+        show_manager
+            .frames
+            .send(MessageKind::InternalMessage(InternalMessage::LaserConfig {
+                acceleration: laser.acceleration,
+                max_speed: laser.max_speed,
+                min_speed: laser.min_speed,
+                x_home: laser.x_home,
+                y_home: laser.y_home,
+                projector_id,
+            }))
+            .await
+            .unwrap();
+    }
+    sleep(Duration::from_millis(laser.config_wait_ms)).await;
+    report.done.push("laser config");
+
+    info!("Init: homing the lasers");
+    if let Err(e) = home_lasers(show_manager, show_job_queue).await {
+        return report.fail("homing", e);
+    }
+    report.done.push("homing");
+
+    info!("Init: setting the DMX startup values");
+    let startup = show_manager
+        .config
+        .dmx
+        .startup
+        .iter()
+        .map(|(&channel, &value)| (channel, value))
+        .collect();
+    for message in [
+        InternalMessage::DmxUpdateState(startup),
+        InternalMessage::DmxSendRequest,
+    ] {
+        show_manager
+            .frames
+            .send(MessageKind::InternalMessage(message))
+            .await
+            .unwrap();
+    }
+    report.done.push("dmx");
+
+    info!("Init: flashing {} lights", show_manager.config.lights.len());
+    let flash = Duration::from_millis(show_manager.config.light.test_step_ms);
+    for light_id in show_manager.config.lights.iter().map(|light| light.id) {
+        for (level, hold) in [(255, flash), (0, Duration::ZERO)] {
+            show_manager
+                .message_queue
+                .send(MessageKind::InternalMessage(InternalMessage::LightLevel {
+                    light_id,
+                    level,
+                }))
+                .await
+                .unwrap();
+            sleep(hold).await;
+        }
+    }
+    report.done.push("lights");
+
+    info!("{}", report);
+    report
+}
+
+/// Home the lasers. With homing reports, wait up to `home_wait` for every
+/// laser in the config to report and home them all again if any of them
+/// fails, carrying on once the wait is up. Without them, idle for `home_wait`
+/// and hope it finished.
+async fn home_lasers(
+    show_manager: &mut ShowManager,
+    show_job_queue: &Arc<Mutex<VecDeque<ShowElement>>>,
+) -> Result<(), Error> {
+    // Reports from before this homing don't count
+    if let Some(home_status) = show_manager.home_status.as_mut() {
+        while home_status.try_recv().is_ok() {}
+    }
+
+    let attempts = show_manager.home_retries + 1;
+    for attempt in 1..=attempts {
+        info!("Homing the projector");
+        show_manager
+            .frames
+            .send(MessageKind::InternalMessage(InternalMessage::LaserHome))
+            .await
+            .unwrap();
+
+        let Some(home_status) = show_manager.home_status.as_mut() else {
+            show_job_queue.lock().await.push_front(ShowElement::Idle {
+                time: show_manager.home_wait,
+            });
+            return Ok(());
+        };
+
+        let mut waiting = show_manager
+            .config
+            .lasers
+            .iter()
+            .map(|laser| laser.id)
+            .collect::<BTreeSet<_>>();
+        let wait = Duration::from_secs(show_manager.home_wait);
+        let deadline = sleep(wait);
+        tokio::pin!(deadline);
+        let failed = loop {
+            if waiting.is_empty() {
+                info!("The lasers are homed");
+                return Ok(());
+            }
+
+            tokio::select! {
+                _ = &mut deadline => {
+                    // Firmware without homing reports never sends any
+                    match waiting.len() == show_manager.config.lasers.len() {
+                        true => info!("No homing reports after {:?}, carrying on", wait),
+                        false => warn!(
+                            "Lasers {:?} didn't report homing after {:?}, carrying on",
+                            waiting, wait
+                        ),
+                    }
+                    return Ok(());
+                }
+                status = home_status.recv() => match status {
+                    Some(HomeStatus::Homed { laser_id }) => {
+                        waiting.remove(&laser_id);
+                    }
+                    Some(HomeStatus::Failed { laser_id }) => break laser_id,
+                    None => {
+                        warn!("Homing reports stopped, waiting on homing from now on");
+                        show_manager.home_status = None;
+                        show_job_queue.lock().await.push_front(ShowElement::Idle {
+                            time: show_manager.home_wait,
+                        });
+                        return Ok(());
                     }
+                },
+            }
+        };
+        warn!("Laser {} failed to home (attempt {})", failed, attempt);
+    }
+
+    Err(Error::msg(format!(
+        "the lasers failed to home {} times",
+        attempts
+    )))
+}
+
+/// Drop everything queued, null out the lasers and wait to be started again
+async fn stop_shows(
+    show_manager: &mut ShowManager,
+    show_job_queue: &Arc<Mutex<VecDeque<ShowElement>>>,
+) {
+    show_manager.stopped = true;
+    let mut show_job_queue = show_job_queue.lock().await;
+    show_job_queue.clear();
+    show_job_queue.push_back(ShowElement::NullOut);
+}
+
+/// Whether another show is loading, or queued to be
+async fn show_waiting(
+    show_manager: &ShowManager,
+    show_job_queue: &Arc<Mutex<VecDeque<ShowElement>>>,
+) -> bool {
+    show_manager.next_show.is_some()
+        || show_job_queue.lock().await.iter().any(|element| {
+            matches!(
+                element,
+                ShowElement::PrepareShow(_) | ShowElement::Transition { .. }
+            )
+        })
+}
+
+/// Drop everything queued and transition into `name`, stopping once it's
+/// played
+async fn close_shows(
+    show_manager: &mut ShowManager,
+    show_job_queue: &Arc<Mutex<VecDeque<ShowElement>>>,
+    name: ShowName,
+) {
+    info!("Closing with {}", name);
+    show_manager.closing = true;
+    let mut show_job_queue = show_job_queue.lock().await;
+    show_job_queue.clear();
+    show_job_queue.push_back(ShowElement::Transition {
+        next: ShowChoice::Name(name),
+    });
+}
 
-                    // Run the init script on the pi from the absolute file
-                    // It is located at ~/Halloween/uart/init_serial.sh
-                    let output = std::process::Command::new("sh")
-                        .arg("/home/pi/Halloween/uart/init_serial.sh")
-                        .output()
-                        .expect("Failed to run init script");
+/// Sleep until `timestamp` milliseconds into the show, handling controls as
+/// they come in. Time spent paused moves `start_time` on, so the frames stay
+/// lined up with the audio. Progress is reported whenever it's due along the
+/// way. Returns the control that ended the show, if one did.
+#[allow(clippy::too_many_arguments)]
+async fn wait_for_frame(
+    start_time: &mut Instant,
+    controls: &mut Option<mpsc::Receiver<ShowControl>>,
+    message_queue: &mpsc::Sender<MessageKind>,
+    shows: &mut ShowMap,
+    config: &Config,
+    tags: &mut TagFilter,
+    next_show: &mut Option<LoadingShow>,
+    show_job_queue: &Arc<Mutex<VecDeque<ShowElement>>>,
+    progress: &mut ShowProgress,
+    timestamp: u64,
+) -> Option<ShowControl> {
+    let mut paused_at: Option<Instant> = None;
+    loop {
+        let due = *start_time + progress.due_after(timestamp);
+        let report_due = *start_time + progress.due_after(progress.next_report_ms);
+        let control = tokio::select! {
+            biased;
+            _ = sleep_until(due), if paused_at.is_none() => return None,
+            _ = sleep_until(report_due), if paused_at.is_none() => {
+                progress.next_report_ms += PROGRESS_INTERVAL;
+                message_queue
+                    .send(MessageKind::InternalMessage(progress.message(*start_time)))
+                    .await
+                    .unwrap();
+                continue;
+            }
+            control = next_control(controls) => control,
+        };
+
+        let audio = match (&control, paused_at) {
+            (ShowControl::Pause, None) => {
+                info!("Pausing the show");
+                paused_at = Some(Instant::now());
+                InternalMessage::AudioPause
+            }
+            (ShowControl::Resume, Some(paused)) => {
+                info!("Resuming the show");
+                *start_time += paused.elapsed();
+                paused_at = None;
+                InternalMessage::AudioResume
+            }
+            (ShowControl::Skip, _) => {
+                info!("Skipping the show");
+                return Some(control);
+            }
+            (ShowControl::Fail(reason), _) => {
+                error!("The show failed: {}", reason);
+                return Some(control);
+            }
+            (ShowControl::Stop, _) => {
+                info!("Stopping shows");
+                return Some(control);
+            }
+            (ShowControl::Close(name), _) => {
+                info!("Closing with {}", name);
+                return Some(control);
+            }
+            // The show that's playing was already loaded, so it's left alone
+            (ShowControl::Rescan, _) => {
+                rescan_shows(shows, config);
+                continue;
+            }
+            (ShowControl::CancelPrepare, _) => {
+                cancel_prepare(next_show);
+                continue;
+            }
+            (ShowControl::Init, _) => {
+                queue_init(show_job_queue).await;
+                continue;
+            }
+            (ShowControl::LaserTest { laser_id, boundary }, _) => {
+                queue_laser_test(show_job_queue, *laser_id, *boundary).await;
+                continue;
+            }
+            // Played once this one's finished
+            (ShowControl::Prepare(choice), _) => {
+                prepare_next(next_show, show_job_queue, choice.clone()).await;
+                continue;
+            }
+            // Keep the show where it's up to, so only what's left of it
+            // speeds up or slows down
+            (ShowControl::SetTimeScale(scale), _) => {
+                if !valid_time_scale(*scale) {
+                    warn!("Ignoring a time scale of {}", scale);
+                    continue;
+                }
+                let now = paused_at.unwrap_or_else(Instant::now);
+                let position = scale_duration(
+                    now.saturating_duration_since(*start_time),
+                    progress.time_scale,
+                );
+                *start_time = now - scale_duration(position, 1.0 / *scale);
+
+                info!("Playing at {}x", scale);
+                let was_real_time = progress.time_scale == 1.0;
+                progress.time_scale = *scale;
+                match was_real_time && *scale != 1.0 {
+                    true => InternalMessage::AudioStop,
+                    false => continue,
                 }
             }
+            (
+                ShowControl::QueueSnapshot(_) | ShowControl::ClearQueue | ShowControl::RemoveAt(_),
+                _,
+            ) => {
+                queue_control(show_job_queue, &control).await;
+                continue;
+            }
+            // Used for the next show that's picked
+            (ShowControl::Tags { include, exclude }, _) => {
+                set_tags(
+                    tags,
+                    TagFilter {
+                        include: include.clone(),
+                        exclude: exclude.clone(),
+                    },
+                );
+                continue;
+            }
+            (control, _) => {
+                info!("Ignoring {:?}, the show is already in that state", control);
+                continue;
+            }
+        };
+        message_queue
+            .send(MessageKind::InternalMessage(audio))
+            .await
+            .unwrap();
+    }
+}
+
+/// Look at or change the show queue, holding the same lock as the worker
+async fn queue_control(show_job_queue: &Arc<Mutex<VecDeque<ShowElement>>>, control: &ShowControl) {
+    let mut show_job_queue = show_job_queue.lock().await;
+    match control {
+        ShowControl::QueueSnapshot(reply) => reply.send(
+            show_job_queue
+                .iter()
+                .enumerate()
+                .map(|(index, element)| ShowElementSummary {
+                    index,
+                    description: element.describe(),
+                })
+                .collect(),
+        ),
+        ShowControl::ClearQueue => {
+            info!("Clearing {} elements from the queue", show_job_queue.len());
+            show_job_queue.clear();
         }
+        ShowControl::RemoveAt(index) => match show_job_queue.remove(*index) {
+            Some(element) => info!("Removed {:?} from the queue", element),
+            None => warn!(
+                "There's nothing at {} in the queue, it has {} elements",
+                index,
+                show_job_queue.len()
+            ),
+        },
+        _ => {}
+    }
+}
+
+/// `duration` times `by`, rounded to the nanosecond so a scale of 1 leaves it
+/// exactly as it was
+fn scale_duration(duration: Duration, by: f32) -> Duration {
+    Duration::from_nanos((duration.as_nanos() as f64 * by as f64).round() as u64)
+}
+
+fn valid_time_scale(scale: f32) -> bool {
+    scale.is_finite() && scale > 0.0
+}
+
+/// Initialise the hardware as soon as the worker is free
+async fn queue_init(show_job_queue: &Arc<Mutex<VecDeque<ShowElement>>>) {
+    info!("Initialising the hardware next");
+    show_job_queue.lock().await.push_front(ShowElement::RunInit);
+}
+
+async fn queue_laser_test(
+    show_job_queue: &Arc<Mutex<VecDeque<ShowElement>>>,
+    laser_id: u8,
+    boundary: bool,
+) {
+    let element = match boundary {
+        true => ShowElement::BoundaryCheck { laser_id },
+        false => ShowElement::LaserTest { laser_id },
+    };
+    info!("{} next", element.describe());
+    show_job_queue.lock().await.push_front(element);
+}
+
+/// Load `choice` as soon as the worker is free, dropping whatever was loading
+/// so it's the next show played
+async fn prepare_next(
+    next_show: &mut Option<LoadingShow>,
+    show_job_queue: &Arc<Mutex<VecDeque<ShowElement>>>,
+    choice: ShowChoice,
+) {
+    info!("Playing {} next", choice.describe());
+    if let Some(show) = next_show.take() {
+        show.cancel();
+    }
+    show_job_queue
+        .lock()
+        .await
+        .push_front(ShowElement::PrepareShow(choice));
+}
+
+fn cancel_prepare(next_show: &mut Option<LoadingShow>) {
+    match next_show.take() {
+        Some(show) => show.cancel(),
+        None => info!("There's no show loading to cancel"),
+    }
+}
+
+fn set_tags(tags: &mut TagFilter, filter: TagFilter) {
+    match filter.is_empty() {
+        true => info!("Picking from all of the shows"),
+        false => info!("Picking shows tagged {:?}", filter),
+    }
+    *tags = filter;
+}
+
+/// Load the shows on disk again in place of `shows`. Anything loading or
+/// playing already has its own copy, so it carries on. Shows whose files
+/// haven't changed keep their parsed frames.
+fn rescan_shows(shows: &mut ShowMap, config: &Config) {
+    let (mut found, discovery) = ShowManager::load_shows(config);
+    for (key, show) in &mut found {
+        if let Some(old) = shows
+            .get(key)
+            .filter(|old| old.path == show.path && old.modified == show.modified)
+        {
+            show.parsed = old.parsed.clone();
+        }
+    }
+    let added = found
+        .keys()
+        .filter(|name| !shows.contains_key(*name))
+        .collect::<BTreeSet<_>>();
+    let removed = shows
+        .keys()
+        .filter(|name| !found.contains_key(*name))
+        .collect::<BTreeSet<_>>();
+    info!(
+        "Rescanned the shows, added {:?}, removed {:?}, skipped {}",
+        added,
+        removed,
+        discovery.skipped.len()
+    );
+
+    *shows = found;
+}
+
+/// Never returns if there's no way to get controls
+async fn next_control(controls: &mut Option<mpsc::Receiver<ShowControl>>) -> ShowControl {
+    if let Some(receiver) = controls.as_mut() {
+        match receiver.recv().await {
+            Some(control) => return control,
+            None => *controls = None,
+        }
+    }
+
+    std::future::pending().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        audio::{Audio, LoadingSong},
+        config::{Laser, Light, Pin, ShowServerConfig, StatsConfig},
+        test_util::TempDir,
+    };
+    use axum::{
+        extract::{Query, State},
+        http::StatusCode,
+        routing::post,
+        Json, Router,
+    };
+    use common::SerializableShow;
+    use std::collections::BTreeMap;
+
+    /// Plays frames every 100ms at `time_scale`, sending each timestamp out
+    /// once it's due
+    fn play(
+        controls: mpsc::Receiver<ShowControl>,
+        time_scale: f32,
+    ) -> (
+        mpsc::Receiver<(u64, Instant)>,
+        mpsc::Receiver<MessageKind>,
+        Instant,
+    ) {
+        let (frame_tx, frame_rx) = mpsc::channel(100);
+        let (message_tx, message_rx) = mpsc::channel(100);
+        let started = Instant::now();
+
+        tokio::spawn(async move {
+            let mut start_time = started;
+            let mut controls = Some(controls);
+            let mut shows = ShowMap::new();
+            let config = Config::default();
+            let mut progress = ShowProgress {
+                time_scale,
+                ..test_progress()
+            };
+            for timestamp in (0..10).map(|frame| frame * 100) {
+                let ended_by = wait_for_frame(
+                    &mut start_time,
+                    &mut controls,
+                    &message_tx,
+                    &mut shows,
+                    &config,
+                    &mut TagFilter::default(),
+                    &mut None,
+                    &Arc::new(Mutex::new(VecDeque::new())),
+                    &mut progress,
+                    timestamp,
+                )
+                .await;
+                if ended_by.is_some() {
+                    break;
+                }
+                frame_tx.send((timestamp, Instant::now())).await.unwrap();
+            }
+        });
+
+        (frame_rx, message_rx, started)
+    }
+
+    fn test_progress() -> ShowProgress {
+        ShowProgress {
+            name: "test".to_string(),
+            total_ms: 10_000,
+            frame_index: 0,
+            next_report_ms: PROGRESS_INTERVAL,
+            time_scale: 1.0,
+        }
+    }
+
+    fn drain<T>(rx: &mut mpsc::Receiver<T>) -> Vec<T> {
+        std::iter::from_fn(|| rx.try_recv().ok()).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pause_and_resume() {
+        let (control_tx, control_rx) = mpsc::channel(10);
+        let (mut frames, mut messages, started) = play(control_rx, 1.0);
+
+        sleep(Duration::from_millis(150)).await;
+        control_tx.send(ShowControl::Pause).await.unwrap();
+        sleep(Duration::from_millis(10)).await;
+        let played = drain(&mut frames);
+        assert_eq!(
+            played
+                .iter()
+                .map(|(timestamp, _)| *timestamp)
+                .collect::<Vec<_>>(),
+            vec![0, 100]
+        );
+        assert!(matches!(
+            drain(&mut messages)[..],
+            [MessageKind::InternalMessage(InternalMessage::AudioPause)]
+        ));
+
+        // Nothing goes out while paused, and pausing again changes nothing
+        control_tx.send(ShowControl::Pause).await.unwrap();
+        sleep(Duration::from_secs(1)).await;
+        assert!(drain(&mut frames).is_empty());
+        assert!(drain(&mut messages).is_empty());
+
+        // Picks up 50ms before the next frame, like it was never paused
+        control_tx.send(ShowControl::Resume).await.unwrap();
+        let (timestamp, played_at) = frames.recv().await.unwrap();
+        assert_eq!(timestamp, 200);
+        assert_eq!(
+            played_at - started,
+            Duration::from_millis(1010) + Duration::from_millis(200)
+        );
+        assert!(matches!(
+            drain(&mut messages)[..],
+            [MessageKind::InternalMessage(InternalMessage::AudioResume)]
+        ));
+
+        let (timestamp, next_at) = frames.recv().await.unwrap();
+        assert_eq!(timestamp, 300);
+        assert_eq!(next_at - played_at, Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_skip() {
+        let (control_tx, control_rx) = mpsc::channel(10);
+        let (mut frames, _messages, _) = play(control_rx, 1.0);
+
+        sleep(Duration::from_millis(250)).await;
+        control_tx.send(ShowControl::Skip).await.unwrap();
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(drain(&mut frames).len(), 3);
+        assert!(frames.recv().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_time_scale() {
+        // All 10 frames go out in a quarter of the time
+        let (_control_tx, control_rx) = mpsc::channel(10);
+        let (mut frames, _messages, started) = play(control_rx, 4.0);
+        let mut played = Vec::new();
+        while let Some((timestamp, played_at)) = frames.recv().await {
+            played.push((timestamp, played_at - started));
+        }
+        assert_eq!(
+            played,
+            (0..10)
+                .map(|frame| (frame * 100, Duration::from_millis(frame * 25)))
+                .collect::<Vec<_>>()
+        );
+
+        // Speeding up part way through only speeds up what's left, and stops
+        // the audio
+        let (control_tx, control_rx) = mpsc::channel(10);
+        let (mut frames, mut messages, started) = play(control_rx, 1.0);
+        sleep(Duration::from_millis(250)).await;
+        control_tx
+            .send(ShowControl::SetTimeScale(2.0))
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(1)).await;
+        assert!(matches!(
+            drain(&mut messages)[..],
+            [MessageKind::InternalMessage(InternalMessage::AudioStop)]
+        ));
+        assert_eq!(drain(&mut frames).len(), 3);
+        let mut last = None;
+        while let Some((timestamp, played_at)) = frames.recv().await {
+            last = Some((timestamp, played_at - started));
+        }
+        assert_eq!(last, Some((900, Duration::from_millis(575))));
+
+        // Rehearsals play without the song
+        let mut worker = Worker::start_with("rehearsal", &[("graveyard", VALID_SHOW)], |manager| {
+            manager.time_scale = 4.0;
+        })
+        .await;
+        worker
+            .queue(vec![
+                ShowElement::PrepareShow(ShowChoice::Name("graveyard".to_string())),
+                ShowElement::NextShow,
+            ])
+            .await;
+        assert_eq!(worker.next().await.unwrap(), "started graveyard");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_late_frames_are_skipped() {
+        let (_control_tx, control_rx) = mpsc::channel(10);
+        let (message_tx, _messages) = mpsc::channel(10);
+        let mut controls = Some(control_rx);
+        let mut shows = ShowMap::new();
+        let config = Config::default();
+
+        let mut start_time = Instant::now();
+        let started = start_time;
+        let mut timing = FrameTiming::default();
+        let mut sent = Vec::new();
+        for timestamp in (0..8).map(|frame| frame * 100) {
+            wait_for_frame(
+                &mut start_time,
+                &mut controls,
+                &message_tx,
+                &mut shows,
+                &config,
+                &mut TagFilter::default(),
+                &mut None,
+                &Arc::new(Mutex::new(VecDeque::new())),
+                &mut test_progress(),
+                timestamp,
+            )
+            .await;
+            let due = start_time + Duration::from_millis(timestamp);
+            if !timing.on_time(due, Instant::now()) {
+                continue;
+            }
+            sent.push((timestamp, Instant::now() - started));
+
+            // Sending the frame at 200ms holds things up past the next two
+            if timestamp == 200 {
+                sleep(Duration::from_millis(250)).await;
+            }
+        }
+
+        // The frames after the hold up go out on time instead of all at once
+        assert_eq!(
+            sent,
+            [0, 100, 200, 500, 600, 700]
+                .map(|ms| (ms, Duration::from_millis(ms)))
+                .to_vec()
+        );
+        assert_eq!(timing.skipped, 2);
+        assert_eq!(timing.max_lateness, Duration::from_millis(150));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_progress_every_second() {
+        let (control_tx, control_rx) = mpsc::channel(10);
+        let (message_tx, mut messages) = mpsc::channel(100);
+
+        let player = tokio::spawn(async move {
+            let mut start_time = Instant::now();
+            let mut controls = Some(control_rx);
+            let mut shows = ShowMap::new();
+            let config = Config::default();
+            let mut progress = test_progress();
+            for (frame_index, timestamp) in [0, 2500, 4000].into_iter().enumerate() {
+                wait_for_frame(
+                    &mut start_time,
+                    &mut controls,
+                    &message_tx,
+                    &mut shows,
+                    &config,
+                    &mut TagFilter::default(),
+                    &mut None,
+                    &Arc::new(Mutex::new(VecDeque::new())),
+                    &mut progress,
+                    timestamp,
+                )
+                .await;
+                progress.frame_index = frame_index;
+            }
+        });
+
+        // Nothing is reported while paused
+        sleep(Duration::from_millis(1500)).await;
+        control_tx.send(ShowControl::Pause).await.unwrap();
+        sleep(Duration::from_secs(2)).await;
+        control_tx.send(ShowControl::Resume).await.unwrap();
+        player.await.unwrap();
+
+        let reports = drain(&mut messages)
+            .into_iter()
+            .filter_map(|message| match message {
+                MessageKind::InternalMessage(InternalMessage::ShowProgress {
+                    elapsed_ms,
+                    frame_index,
+                    ..
+                }) => Some((elapsed_ms, frame_index)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(reports, vec![(1000, 0), (2000, 0), (3000, 1)]);
+    }
+
+    const VALID_SHOW: &str = r#"{ "0": { "turret-1": 1 }, "2500": { "turret-1": 0 } }"#;
+
+    /// Write a show folder with one instruction file, and a song if `song`
+    fn write_show(dir: &Path, name: &str, instructions: &str, song: bool) {
+        let show_dir = dir.join(name);
+        std::fs::create_dir_all(&show_dir).unwrap();
+        std::fs::write(show_dir.join("instructions-exported-1.json"), instructions).unwrap();
+        if song {
+            std::fs::write(show_dir.join(format!("{}.mp3", name)), []).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_load_shows_skips_unplayable_ones() {
+        let dir = TempDir::new("shows");
+        write_show(&dir, "graveyard", VALID_SHOW, true);
+        write_show(&dir, "silent", VALID_SHOW, false);
+        write_show(&dir, "corrupt", "{ \"0\": ", true);
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+
+        let config = Config {
+            shows_dir: dir.to_path_buf(),
+            ..Default::default()
+        };
+        let (shows, discovery) = ShowManager::load_shows(&config);
+
+        // The corrupt one isn't read yet, so it's found like any other, and
+        // one without a song plays without audio
+        assert_eq!(
+            discovery.loaded,
+            vec![
+                "corrupt-instructions-exported-1.json".to_string(),
+                "graveyard-instructions-exported-1.json".to_string(),
+                "silent-instructions-exported-1.json".to_string()
+            ]
+        );
+        assert_eq!(shows.len(), 3);
+        assert!(shows.values().all(|show| show.parsed.is_none()));
+
+        assert_eq!(discovery.skipped.len(), 1);
+        assert_eq!(discovery.skipped[0].0, dir.join("empty"));
+        assert_eq!(discovery.skipped[0].1, "there are no instruction files");
+    }
+
+    #[tokio::test]
+    async fn test_shows_are_parsed_when_prepared() {
+        let dir = TempDir::new("parse");
+        write_show(&dir, "graveyard", VALID_SHOW, true);
+        write_show(&dir, "corrupt", "{ \"0\": ", true);
+
+        let config = Config {
+            shows_dir: dir.to_path_buf(),
+            ..Default::default()
+        };
+        let (shows, _) = ShowManager::load_shows(&config);
+        let (tx, _rx) = mpsc::channel(100);
+        let mut manager = ShowManager::new(shows, tx, &config);
+
+        let graveyard = "graveyard-instructions-exported-1.json";
+        assert_eq!(manager.parse_show(graveyard).unwrap().frames.len(), 2);
+        assert!(manager.shows[graveyard].parsed.is_some());
+
+        // Once it's parsed, the file isn't needed
+        std::fs::remove_file(&manager.shows[graveyard].path).unwrap();
+        assert_eq!(manager.parse_show(graveyard).unwrap().frames.len(), 2);
+
+        // A show that doesn't parse is dropped
+        assert!(manager.show_variant("corrupt").is_none());
+        assert_eq!(manager.show_names(), vec!["graveyard"]);
+    }
+
+    #[test]
+    fn test_tag_filter() {
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>();
+        let filter = TagFilter {
+            include: tags(&["kids", "upbeat"]),
+            exclude: tags(&["scary"]),
+        };
+
+        assert!(filter.matches(&tags(&["kids"])));
+        assert!(filter.matches(&tags(&["upbeat", "long"])));
+        assert!(!filter.matches(&tags(&["long"])));
+        assert!(!filter.matches(&tags(&["kids", "scary"])));
+        assert!(!filter.matches(&[]));
+
+        // Only excluding lets untagged shows through
+        let filter = TagFilter {
+            include: Vec::new(),
+            exclude: tags(&["scary"]),
+        };
+        assert!(filter.matches(&[]));
+        assert!(!filter.matches(&tags(&["scary"])));
+        assert!(TagFilter::default().matches(&tags(&["scary"])));
+    }
+
+    #[tokio::test]
+    async fn test_pick_random_by_tags() {
+        let mut shows = ShowMap::new();
+        for (name, tags) in [("graveyard", "scary"), ("pumpkins", "kids"), ("bats", "")] {
+            let tags = tags
+                .split_terminator(',')
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            shows.insert(
+                format!("{}-instructions.json", name),
+                IndexedShow {
+                    name: name.to_string(),
+                    tags: tags.clone(),
+                    path: PathBuf::from(name),
+                    modified: None,
+                    parsed: Some(UnloadedShow {
+                        name: name.to_string(),
+                        frames: Vec::new(),
+                        looping: false,
+                        next: None,
+                        tags,
+                    }),
+                },
+            );
+        }
+        let (tx, _rx) = mpsc::channel(100);
+        let mut config = Config::default();
+        config.playlist.seed = Some(7);
+        let mut manager = ShowManager::new(shows, tx, &config);
+        let mut pick = |include: &[&str], exclude: &[&str]| {
+            let filter = TagFilter {
+                include: include.iter().map(|tag| tag.to_string()).collect(),
+                exclude: exclude.iter().map(|tag| tag.to_string()).collect(),
+            };
+            (0..20)
+                .map(|_| manager.pick_random(None, filter.clone()).unwrap().name)
+                .collect::<BTreeSet<_>>()
+        };
+
+        assert_eq!(
+            pick(&["kids"], &[]),
+            BTreeSet::from(["pumpkins".to_string()])
+        );
+        assert_eq!(
+            pick(&[], &["scary"]),
+            BTreeSet::from(["pumpkins".to_string(), "bats".to_string()])
+        );
+
+        // Nothing matches, so any show can be picked
+        assert_eq!(pick(&["christmas"], &[]).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_rescan_picks_up_new_shows() {
+        let dir = TempDir::new("rescan");
+        write_show(&dir, "graveyard", VALID_SHOW, true);
+        write_show(&dir, "haunted", VALID_SHOW, true);
+
+        let config = Config {
+            shows_dir: dir.to_path_buf(),
+            ..Default::default()
+        };
+        let (shows, _) = ShowManager::load_shows(&config);
+        let (tx, _rx) = mpsc::channel(100);
+        let mut manager = ShowManager::new(shows, tx, &config);
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        assert_eq!(manager.show_names(), vec!["graveyard", "haunted"]);
+
+        // A show copied in while running, and one taken out
+        write_show(&dir, "pumpkins", VALID_SHOW, true);
+        std::fs::remove_dir_all(dir.join("haunted")).unwrap();
+        idle_control(&mut manager, &queue, ShowControl::Rescan).await;
+
+        assert_eq!(manager.show_names(), vec!["graveyard", "pumpkins"]);
+        assert_eq!(manager.show_variant("pumpkins").unwrap().frames.len(), 2);
+        assert!(manager.show_variant("haunted").is_none());
+        assert!(queue.lock().await.is_empty());
+    }
+
+    const LOOPING_SHOW: &str =
+        r#"{ "loop": true, "0": { "turret-1": 1 }, "1000": { "turret-1": 0 } }"#;
+
+    /// `ms` of silence as a WAV, which is enough for the song to load
+    /// whatever it's called
+    fn write_song(path: &Path, ms: u32) {
+        let data_len = ms * 16;
+        let mut wav = Vec::new();
+        wav.extend(b"RIFF");
+        wav.extend((36 + data_len).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        // PCM, mono, 8kHz, 16 bit
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(8000u32.to_le_bytes());
+        wav.extend(16000u32.to_le_bytes());
+        wav.extend(2u16.to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend(data_len.to_le_bytes());
+        wav.resize(wav.len() + data_len as usize, 0);
+        std::fs::write(path, wav).unwrap();
+    }
+
+    /// What a message does to the outputs, leaving out the show's own frames
+    fn describe(message: &MessageKind) -> Option<String> {
+        let MessageKind::InternalMessage(message) = message;
+        Some(match message {
+            InternalMessage::AudioFadeOut(fade) => format!("fade audio {:?}", fade),
+            InternalMessage::AudioStop => "stop audio".to_string(),
+            InternalMessage::DmxFadeToBlackout(fade) => format!("fade dmx {:?}", fade),
+            InternalMessage::DmxBlackout => "dmx blackout".to_string(),
+            InternalMessage::DmxZeroOut => "dmx zero out".to_string(),
+            InternalMessage::AllLightsOff(_) => "lights off".to_string(),
+            InternalMessage::Laser(_) => "null out".to_string(),
+            InternalMessage::ShowStarted(name) => format!("started {}", name),
+            InternalMessage::Audio { .. } => "play song".to_string(),
+            InternalMessage::LaserHome => "home".to_string(),
+            InternalMessage::Alert(alert) => format!("alert: {}", alert),
+            _ => return None,
+        })
+    }
+
+    /// A show worker playing from a folder of shows
+    struct Worker {
+        dir: TempDir,
+        messages: mpsc::Receiver<MessageKind>,
+        controls: mpsc::Sender<ShowControl>,
+        queue: mpsc::Sender<Vec<ShowElement>>,
+        handle: JoinHandle<()>,
+    }
+
+    impl Worker {
+        /// Start one on `shows`, each a name and its instructions
+        async fn start(test: &str, shows: &[(&str, &str)]) -> Self {
+            Self::start_with(test, shows, |_| {}).await
+        }
+
+        /// Start one, changing the manager with `setup` first
+        async fn start_with(
+            test: &str,
+            shows: &[(&str, &str)],
+            setup: impl FnOnce(&mut ShowManager),
+        ) -> Self {
+            let dir = TempDir::new(test);
+            for (name, instructions) in shows {
+                write_show(&dir, name, instructions, false);
+                write_song(&Audio::song_path(&dir, name), 100);
+            }
+
+            let mut config = Config {
+                shows_dir: dir.to_path_buf(),
+                transition_ms: 1_000,
+                ..Default::default()
+            };
+            config.playlist.state_file = dir.join("show-history.json");
+            config.stats.data_dir = dir.join("data");
+            let (shows, _) = ShowManager::load_shows(&config);
+
+            let (message_tx, messages) = mpsc::channel(1000);
+            let (controls, control_rx) = mpsc::channel(10);
+            let (queue, queue_rx) = mpsc::channel(10);
+            let mut manager = ShowManager::new(shows, message_tx, &config);
+            manager.controls = Some(control_rx);
+            setup(&mut manager);
+            let handle = manager
+                .start_show_worker(queue_rx, CancellationToken::new())
+                .await;
+
+            Worker {
+                dir,
+                messages,
+                controls,
+                queue,
+                handle,
+            }
+        }
+
+        /// The next message that does something to the outputs, or `None`
+        /// once nothing has happened for a minute
+        async fn next(&mut self) -> Option<String> {
+            while let Ok(Some(message)) =
+                tokio::time::timeout(Duration::from_secs(60), self.messages.recv()).await
+            {
+                if let Some(description) = describe(&message) {
+                    return Some(description);
+                }
+            }
+            None
+        }
+
+        async fn queue(&self, elements: Vec<ShowElement>) {
+            self.queue.send(elements).await.unwrap();
+        }
+
+        async fn control(&self, control: ShowControl) {
+            self.controls.send(control).await.unwrap();
+        }
+
+        /// Nothing more will be queued
+        fn close_queue(&mut self) {
+            self.queue = mpsc::channel(1).0;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transition_and_close() {
+        let mut worker = Worker::start(
+            "transition",
+            &[("graveyard", VALID_SHOW), ("goodnight", VALID_SHOW)],
+        )
+        .await;
+
+        // Fade in to the first show like at startup, then close with the
+        // other one partway through it
+        worker
+            .queue(vec![ShowElement::Transition {
+                next: ShowChoice::Name("graveyard".to_string()),
+            }])
+            .await;
+
+        let mut seen = Vec::new();
+        while let Some(description) = worker.next().await {
+            if description == "started graveyard" {
+                worker
+                    .control(ShowControl::Close("goodnight".to_string()))
+                    .await;
+            }
+            seen.push(description);
+        }
+
+        let transition = |name: &str| {
+            vec![
+                "fade audio 1s".to_string(),
+                "fade dmx 1s".to_string(),
+                "null out".to_string(),
+                "dmx blackout".to_string(),
+                "lights off".to_string(),
+                "play song".to_string(),
+                format!("started {}", name),
+            ]
+        };
+        let mut expected = transition("graveyard");
+        expected.extend(transition("goodnight"));
+        // The closing show plays out, then everything stops
+        expected.extend(
+            ["stop audio", "dmx zero out", "lights off", "null out"].map(ToString::to_string),
+        );
+        assert_eq!(seen, expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_looping_show() {
+        let mut worker = Worker::start(
+            "looping",
+            &[("pumpkins", LOOPING_SHOW), ("graveyard", VALID_SHOW)],
+        )
+        .await;
+        worker
+            .queue(vec![
+                ShowElement::PrepareShow(ShowChoice::Name("pumpkins".to_string())),
+                ShowElement::NextShow,
+            ])
+            .await;
+
+        // It plays again with nothing else queued, without starting over
+        let mut seen = Vec::new();
+        while seen.iter().filter(|seen| *seen == "play song").count() < 3 {
+            seen.push(worker.next().await.unwrap());
+        }
+        assert_eq!(
+            seen,
+            ["play song", "started pumpkins", "play song", "play song"]
+        );
+
+        // Skipping cuts it short
+        worker.control(ShowControl::Skip).await;
+        let mut seen = Vec::new();
+        while seen.last().map(String::as_str) != Some("started graveyard") {
+            seen.push(worker.next().await.unwrap());
+        }
+        assert_eq!(
+            seen,
+            [
+                "fade audio 2s",
+                "dmx zero out",
+                "lights off",
+                "null out",
+                "home",
+                "play song",
+                "started graveyard"
+            ]
+        );
+
+        // Every loop counts towards the one play
+        let stats = ShowStats::load(&StatsConfig {
+            data_dir: worker.dir.join("data"),
+        });
+        assert_eq!(stats.plays().len(), 1);
+        assert_eq!(stats.plays()[0].name, "pumpkins");
+        assert_eq!(stats.plays()[0].outcome, PlayOutcome::Skipped);
+        assert!(stats.plays()[0].duration_ms >= 2000);
+
+        // Queueing another show lets it finish, then moves on
+        let mut worker = Worker::start("looping-queued", &[("pumpkins", LOOPING_SHOW)]).await;
+        worker
+            .queue(vec![
+                ShowElement::PrepareShow(ShowChoice::Name("pumpkins".to_string())),
+                ShowElement::NextShow,
+            ])
+            .await;
+        assert_eq!(worker.next().await.unwrap(), "play song");
+        worker
+            .queue(vec![ShowElement::Transition {
+                next: ShowChoice::Name("pumpkins".to_string()),
+            }])
+            .await;
+        let mut seen = Vec::new();
+        while seen.last().map(String::as_str) != Some("fade audio 1s") {
+            seen.push(worker.next().await.unwrap());
+        }
+        assert_eq!(
+            seen,
+            [
+                "started pumpkins",
+                "stop audio",
+                "dmx zero out",
+                "lights off",
+                "fade audio 1s"
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_show_without_a_song() {
+        let mut worker = Worker::start("no-song", &[("silent", VALID_SHOW)]).await;
+        std::fs::remove_file(Audio::song_path(&worker.dir, "silent")).unwrap();
+        worker
+            .queue(vec![ShowElement::PrepareShow(ShowChoice::Name(
+                "silent".to_string(),
+            ))])
+            .await;
+
+        // Nothing to play, and it ends on its last frame
+        assert_eq!(worker.next().await.unwrap(), "started silent");
+        let started = Instant::now();
+        assert_eq!(worker.next().await.unwrap(), "stop audio");
+        let played = started.elapsed();
+        assert!(
+            played >= Duration::from_millis(2500) && played < Duration::from_millis(2600),
+            "{:?}",
+            played
+        );
+
+        let stats = ShowStats::load(&StatsConfig {
+            data_dir: worker.dir.join("data"),
+        });
+        assert_eq!(stats.plays()[0].outcome, PlayOutcome::Completed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unparseable_shows_are_skipped() {
+        let mut worker = Worker::start(
+            "unparseable",
+            &[
+                ("corrupt", "{ \"0\": "),
+                ("laser-zero", r#"{ "0": { "laser-0": 1 } }"#),
+                ("graveyard", VALID_SHOW),
+            ],
+        )
+        .await;
+        worker
+            .queue(vec![
+                ShowElement::PrepareShow(ShowChoice::Name("corrupt".to_string())),
+                ShowElement::PrepareShow(ShowChoice::Name("laser-zero".to_string())),
+            ])
+            .await;
+
+        // The corrupt show is dropped, and a laser that doesn't exist is left
+        // out of its show
+        let mut seen = Vec::new();
+        while seen.last().map(String::as_str) != Some("started laser-zero") {
+            seen.push(worker.next().await.unwrap());
+        }
+        assert!(!seen.contains(&"started corrupt".to_string()));
+
+        // The worker carries on to the next show
+        worker
+            .queue(vec![ShowElement::PrepareShow(ShowChoice::Name(
+                "graveyard".to_string(),
+            ))])
+            .await;
+        while worker.next().await.unwrap() != "started graveyard" {}
+        assert!(!worker.handle.is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_show_with_a_broken_song() {
+        let mut worker = Worker::start(
+            "broken-song",
+            &[("graveyard", VALID_SHOW), ("pumpkins", VALID_SHOW)],
+        )
+        .await;
+        std::fs::write(Audio::song_path(&worker.dir, "graveyard"), "not a song").unwrap();
+        worker
+            .queue(vec![ShowElement::PrepareShow(ShowChoice::Name(
+                "graveyard".to_string(),
+            ))])
+            .await;
+        sleep(Duration::from_secs(1)).await;
+
+        // It's skipped instead of waited on, and the next show plays
+        worker
+            .queue(vec![ShowElement::PrepareShow(ShowChoice::Name(
+                "pumpkins".to_string(),
+            ))])
+            .await;
+        let mut seen = Vec::new();
+        while seen.last().map(String::as_str) != Some("started pumpkins") {
+            seen.push(worker.next().await.unwrap());
+        }
+        assert_eq!(seen, ["play song", "started pumpkins"]);
+
+        let stats = ShowStats::load(&StatsConfig {
+            data_dir: worker.dir.join("data"),
+        });
+        assert_eq!(stats.plays()[0].name, "graveyard");
+        assert_eq!(stats.plays()[0].outcome, PlayOutcome::Errored);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_show_without_frames_or_song() {
+        let mut worker = Worker::start("empty", &[("empty", "{}")]).await;
+        std::fs::remove_file(Audio::song_path(&worker.dir, "empty")).unwrap();
+        worker
+            .queue(vec![ShowElement::PrepareShow(ShowChoice::Name(
+                "empty".to_string(),
+            ))])
+            .await;
+
+        // There's nothing to play, so it ends straight away
+        assert_eq!(worker.next().await.unwrap(), "started empty");
+        let started = Instant::now();
+        assert_eq!(worker.next().await.unwrap(), "stop audio");
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert!(!worker.handle.is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_song_longer_than_frames() {
+        let mut worker = Worker::start("long-song", &[("graveyard", VALID_SHOW)]).await;
+        write_song(&Audio::song_path(&worker.dir, "graveyard"), 4000);
+        worker
+            .queue(vec![ShowElement::PrepareShow(ShowChoice::Name(
+                "graveyard".to_string(),
+            ))])
+            .await;
+
+        // The last frame's held until the song's done, rather than cutting
+        // it off
+        assert_eq!(worker.next().await.unwrap(), "play song");
+        assert_eq!(worker.next().await.unwrap(), "started graveyard");
+        let started = Instant::now();
+        assert_eq!(worker.next().await.unwrap(), "stop audio");
+        let played = started.elapsed();
+        assert!(
+            played >= Duration::from_millis(4000) && played < Duration::from_millis(4100),
+            "{:?}",
+            played
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_show() {
+        let mut worker = Worker::start(
+            "failed",
+            &[("pumpkins", LOOPING_SHOW), ("graveyard", VALID_SHOW)],
+        )
+        .await;
+        worker
+            .queue(vec![
+                ShowElement::PrepareShow(ShowChoice::Name("pumpkins".to_string())),
+                ShowElement::NextShow,
+            ])
+            .await;
+        assert_eq!(worker.next().await.unwrap(), "play song");
+        assert_eq!(worker.next().await.unwrap(), "started pumpkins");
+
+        // The lasers are nulled out and homed before the next show
+        worker
+            .control(ShowControl::Fail("the song stopped".to_string()))
+            .await;
+        let mut seen = Vec::new();
+        while seen.last().map(String::as_str) != Some("started graveyard") {
+            seen.push(worker.next().await.unwrap());
+        }
+        assert_eq!(
+            seen,
+            [
+                "fade audio 2s",
+                "dmx zero out",
+                "lights off",
+                "alert: pumpkins failed, the song stopped",
+                "null out",
+                "home",
+                "play song",
+                "started graveyard"
+            ]
+        );
+
+        let stats = ShowStats::load(&StatsConfig {
+            data_dir: worker.dir.join("data"),
+        });
+        assert_eq!(stats.plays()[0].outcome, PlayOutcome::Errored);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_run_once() {
+        let mut worker = Worker::start_with("run-once", &[("pumpkins", LOOPING_SHOW)], |manager| {
+            manager.run_once = true;
+            manager.closing = true;
+        })
+        .await;
+        worker
+            .queue(vec![
+                ShowElement::PrepareShow(ShowChoice::Name("pumpkins".to_string())),
+                ShowElement::NextShow,
+            ])
+            .await;
+        worker.close_queue();
+
+        // Played once rather than looped, then the worker stops
+        let mut seen = Vec::new();
+        while let Some(description) = worker.next().await {
+            seen.push(description);
+        }
+        assert_eq!(
+            seen,
+            [
+                "play song",
+                "started pumpkins",
+                "stop audio",
+                "dmx zero out",
+                "lights off",
+                "null out"
+            ]
+        );
+        assert!((&mut worker.handle).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_state_survives_restart() {
+        let state_dir = TempDir::new("saved-state-store");
+        let state_path = state_dir.join("state.json");
+        let saved_state = StateStore::new(state_path.clone(), ControllerState::default());
+        let mut worker =
+            Worker::start_with("saved-state", &[("pumpkins", LOOPING_SHOW)], |manager| {
+                manager.run_once = true;
+                manager.closing = true;
+                manager.saved_state = Some(saved_state.clone());
+            })
+            .await;
+        worker
+            .queue(vec![
+                ShowElement::PrepareShow(ShowChoice::Name("pumpkins".to_string())),
+                ShowElement::NextShow,
+            ])
+            .await;
+        worker.close_queue();
+        while worker.next().await.is_some() {}
+
+        // The closing show stopped the shows, which a restart carries on with
+        let state = ControllerState::load(&state_path).unwrap();
+        assert_eq!(state.last_show.as_deref(), Some("pumpkins"));
+        assert!(state.shows_stopped);
+
+        let (tx, _rx) = mpsc::channel(100);
+        let mut manager = ShowManager::new(ShowMap::new(), tx, &Config::default());
+        manager.resume(&state);
+        assert_eq!(manager.last_show_name.as_deref(), Some("pumpkins"));
+        assert!(manager.stopped);
+    }
+
+    /// Stands in for the show-server, telling the test what it was asked
+    fn show_server_stub(events: mpsc::UnboundedSender<String>) -> Router {
+        async fn upload(
+            State(events): State<mpsc::UnboundedSender<String>>,
+            Json(show): Json<SerializableShow>,
+        ) -> StatusCode {
+            events
+                .send(format!(
+                    "upload {}, {} frames",
+                    show.name,
+                    show.frames.len()
+                ))
+                .unwrap();
+            StatusCode::OK
+        }
+
+        async fn start(
+            State(events): State<mpsc::UnboundedSender<String>>,
+            Query(query): Query<HashMap<String, String>>,
+        ) -> StatusCode {
+            events
+                .send(format!("start after {}ms", query["delay_ms"]))
+                .unwrap();
+            StatusCode::OK
+        }
+
+        Router::new()
+            .route("/show/upload", post(upload))
+            .route("/show/start", post(start))
+            .with_state(events)
+    }
+
+    #[tokio::test]
+    async fn test_show_server() {
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, show_server_stub(events_tx))
+                .await
+                .unwrap()
+        });
+
+        let shows = [(
+            "pumpkins",
+            r#"{ "0": { "turret-1": 1 }, "500": { "light-1": true }, "1000": { "turret-1": 0 } }"#,
+        )];
+        let mut worker = Worker::start_with("show-server", &shows, |manager| {
+            manager.show_server = Some(ShowServer::new(&ShowServerConfig {
+                url: format!("http://{}/", addr),
+                enabled: true,
+                start_delay_ms: 250,
+            }));
+        })
+        .await;
+        worker
+            .queue(vec![
+                ShowElement::PrepareShow(ShowChoice::Name("pumpkins".to_string())),
+                ShowElement::NextShow,
+            ])
+            .await;
+        assert_eq!(worker.next().await.unwrap(), "play song");
+
+        let mut seen = Vec::new();
+        while seen.len() < 2 {
+            let event = tokio::time::timeout(Duration::from_secs(10), events.recv()).await;
+            seen.push(event.unwrap().unwrap());
+        }
+        assert_eq!(seen, ["upload pumpkins, 3 frames", "start after 250ms"]);
+
+        // The show plays the same when the show-server can't be reached
+        let mut worker = Worker::start_with("show-server-down", &shows, |manager| {
+            manager.show_server = Some(ShowServer::new(&ShowServerConfig {
+                url: "http://127.0.0.1:1".to_string(),
+                enabled: true,
+                start_delay_ms: 0,
+            }));
+        })
+        .await;
+        worker
+            .queue(vec![
+                ShowElement::PrepareShow(ShowChoice::Name("pumpkins".to_string())),
+                ShowElement::NextShow,
+            ])
+            .await;
+        assert_eq!(worker.next().await.unwrap(), "play song");
+        assert_eq!(worker.next().await.unwrap(), "started pumpkins");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_run_init() {
+        let mut worker = Worker::start_with("init", &[("graveyard", VALID_SHOW)], |manager| {
+            manager.config.lasers = vec![Laser { id: 1 }, Laser { id: 2 }];
+            manager.config.lights = [3, 5]
+                .map(|id| Light {
+                    pin: Pin::Gpio(pi_pinout::GpioPin(id)),
+                    id,
+                    name: None,
+                    pwm: false,
+                    inverted: None,
+                    min_toggle_interval_ms: 0,
+                })
+                .to_vec();
+            manager.config.dmx.startup = BTreeMap::from([(10, 128)]);
+        })
+        .await;
+        worker.control(ShowControl::Init).await;
+
+        let mut seen = Vec::new();
+        loop {
+            let MessageKind::InternalMessage(message) = worker.messages.recv().await.unwrap();
+            seen.push(match message {
+                InternalMessage::LaserConfig {
+                    projector_id,
+                    acceleration,
+                    ..
+                } => format!("configure laser {} at {}", projector_id, acceleration),
+                InternalMessage::LaserHome => "home".to_string(),
+                InternalMessage::DmxUpdateState(state) => format!("dmx {:?}", state),
+                InternalMessage::DmxSendRequest => "send dmx".to_string(),
+                InternalMessage::LightLevel { light_id, level } => {
+                    format!("light {} at {}", light_id, level)
+                }
+                InternalMessage::InitReport(report) => {
+                    seen.push(report.to_string());
+                    break;
+                }
+                _ => continue,
+            });
+        }
+        assert_eq!(
+            seen,
+            [
+                "configure laser 1 at 100000",
+                "configure laser 2 at 100000",
+                "home",
+                "dmx [(10, 128)]",
+                "send dmx",
+                "light 3 at 255",
+                "light 3 at 0",
+                "light 5 at 255",
+                "light 5 at 0",
+                "Init finished: laser config, homing, dmx, lights"
+            ]
+        );
+
+        // A value that doesn't fit stops it before anything's sent
+        let mut worker =
+            Worker::start_with("init-failed", &[("graveyard", VALID_SHOW)], |manager| {
+                manager.config.lasers = vec![Laser { id: 1 }];
+                manager.config.laser.min_speed = 0x2000;
+            })
+            .await;
+        worker.queue(vec![ShowElement::RunInit]).await;
+        loop {
+            let MessageKind::InternalMessage(message) = worker.messages.recv().await.unwrap();
+            match message {
+                InternalMessage::InitReport(report) => {
+                    assert_eq!(
+                        report.to_string(),
+                        "Init failed at laser config, min_speed 8192 is larger than the maximum of 8191"
+                    );
+                    break;
+                }
+                message => assert!(
+                    !matches!(message, InternalMessage::LaserConfig { .. }),
+                    "{:?}",
+                    message
+                ),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_chained_show() {
+        let intro = r#"{ "next": "finale", "0": { "turret-1": 1 }, "1000": { "turret-1": 0 } }"#;
+        let mut worker =
+            Worker::start("chained", &[("intro", intro), ("finale", VALID_SHOW)]).await;
+        worker
+            .queue(vec![
+                ShowElement::PrepareShow(ShowChoice::Name("intro".to_string())),
+                ShowElement::NextShow,
+            ])
+            .await;
+
+        // The finale follows on without homing in between
+        let mut seen = Vec::new();
+        while seen.last().map(String::as_str) != Some("started finale") {
+            seen.push(worker.next().await.unwrap());
+        }
+        assert_eq!(
+            seen,
+            [
+                "play song",
+                "started intro",
+                "stop audio",
+                "dmx zero out",
+                "lights off",
+                "play song",
+                "started finale"
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_prepare() {
+        let mut worker = Worker::start(
+            "prepare",
+            &[
+                ("graveyard", VALID_SHOW),
+                ("pumpkins", VALID_SHOW),
+                ("finale", VALID_SHOW),
+            ],
+        )
+        .await;
+        let prepare = |name: &str| ShowControl::Prepare(ShowChoice::Name(name.to_string()));
+
+        // Nothing's playing, so it starts straight away
+        worker.control(prepare("graveyard")).await;
+        assert_eq!(worker.next().await.unwrap(), "play song");
+        assert_eq!(worker.next().await.unwrap(), "started graveyard");
+
+        // Otherwise it's the next show played
+        worker.control(prepare("finale")).await;
+        let mut seen = Vec::new();
+        while !seen
+            .last()
+            .is_some_and(|seen: &String| seen.starts_with("started"))
+        {
+            seen.push(worker.next().await.unwrap());
+        }
+        assert_eq!(seen.first().unwrap(), "stop audio");
+        assert_eq!(seen.last().unwrap(), "started finale");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_named_show_replaces_a_picked_one() {
+        let mut worker = Worker::start(
+            "replace",
+            &[("graveyard", VALID_SHOW), ("pumpkins", VALID_SHOW)],
+        )
+        .await;
+
+        // The random pick can't be pumpkins, and it's still loading when
+        // pumpkins is asked for
+        worker
+            .queue(vec![
+                ShowElement::PrepareShow(ShowChoice::Random {
+                    last_song: Some("pumpkins".to_string()),
+                }),
+                ShowElement::PrepareShow(ShowChoice::Name("pumpkins".to_string())),
+            ])
+            .await;
+
+        let mut seen = Vec::new();
+        while seen.last().map(String::as_str) != Some("started pumpkins") {
+            seen.push(worker.next().await.unwrap());
+        }
+        assert_eq!(seen, ["play song", "started pumpkins"]);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_prepare() {
+        let (tx, _rx) = mpsc::channel(100);
+        let mut manager = ShowManager::new(ShowMap::new(), tx, &Config::default());
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let song = LoadingSong {
+            name: "graveyard".to_string(),
+            stream: Arc::new(std::sync::Mutex::new(None)),
+            failed: Default::default(),
+            cancelled: Default::default(),
+        };
+        manager.next_show = Some(LoadingShow {
+            song: Some(song.clone()),
+            name: "graveyard".to_string(),
+            frames: Vec::new(),
+            looping: false,
+            next: None,
+        });
+
+        idle_control(&mut manager, &queue, ShowControl::CancelPrepare).await;
+        assert!(manager.next_show.is_none());
+        assert!(song.cancelled.load(std::sync::atomic::Ordering::Relaxed));
+
+        // Nothing else is queued in its place
+        assert!(queue.lock().await.is_empty());
+    }
+
+    /// Each element of the queue, as the worker describes it
+    async fn snapshot(
+        manager: &mut ShowManager,
+        queue: &Arc<Mutex<VecDeque<ShowElement>>>,
+    ) -> Vec<String> {
+        let (reply, answer) = SnapshotReply::new();
+        idle_control(manager, queue, ShowControl::QueueSnapshot(reply)).await;
+        let summary = answer.await.unwrap();
+        assert!(summary
+            .iter()
+            .enumerate()
+            .all(|(index, element)| element.index == index));
+        summary
+            .into_iter()
+            .map(|element| element.description)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_edit_queue_while_idle() {
+        let (tx, _rx) = mpsc::channel(100);
+        let mut manager = ShowManager::new(ShowMap::new(), tx, &Config::default());
+        let queue = Arc::new(Mutex::new(VecDeque::from([
+            ShowElement::Home,
+            ShowElement::PrepareShow(ShowChoice::Name("graveyard".to_string())),
+            ShowElement::Idle { time: 5 },
+            ShowElement::NextShow,
+        ])));
+        assert_eq!(
+            snapshot(&mut manager, &queue).await,
+            [
+                "Home the lasers",
+                "Prepare graveyard",
+                "Idle for 5s",
+                "Play the next show"
+            ]
+        );
+
+        idle_control(&mut manager, &queue, ShowControl::RemoveAt(1)).await;
+        // Past the end, so nothing happens
+        idle_control(&mut manager, &queue, ShowControl::RemoveAt(3)).await;
+        assert_eq!(
+            snapshot(&mut manager, &queue).await,
+            ["Home the lasers", "Idle for 5s", "Play the next show"]
+        );
+
+        idle_control(&mut manager, &queue, ShowControl::ClearQueue).await;
+        assert!(snapshot(&mut manager, &queue).await.is_empty());
+
+        let test = |laser_id, boundary| ShowControl::LaserTest { laser_id, boundary };
+        idle_control(&mut manager, &queue, test(2, true)).await;
+        idle_control(&mut manager, &queue, test(ALL_LASERS, false)).await;
+        assert_eq!(
+            snapshot(&mut manager, &queue).await,
+            ["Test laser 15", "Draw the boundary on laser 2"]
+        );
+    }
+
+    /// Answer each time the lasers are homed with the next reports in
+    /// `statuses`
+    async fn mock_picos(
+        messages: &mut mpsc::Receiver<MessageKind>,
+        reports: &mpsc::Sender<HomeStatus>,
+        statuses: Vec<Vec<HomeStatus>>,
+    ) {
+        for statuses in statuses {
+            let Some(MessageKind::InternalMessage(InternalMessage::LaserHome)) =
+                messages.recv().await
+            else {
+                panic!("Expected the lasers to be homed");
+            };
+            for status in statuses {
+                reports.send(status).await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_homing_with_reports() {
+        let (tx, mut messages) = mpsc::channel(100);
+        let config = Config {
+            lasers: vec![Laser { id: 1 }, Laser { id: 2 }],
+            ..Default::default()
+        };
+        let mut manager = ShowManager::new(ShowMap::new(), tx, &config);
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let (reports, home_status) = mpsc::channel(10);
+        manager.home_status = Some(home_status);
+        let homed = || {
+            vec![
+                HomeStatus::Homed { laser_id: 1 },
+                HomeStatus::Homed { laser_id: 2 },
+            ]
+        };
+        let failed = || vec![HomeStatus::Failed { laser_id: 2 }];
+
+        // A stale report is ignored, and failures are retried
+        reports
+            .send(HomeStatus::Homed { laser_id: 2 })
+            .await
+            .unwrap();
+        let (homed, _) = tokio::join!(
+            home_lasers(&mut manager, &queue),
+            mock_picos(&mut messages, &reports, vec![failed(), failed(), homed()]),
+        );
+        assert!(homed.is_ok());
+        assert!(queue.lock().await.is_empty());
+
+        // Failing every retry gives up
+        let (homed, _) = tokio::join!(
+            home_lasers(&mut manager, &queue),
+            mock_picos(&mut messages, &reports, vec![failed(), failed(), failed()]),
+        );
+        assert_eq!(
+            homed.unwrap_err().to_string(),
+            "the lasers failed to home 3 times"
+        );
+
+        // Not hearing from every laser carries on once the wait is up
+        let started = Instant::now();
+        let (homed, _) = tokio::join!(
+            home_lasers(&mut manager, &queue),
+            mock_picos(
+                &mut messages,
+                &reports,
+                vec![vec![HomeStatus::Homed { laser_id: 1 }]]
+            ),
+        );
+        assert!(homed.is_ok());
+        assert_eq!(started.elapsed(), Duration::from_secs(15));
+        assert!(messages.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_homing_without_reports() {
+        let (tx, mut messages) = mpsc::channel(100);
+        let mut config = Config::default();
+        config.laser.home_wait_secs = 5;
+        let mut manager = ShowManager::new(ShowMap::new(), tx, &config);
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+
+        home_lasers(&mut manager, &queue).await.unwrap();
+        assert!(matches!(
+            messages.try_recv(),
+            Ok(MessageKind::InternalMessage(InternalMessage::LaserHome))
+        ));
+        assert!(matches!(
+            queue.lock().await.as_slices().0,
+            [ShowElement::Idle { time: 5 }]
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_homing_stops_the_shows() {
+        let (reports, home_status) = mpsc::channel(10);
+        let mut worker = Worker::start_with("homing", &[("graveyard", VALID_SHOW)], |manager| {
+            manager.home_status = Some(home_status);
+            manager.config.lasers = vec![Laser { id: 1 }];
+        })
+        .await;
+        worker
+            .queue(vec![
+                ShowElement::Home,
+                ShowElement::PrepareShow(ShowChoice::Name("graveyard".to_string())),
+            ])
+            .await;
+
+        let mut seen = Vec::new();
+        while let Some(description) = worker.next().await {
+            if description == "home" {
+                reports
+                    .send(HomeStatus::Failed { laser_id: 1 })
+                    .await
+                    .unwrap();
+            }
+            seen.push(description);
+        }
+
+        // The show is never prepared
+        assert_eq!(
+            seen,
+            [
+                "home",
+                "home",
+                "home",
+                "alert: Stopped the shows, the lasers failed to home 3 times",
+                "null out"
+            ]
+        );
     }
 }
@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use common::SerializableShow;
+use log::warn;
+use tokio::sync::mpsc;
+
+use crate::config::ShowServerConfig;
+
+use super::prelude::UnloadedShow;
+
+/// Long enough for a big show to upload, short enough that a hung
+/// show-server doesn't hold back the shows after it
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum Request {
+    Upload(SerializableShow),
+    Start,
+}
+
+/// Keeps the show-server playing along with the controller. The requests
+/// happen on their own task in the order they're made, so a show is uploaded
+/// before it's started, and a slow or missing show-server never holds up
+/// playback here.
+pub struct ShowServer {
+    tx: mpsc::UnboundedSender<Request>,
+}
+
+impl ShowServer {
+    pub fn new(config: &ShowServerConfig) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(send_requests(reqwest::Client::new(), config.clone(), rx));
+
+        ShowServer { tx }
+    }
+
+    /// Send a show's frames ahead of it starting
+    pub fn upload(&self, show: &UnloadedShow) {
+        self.send(Request::Upload(show.to_serializable()));
+    }
+
+    /// Start the last show uploaded, as its audio starts
+    pub fn start(&self) {
+        self.send(Request::Start);
+    }
+
+    fn send(&self, request: Request) {
+        if self.tx.send(request).is_err() {
+            warn!("The show-server task has stopped");
+        }
+    }
+}
+
+/// Post each request as it comes in. Failures are only logged, since a retry
+/// would land after the moment it was meant for.
+async fn send_requests(
+    client: reqwest::Client,
+    config: ShowServerConfig,
+    mut rx: mpsc::UnboundedReceiver<Request>,
+) {
+    let url = config.url.trim_end_matches('/');
+
+    while let Some(request) = rx.recv().await {
+        let (action, request) = match request {
+            Request::Upload(show) => (
+                format!("upload {}", show.name),
+                client.post(format!("{}/show/upload", url)).json(&show),
+            ),
+            Request::Start => (
+                "start the show".to_string(),
+                client.post(format!(
+                    "{}/show/start?delay_ms={}",
+                    url, config.start_delay_ms
+                )),
+            ),
+        };
+
+        let result = request
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = result {
+            warn!("Failed to {} on the show-server {}: {}", action, url, e);
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::Error;
+use chrono::{DateTime, Local};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::config::StatsConfig;
+
+/// What the stats are kept in, under `StatsConfig::data_dir`
+pub const STATS_FILE: &str = "show-stats.json";
+
+/// How a show that was due to play ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlayOutcome {
+    /// Played to the end
+    Completed,
+    /// Skipped for the next show
+    Skipped,
+    /// Cut short by stopping or closing the shows
+    Stopped,
+    /// Its song never loaded, so it didn't play
+    Errored,
+}
+
+/// One time a show played
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShowPlay {
+    pub name: String,
+    pub started: DateTime<Local>,
+    /// How long it played for, every loop included
+    pub duration_ms: u64,
+    pub outcome: PlayOutcome,
+    /// The latest any of its frames were reached, from `FrameTiming`
+    #[serde(default)]
+    pub max_lateness_ms: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StatsFile {
+    plays: Vec<ShowPlay>,
+}
+
+/// Everything one show has done
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ShowTotals {
+    pub plays: u32,
+    pub completed: u32,
+    pub skipped: u32,
+    pub stopped: u32,
+    pub errored: u32,
+    pub played: Duration,
+    /// The latest any frame was reached over all its plays
+    pub max_lateness: Duration,
+}
+
+/// Every show that's played, kept across runs
+pub struct ShowStats {
+    path: PathBuf,
+    plays: Vec<ShowPlay>,
+}
+
+impl ShowStats {
+    /// Pick up the stats kept so far, or start afresh without any
+    pub fn load(config: &StatsConfig) -> Self {
+        let path = config.data_dir.join(STATS_FILE);
+        let plays = match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str::<StatsFile>(&contents) {
+                Ok(file) => file.plays,
+                Err(e) => {
+                    warn!("Ignoring the show stats in {}: {}", path.display(), e);
+                    Vec::new()
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                warn!(
+                    "Couldn't read the show stats from {}: {}",
+                    path.display(),
+                    e
+                );
+                Vec::new()
+            }
+        };
+
+        ShowStats { path, plays }
+    }
+
+    pub fn plays(&self) -> &[ShowPlay] {
+        &self.plays
+    }
+
+    /// Write down a play, saving straight away so a crash doesn't lose it
+    pub fn record(&mut self, play: ShowPlay) {
+        self.plays.push(play);
+        if let Err(e) = self.save() {
+            warn!(
+                "Couldn't save the show stats to {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    /// The stats go to a temporary file that's renamed over the old one, so
+    /// a crash part way through leaves the old file whole
+    fn save(&self) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let temp = self.path.with_extension("json.tmp");
+        let mut file = File::create(&temp)?;
+        serde_json::to_writer(
+            &mut file,
+            &StatsFile {
+                plays: self.plays.clone(),
+            },
+        )?;
+        file.flush()?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.path)?;
+
+        Ok(())
+    }
+
+    /// Each show's totals, by name
+    pub fn totals(&self) -> BTreeMap<String, ShowTotals> {
+        let mut totals = BTreeMap::<_, ShowTotals>::new();
+        for play in &self.plays {
+            let show = totals.entry(play.name.clone()).or_default();
+            show.plays += 1;
+            show.played += Duration::from_millis(play.duration_ms);
+            show.max_lateness = show
+                .max_lateness
+                .max(Duration::from_millis(play.max_lateness_ms));
+            match play.outcome {
+                PlayOutcome::Completed => show.completed += 1,
+                PlayOutcome::Skipped => show.skipped += 1,
+                PlayOutcome::Stopped => show.stopped += 1,
+                PlayOutcome::Errored => show.errored += 1,
+            }
+        }
+        totals
+    }
+
+    /// The totals as a table, a show to a line
+    pub fn table(&self) -> String {
+        let totals = self.totals();
+        let width = totals.keys().map(String::len).max().unwrap_or(0).max(4);
+
+        let mut table = format!(
+            "{:width$}  {:>5}  {:>9}  {:>7}  {:>7}  {:>7}  {:>8}\n",
+            "show", "plays", "completed", "skipped", "stopped", "errored", "played"
+        );
+        for (name, show) in totals {
+            let secs = show.played.as_secs();
+            table.push_str(&format!(
+                "{:width$}  {:>5}  {:>9}  {:>7}  {:>7}  {:>7}  {:>8}\n",
+                name,
+                show.plays,
+                show.completed,
+                show.skipped,
+                show.stopped,
+                show.errored,
+                format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+            ));
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn play(name: &str, duration_ms: u64, outcome: PlayOutcome) -> ShowPlay {
+        ShowPlay {
+            name: name.to_string(),
+            started: Local.with_ymd_and_hms(2024, 10, 31, 19, 0, 0).unwrap(),
+            duration_ms,
+            outcome,
+            max_lateness_ms: 0,
+        }
+    }
+
+    #[test]
+    fn test_stats_are_kept_across_runs() {
+        let dir = TempDir::new("stats");
+        let config = StatsConfig {
+            data_dir: dir.join("data"),
+        };
+
+        let mut stats = ShowStats::load(&config);
+        assert!(stats.plays().is_empty());
+        stats.record(play("graveyard", 180_000, PlayOutcome::Completed));
+        stats.record(play("pumpkins", 20_000, PlayOutcome::Skipped));
+
+        // Nothing's left behind from writing it
+        let stats = ShowStats::load(&config);
+        assert_eq!(stats.plays().len(), 2);
+        assert_eq!(
+            stats.plays()[1],
+            play("pumpkins", 20_000, PlayOutcome::Skipped)
+        );
+        assert!(!dir.join("data").join("show-stats.json.tmp").exists());
+
+        // A file that can't be read starts again
+        std::fs::write(dir.join("data").join(STATS_FILE), "{ \"plays\": [").unwrap();
+        assert!(ShowStats::load(&config).plays().is_empty());
+    }
+
+    #[test]
+    fn test_totals_table() {
+        let stats = ShowStats {
+            path: PathBuf::new(),
+            plays: vec![
+                ShowPlay {
+                    max_lateness_ms: 35,
+                    ..play("graveyard", 180_000, PlayOutcome::Completed)
+                },
+                play("graveyard", 3_600_000, PlayOutcome::Stopped),
+                play("bats", 0, PlayOutcome::Errored),
+            ],
+        };
+
+        assert_eq!(
+            stats.totals()["graveyard"],
+            ShowTotals {
+                plays: 2,
+                completed: 1,
+                stopped: 1,
+                played: Duration::from_secs(3780),
+                max_lateness: Duration::from_millis(35),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            stats.table(),
+            "\
+show       plays  completed  skipped  stopped  errored    played
+bats           1          0        0        0        1   0:00:00
+graveyard      2          1        0        1        0   1:03:00
+"
+        );
+    }
+}
@@ -0,0 +1,353 @@
+use std::{fmt, fs, path::Path};
+
+use serde::{
+    de::{IgnoredAny, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use serde_json::Value;
+
+use crate::{
+    config::{validate_dmx_channel, Config},
+    laser::pattern::{MAX_X, MAX_Y},
+};
+
+use super::{
+    prelude::{show_version, DmxStateIndex, ShowFileV2, UnloadedShow, SHOW_FORMAT_VERSION},
+    MAX_LASERS,
+};
+
+/// Something wrong with a show file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShowIssue {
+    /// Roughly where in the file it is, when that can be told
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ShowIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Load a show file, then check for the mistakes that loading lets through.
+/// The show is `None` if it couldn't be loaded at all.
+pub fn validate_show(path: &Path, config: &Config) -> (Option<UnloadedShow>, Vec<ShowIssue>) {
+    let show = match UnloadedShow::load_show_file(path, config) {
+        Ok(show) => show,
+        Err(e) => {
+            let line = e.downcast_ref::<serde_json::Error>().map(|e| e.line());
+            let issue = ShowIssue {
+                line,
+                message: e.to_string(),
+            };
+            return (None, vec![issue]);
+        }
+    };
+
+    // It was just loaded, so it can be read and parsed
+    let contents = fs::read_to_string(path).unwrap_or_default();
+    let mut checks = Checks {
+        contents: &contents,
+        issues: Vec::new(),
+    };
+
+    if show_version(&contents) == SHOW_FORMAT_VERSION {
+        if let Ok(show_file) = serde_json::from_str::<ShowFileV2>(&contents) {
+            checks.frames_v2(config, &show_file);
+        }
+    } else {
+        if let Ok(order) = serde_json::from_str::<FrameOrder>(&contents) {
+            checks.frame_order(&order.0);
+        }
+        if let Ok(Value::Object(frames)) = serde_json::from_str(&contents) {
+            for (timestamp, frame) in frames.iter().filter(|(key, _)| *key != "song") {
+                for (device, state) in frame.as_object().into_iter().flatten() {
+                    checks.device(config, timestamp, device, state);
+                }
+            }
+        }
+    }
+
+    let mut issues = checks.issues;
+    issues.sort_by_key(|issue| issue.line);
+    (Some(show), issues)
+}
+
+struct Checks<'a> {
+    contents: &'a str,
+    issues: Vec<ShowIssue>,
+}
+
+impl Checks<'_> {
+    fn issue(&mut self, line: Option<usize>, message: String) {
+        self.issues.push(ShowIssue { line, message });
+    }
+
+    /// Frames are sorted when they're loaded, so one out of place plays at a
+    /// different point than it was written for
+    fn frame_order(&mut self, timestamps: &[String]) {
+        let mut previous: Option<u64> = None;
+        for key in timestamps.iter().filter(|key| *key != "song") {
+            let Ok(timestamp) = key.parse::<u64>() else {
+                continue;
+            };
+            if let Some(previous) = previous.filter(|previous| timestamp <= *previous) {
+                let line = self.line_of(key, None);
+                self.out_of_order(line, timestamp, previous);
+            }
+            previous = Some(timestamp);
+        }
+    }
+
+    fn device(&mut self, config: &Config, timestamp: &str, device: &str, state: &Value) {
+        let line = self.line_of(device, Some(timestamp));
+        let at = format!("{} at {}ms", device, timestamp);
+
+        if let Some(id) = device.strip_prefix("light-") {
+            self.light(config, line, &at, id.parse().ok());
+        } else if let Some(id) = device.strip_prefix("laser-") {
+            self.laser(line, &at, id.parse().ok());
+            let points = state.get("points").and_then(Value::as_array);
+            let points = points.into_iter().flatten().map(|point| {
+                let coord = |i: usize| point.get(i).and_then(Value::as_u64).unwrap_or(0);
+                (coord(0), coord(1))
+            });
+            self.points(line, &at, points);
+        } else if device == "dmx" {
+            for channel in state.as_object().into_iter().flat_map(|state| state.keys()) {
+                let index = match channel.split_once('.') {
+                    Some((device, var)) => config.get_dmx_state_var_position(device, var),
+                    None => channel.parse::<DmxStateIndex>().map_err(Into::into),
+                };
+                self.dmx_channel(line, &at, index);
+            }
+        }
+    }
+
+    /// The second format's frames are a list, so each one is found by where
+    /// its timestamp is
+    fn frames_v2(&mut self, config: &Config, show_file: &ShowFileV2) {
+        let mut previous: Option<u64> = None;
+        for (i, frame) in show_file.frames.iter().enumerate() {
+            let line = self.line_of_nth("timestamp", i);
+            let timestamp = frame.timestamp;
+            if let Some(previous) = previous.filter(|previous| timestamp <= *previous) {
+                self.out_of_order(line, timestamp, previous);
+            }
+            previous = Some(timestamp);
+
+            for (index, level) in frame.lights.iter().enumerate() {
+                if level.is_some() {
+                    let at = format!("light-{} at {}ms", index + 1, timestamp);
+                    self.light(config, line, &at, u8::try_from(index + 1).ok());
+                }
+            }
+            for (index, laser) in frame.lasers.iter().enumerate() {
+                let at = format!("laser-{} at {}ms", index + 1, timestamp);
+                let points = laser.iter().flat_map(|laser| &laser.points);
+                self.points(line, &at, points.map(|(x, y)| (*x as u64, *y as u64)));
+            }
+            for (channel, _) in &frame.dmx {
+                let at = format!("dmx at {}ms", timestamp);
+                self.dmx_channel(line, &at, Ok(*channel));
+            }
+        }
+    }
+
+    fn out_of_order(&mut self, line: Option<usize>, timestamp: u64, previous: u64) {
+        self.issue(
+            line,
+            format!(
+                "frame {} comes after frame {}, timestamps have to increase",
+                timestamp, previous
+            ),
+        );
+    }
+
+    fn light(&mut self, config: &Config, line: Option<usize>, at: &str, id: Option<u8>) {
+        let known = id.is_some_and(|id| config.lights.iter().any(|light| light.id == id));
+        if !known {
+            self.issue(
+                line,
+                format!(
+                    "{}: isn't one of the {} lights in the config",
+                    at,
+                    config.lights.len()
+                ),
+            );
+        }
+    }
+
+    fn laser(&mut self, line: Option<usize>, at: &str, index: Option<usize>) {
+        if !index.is_some_and(|index| (1..=MAX_LASERS).contains(&index)) {
+            self.issue(
+                line,
+                format!("{}: isn't one of the {} lasers", at, MAX_LASERS),
+            );
+        }
+    }
+
+    /// Only the first point that's out of bounds is reported
+    fn points(&mut self, line: Option<usize>, at: &str, points: impl Iterator<Item = (u64, u64)>) {
+        for (x, y) in points {
+            if x > MAX_X as u64 || y > MAX_Y as u64 {
+                let message = format!("({}, {}) is outside of {}x{}", x, y, MAX_X, MAX_Y);
+                self.issue(line, format!("{}: {}", at, message));
+                break;
+            }
+        }
+    }
+
+    fn dmx_channel(
+        &mut self,
+        line: Option<usize>,
+        at: &str,
+        index: Result<DmxStateIndex, anyhow::Error>,
+    ) {
+        if let Err(e) = index.and_then(validate_dmx_channel) {
+            self.issue(line, format!("{}: {}", at, e));
+        }
+    }
+
+    /// The line `key` is first used as a key on, after the frame at
+    /// `timestamp` if there is one
+    fn line_of(&self, key: &str, timestamp: Option<&str>) -> Option<usize> {
+        let find = |from: usize, key: &str| {
+            self.contents[from..]
+                .find(&format!("\"{}\"", key))
+                .map(|offset| from + offset)
+        };
+
+        let from = match timestamp {
+            Some(timestamp) => find(0, timestamp)?,
+            None => 0,
+        };
+        let offset = find(from, key)?;
+        Some(self.line_at(offset))
+    }
+
+    /// The line of the `n`th use of `key`, counting from 0
+    fn line_of_nth(&self, key: &str, n: usize) -> Option<usize> {
+        let (offset, _) = self
+            .contents
+            .match_indices(&format!("\"{}\"", key))
+            .nth(n)?;
+        Some(self.line_at(offset))
+    }
+
+    fn line_at(&self, offset: usize) -> usize {
+        self.contents[..offset].matches('\n').count() + 1
+    }
+}
+
+/// The keys of a show file in the order they're written, which a map loses
+struct FrameOrder(Vec<String>);
+
+impl<'de> Deserialize<'de> for FrameOrder {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KeyVisitor;
+
+        impl<'de> Visitor<'de> for KeyVisitor {
+            type Value = FrameOrder;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an object of frames")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<FrameOrder, A::Error> {
+                let mut keys = Vec::new();
+                while let Some(key) = map.next_key::<String>()? {
+                    map.next_value::<IgnoredAny>()?;
+                    keys.push(key);
+                }
+                Ok(FrameOrder(keys))
+            }
+        }
+
+        deserializer.deserialize_map(KeyVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    fn two_lights() -> Config {
+        Config {
+            lights: (1..=2)
+                .map(|id| config::Light {
+                    pin: config::Pin::Gpio(pi_pinout::GpioPin(id)),
+                    id,
+                    name: None,
+                    pwm: true,
+                    inverted: None,
+                    min_toggle_interval_ms: 0,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn validate(name: &str) -> (Option<UnloadedShow>, Vec<String>) {
+        let path = format!("tests/fixtures/shows/{}/instructions.json", name);
+        let (show, issues) = validate_show(Path::new(&path), &two_lights());
+        (show, issues.iter().map(ToString::to_string).collect())
+    }
+
+    #[test]
+    fn test_valid_show() {
+        let (show, issues) = validate("validate-ok");
+        assert!(issues.is_empty(), "{:?}", issues);
+        assert_eq!(show.unwrap().frames.len(), 2);
+
+        // It plays without audio
+        let (show, issues) = validate("validate-no-song");
+        assert!(issues.is_empty(), "{:?}", issues);
+        assert!(show.is_some());
+    }
+
+    #[test]
+    fn test_each_problem() {
+        for (name, expected) in [
+            (
+                "validate-order",
+                "8: frame 1000 comes after frame 2000, timestamps have to increase",
+            ),
+            (
+                "validate-lights",
+                "6: light-9 at 1000ms: isn't one of the 2 lights in the config",
+            ),
+            (
+                "validate-laser",
+                "3: laser-1 at 0ms: (320, 40) is outside of 300x300",
+            ),
+            (
+                "validate-laser-id",
+                "3: laser-0 at 0ms: isn't one of the 5 lasers",
+            ),
+            (
+                "validate-dmx",
+                "3: dmx at 0ms: DMX channel 600 is outside of 1..=512",
+            ),
+            (
+                "validate-v2",
+                "9: light-3 at 1000ms: isn't one of the 2 lights in the config",
+            ),
+        ] {
+            let (show, issues) = validate(name);
+            assert!(show.is_some(), "{}", name);
+            assert_eq!(issues, vec![expected.to_string()], "{}", name);
+        }
+
+        // A file that can't be loaded points at where it broke
+        let (show, issues) = validate("validate-json");
+        assert!(show.is_none());
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].starts_with("4: trailing comma"), "{}", issues[0]);
+    }
+}
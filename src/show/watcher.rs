@@ -0,0 +1,83 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::Error;
+use log::{error, info, warn};
+use notify::{RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use crate::{InternalMessage, MessageKind};
+
+use super::prelude::ShowControl;
+
+/// How long the shows folder has to be left alone before it's rescanned.
+/// Copying a show in touches it many times, so this waits for it to finish.
+pub const WATCH_SETTLE: Duration = Duration::from_secs(2);
+
+/// Rescan the shows whenever anything under `shows_dir` changes
+pub fn watch_shows(
+    shows_dir: &Path,
+    settle: Duration,
+    message_queue: mpsc::Sender<MessageKind>,
+) -> Result<(), Error> {
+    let (changed_tx, mut changed_rx) = mpsc::unbounded_channel();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) if event.kind.is_access() => {}
+            Ok(_) => {
+                let _ = changed_tx.send(());
+            }
+            Err(e) => warn!("Error watching the shows: {}", e),
+        })?;
+    watcher.watch(shows_dir, RecursiveMode::Recursive)?;
+    info!("Watching {} for new shows", shows_dir.display());
+
+    tokio::spawn(async move {
+        // Stops watching once this is dropped
+        let _watcher = watcher;
+
+        while changed_rx.recv().await.is_some() {
+            // Wait until nothing has changed for a while
+            while let Ok(Some(())) = tokio::time::timeout(settle, changed_rx.recv()).await {}
+
+            info!("The shows changed on disk");
+            if message_queue
+                .send(MessageKind::InternalMessage(InternalMessage::ShowControl(
+                    ShowControl::Rescan,
+                )))
+                .await
+                .is_err()
+            {
+                error!("The message queue has stopped");
+                return;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[tokio::test]
+    async fn test_new_show_sends_rescan() {
+        let dir = TempDir::new("watch");
+
+        let (tx, mut rx) = mpsc::channel(10);
+        watch_shows(&dir, Duration::from_millis(200), tx).unwrap();
+
+        let show_dir = dir.join("graveyard");
+        std::fs::create_dir_all(&show_dir).unwrap();
+        std::fs::write(show_dir.join("instructions-exported-1.json"), "{}").unwrap();
+
+        let message = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await;
+        let Ok(Some(MessageKind::InternalMessage(InternalMessage::ShowControl(
+            ShowControl::Rescan,
+        )))) = message
+        else {
+            panic!("Expected a rescan");
+        };
+    }
+}
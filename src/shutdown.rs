@@ -0,0 +1,287 @@
+use std::time::Duration;
+
+use anyhow::Error;
+use log::{error, info};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::{sleep_until, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{dmx::DmxMessage, prelude::FrameSendPack, InternalMessage, MessageKind};
+
+/// How long shutdown waits on each task before giving up on it
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long the audio takes to fade out on the way down
+pub const SHUTDOWN_FADE: Duration = Duration::from_secs(1);
+
+/// Tasks stop a stage at a time, in this order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// The show worker and anything else that starts shows
+    Shows,
+    /// The show manager's laser and DMX lane, which sends out what the shows
+    /// left in it before the outputs are turned off
+    Frames,
+    /// The main message queue, once the outputs have been told to turn off
+    Receiver,
+    /// The tasks driving the hardware, which finish what's queued for them
+    Outputs,
+    /// The UART, last so it writes out everything the outputs sent it
+    Uart,
+}
+
+const STAGES: [Stage; 5] = [
+    Stage::Shows,
+    Stage::Frames,
+    Stage::Receiver,
+    Stage::Outputs,
+    Stage::Uart,
+];
+
+/// Hands each task a token for its stage, and stops them in order so the
+/// hardware is left off rather than wherever the last frame put it
+#[derive(Default)]
+pub struct Shutdown {
+    tokens: [CancellationToken; STAGES.len()],
+    tasks: Vec<(Stage, &'static str, JoinHandle<()>)>,
+}
+
+impl Shutdown {
+    /// What a task in `stage` stops on
+    pub fn token(&self, stage: Stage) -> CancellationToken {
+        self.tokens[stage as usize].clone()
+    }
+
+    /// Wait on a task when its stage stops
+    pub fn track(&mut self, stage: Stage, name: &'static str, handle: JoinHandle<()>) {
+        self.tasks.push((stage, name, handle));
+    }
+
+    /// Stop the shows, fade the audio, null out the lasers, black out the DMX
+    /// and turn off the lights, then stop everything else and let the UART
+    /// write out what's left. Tasks that don't stop in time are aborted and
+    /// named in the error. `dmx` is `None` when the DMX is turned off.
+    pub async fn run(
+        mut self,
+        message_queue: &mpsc::Sender<MessageKind>,
+        dmx: Option<&mpsc::Sender<DmxMessage>>,
+    ) -> Result<(), Error> {
+        let mut stuck = Vec::new();
+        for stage in STAGES {
+            if stage == Stage::Receiver {
+                let fade_end = Instant::now() + SHUTDOWN_FADE;
+                if let Err(e) = turn_off_outputs(message_queue, dmx).await {
+                    error!("Failed to turn off the outputs: {}", e);
+                }
+                sleep_until(fade_end).await;
+            }
+
+            info!("Stopping {:?}...", stage);
+            self.tokens[stage as usize].cancel();
+            let (tasks, rest) = std::mem::take(&mut self.tasks)
+                .into_iter()
+                .partition::<Vec<_>, _>(|(task_stage, _, _)| *task_stage == stage);
+            self.tasks = rest;
+
+            for (_, name, mut handle) in tasks {
+                if tokio::time::timeout(SHUTDOWN_TIMEOUT, &mut handle)
+                    .await
+                    .is_err()
+                {
+                    handle.abort();
+                    stuck.push(name);
+                }
+            }
+        }
+
+        match stuck.is_empty() {
+            true => Ok(()),
+            false => Err(Error::msg(format!(
+                "{} didn't stop in time",
+                stuck.join(", ")
+            ))),
+        }
+    }
+}
+
+/// Send the outputs their off states through the message queue, so they land
+/// after anything the shows left in it
+async fn turn_off_outputs(
+    message_queue: &mpsc::Sender<MessageKind>,
+    dmx: Option<&mpsc::Sender<DmxMessage>>,
+) -> Result<(), Error> {
+    // Including anything the operator was holding, unless the DMX is turned
+    // off
+    if let Some(dmx) = dmx {
+        dmx.send(DmxMessage::ClearOverrides)
+            .await
+            .map_err(|_| Error::msg("the DMX task has stopped"))?;
+    }
+
+    let (lights_off_tx, lights_off_rx) = oneshot::channel();
+    for message in [
+        InternalMessage::AudioFadeOut(SHUTDOWN_FADE),
+        InternalMessage::Laser(FrameSendPack::null_out().into()),
+        InternalMessage::DmxBlackout,
+        InternalMessage::AllLightsOff(lights_off_tx),
+    ] {
+        message_queue
+            .send(MessageKind::InternalMessage(message))
+            .await
+            .map_err(|_| Error::msg("the receiver task has stopped"))?;
+    }
+
+    match tokio::time::timeout(SHUTDOWN_TIMEOUT, lights_off_rx).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(_)) => Err(Error::msg("the receiver task dropped the lights off")),
+        Err(_) => Err(Error::msg("timed out turning off the lights")),
+    }
+}
+
+/// The next message, or `None` once the senders are gone. When `cancel`
+/// fires the channel is closed, so what's already queued is still handed out
+/// before it runs dry.
+pub async fn recv_until<T>(rx: &mut mpsc::Receiver<T>, cancel: &CancellationToken) -> Option<T> {
+    tokio::select! {
+        message = rx.recv() => message,
+        _ = cancel.cancelled() => {
+            rx.close();
+            rx.recv().await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{
+        config::{Config, DmxSinkKind},
+        dmx::DmxState,
+        laser::{LaserController, LaserMessage},
+        show::prelude::{ShowElement, ShowManager, ShowMap},
+        turret::TurretController,
+        uart::{port::mock::MockPort, UartController},
+    };
+
+    /// Stands in for the receiver in `main`, passing the lasers and DMX on
+    /// and noting everything else
+    async fn receiver(
+        mut rx: mpsc::Receiver<MessageKind>,
+        laser_tx: mpsc::Sender<LaserMessage>,
+        dmx_tx: mpsc::Sender<DmxMessage>,
+        seen: Arc<Mutex<Vec<String>>>,
+        cancel: CancellationToken,
+    ) {
+        while let Some(MessageKind::InternalMessage(message)) = recv_until(&mut rx, &cancel).await {
+            let description = match message {
+                InternalMessage::AudioFadeOut(_) => "fade audio",
+                InternalMessage::Laser(frame) => {
+                    laser_tx.send(LaserMessage::Frame(frame)).await.unwrap();
+                    "null out"
+                }
+                InternalMessage::DmxBlackout => {
+                    dmx_tx.send(DmxMessage::Blackout).await.unwrap();
+                    "dmx blackout"
+                }
+                InternalMessage::AllLightsOff(done) => {
+                    done.send(()).unwrap();
+                    "lights off"
+                }
+                _ => continue,
+            };
+            seen.lock().unwrap().push(description.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_in_order() {
+        let mut shutdown = Shutdown::default();
+        let mut config = Config::default();
+        config.dmx.sink = Some(DmxSinkKind::Uart);
+
+        let port = MockPort::new(None);
+        let writes = port.writes.clone();
+        let (uart_tx, uart_rx) = mpsc::channel(100);
+        let uart = UartController::with_port(Box::new(port));
+        let uart = tokio::spawn(uart.start(uart_rx, shutdown.token(Stage::Uart)));
+        shutdown.track(Stage::Uart, "UART", uart);
+
+        let (laser_tx, laser_rx) = mpsc::channel(100);
+        let (uart_tx_clone, cancel) = (uart_tx.clone(), shutdown.token(Stage::Outputs));
+        let lasers = tokio::spawn(async move {
+            let mut lasers = LaserController::init(&Config::default());
+            lasers.start(laser_rx, uart_tx_clone, cancel).await;
+        });
+        shutdown.track(Stage::Outputs, "lasers", lasers);
+
+        let (dmx_tx, dmx_rx) = mpsc::channel(100);
+        let dmx = DmxState::init(config.clone());
+        let dmx = tokio::spawn(dmx.start(dmx_rx, uart_tx, shutdown.token(Stage::Outputs)));
+        shutdown.track(Stage::Outputs, "DMX", dmx);
+
+        let (_turret_tx, turret_rx) = mpsc::channel(100);
+        let turrets = TurretController::init(&config).unwrap();
+        let turrets = tokio::spawn(turrets.start(turret_rx, shutdown.token(Stage::Outputs)));
+        shutdown.track(Stage::Outputs, "turrets", turrets);
+
+        let (message_tx, message_rx) = mpsc::channel(100);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let receiver = tokio::spawn(receiver(
+            message_rx,
+            laser_tx,
+            dmx_tx.clone(),
+            seen.clone(),
+            shutdown.token(Stage::Receiver),
+        ));
+        shutdown.track(Stage::Receiver, "receiver", receiver);
+
+        // The show worker is part way through waiting when it's stopped
+        let (queue_tx, queue_rx) = mpsc::channel(10);
+        let manager = ShowManager::new(ShowMap::new(), message_tx.clone(), &config);
+        let worker = manager
+            .start_show_worker(queue_rx, shutdown.token(Stage::Shows))
+            .await;
+        shutdown.track(Stage::Shows, "show worker", worker);
+        queue_tx
+            .send(vec![ShowElement::Idle { time: 60 }])
+            .await
+            .unwrap();
+
+        // Something for the blackout to turn off
+        dmx_tx
+            .send(DmxMessage::UpdateState(vec![(1, 255)]))
+            .await
+            .unwrap();
+        dmx_tx.send(DmxMessage::Send).await.unwrap();
+        // Past the send window, so it goes out on its own
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let started = Instant::now();
+        shutdown.run(&message_tx, Some(&dmx_tx)).await.unwrap();
+        assert!(started.elapsed() < SHUTDOWN_FADE + SHUTDOWN_TIMEOUT);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["fade audio", "null out", "dmx blackout", "lights off"]
+        );
+
+        // The UART wrote out the null out and the blackout before it stopped
+        let writes = writes.lock().unwrap();
+        assert!(writes
+            .iter()
+            .any(|data| data.len() == 4 * 51 && data[4..].iter().all(|&byte| byte == 0)));
+        let last_dmx = writes
+            .iter()
+            .rev()
+            .find(|data| data[0] >> 4 == 0xA)
+            .unwrap();
+        assert!(last_dmx[1..].iter().all(|&byte| byte == 0));
+        assert!(writes
+            .iter()
+            .any(|data| data[0] >> 4 == 0xA && data[1] == 255));
+    }
+}
@@ -0,0 +1,300 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Error;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::show::prelude::{DmxStateData, DmxStateIndex, ShowName};
+
+/// What the state is kept in, under `StatsConfig::data_dir`
+pub const STATE_FILE: &str = "state.json";
+
+/// Goes up whenever the snapshot changes in a way older versions would get
+/// wrong. Fields that are added need a default, so older snapshots still load.
+pub const STATE_VERSION: u32 = 1;
+
+/// How often the state is saved, on top of whenever it changes
+pub const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What the controller was doing, so a restart carries on from there
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ControllerState {
+    pub version: u32,
+    /// The last show that started playing
+    pub last_show: Option<ShowName>,
+    /// Where the next show in the playlist's order is
+    pub playlist_position: usize,
+    /// Shows don't start on their own until they're started again
+    pub shows_stopped: bool,
+    /// Whether the schedule's hours were open at its last check
+    pub schedule_open: Option<bool>,
+    /// The DMX channels the operator is holding, by address
+    pub dmx_overrides: BTreeMap<DmxStateIndex, DmxStateData>,
+}
+
+impl Default for ControllerState {
+    fn default() -> Self {
+        ControllerState {
+            version: STATE_VERSION,
+            last_show: None,
+            playlist_position: 0,
+            shows_stopped: false,
+            schedule_open: None,
+            dmx_overrides: BTreeMap::new(),
+        }
+    }
+}
+
+impl ControllerState {
+    /// The state from the last run, if there's one that can be used. One that
+    /// can't be read is logged and left for the next save to replace, so it
+    /// never stops the controller starting.
+    pub fn load(path: &Path) -> Option<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!(
+                    "Couldn't read the controller state from {}: {}",
+                    path.display(),
+                    e
+                );
+                return None;
+            }
+        };
+
+        match Self::parse(&contents) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                warn!("Ignoring the controller state in {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// A snapshot from a newer version could mean anything, so it isn't
+    /// guessed at
+    fn parse(contents: &str) -> Result<Self, Error> {
+        #[derive(Deserialize)]
+        struct Version {
+            #[serde(default)]
+            version: u32,
+        }
+
+        let version = serde_json::from_str::<Version>(contents)?.version;
+        if version > STATE_VERSION {
+            return Err(Error::msg(format!(
+                "it's version {}, and only up to {} can be read",
+                version, STATE_VERSION
+            )));
+        }
+
+        Ok(serde_json::from_str(contents)?)
+    }
+}
+
+/// The controller's state, shared by everything that keeps a part of it.
+/// Clones share the same state.
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    path: PathBuf,
+    state: Arc<Mutex<ControllerState>>,
+}
+
+impl StateStore {
+    pub fn new(path: PathBuf, state: ControllerState) -> Self {
+        StateStore {
+            path,
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    pub fn get(&self) -> ControllerState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Change the state, saving it straight away if that changed anything
+    pub fn update(&self, change: impl FnOnce(&mut ControllerState)) {
+        let mut state = self.state.lock().unwrap();
+        let before = state.clone();
+        change(&mut state);
+        if *state != before {
+            self.save_locked(&state);
+        }
+    }
+
+    /// Save the state as it is now
+    pub fn save(&self) {
+        let state = self.state.lock().unwrap();
+        self.save_locked(&state);
+    }
+
+    /// Saving with the lock held keeps two saves from sharing the temporary
+    /// file
+    fn save_locked(&self, state: &ControllerState) {
+        if let Err(e) = write(&self.path, state) {
+            warn!(
+                "Couldn't save the controller state to {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    /// Save every `STATE_SAVE_INTERVAL` until cancelled, then once more
+    pub async fn run(self, cancel: CancellationToken) {
+        let mut interval = tokio::time::interval(STATE_SAVE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => self.save(),
+                _ = cancel.cancelled() => break,
+            }
+        }
+
+        self.save();
+        info!("Saved the controller state to {}", self.path.display());
+    }
+}
+
+/// The state goes to a temporary file that's renamed over the old one, so a
+/// crash part way through leaves the old file whole
+fn write(path: &Path, state: &ControllerState) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let temp = path.with_extension("json.tmp");
+    let mut file = File::create(&temp)?;
+    serde_json::to_writer_pretty(&mut file, state)?;
+    file.flush()?;
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, NaiveTime, Weekday};
+    use tokio::sync::mpsc;
+
+    use crate::{
+        config::{Config, ScheduleConfig},
+        dmx::{DmxHandle, DmxMessage, DmxState},
+        schedule::Scheduler,
+        show::prelude::ShowControl,
+        test_util::TempDir,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = TempDir::new("state-round-trip");
+        let path = dir.join("data").join(STATE_FILE);
+        assert_eq!(ControllerState::load(&path), None);
+
+        let state = ControllerState {
+            last_show: Some("graveyard".to_string()),
+            playlist_position: 2,
+            shows_stopped: true,
+            schedule_open: Some(true),
+            dmx_overrides: BTreeMap::from([(3, 77), (512, 255)]),
+            ..Default::default()
+        };
+        let store = StateStore::new(path.clone(), ControllerState::default());
+        store.update(|saved| *saved = state.clone());
+
+        assert_eq!(ControllerState::load(&path), Some(state));
+        assert!(!path.with_extension("json.tmp").exists());
+    }
+
+    #[test]
+    fn test_unreadable_snapshots() {
+        let dir = TempDir::new("state-unreadable");
+        let path = dir.join(STATE_FILE);
+
+        // Cut short part way through a write
+        fs::write(&path, "{ \"version\": 1, \"last_show\": ").unwrap();
+        assert_eq!(ControllerState::load(&path), None);
+
+        // From a version that hasn't been written yet
+        fs::write(&path, "{ \"version\": 2, \"shows_stopped\": true }").unwrap();
+        assert_eq!(ControllerState::load(&path), None);
+
+        // Anything an older snapshot didn't have starts from its default
+        fs::write(&path, "{ \"version\": 1, \"last_show\": \"bats\" }").unwrap();
+        assert_eq!(
+            ControllerState::load(&path),
+            Some(ControllerState {
+                last_show: Some("bats".to_string()),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restart() {
+        let dir = TempDir::new("state-restart");
+        let path = dir.join(STATE_FILE);
+        let config = Config::default();
+        let schedule = ScheduleConfig {
+            enabled: true,
+            start: NaiveTime::from_hms_opt(18, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+            days: vec![Weekday::Fri],
+            ..Default::default()
+        };
+        // Friday the 6th of October 2023
+        let at = |hour| {
+            NaiveDate::from_ymd_opt(2023, 10, 6)
+                .unwrap()
+                .and_hms_opt(hour, 0, 0)
+                .unwrap()
+        };
+
+        // The first run holds a channel and sees the hours open
+        let store = StateStore::new(path.clone(), ControllerState::default());
+        let mut dmx = DmxState::init(config.clone());
+        dmx.resume(store.clone());
+        let (dmx_tx, dmx_rx) = mpsc::channel(10);
+        let (uart_tx, _uart_rx) = mpsc::channel(10);
+        tokio::spawn(dmx.start(dmx_rx, uart_tx, CancellationToken::new()));
+        let handle = DmxHandle::new(dmx_tx);
+        handle
+            .tx
+            .send(DmxMessage::Override {
+                channel: 3,
+                value: Some(77),
+            })
+            .await
+            .unwrap();
+        handle.query().await.unwrap();
+
+        let mut scheduler = Scheduler::new(&schedule).unwrap();
+        scheduler.resume(store.clone());
+        assert_eq!(scheduler.update(at(19)), Some(ShowControl::Start));
+        drop(store);
+
+        // After a restart the channel's still held, and the hours being open
+        // doesn't start shows that were stopped by hand
+        let store = StateStore::new(path.clone(), ControllerState::load(&path).unwrap());
+        let mut dmx = DmxState::init(config);
+        dmx.resume(store.clone());
+        assert_eq!(dmx.snapshot().overrides[2], Some(77));
+
+        let mut scheduler = Scheduler::new(&schedule).unwrap();
+        scheduler.resume(store);
+        assert_eq!(scheduler.update(at(20)), None);
+        assert_eq!(scheduler.update(at(22)), Some(ShowControl::Stop));
+    }
+}
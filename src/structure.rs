@@ -1,54 +1,465 @@
 use std::{
+    fmt,
     fs::{self, File},
-    io::Write,
-    path::Path,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
 };
 
+use anyhow::Error;
+use serde::Serialize;
+
 use crate::prelude::Audio;
 
-use crate::{prelude::prelude::ShowManager, show::prelude::UnloadedShow};
+use crate::{config::Config, prelude::prelude::ShowManager, show::prelude::UnloadedShow};
+
+/// Written and removed again to check a folder can be written to
+const WRITE_CHECK_FILE: &str = ".rusty-halloween-write-check";
+
+/// A folder the controller reads from or writes to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Folder {
+    pub name: &'static str,
+    pub path: PathBuf,
+    /// Checked for write access and free space, not just that it's there
+    pub writes: bool,
+    /// Made when it's missing, rather than reported
+    pub create: bool,
+}
+
+/// Every folder the config points at, and how much free space the ones that
+/// are written to need
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileStructure {
+    pub folders: Vec<Folder>,
+    pub min_free_mb: u64,
+}
+
+/// What was found in one folder
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FolderReport {
+    pub name: &'static str,
+    pub path: PathBuf,
+    pub created: bool,
+    /// Only known for the folders that are written to
+    pub free_mb: Option<u64>,
+    pub problem: Option<String>,
+}
+
+/// What `verify` or `check` found, for the log and the health checks
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StructureReport {
+    pub folders: Vec<FolderReport>,
+    pub min_free_mb: u64,
+}
 
-pub struct FileStructure {}
+impl StructureReport {
+    pub fn is_ok(&self) -> bool {
+        self.folders.iter().all(|folder| folder.problem.is_none())
+    }
+
+    /// Each problem, with the folder it's in
+    pub fn problems(&self) -> Vec<String> {
+        self.folders
+            .iter()
+            .filter_map(|folder| {
+                let problem = folder.problem.as_ref()?;
+                Some(format!(
+                    "{} ({}): {}",
+                    folder.name,
+                    folder.path.display(),
+                    problem
+                ))
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for StructureReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for folder in &self.folders {
+            write!(f, "{} ({}): ", folder.name, folder.path.display())?;
+            match (&folder.problem, folder.created) {
+                (Some(problem), _) => write!(f, "{}", problem)?,
+                (None, true) => write!(f, "created")?,
+                (None, false) => write!(f, "ok")?,
+            }
+            match folder.free_mb {
+                Some(free_mb) => writeln!(f, ", {} MB free", free_mb)?,
+                None => writeln!(f)?,
+            }
+        }
+
+        Ok(())
+    }
+}
 
 impl FileStructure {
-    pub fn verify() {
-        // Make sure there is a folder in this directory called shows. If there
-        // isn't, create it.
-        if !Path::new("shows").exists() {
-            fs::create_dir("shows").unwrap();
-        }
-
-        // Make sure every embedded song is in its own folder
-        Audio::get_embedded_sounds().iter().for_each(|sound| {
-            if !Path::new(&format!("shows/{}", sound)).exists() {
-                fs::create_dir(format!("shows/{}", sound)).unwrap();
-            }
-        });
-
-        // Save each embedded song to its own folder
-        Audio::get_embedded_sounds().iter().for_each(|sound| {
-            let name = format!("shows/{}/{}.mp3", sound, sound);
-            if !Path::new(&name).exists() {
-                File::create(&name)
-                    .unwrap()
-                    .write_all(&Audio::get_sound_file(sound))
-                    .unwrap();
-            }
-        });
-
-        // Create a instructions.json file for each folder
-        Audio::get_embedded_sounds().iter().for_each(|sound| {
-            let name = format!("shows/{}/instructions.json", sound);
-            if !Path::new(&name).exists() {
-                std::fs::write(
-                    name,
-                    ShowManager::save_show(UnloadedShow {
+    /// The folders `config` uses. The config's own folder is only ever
+    /// checked, since it has to be there for the config to have loaded.
+    pub fn from_config(config: &Config, config_path: &Path) -> Self {
+        let folder = |name, path: &Path, writes, create| Folder {
+            name,
+            path: path.to_path_buf(),
+            writes,
+            create,
+        };
+
+        let mut folders = vec![
+            folder("shows", &config.shows_dir, false, true),
+            folder("data", &config.stats.data_dir, true, true),
+        ];
+        if config.recording.enabled {
+            folders.push(folder("recordings", &config.recording.dir, true, true));
+        }
+        if let Some(file) = &config.logging.file {
+            folders.push(folder("logs", &file.dir, true, true));
+        }
+        let config_dir = match config_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        folders.push(folder("config", config_dir, false, false));
+
+        FileStructure {
+            folders,
+            min_free_mb: config.storage.min_free_mb,
+        }
+    }
+
+    /// Make any folders that are missing, then check them all
+    pub fn verify(&self) -> StructureReport {
+        self.inspect(true)
+    }
+
+    /// Check the folders without changing anything
+    pub fn check(&self) -> StructureReport {
+        self.inspect(false)
+    }
+
+    fn inspect(&self, create: bool) -> StructureReport {
+        StructureReport {
+            folders: self
+                .folders
+                .iter()
+                .map(|folder| self.inspect_folder(folder, create && folder.create))
+                .collect(),
+            min_free_mb: self.min_free_mb,
+        }
+    }
+
+    fn inspect_folder(&self, folder: &Folder, create: bool) -> FolderReport {
+        let mut report = FolderReport {
+            name: folder.name,
+            path: folder.path.clone(),
+            created: false,
+            free_mb: None,
+            problem: None,
+        };
+
+        if !folder.path.exists() {
+            if !create {
+                report.problem = Some("it's missing".to_string());
+                return report;
+            }
+            if let Err(e) = fs::create_dir_all(&folder.path) {
+                report.problem = Some(format!("couldn't make it: {}", e));
+                return report;
+            }
+            report.created = true;
+        }
+
+        if !folder.path.is_dir() {
+            report.problem = Some("it isn't a folder".to_string());
+            return report;
+        }
+        if let Err(e) = fs::read_dir(&folder.path) {
+            report.problem = Some(format!("can't read it: {}", e));
+            return report;
+        }
+        if !folder.writes {
+            return report;
+        }
+
+        if let Err(e) = write_check(&folder.path) {
+            report.problem = Some(format!("can't write to it: {}", e));
+            return report;
+        }
+        match free_bytes(&folder.path) {
+            Ok(free) => {
+                let free_mb = free / (1024 * 1024);
+                report.free_mb = Some(free_mb);
+                if free_mb < self.min_free_mb {
+                    report.problem = Some(format!(
+                        "only {} MB free, {} MB is needed",
+                        free_mb, self.min_free_mb
+                    ));
+                }
+            }
+            Err(e) => report.problem = Some(format!("couldn't get the free space: {}", e)),
+        }
+
+        report
+    }
+
+    /// Ask before making each missing folder and adding the built in songs,
+    /// then check everything
+    pub fn setup<R: BufRead, W: Write>(
+        &self,
+        prompt: &mut Prompt<R, W>,
+    ) -> Result<StructureReport, Error> {
+        for folder in &self.folders {
+            if !folder.create || folder.path.exists() {
+                continue;
+            }
+
+            let question = format!(
+                "The {} folder {} is missing. Make it?",
+                folder.name,
+                folder.path.display()
+            );
+            if prompt.ask(&question, true)? {
+                fs::create_dir_all(&folder.path).map_err(|e| {
+                    Error::msg(format!("Couldn't make {}: {}", folder.path.display(), e))
+                })?;
+            }
+        }
+
+        // Something to play straight away
+        let songs = Audio::get_embedded_sounds();
+        let shows = self.folders.iter().find(|folder| folder.name == "shows");
+        if let Some(shows) = shows.filter(|shows| shows.path.is_dir() && !songs.is_empty()) {
+            let question = format!(
+                "Add a show for each of the {} built in songs to {}?",
+                songs.len(),
+                shows.path.display()
+            );
+            if prompt.ask(&question, false)? {
+                add_embedded_shows(&shows.path)?;
+            }
+        }
+
+        let report = self.check();
+        prompt.say(&report.to_string())?;
+        Ok(report)
+    }
+}
+
+/// Asks yes or no questions, reading answers from `input`
+pub struct Prompt<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Prompt<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Prompt { input, output }
+    }
+
+    /// An empty answer, or running out of input, takes `default`
+    pub fn ask(&mut self, question: &str, default: bool) -> Result<bool, Error> {
+        loop {
+            let choices = match default {
+                true => "[Y/n]",
+                false => "[y/N]",
+            };
+            write!(self.output, "{} {} ", question, choices)?;
+            self.output.flush()?;
+
+            let mut answer = String::new();
+            if self.input.read_line(&mut answer)? == 0 {
+                writeln!(self.output)?;
+                return Ok(default);
+            }
+            match answer.trim().to_lowercase().as_str() {
+                "" => return Ok(default),
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => writeln!(self.output, "Answer y or n")?,
+            }
+        }
+    }
+
+    pub fn say(&mut self, message: &str) -> Result<(), Error> {
+        writeln!(self.output, "{}", message)?;
+        Ok(())
+    }
+}
+
+/// Give each embedded song its own show folder with a copy of the song and a
+/// flashing show to go with it, leaving any that are already there
+fn add_embedded_shows(shows_dir: &Path) -> Result<(), Error> {
+    for sound in Audio::get_embedded_sounds() {
+        let dir = shows_dir.join(&sound);
+        fs::create_dir_all(&dir)?;
+
+        let song = dir.join(format!("{}.mp3", sound));
+        if !song.exists() {
+            File::create(&song)?.write_all(&Audio::get_sound_file(&sound))?;
+        }
+
+        let instructions = dir.join("instructions.json");
+        if !instructions.exists() {
+            fs::write(
+                instructions,
+                ShowManager::save_show(
+                    UnloadedShow {
                         name: format!("{}.mp3", sound),
                         frames: UnloadedShow::row_flashing(),
-                    }),
-                )
-                .unwrap();
-            }
-        });
+                        looping: false,
+                        next: None,
+                        tags: Vec::new(),
+                    },
+                    &Config::default(),
+                ),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Permissions alone don't show a read-only mount or a full disk
+fn write_check(dir: &Path) -> io::Result<()> {
+    let path = dir.join(WRITE_CHECK_FILE);
+    File::create(&path)?.write_all(b"ok")?;
+    fs::remove_file(path)
+}
+
+/// Space on the disk `path` is on that isn't kept for root
+#[cfg(unix)]
+fn free_bytes(path: &Path) -> io::Result<u64> {
+    use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stats = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is nul terminated and `stats` is only read once statvfs
+    // has filled it in
+    let stats = unsafe {
+        if libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stats.assume_init()
+    };
+
+    // They're narrower than u64 on some targets
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_bytes(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "only supported on unix",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::{RecordingConfig, StatsConfig, StorageConfig},
+        test_util::TempDir,
+    };
+
+    use super::*;
+
+    fn structure(dir: &Path) -> FileStructure {
+        let config = Config {
+            shows_dir: dir.join("shows"),
+            stats: StatsConfig {
+                data_dir: dir.join("data"),
+            },
+            recording: RecordingConfig {
+                enabled: true,
+                dir: dir.join("recordings"),
+                ..Default::default()
+            },
+            storage: StorageConfig { min_free_mb: 0 },
+            ..Default::default()
+        };
+        FileStructure::from_config(&config, &dir.join("hardware.json"))
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = TempDir::new("structure-verify");
+        let structure = structure(&dir);
+        let names = structure
+            .folders
+            .iter()
+            .map(|folder| folder.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["shows", "data", "recordings", "config"]);
+
+        // Nothing's made by checking
+        let report = structure.check();
+        assert_eq!(report.problems().len(), 3);
+        assert!(!dir.join("shows").exists());
+
+        let report = structure.verify();
+        assert!(report.is_ok(), "{}", report);
+        assert!(report.folders[..3].iter().all(|folder| folder.created));
+        assert!(dir.join("shows").is_dir() && dir.join("recordings").is_dir());
+        assert!(!dir.join("data").join(WRITE_CHECK_FILE).exists());
+        #[cfg(unix)]
+        assert!(report.folders[1].free_mb.is_some());
+
+        // Already there the second time
+        let report = structure.verify();
+        assert!(report.is_ok());
+        assert!(report.folders.iter().all(|folder| !folder.created));
+    }
+
+    #[test]
+    fn test_problems() {
+        let dir = TempDir::new("structure-problems");
+        let mut structure = structure(&dir);
+        fs::write(dir.join("data"), "not a folder").unwrap();
+        structure.verify();
+
+        structure.min_free_mb = u64::MAX;
+        let report = structure.check();
+        assert_eq!(report.folders[0].problem, None);
+        assert_eq!(
+            report.folders[1].problem.as_deref(),
+            Some("it isn't a folder")
+        );
+        #[cfg(unix)]
+        assert!(report.folders[2]
+            .problem
+            .as_deref()
+            .unwrap()
+            .ends_with("MB is needed"));
+        assert_eq!(report.problems().len(), 2);
+        assert!(!report.is_ok());
+
+        // The config's folder is never made
+        structure.folders.last_mut().unwrap().path = dir.join("gone");
+        let report = structure.verify();
+        assert_eq!(
+            report.folders.last().unwrap().problem.as_deref(),
+            Some("it's missing")
+        );
+        assert!(!dir.join("gone").exists());
+    }
+
+    #[test]
+    fn test_setup() {
+        let dir = TempDir::new("structure-setup");
+        let structure = structure(&dir);
+
+        // Yes to the shows, no to the data, and the default for the rest
+        let mut output = Vec::new();
+        let mut prompt = Prompt::new("y\nmaybe\nn\n\n".as_bytes(), &mut output);
+        let report = structure.setup(&mut prompt).unwrap();
+
+        assert!(dir.join("shows").is_dir());
+        assert!(!dir.join("data").exists());
+        assert!(dir.join("recordings").is_dir());
+        assert_eq!(report.problems().len(), 1);
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Answer y or n"));
+        assert!(output.contains("data ("));
     }
 }
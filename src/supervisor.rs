@@ -0,0 +1,383 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{error, info};
+use serde::Serialize;
+use tokio::{
+    sync::{
+        mpsc::{
+            self,
+            error::{SendError, TrySendError},
+        },
+        watch,
+    },
+    task::{JoinError, JoinHandle},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+
+/// How many times a task can be restarted within `RESTART_WINDOW` before
+/// it's left stopped
+pub const RESTART_BUDGET: usize = 5;
+
+pub const RESTART_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// How long a task that stopped is left before it's started again, so one
+/// that fails straight away doesn't spin
+pub const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Sends to a supervised task. Each restart gets a fresh channel, and this
+/// follows it there.
+pub struct TaskSender<T>(watch::Receiver<mpsc::Sender<T>>);
+
+impl<T> TaskSender<T> {
+    /// The channel to the task that's running now
+    pub fn current(&self) -> mpsc::Sender<T> {
+        self.0.borrow().clone()
+    }
+
+    pub async fn send(&self, message: T) -> Result<(), SendError<T>> {
+        self.current().send(message).await
+    }
+
+    pub fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
+        self.current().try_send(message)
+    }
+}
+
+impl<T> Clone for TaskSender<T> {
+    fn clone(&self) -> Self {
+        TaskSender(self.0.clone())
+    }
+}
+
+impl<T> TaskSender<T> {
+    /// For a task that isn't running at all, so sending fails straight away
+    pub fn closed() -> Self {
+        mpsc::channel(1).0.into()
+    }
+}
+
+/// For a task that's never restarted
+impl<T> From<mpsc::Sender<T>> for TaskSender<T> {
+    fn from(tx: mpsc::Sender<T>) -> Self {
+        TaskSender(watch::channel(tx).1)
+    }
+}
+
+/// How one task has been restarted, for the health checks
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RestartCount {
+    pub total: usize,
+    /// Within the last `RESTART_WINDOW`
+    pub recent: usize,
+    /// Why it last stopped
+    pub last_cause: Option<String>,
+    /// It was restarted too often, so it's been left stopped
+    pub given_up: bool,
+}
+
+#[derive(Debug, Default)]
+struct TaskRestarts {
+    total: usize,
+    recent: VecDeque<Instant>,
+    last_cause: Option<String>,
+    given_up: bool,
+}
+
+/// Every supervised task's restarts. Clones share the same counts.
+#[derive(Debug, Clone, Default)]
+pub struct Restarts(Arc<Mutex<BTreeMap<&'static str, TaskRestarts>>>);
+
+impl Restarts {
+    /// Note that `name` stopped, returning whether it can be restarted
+    /// without going over `budget` in `window`
+    fn record(&self, name: &'static str, cause: String, budget: usize, window: Duration) -> bool {
+        let now = Instant::now();
+        let mut tasks = self.0.lock().unwrap();
+        let task = tasks.entry(name).or_default();
+        while task
+            .recent
+            .front()
+            .is_some_and(|at| now.saturating_duration_since(*at) >= window)
+        {
+            task.recent.pop_front();
+        }
+        task.last_cause = Some(cause);
+
+        if task.recent.len() >= budget {
+            task.given_up = true;
+            return false;
+        }
+        task.total += 1;
+        task.recent.push_back(now);
+        true
+    }
+
+    /// Only tasks that have stopped at least once are in it
+    pub fn report(&self, window: Duration) -> BTreeMap<&'static str, RestartCount> {
+        let now = Instant::now();
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, task)| {
+                let count = RestartCount {
+                    total: task.total,
+                    recent: task
+                        .recent
+                        .iter()
+                        .filter(|at| now.saturating_duration_since(**at) < window)
+                        .count(),
+                    last_cause: task.last_cause.clone(),
+                    given_up: task.given_up,
+                };
+                (*name, count)
+            })
+            .collect()
+    }
+
+    /// The tasks that were left stopped
+    pub fn given_up(&self) -> Vec<&'static str> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, task)| task.given_up)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+}
+
+/// Starts tasks again when they panic or stop before they're told to, up to
+/// `budget` times in `window` each
+#[derive(Debug, Clone)]
+pub struct Supervisor {
+    pub restarts: Restarts,
+    pub budget: usize,
+    pub window: Duration,
+    pub delay: Duration,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Supervisor {
+            restarts: Restarts::default(),
+            budget: RESTART_BUDGET,
+            window: RESTART_WINDOW,
+            delay: RESTART_DELAY,
+        }
+    }
+}
+
+impl Supervisor {
+    /// Run `start` on a channel of `capacity`, running it again on a fresh
+    /// one whenever it stops before `cancel` fires. Returns the sender, which
+    /// follows it across restarts, and the supervising task, which owns the
+    /// task's handle and finishes once the task has stopped for good.
+    pub fn spawn<T, F, Fut>(
+        &self,
+        name: &'static str,
+        capacity: usize,
+        cancel: CancellationToken,
+        mut start: F,
+    ) -> (TaskSender<T>, JoinHandle<()>)
+    where
+        T: Send + 'static,
+        F: FnMut(mpsc::Receiver<T>, CancellationToken) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(capacity);
+        let (senders, sender) = watch::channel(tx);
+        let mut task = tokio::spawn(start(rx, cancel.clone()));
+
+        let supervisor = self.clone();
+        let supervising = tokio::spawn(async move {
+            loop {
+                let result = task.await;
+                // It's meant to stop on the way out
+                if cancel.is_cancelled() {
+                    return;
+                }
+
+                let cause = cause(result);
+                error!("The {} task stopped, {}", name, cause);
+                if !supervisor
+                    .restarts
+                    .record(name, cause, supervisor.budget, supervisor.window)
+                {
+                    error!(
+                        "The {} task stopped {} times in {}s, leaving it stopped",
+                        name,
+                        supervisor.budget + 1,
+                        supervisor.window.as_secs()
+                    );
+                    return;
+                }
+
+                tokio::select! {
+                    _ = tokio::time::sleep(supervisor.delay) => {}
+                    _ = cancel.cancelled() => return,
+                }
+
+                info!("Restarting the {} task", name);
+                let (tx, rx) = mpsc::channel(capacity);
+                senders.send_replace(tx);
+                task = tokio::spawn(start(rx, cancel.clone()));
+            }
+        });
+
+        (TaskSender(sender), supervising)
+    }
+}
+
+/// Why a task stopped, with the panic message if it panicked
+fn cause(result: Result<(), JoinError>) -> String {
+    match result {
+        Ok(()) => "it returned".to_string(),
+        Err(e) if e.is_panic() => {
+            let panic = e.into_panic();
+            match panic
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+            {
+                Some(message) => format!("it panicked: {}", message),
+                None => "it panicked".to_string(),
+            }
+        }
+        Err(e) => e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::sync::oneshot;
+
+    use crate::{
+        config::Config,
+        dmx::{DmxHandle, DmxMessage, DmxState},
+        shutdown::recv_until,
+    };
+
+    use super::*;
+
+    type Ask = (u8, oneshot::Sender<usize>);
+
+    /// The answer from a task that replies with how many times it's been
+    /// started, or panics when it's sent 0
+    async fn ask(tx: &TaskSender<Ask>, message: u8) -> Option<usize> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        tx.send((message, reply_tx)).await.ok()?;
+        reply_rx.await.ok()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restart() {
+        let supervisor = Supervisor {
+            budget: 2,
+            ..Default::default()
+        };
+        let starts = Arc::new(AtomicUsize::new(0));
+        let counted = starts.clone();
+        let (tx, supervising) = supervisor.spawn(
+            "counter",
+            10,
+            CancellationToken::new(),
+            move |mut rx: mpsc::Receiver<Ask>, _| {
+                let started = counted.fetch_add(1, Ordering::SeqCst) + 1;
+                async move {
+                    while let Some((message, reply)) = rx.recv().await {
+                        if message == 0 {
+                            panic!("simulated panic");
+                        }
+                        let _ = reply.send(started);
+                    }
+                }
+            },
+        );
+
+        assert_eq!(ask(&tx, 1).await, Some(1));
+        assert_eq!(ask(&tx, 0).await, None);
+        tokio::time::sleep(RESTART_DELAY * 2).await;
+        assert_eq!(ask(&tx, 1).await, Some(2));
+        assert_eq!(
+            supervisor.restarts.report(RESTART_WINDOW)["counter"],
+            RestartCount {
+                total: 1,
+                recent: 1,
+                last_cause: Some("it panicked: simulated panic".to_string()),
+                given_up: false,
+            }
+        );
+
+        // Only twice an hour, then it's left stopped
+        assert_eq!(ask(&tx, 0).await, None);
+        tokio::time::sleep(RESTART_DELAY * 2).await;
+        assert_eq!(ask(&tx, 0).await, None);
+        supervising.await.unwrap();
+        assert_eq!(ask(&tx, 1).await, None);
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+        assert_eq!(supervisor.restarts.given_up(), ["counter"]);
+
+        // Restarts from over an hour ago aren't recent any more
+        tokio::time::advance(RESTART_WINDOW).await;
+        let report = supervisor.restarts.report(RESTART_WINDOW);
+        assert_eq!((report["counter"].total, report["counter"].recent), (2, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancelled() {
+        let supervisor = Supervisor::default();
+        let cancel = CancellationToken::new();
+        let (_tx, supervising) = supervisor.spawn(
+            "stops",
+            10,
+            cancel.clone(),
+            |mut rx: mpsc::Receiver<()>, cancel| async move {
+                recv_until(&mut rx, &cancel).await;
+            },
+        );
+
+        // Stopping on the way out isn't a failure
+        cancel.cancel();
+        supervising.await.unwrap();
+        assert!(supervisor.restarts.report(RESTART_WINDOW).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dmx_restart() {
+        let supervisor = Supervisor::default();
+        let (uart_tx, _uart_rx) = mpsc::channel(100);
+        let mut panics = true;
+        let (dmx_tx, _supervising) =
+            supervisor.spawn("DMX", 100, CancellationToken::new(), move |rx, cancel| {
+                let (dmx, uart_tx) = (DmxState::init(Config::default()), uart_tx.clone());
+                let panics = std::mem::take(&mut panics);
+                async move {
+                    if panics {
+                        panic!("simulated panic");
+                    }
+                    dmx.start(rx, uart_tx, cancel).await;
+                }
+            });
+        let handle = DmxHandle::new(dmx_tx);
+
+        // The new task picks up messages sent after it's restarted
+        tokio::time::sleep(RESTART_DELAY * 2).await;
+        handle
+            .tx
+            .send(DmxMessage::UpdateState(vec![(1, 200)]))
+            .await
+            .unwrap();
+        handle.tx.send(DmxMessage::Send).await.unwrap();
+        assert_eq!(handle.query().await.unwrap().values[0], 200);
+        assert_eq!(supervisor.restarts.report(RESTART_WINDOW)["DMX"].total, 1);
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Error;
+use log::{error, info};
+use tokio::{sync::mpsc, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+#[cfg(feature = "pi")]
+use rppal::gpio::{Gpio, OutputPin};
+
+#[cfg(feature = "pi")]
+use crate::config::Pin;
+use crate::{config::Config, safety::OutputsEnabled, shutdown::recv_until};
+
+#[derive(Debug)]
+pub enum TurretMessage {
+    Fire(u8),
+}
+
+/// The valve on a turret
+enum Valve {
+    #[cfg(feature = "pi")]
+    Gpio(OutputPin),
+    /// Not on a Pi, or simulated, so only whether it's open is kept
+    Stub { open: bool },
+}
+
+impl Valve {
+    fn set(&mut self, open: bool) {
+        match self {
+            #[cfg(feature = "pi")]
+            Valve::Gpio(pin) => {
+                if open {
+                    pin.set_high();
+                } else {
+                    pin.set_low();
+                }
+            }
+            Valve::Stub { open: stub } => *stub = open,
+        }
+    }
+}
+
+struct Turret {
+    id: u8,
+    pulse: Duration,
+    min_refire: Duration,
+    last_fired: Option<Instant>,
+    /// Shared with the task that closes it again
+    valve: Arc<Mutex<Valve>>,
+}
+
+/// Fires the air cannon turrets, holding each one off until it's allowed to
+/// fire again
+pub struct TurretController {
+    turrets: Vec<Turret>,
+    /// Nothing fires while the e-stop is tripped
+    pub outputs_enabled: OutputsEnabled,
+}
+
+impl TurretController {
+    pub fn init(config: &Config) -> Result<Self, Error> {
+        let mut turrets = Vec::new();
+
+        for trigger in &config.turret_triggers {
+            // Simulating never opens a real valve, even on the Pi
+            #[cfg(feature = "pi")]
+            let valve = if config.mode.is_simulated() {
+                Valve::Stub { open: false }
+            } else {
+                let gpio = match trigger.pin {
+                    Pin::Physical(pin) => pin.into(),
+                    Pin::Gpio(pin) => pin,
+                    Pin::WiringPi(pin) => pin.into(),
+                    Pin::I2cExpander { .. } => {
+                        return Err(Error::msg(format!(
+                            "Turret {} has to be on one of the Pi's pins",
+                            trigger.id
+                        )))
+                    }
+                };
+                info!("Turret {}: initializing on pin {}", trigger.id, gpio.0);
+                // Closed from the start so nothing fires while booting
+                Valve::Gpio(Gpio::new()?.get(gpio.0)?.into_output_low())
+            };
+            #[cfg(not(feature = "pi"))]
+            let valve = Valve::Stub { open: false };
+
+            turrets.push(Turret {
+                id: trigger.id,
+                pulse: Duration::from_millis(trigger.pulse_ms),
+                min_refire: Duration::from_millis(trigger.min_refire_ms),
+                last_fired: None,
+                valve: Arc::new(Mutex::new(valve)),
+            });
+        }
+
+        Ok(TurretController {
+            turrets,
+            outputs_enabled: OutputsEnabled::default(),
+        })
+    }
+
+    pub async fn start(mut self, mut rx: mpsc::Receiver<TurretMessage>, cancel: CancellationToken) {
+        while let Some(message) = recv_until(&mut rx, &cancel).await {
+            match message {
+                TurretMessage::Fire(turret_id) => {
+                    if let Err(e) = self.fire(turret_id) {
+                        error!("Turret {}: not firing: {}", turret_id, e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Open a turret's valve for its pulse, unless it fired too recently
+    pub fn fire(&mut self, turret_id: u8) -> Result<(), Error> {
+        if !self.outputs_enabled.is_enabled() {
+            return Err(Error::msg(
+                "turrets can't fire until the e-stop is re-armed",
+            ));
+        }
+
+        let turret = self
+            .turrets
+            .iter_mut()
+            .find(|turret| turret.id == turret_id)
+            .ok_or_else(|| Error::msg("it isn't in the config"))?;
+
+        let now = Instant::now();
+        if let Some(last_fired) = turret.last_fired {
+            let since = now - last_fired;
+            if since < turret.min_refire {
+                return Err(Error::msg(format!(
+                    "it fired {}ms ago and is locked out for {}ms",
+                    since.as_millis(),
+                    turret.min_refire.as_millis()
+                )));
+            }
+        }
+
+        info!("Turret {}: firing", turret_id);
+        turret.last_fired = Some(now);
+        turret.valve.lock().unwrap().set(true);
+
+        let (valve, pulse) = (turret.valve.clone(), turret.pulse);
+        tokio::spawn(async move {
+            tokio::time::sleep(pulse).await;
+            valve.lock().unwrap().set(false);
+        });
+
+        Ok(())
+    }
+}
+
+/// Never leave a valve open
+impl Drop for TurretController {
+    fn drop(&mut self) {
+        for turret in &self.turrets {
+            turret.valve.lock().unwrap().set(false);
+        }
+    }
+}
+
+// The valves are only stubs off the Pi, and these fire them
+#[cfg(all(test, not(feature = "pi")))]
+mod tests {
+    use super::*;
+    use crate::config::{Pin, RunMode, TurretTrigger};
+
+    fn config() -> Config {
+        Config {
+            turret_triggers: vec![TurretTrigger {
+                pin: Pin::Gpio(pi_pinout::GpioPin(5)),
+                id: 1,
+                pulse_ms: 100,
+                min_refire_ms: 2000,
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn controller() -> TurretController {
+        TurretController::init(&config()).unwrap()
+    }
+
+    fn is_open(turrets: &TurretController) -> bool {
+        match *turrets.turrets[0].valve.lock().unwrap() {
+            Valve::Stub { open } => open,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lockout() {
+        let mut turrets = controller();
+
+        turrets.fire(1).unwrap();
+        assert!(is_open(&turrets));
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(!is_open(&turrets));
+
+        // Still refilling
+        tokio::time::sleep(Duration::from_millis(1800)).await;
+        assert!(turrets.fire(1).is_err());
+        assert!(!is_open(&turrets));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        turrets.fire(1).unwrap();
+        assert!(is_open(&turrets));
+
+        assert!(turrets.fire(2).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_estop() {
+        let mut turrets = controller();
+        turrets.outputs_enabled.disable();
+        assert!(turrets.fire(1).is_err());
+        assert!(!is_open(&turrets));
+
+        // A refused shot doesn't start the lockout
+        turrets.outputs_enabled.rearm();
+        turrets.fire(1).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_simulated() {
+        let config = Config {
+            mode: RunMode::Simulate,
+            ..config()
+        };
+        let mut turrets = TurretController::init(&config).unwrap();
+
+        // Only the stub opens
+        turrets.fire(1).unwrap();
+        assert!(is_open(&turrets));
+    }
+}
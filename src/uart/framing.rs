@@ -0,0 +1,149 @@
+/// Starts a status packet from a Pico. An ack with a status of `0xF` would
+/// look the same, so that status is never sent.
+pub const STATUS_MAGIC: u8 = 0xFF;
+
+/// Longest payload a status packet can carry
+pub const MAX_STATUS_LEN: usize = 32;
+
+/// Something the Picos sent back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Received {
+    /// A single ack byte, see `parse_ack`
+    Ack(u8),
+    /// The payload of a status packet
+    Status(Vec<u8>),
+}
+
+/// Splits the bytes coming off the line into acks and status packets.
+///
+/// A status packet is `STATUS_MAGIC`, the payload length, the payload, then
+/// the XOR of the payload bytes. Any other byte outside of a packet is an ack.
+/// A packet with a bad length or checksum has its magic byte skipped, and
+/// framing carries on from the byte after it.
+#[derive(Debug, Default)]
+pub struct Framer {
+    buf: Vec<u8>,
+    /// Bytes thrown away because they didn't frame
+    pub skipped: u64,
+}
+
+impl Framer {
+    /// Add bytes read from the line, returning everything they complete.
+    /// Part of a packet is kept until the rest of it arrives.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Received> {
+        self.buf.extend_from_slice(bytes);
+
+        let mut received = Vec::new();
+        let mut start = 0;
+        while let Some(&first) = self.buf.get(start) {
+            if first != STATUS_MAGIC {
+                received.push(Received::Ack(first));
+                start += 1;
+                continue;
+            }
+
+            let Some(&len) = self.buf.get(start + 1) else {
+                break;
+            };
+            let len = len as usize;
+            if len == 0 || len > MAX_STATUS_LEN {
+                self.skipped += 1;
+                start += 1;
+                continue;
+            }
+
+            let end = start + 2 + len;
+            let Some(&checksum) = self.buf.get(end) else {
+                break;
+            };
+            let payload = &self.buf[start + 2..end];
+            if payload.iter().fold(0, |acc, byte| acc ^ byte) != checksum {
+                self.skipped += 1;
+                start += 1;
+                continue;
+            }
+
+            received.push(Received::Status(payload.to_vec()));
+            start = end + 1;
+        }
+
+        self.buf.drain(..start);
+        received
+    }
+}
+
+/// Build a status packet around `payload`, the way a Pico sends one
+pub fn status_packet(payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![STATUS_MAGIC, payload.len() as u8];
+    packet.extend_from_slice(payload);
+    packet.push(payload.iter().fold(0, |acc, byte| acc ^ byte));
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_packets() {
+        let packet = status_packet(&[2, 0x10, 0x20]);
+
+        // One byte at a time
+        let mut framer = Framer::default();
+        for byte in &packet[..packet.len() - 1] {
+            assert!(framer.push(&[*byte]).is_empty());
+        }
+        assert_eq!(
+            framer.push(&packet[packet.len() - 1..]),
+            vec![Received::Status(vec![2, 0x10, 0x20])]
+        );
+
+        // Split across an ack
+        let mut framer = Framer::default();
+        assert_eq!(framer.push(&[0x30, packet[0]]), vec![Received::Ack(0x30)]);
+        assert_eq!(
+            framer.push(&packet[1..]),
+            vec![Received::Status(vec![2, 0x10, 0x20])]
+        );
+        assert_eq!(framer.skipped, 0);
+    }
+
+    #[test]
+    fn test_concatenated_packets() {
+        let mut bytes = status_packet(&[1, 2]);
+        bytes.push(0x40);
+        bytes.extend(status_packet(&[3]));
+        bytes.extend(status_packet(&[4, 5, 6]));
+
+        let mut framer = Framer::default();
+        assert_eq!(
+            framer.push(&bytes),
+            vec![
+                Received::Status(vec![1, 2]),
+                Received::Ack(0x40),
+                Received::Status(vec![3]),
+                Received::Status(vec![4, 5, 6]),
+            ]
+        );
+        assert_eq!(framer.skipped, 0);
+    }
+
+    #[test]
+    fn test_skip_garbage() {
+        let mut framer = Framer::default();
+
+        // A zero length, then a bad checksum, then a good packet
+        let mut bytes = vec![STATUS_MAGIC, 0];
+        bytes.extend([STATUS_MAGIC, 1, 7, 0]);
+        bytes.extend(status_packet(&[9]));
+
+        let received = framer.push(&bytes);
+        assert_eq!(received.last(), Some(&Received::Status(vec![9])));
+        assert_eq!(framer.skipped, 2);
+
+        // Too long to be a packet
+        let len = MAX_STATUS_LEN as u8 + 1;
+        assert_eq!(framer.push(&[STATUS_MAGIC, len]), vec![Received::Ack(len)]);
+        assert_eq!(framer.skipped, 3);
+    }
+}
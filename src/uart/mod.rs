@@ -1,73 +1,1186 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
 use anyhow::Error;
-use log::error;
-use tokio::sync::mpsc;
+use log::{debug, error, info, warn};
+use tokio::{
+    sync::{broadcast, mpsc, oneshot},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+
+pub mod framing;
+pub mod port;
+
+use self::{
+    framing::{Framer, Received},
+    port::SerialPort,
+};
+use crate::{
+    config::{RunMode, UartBackend, UartConfig},
+    health::Heartbeat,
+    metrics::Metrics,
+    shutdown::recv_until,
+};
+
+/// How often the line is read
+const READ_POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// How long to wait before restarting the UART task after it stops, doubling
+/// each time it fails again up to the max
+pub const UART_RESTART_MIN: Duration = Duration::from_millis(100);
+pub const UART_RESTART_MAX: Duration = Duration::from_secs(5);
+
+/// How long to wait before re-opening the device after it goes away, doubling
+/// each time it can't be opened up to the max
+pub const UART_REOPEN_MIN: Duration = Duration::from_millis(100);
+pub const UART_REOPEN_MAX: Duration = Duration::from_secs(5);
+
+/// Laser frames held while the device is gone. The oldest are dropped first.
+pub const UART_PENDING_LASER: usize = 16;
+
+/// Most messages taken off the channel before the next one is handled
+const QUEUE_BATCH: usize = 32;
 
-#[cfg(feature = "pi")]
-use rppal::uart::{Parity, Uart};
+/// How often the traffic is logged, if there was any
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
 
 pub enum UartMessage {
-    Laser(Vec<u8>),
+    /// A laser frame, with `written` told once it's on the line
+    Laser {
+        data: Vec<u8>,
+        written: oneshot::Sender<()>,
+    },
+    /// A laser frame whose header carries `sequence`. The Pico's status byte
+    /// is sent back on `ack` once it arrives.
+    LaserWithAck {
+        data: Vec<u8>,
+        sequence: u8,
+        ack: oneshot::Sender<u8>,
+    },
     DMX(Vec<u8>),
+    Stats(oneshot::Sender<UartStats>),
+    /// Everything for show frame `frame_id` has been sent. Once one of these
+    /// arrives, laser and DMX frames are held until the next one and then
+    /// written back to back.
+    Flush {
+        frame_id: u64,
+    },
+}
+
+/// What went out for one kind of message
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TrafficStats {
+    pub messages: u64,
+    pub bytes: u64,
+    pub errors: u64,
+    /// Longest a message took to write and drain
+    pub max_drain: Duration,
+}
+
+impl TrafficStats {
+    fn record(&mut self, bytes: usize, result: &Result<Duration, Error>) {
+        match result {
+            Ok(took) => {
+                self.messages += 1;
+                self.bytes += bytes as u64;
+                self.max_drain = self.max_drain.max(*took);
+            }
+            Err(_) => self.errors += 1,
+        }
+    }
+}
+
+impl std::fmt::Display for TrafficStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} messages, {} bytes, {} errors, {}ms max drain",
+            self.messages,
+            self.bytes,
+            self.errors,
+            self.max_drain.as_millis()
+        )
+    }
+}
+
+/// Sent to everything subscribed to the UART
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UartEvent {
+    /// The payload of a status packet from a laser Pico
+    LaserStatus(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UartStats {
+    /// Whether the device is open and the last write went through
+    pub healthy: bool,
+    pub laser: TrafficStats,
+    pub dmx: TrafficStats,
+    pub write_errors: u64,
+    /// Times the device came back after going away
+    pub reopens: u64,
+    /// DMX frames replaced by newer ones before they went out, along with
+    /// laser frames dropped while the device was gone
+    pub dropped: u64,
+    /// Messages held right now
+    pub pending: usize,
+    /// Bytes read that didn't frame, along with acks nobody was waiting for
+    pub skipped: u64,
+}
+
+/// Messages taken off the channel that haven't been handled yet, so the most
+/// urgent go first when the line is backed up.
+///
+/// - Laser frames go first, in the order they were sent.
+/// - DMX frames go next. Only the newest frame for each header byte, so each
+///   controller and universe, is kept, and they go in header order.
+/// - Anything else, like stats, goes last in the order it was sent.
+///
+/// Once show frames are being flushed, everything goes in the order it was
+/// sent instead, so each flush only writes its own frame. DMX frames are
+/// still only kept newest, but never across a flush.
+#[derive(Default)]
+struct Queue {
+    laser: VecDeque<UartMessage>,
+    dmx: BTreeMap<u8, Vec<u8>>,
+    other: VecDeque<UartMessage>,
+    /// Used instead of the lanes above while `in_order` is set
+    frames: VecDeque<UartMessage>,
+    in_order: bool,
+}
+
+impl Queue {
+    /// Returns whether it replaced a DMX frame
+    fn push(&mut self, message: UartMessage) -> bool {
+        if matches!(message, UartMessage::Flush { .. }) && !self.in_order {
+            // Whatever came before the first flush still goes first
+            while let Some(message) = self.pop_sorted() {
+                self.frames.push_back(message);
+            }
+            self.in_order = true;
+        }
+
+        if self.in_order {
+            return self.push_in_order(message);
+        }
+
+        match message {
+            UartMessage::Laser { .. } | UartMessage::LaserWithAck { .. } => {
+                self.laser.push_back(message)
+            }
+            UartMessage::DMX(data) => {
+                let header = data.first().copied().unwrap_or_default();
+                return self.dmx.insert(header, data).is_some();
+            }
+            UartMessage::Stats(_) | UartMessage::Flush { .. } => self.other.push_back(message),
+        }
+        false
+    }
+
+    /// Returns whether it replaced a DMX frame from the same show frame
+    fn push_in_order(&mut self, message: UartMessage) -> bool {
+        if let UartMessage::DMX(data) = &message {
+            let header = data.first().copied();
+            let older = self
+                .frames
+                .iter_mut()
+                .rev()
+                .take_while(|queued| !matches!(queued, UartMessage::Flush { .. }))
+                .find(|queued| match queued {
+                    UartMessage::DMX(older) => older.first().copied() == header,
+                    _ => false,
+                });
+            if let Some(older) = older {
+                *older = message;
+                return true;
+            }
+        }
+
+        self.frames.push_back(message);
+        false
+    }
+
+    fn pop(&mut self) -> Option<UartMessage> {
+        self.frames.pop_front().or_else(|| self.pop_sorted())
+    }
+
+    fn pop_sorted(&mut self) -> Option<UartMessage> {
+        self.laser
+            .pop_front()
+            .or_else(|| self.dmx.pop_first().map(|(_, data)| UartMessage::DMX(data)))
+            .or_else(|| self.other.pop_front())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+            && self.laser.is_empty()
+            && self.dmx.is_empty()
+            && self.other.is_empty()
+    }
+}
+
+/// Opens the device, and is called again every time it has to be re-opened
+pub type PortOpener = Box<dyn FnMut() -> Result<Box<dyn SerialPort>, Error> + Send>;
+
+/// The Pico acks a laser frame with a single byte, holding the sequence
+/// number from the frame's header in the high nibble and a status in the low
+/// nibble. A status of 0 means the frame was accepted.
+pub fn parse_ack(byte: u8) -> (u8, u8) {
+    (byte >> 4, byte & 0xF)
 }
 
 pub struct UartController {
-    #[cfg(feature = "pi")]
-    uart: Uart,
+    /// Empty while the device is gone
+    port: Option<Box<dyn SerialPort>>,
+    open: PortOpener,
+    pending_ack: Option<(u8, oneshot::Sender<u8>)>,
+    /// Laser frames waiting for the device to come back, oldest first
+    pending_laser: VecDeque<UartMessage>,
+    /// Only the newest DMX frame for each header byte, so each controller
+    /// and universe, is worth sending once it's back
+    pending_dmx: BTreeMap<u8, Vec<u8>>,
+    next_reopen: Instant,
+    reopen_backoff: Duration,
+    stats: UartStats,
+    chunk_size: Option<usize>,
+    chunk_pause: Duration,
+    dmx_break_us: Option<u64>,
+    framer: Framer,
+    queue: Queue,
+    /// Whether frame flushes are coming in, so messages are held until them
+    barrier: bool,
+    /// Held until the frame is flushed
+    frame: Vec<UartMessage>,
+    /// When the held frame gets written even without a flush
+    frame_deadline: Option<Instant>,
+    flush_timeout: Duration,
+    /// Status packets from the Picos go out here. Set this to a sender that
+    /// outlives the controller so subscribers survive a restart.
+    pub events: broadcast::Sender<UartEvent>,
+    /// Beats while the loop is running, for the health checks. Set this to
+    /// one that outlives the controller, like `events`.
+    pub heartbeat: Heartbeat,
+    pub metrics: Metrics,
 }
 
 impl UartController {
-    pub async fn init() -> Result<Self, Error> {
-        #[cfg(feature = "pi")]
-        let uart = Uart::with_path("/dev/serial0", 57_600, Parity::None, 8, 1)?;
+    /// Never fails, if the device isn't there yet it keeps being re-opened in
+    /// the background so everything else can start. Simulating loops it back
+    /// whatever the config says.
+    pub async fn init(config: &UartConfig, mode: RunMode) -> Self {
+        let flush_timeout = Duration::from_millis(config.flush_timeout_ms);
+        let (chunk_size, chunk_pause, dmx_break_us) = (
+            config.chunk_size,
+            Duration::from_micros(config.chunk_pause_us),
+            config.dmx_break_us,
+        );
+        let config = config.clone();
+        let mut controller = Self::with_opener(Box::new(move || open_port(&config, mode)));
+        controller.chunk_size = chunk_size;
+        controller.chunk_pause = chunk_pause;
+        controller.dmx_break_us = dmx_break_us;
+        controller.flush_timeout = flush_timeout;
+        controller
+    }
 
-        Ok(UartController {
-            #[cfg(feature = "pi")]
-            uart,
-        })
+    /// Use a port that can't be re-opened if it fails
+    pub fn with_port(port: Box<dyn SerialPort>) -> Self {
+        let mut port = Some(port);
+        Self::with_opener(Box::new(move || {
+            port.take()
+                .ok_or_else(|| Error::msg("the port can't be re-opened"))
+        }))
     }
 
-    pub fn send_data(&mut self, data: Vec<u8>) -> Result<(), Error> {
-        #[cfg(feature = "pi")]
-        {
-            // Send data in chunks of 8 bytes
-            for chunk in data.chunks(8) {
-                self.uart.write(chunk)?;
+    pub fn with_opener(mut open: PortOpener) -> Self {
+        let port = match open() {
+            Ok(port) => Some(port),
+            Err(e) => {
+                warn!("Couldn't open the UART, retrying in the background: {}", e);
+                None
             }
+        };
 
-            // Block until the data is sent
-            self.uart.drain()?;
+        UartController {
+            stats: UartStats {
+                healthy: port.is_some(),
+                laser: TrafficStats::default(),
+                dmx: TrafficStats::default(),
+                write_errors: 0,
+                reopens: 0,
+                dropped: 0,
+                pending: 0,
+                skipped: 0,
+            },
+            port,
+            open,
+            pending_ack: None,
+            pending_laser: VecDeque::new(),
+            pending_dmx: BTreeMap::new(),
+            next_reopen: Instant::now() + UART_REOPEN_MIN,
+            reopen_backoff: UART_REOPEN_MIN,
+            chunk_size: None,
+            chunk_pause: Duration::ZERO,
+            dmx_break_us: None,
+            framer: Framer::default(),
+            queue: Queue::default(),
+            barrier: false,
+            frame: Vec::new(),
+            frame_deadline: None,
+            flush_timeout: Duration::from_millis(100),
+            events: broadcast::channel(64).0,
+            heartbeat: Heartbeat::default(),
+            metrics: Metrics::default(),
         }
+    }
+
+    /// A failed write closes the device, and it's re-opened from `start`.
+    /// Returns how long the write took, including draining it.
+    pub fn send_data(&mut self, data: &[u8]) -> Result<Duration, Error> {
+        let port = self
+            .port
+            .as_mut()
+            .ok_or_else(|| Error::msg("the UART is closed"))?;
+
+        let started = std::time::Instant::now();
+        if let Err(e) = port::write_chunked(port.as_mut(), data, self.chunk_size, self.chunk_pause)
+        {
+            self.stats.write_errors += 1;
+            self.close(&e);
+            return Err(e);
+        }
+        let took = started.elapsed();
+
+        if !self.stats.healthy {
+            info!("The UART is writing again");
+            self.stats.healthy = true;
+        }
+
         // Print out the array of bytes that were sent in binary format
         // for byte in &data {
         //     print!("{:08b} ", byte);
         // }
         // println!();
 
-        Ok(())
+        Ok(took)
+    }
+
+    /// Start the frame with a break if the converter needs one
+    fn send_dmx(&mut self, data: &[u8]) -> Result<Duration, Error> {
+        if let (Some(break_us), Some(port)) = (self.dmx_break_us, self.port.as_mut()) {
+            if let Err(e) = port.send_break(break_us) {
+                self.stats.write_errors += 1;
+                self.close(&e);
+                return Err(e);
+            }
+        }
+
+        self.send_data(data)
+    }
+
+    /// Drop the device so it gets re-opened from `start`
+    fn close(&mut self, e: &Error) {
+        // Only log the first failure, a missing device fails every write
+        if self.stats.healthy {
+            error!("Lost the UART, re-opening it: {}", e);
+        }
+        self.stats.healthy = false;
+        self.port = None;
+        self.pending_ack = None;
+        self.next_reopen = Instant::now() + self.reopen_backoff;
+    }
+
+    pub fn stats(&self) -> UartStats {
+        UartStats {
+            pending: self.pending_laser.len() + self.pending_dmx.len(),
+            skipped: self.stats.skipped + self.framer.skipped,
+            ..self.stats.clone()
+        }
+    }
+
+    fn reopen(&mut self) {
+        match (self.open)() {
+            Ok(port) => {
+                info!("Re-opened the UART");
+                self.port = Some(port);
+                self.stats.reopens += 1;
+                self.reopen_backoff = UART_REOPEN_MIN;
+                self.flush();
+            }
+            Err(e) => {
+                warn!(
+                    "Couldn't re-open the UART, retrying in {}ms: {}",
+                    self.reopen_backoff.as_millis(),
+                    e
+                );
+                self.next_reopen = Instant::now() + self.reopen_backoff;
+                self.reopen_backoff = (self.reopen_backoff * 2).min(UART_REOPEN_MAX);
+            }
+        }
     }
 
-    pub async fn start(mut self, mut rx: mpsc::Receiver<UartMessage>) {
-        while let Some(message) = rx.recv().await {
-            match message {
-                UartMessage::Laser(data) => {
-                    // Print out the array of bytes that were sent in binary format
-                    for byte in &data {
-                        print!("{:08b} ", byte);
+    /// Hold a message until the device is back
+    fn hold(&mut self, message: UartMessage) {
+        match message {
+            UartMessage::DMX(data) => {
+                let header = data.first().copied().unwrap_or_default();
+                if self.pending_dmx.insert(header, data).is_some() {
+                    self.stats.dropped += 1;
+                }
+            }
+            UartMessage::Stats(_) | UartMessage::Flush { .. } => {}
+            laser => {
+                if self.pending_laser.len() == UART_PENDING_LASER {
+                    self.pending_laser.pop_front();
+                    self.stats.dropped += 1;
+                }
+                self.pending_laser.push_back(laser);
+            }
+        }
+    }
+
+    /// Send everything that was held, unless the device goes away again
+    fn flush(&mut self) {
+        for message in std::mem::take(&mut self.pending_laser) {
+            // Nobody is waiting on these anymore
+            let abandoned = match &message {
+                UartMessage::Laser { written, .. } => written.is_closed(),
+                UartMessage::LaserWithAck { ack, .. } => ack.is_closed(),
+                _ => false,
+            };
+            if !abandoned {
+                self.handle(message);
+            }
+        }
+
+        for (_, data) in std::mem::take(&mut self.pending_dmx) {
+            self.handle(UartMessage::DMX(data));
+        }
+    }
+
+    /// Read whatever is on the line. Acks go to whoever is waiting for them,
+    /// and acks for anything other than the latest frame are stale and get
+    /// dropped. Status packets go out to the subscribers.
+    fn poll_read(&mut self) {
+        let Some(port) = self.port.as_mut() else {
+            return;
+        };
+
+        let mut buf = [0; 64];
+        let read = match port.read(&mut buf, Duration::ZERO) {
+            Ok(read) => read,
+            Err(e) => {
+                self.close(&e);
+                return;
+            }
+        };
+
+        for received in self.framer.push(&buf[..read]) {
+            match received {
+                Received::Ack(byte) => {
+                    let (sequence, status) = parse_ack(byte);
+                    match self.pending_ack.take() {
+                        Some((expected, ack)) if expected == sequence => {
+                            let _ = ack.send(status);
+                        }
+                        pending => {
+                            warn!("Dropping stale laser ack {:#04X}", byte);
+                            self.stats.skipped += 1;
+                            self.pending_ack = pending;
+                        }
                     }
-                    println!();
+                }
+                Received::Status(payload) => {
+                    // Nobody might be listening
+                    let _ = self.events.send(UartEvent::LaserStatus(payload));
+                }
+            }
+        }
+
+        // The laser controller gave up on this one
+        if self
+            .pending_ack
+            .as_ref()
+            .is_some_and(|(_, ack)| ack.is_closed())
+        {
+            self.pending_ack = None;
+        }
+    }
 
-                    if let Err(e) = self.send_data(data) {
-                        error!("Failed to send projector data: {}", e);
+    pub async fn start(mut self, mut rx: mpsc::Receiver<UartMessage>, cancel: CancellationToken) {
+        let mut poll = tokio::time::interval(READ_POLL_INTERVAL);
+        poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let mut report =
+            tokio::time::interval_at(Instant::now() + REPORT_INTERVAL, REPORT_INTERVAL);
+        let mut reported = self.stats();
+        let mut heartbeat = Heartbeat::ticker();
+
+        loop {
+            // Take what's waiting so it can be sorted by priority
+            for _ in 0..QUEUE_BATCH {
+                let Ok(message) = rx.try_recv() else {
+                    break;
+                };
+                self.queue(message);
+            }
+            self.metrics
+                .set_queue_depth("uart", self.pending_laser.len() + self.pending_dmx.len());
+
+            let frame_deadline = self.frame_deadline.unwrap_or_else(Instant::now);
+            tokio::select! {
+                biased;
+
+                // DMX traffic keeps flowing while an ack is waited on
+                _ = poll.tick(), if self.port.is_some() => self.poll_read(),
+                _ = tokio::time::sleep_until(self.next_reopen), if self.port.is_none() => self.reopen(),
+                _ = report.tick() => {
+                    let stats = self.stats();
+                    if (&stats.laser, &stats.dmx) != (&reported.laser, &reported.dmx) {
+                        info!("UART laser traffic: {}", stats.laser);
+                        info!("UART DMX traffic: {}", stats.dmx);
                     }
+                    reported = stats;
                 }
-                UartMessage::DMX(data) => {
-                    if let Err(e) = self.send_data(data) {
-                        error!("Failed to send DMX data: {}", e);
+                _ = heartbeat.tick() => self.heartbeat.beat(),
+                _ = std::future::ready(()), if !self.queue.is_empty() => {
+                    if let Some(message) = self.queue.pop() {
+                        self.dispatch(message);
                     }
                 }
+                _ = tokio::time::sleep_until(frame_deadline), if self.frame_deadline.is_some() => {
+                    warn!("A show frame was never flushed, writing it anyway");
+                    self.barrier = false;
+                    self.queue.in_order = false;
+                    self.write_frame();
+                }
+                // Only stops once everything queued is handled
+                message = recv_until(&mut rx, &cancel) => {
+                    let Some(message) = message else {
+                        break;
+                    };
+                    self.queue(message);
+                }
             }
-            // // Add a 50ms delay before the next data is handled
-            // tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
         }
+
+        self.write_frame();
+    }
+
+    /// Hold laser and DMX frames for the frame barrier, if flushes are coming
+    /// in. Anything waiting on an ack goes straight out so the ack isn't held
+    /// up by the rest of the frame.
+    fn dispatch(&mut self, message: UartMessage) {
+        match message {
+            UartMessage::Flush { frame_id } => {
+                if !self.barrier {
+                    info!("Show frames are being flushed, holding UART writes for them");
+                    self.barrier = true;
+                }
+
+                let messages = self.frame.len();
+                let started = std::time::Instant::now();
+                self.write_frame();
+                debug!(
+                    "Flushed frame {} with {} messages in {}us",
+                    frame_id,
+                    messages,
+                    started.elapsed().as_micros()
+                );
+            }
+            UartMessage::Laser { .. } | UartMessage::DMX(_) if self.barrier => {
+                self.frame_deadline
+                    .get_or_insert_with(|| Instant::now() + self.flush_timeout);
+                self.frame.push(message);
+            }
+            message => self.handle(message),
+        }
+    }
+
+    /// Write everything held for the frame, in the order it came
+    fn write_frame(&mut self) {
+        self.frame_deadline = None;
+        for message in std::mem::take(&mut self.frame) {
+            self.handle(message);
+        }
+    }
+
+    /// Bytes that made it out, for the metrics
+    fn count_bytes(&self, kind: &str, bytes: usize, result: &Result<Duration, Error>) {
+        if result.is_ok() {
+            self.metrics
+                .uart_bytes
+                .with_label_values(&[kind])
+                .inc_by(bytes as u64);
+        }
+    }
+
+    fn queue(&mut self, message: UartMessage) {
+        if self.queue.push(message) {
+            self.stats.dropped += 1;
+        }
+    }
+
+    fn handle(&mut self, message: UartMessage) {
+        if self.port.is_none() {
+            if let UartMessage::Stats(reply) = message {
+                let _ = reply.send(self.stats());
+            } else {
+                self.hold(message);
+            }
+            return;
+        }
+
+        match message {
+            UartMessage::Laser { data, written } => {
+                // Print out the array of bytes that were sent in binary format
+                for byte in &data {
+                    print!("{:08b} ", byte);
+                }
+                println!();
+
+                let result = self.send_data(&data);
+                self.stats.laser.record(data.len(), &result);
+                self.count_bytes("laser", data.len(), &result);
+                match result {
+                    Ok(_) => {
+                        let _ = written.send(());
+                    }
+                    Err(_) => self.hold(UartMessage::Laser { data, written }),
+                }
+            }
+            UartMessage::LaserWithAck {
+                data,
+                sequence,
+                ack,
+            } => {
+                // Anything left in the buffer belongs to an older frame
+                self.poll_read();
+                self.pending_ack = None;
+
+                let result = self.send_data(&data);
+                self.stats.laser.record(data.len(), &result);
+                self.count_bytes("laser", data.len(), &result);
+                match result {
+                    Ok(_) => self.pending_ack = Some((sequence, ack)),
+                    Err(_) => self.hold(UartMessage::LaserWithAck {
+                        data,
+                        sequence,
+                        ack,
+                    }),
+                }
+            }
+            UartMessage::DMX(data) => {
+                let result = self.send_dmx(&data);
+                self.stats.dmx.record(data.len(), &result);
+                self.count_bytes("dmx", data.len(), &result);
+                if result.is_err() {
+                    self.hold(UartMessage::DMX(data));
+                }
+            }
+            UartMessage::Stats(reply) => {
+                let _ = reply.send(self.stats());
+            }
+            UartMessage::Flush { .. } => {}
+        }
+        // // Add a 50ms delay before the next data is handled
+        // tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    }
+}
+
+/// The backend picked in the config, or the Pi's UART on the Pi and nothing
+/// everywhere else. Simulating always loops back, even on the Pi.
+pub fn backend(config: &UartConfig, mode: RunMode) -> UartBackend {
+    if mode.is_simulated() {
+        return UartBackend::Loopback;
+    }
+
+    config.backend.unwrap_or(match cfg!(feature = "pi") {
+        true => UartBackend::Pi,
+        false => UartBackend::Null,
+    })
+}
+
+pub fn open_port(config: &UartConfig, mode: RunMode) -> Result<Box<dyn SerialPort>, Error> {
+    match backend(config, mode) {
+        #[cfg(feature = "pi")]
+        UartBackend::Pi => Ok(Box::new(port::PiPort::open(config)?)),
+        #[cfg(not(feature = "pi"))]
+        UartBackend::Pi => Err(Error::msg("the Pi's UART needs the pi feature")),
+        #[cfg(feature = "serial")]
+        UartBackend::Serial => Ok(Box::new(port::UsbSerialPort::open(config)?)),
+        #[cfg(not(feature = "serial"))]
+        UartBackend::Serial => Err(Error::msg("USB serial ports need the serial feature")),
+        UartBackend::Loopback => Ok(Box::new(port::LoopbackPort::default())),
+        UartBackend::Null => Ok(Box::new(port::NullPort)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// A device that can be unplugged and plugged back in
+    #[derive(Default)]
+    struct Line {
+        plugged_in: bool,
+        writes: Vec<Vec<u8>>,
+        /// How many writes had gone out when each break was sent
+        breaks: Vec<usize>,
+    }
+
+    struct FakePort {
+        line: Arc<Mutex<Line>>,
+        unsent: Vec<u8>,
+    }
+
+    impl SerialPort for FakePort {
+        fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+            if !self.line.lock().unwrap().plugged_in {
+                return Err(Error::msg("device unplugged"));
+            }
+            self.unsent.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn drain(&mut self) -> Result<(), Error> {
+            let data = std::mem::take(&mut self.unsent);
+            self.line.lock().unwrap().writes.push(data);
+            Ok(())
+        }
+
+        fn read(&mut self, _buf: &mut [u8], _timeout: Duration) -> Result<usize, Error> {
+            Ok(0)
+        }
+
+        fn send_break(&mut self, _duration_us: u64) -> Result<(), Error> {
+            let mut line = self.line.lock().unwrap();
+            let written = line.writes.len();
+            line.breaks.push(written);
+            Ok(())
+        }
+    }
+
+    fn spawn(line: &Arc<Mutex<Line>>) -> mpsc::Sender<UartMessage> {
+        spawn_with(line, None)
+    }
+
+    fn spawn_with(line: &Arc<Mutex<Line>>, dmx_break_us: Option<u64>) -> mpsc::Sender<UartMessage> {
+        let line = line.clone();
+        let mut controller =
+            UartController::with_opener(Box::new(move || match line.lock().unwrap().plugged_in {
+                true => Ok(Box::new(FakePort {
+                    line: line.clone(),
+                    unsent: Vec::new(),
+                }) as Box<dyn SerialPort>),
+                false => Err(Error::msg("no such device")),
+            }));
+        controller.dmx_break_us = dmx_break_us;
+
+        let (uart_tx, uart_rx) = mpsc::channel(100);
+        tokio::spawn(controller.start(uart_rx, CancellationToken::new()));
+        uart_tx
+    }
+
+    async fn stats(uart_tx: &mpsc::Sender<UartMessage>) -> UartStats {
+        let (stats_tx, stats_rx) = oneshot::channel();
+        uart_tx.send(UartMessage::Stats(stats_tx)).await.unwrap();
+        stats_rx.await.unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reopen_after_write_error() {
+        let line = Arc::new(Mutex::new(Line {
+            plugged_in: true,
+            ..Default::default()
+        }));
+        let uart_tx = spawn(&line);
+
+        uart_tx.send(UartMessage::DMX(vec![0xA0, 1])).await.unwrap();
+        assert!(stats(&uart_tx).await.healthy);
+        line.lock().unwrap().plugged_in = false;
+
+        // Newest wins for DMX
+        for value in 2..5 {
+            uart_tx
+                .send(UartMessage::DMX(vec![0xA0, value]))
+                .await
+                .unwrap();
+        }
+
+        // The oldest laser frames go first
+        let mut written = Vec::new();
+        for frame in 0..UART_PENDING_LASER as u8 + 2 {
+            let (written_tx, written_rx) = oneshot::channel();
+            uart_tx
+                .send(UartMessage::Laser {
+                    data: vec![0x10, frame],
+                    written: written_tx,
+                })
+                .await
+                .unwrap();
+            written.push(written_rx);
+        }
+
+        let degraded = stats(&uart_tx).await;
+        assert!(!degraded.healthy);
+        assert_eq!(degraded.write_errors, 1);
+        assert_eq!(degraded.dropped, 4);
+        assert_eq!(degraded.pending, UART_PENDING_LASER + 1);
+
+        // Still gone after a few tries
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(stats(&uart_tx).await.reopens, 0);
+
+        line.lock().unwrap().plugged_in = true;
+        tokio::time::sleep(UART_REOPEN_MAX).await;
+
+        let recovered = stats(&uart_tx).await;
+        assert!(recovered.healthy);
+        assert_eq!(recovered.reopens, 1);
+        assert_eq!(recovered.pending, 0);
+
+        let mut expected = vec![vec![0xA0, 1]];
+        expected.extend((2..UART_PENDING_LASER as u8 + 2).map(|frame| vec![0x10, frame]));
+        expected.push(vec![0xA0, 4]);
+        assert_eq!(line.lock().unwrap().writes, expected);
+
+        for (frame, written_rx) in written.into_iter().enumerate() {
+            assert_eq!(written_rx.await.is_ok(), frame >= 2);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reopen_keeps_each_universe() {
+        let line = Arc::new(Mutex::new(Line::default()));
+        let uart_tx = spawn(&line);
+
+        // The newest frame for each universe is held, not just the newest one
+        for data in [vec![0xB0, 1], vec![0xA0, 1], vec![0xA0, 2]] {
+            uart_tx.send(UartMessage::DMX(data)).await.unwrap();
+        }
+        let degraded = stats(&uart_tx).await;
+        assert_eq!(degraded.pending, 2);
+        assert_eq!(degraded.dropped, 1);
+
+        // And they go out in header order once it's back
+        line.lock().unwrap().plugged_in = true;
+        tokio::time::sleep(UART_REOPEN_MAX).await;
+        assert_eq!(
+            line.lock().unwrap().writes,
+            vec![vec![0xA0, 2], vec![0xB0, 1]]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_init_without_device() {
+        let line = Arc::new(Mutex::new(Line::default()));
+        let uart_tx = spawn(&line);
+        assert!(!stats(&uart_tx).await.healthy);
+
+        uart_tx.send(UartMessage::DMX(vec![0xA0, 1])).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(line.lock().unwrap().writes.is_empty());
+
+        line.lock().unwrap().plugged_in = true;
+        tokio::time::sleep(UART_REOPEN_MAX).await;
+        assert_eq!(line.lock().unwrap().writes, vec![vec![0xA0, 1]]);
+        assert!(stats(&uart_tx).await.healthy);
+    }
+
+    #[test]
+    fn test_backend_from_config() {
+        let config = |backend| UartConfig {
+            backend,
+            ..Default::default()
+        };
+
+        let hardware = RunMode::Hardware;
+        let mut port = open_port(&config(Some(UartBackend::Loopback)), hardware).unwrap();
+        port::write_chunked(port.as_mut(), &[1, 2, 3], Some(2), Duration::ZERO).unwrap();
+        let mut buf = [0; 3];
+        assert_eq!(port.read(&mut buf, Duration::ZERO).unwrap(), 3);
+
+        assert!(open_port(&config(None), hardware).is_ok());
+        assert!(open_port(&config(Some(UartBackend::Null)), hardware).is_ok());
+        #[cfg(not(feature = "pi"))]
+        assert!(open_port(&config(Some(UartBackend::Pi)), hardware).is_err());
+
+        // Simulating loops back whatever's picked
+        for picked in [None, Some(UartBackend::Pi), Some(UartBackend::Serial)] {
+            assert_eq!(
+                backend(&config(picked), RunMode::Simulate),
+                UartBackend::Loopback
+            );
+            assert!(open_port(&config(picked), RunMode::Simulate).is_ok());
+        }
+        assert_eq!(
+            backend(&config(Some(UartBackend::Serial)), hardware),
+            UartBackend::Serial
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_laser_status() {
+        let controller = UartController::with_port(Box::new(port::LoopbackPort::default()));
+        let mut events = controller.events.subscribe();
+        let (uart_tx, uart_rx) = mpsc::channel(10);
+        tokio::spawn(controller.start(uart_rx, CancellationToken::new()));
+
+        // Whatever's written comes back, so write what a Pico would send
+        let mut data = vec![0x55];
+        data.extend(framing::status_packet(&[3, 1]));
+        uart_tx.send(UartMessage::DMX(data)).await.unwrap();
+
+        assert_eq!(
+            events.recv().await.unwrap(),
+            UartEvent::LaserStatus(vec![3, 1])
+        );
+        // Nobody was waiting for the ack
+        assert_eq!(stats(&uart_tx).await.skipped, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_laser_first() {
+        let line = Arc::new(Mutex::new(Line {
+            plugged_in: true,
+            ..Default::default()
+        }));
+
+        // Everything is waiting before the controller gets to any of it, like
+        // when a slow write held it up
+        let (uart_tx, uart_rx) = mpsc::channel(100);
+        let mut written = Vec::new();
+        for i in 0..3u8 {
+            uart_tx.send(UartMessage::DMX(vec![0xA0, i])).await.unwrap();
+            uart_tx.send(UartMessage::DMX(vec![0xB0, i])).await.unwrap();
+
+            let (written_tx, written_rx) = oneshot::channel();
+            uart_tx
+                .send(UartMessage::Laser {
+                    data: vec![0x10, i],
+                    written: written_tx,
+                })
+                .await
+                .unwrap();
+            written.push(written_rx);
+        }
+
+        let opener_line = line.clone();
+        let controller = UartController::with_opener(Box::new(move || {
+            Ok(Box::new(FakePort {
+                line: opener_line.clone(),
+                unsent: Vec::new(),
+            }) as Box<dyn SerialPort>)
+        }));
+        tokio::spawn(controller.start(uart_rx, CancellationToken::new()));
+
+        for written_rx in written {
+            written_rx.await.unwrap();
+        }
+        assert_eq!(stats(&uart_tx).await.dropped, 4);
+        assert_eq!(
+            line.lock().unwrap().writes,
+            vec![
+                vec![0x10, 0],
+                vec![0x10, 1],
+                vec![0x10, 2],
+                vec![0xA0, 2],
+                vec![0xB0, 2],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_traffic_stats() {
+        let controller = UartController::with_port(Box::new(port::LoopbackPort::default()));
+        let (uart_tx, uart_rx) = mpsc::channel(10);
+        tokio::spawn(controller.start(uart_rx, CancellationToken::new()));
+
+        for len in [4, 8] {
+            let (written_tx, written_rx) = oneshot::channel();
+            uart_tx
+                .send(UartMessage::Laser {
+                    data: vec![0x10; len],
+                    written: written_tx,
+                })
+                .await
+                .unwrap();
+            written_rx.await.unwrap();
+        }
+        uart_tx
+            .send(UartMessage::DMX(vec![0xA0; 513]))
+            .await
+            .unwrap();
+
+        let stats = stats(&uart_tx).await;
+        assert_eq!((stats.laser.messages, stats.laser.bytes), (2, 12));
+        assert_eq!((stats.dmx.messages, stats.dmx.bytes), (1, 513));
+        assert_eq!(stats.laser.errors + stats.dmx.errors, 0);
+    }
+
+    #[tokio::test]
+    async fn test_dmx_break() {
+        let line = Arc::new(Mutex::new(Line {
+            plugged_in: true,
+            ..Default::default()
+        }));
+        let uart_tx = spawn_with(&line, Some(100));
+
+        let (written_tx, written_rx) = oneshot::channel();
+        uart_tx
+            .send(UartMessage::Laser {
+                data: vec![0x10, 0],
+                written: written_tx,
+            })
+            .await
+            .unwrap();
+        written_rx.await.unwrap();
+        for header in [0xA0, 0xB0] {
+            uart_tx
+                .send(UartMessage::DMX(vec![header, 1]))
+                .await
+                .unwrap();
+        }
+        stats(&uart_tx).await;
+
+        // Right before each DMX frame, and never before a laser frame
+        assert_eq!(line.lock().unwrap().breaks, vec![1, 2]);
+    }
+
+    fn laser(frame: u8) -> UartMessage {
+        UartMessage::Laser {
+            data: vec![0x10, frame],
+            written: oneshot::channel().0,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_frame_flush() {
+        let line = Arc::new(Mutex::new(Line {
+            plugged_in: true,
+            ..Default::default()
+        }));
+        let uart_tx = spawn(&line);
+
+        // Nothing is held until the first flush
+        uart_tx.send(UartMessage::DMX(vec![0xA0, 0])).await.unwrap();
+        uart_tx
+            .send(UartMessage::Flush { frame_id: 0 })
+            .await
+            .unwrap();
+        stats(&uart_tx).await;
+        assert_eq!(line.lock().unwrap().writes.len(), 1);
+
+        for message in [
+            UartMessage::DMX(vec![0xB0, 1]),
+            laser(1),
+            UartMessage::DMX(vec![0xA0, 1]),
+        ] {
+            uart_tx.send(message).await.unwrap();
+            stats(&uart_tx).await;
+        }
+        assert_eq!(line.lock().unwrap().writes.len(), 1);
+
+        uart_tx
+            .send(UartMessage::Flush { frame_id: 1 })
+            .await
+            .unwrap();
+        stats(&uart_tx).await;
+        assert_eq!(
+            line.lock().unwrap().writes,
+            vec![vec![0xA0, 0], vec![0xB0, 1], vec![0x10, 1], vec![0xA0, 1],]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_missing_flush_times_out() {
+        let line = Arc::new(Mutex::new(Line {
+            plugged_in: true,
+            ..Default::default()
+        }));
+        let uart_tx = spawn(&line);
+
+        uart_tx
+            .send(UartMessage::Flush { frame_id: 0 })
+            .await
+            .unwrap();
+        stats(&uart_tx).await;
+        uart_tx.send(laser(1)).await.unwrap();
+        uart_tx.send(UartMessage::DMX(vec![0xA0, 1])).await.unwrap();
+        stats(&uart_tx).await;
+        assert!(line.lock().unwrap().writes.is_empty());
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(
+            line.lock().unwrap().writes,
+            vec![vec![0x10, 1], vec![0xA0, 1]]
+        );
+
+        // Back to writing straight away until the next flush
+        uart_tx.send(UartMessage::DMX(vec![0xA0, 2])).await.unwrap();
+        stats(&uart_tx).await;
+        assert_eq!(line.lock().unwrap().writes.len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_only_writes_its_frame() {
+        let line = Arc::new(Mutex::new(Line {
+            plugged_in: true,
+            ..Default::default()
+        }));
+
+        // Two frames are waiting before the controller gets to any of it,
+        // like when a slow write held it up
+        let (uart_tx, uart_rx) = mpsc::channel(100);
+        for frame in 0..=2 {
+            if frame > 0 {
+                uart_tx.send(laser(frame)).await.unwrap();
+                uart_tx
+                    .send(UartMessage::DMX(vec![0xA0, frame]))
+                    .await
+                    .unwrap();
+            }
+            // Frame 2's flush hasn't come yet
+            if frame < 2 {
+                uart_tx
+                    .send(UartMessage::Flush {
+                        frame_id: frame as u64,
+                    })
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let opener_line = line.clone();
+        let controller = UartController::with_opener(Box::new(move || {
+            Ok(Box::new(FakePort {
+                line: opener_line.clone(),
+                unsent: Vec::new(),
+            }) as Box<dyn SerialPort>)
+        }));
+        tokio::spawn(controller.start(uart_rx, CancellationToken::new()));
+
+        // Frame 1's DMX isn't replaced by frame 2's, and its flush doesn't
+        // write frame 2
+        assert_eq!(stats(&uart_tx).await.dropped, 0);
+        assert_eq!(
+            line.lock().unwrap().writes,
+            vec![vec![0x10, 1], vec![0xA0, 1]]
+        );
+
+        uart_tx
+            .send(UartMessage::Flush { frame_id: 2 })
+            .await
+            .unwrap();
+        stats(&uart_tx).await;
+        assert_eq!(
+            line.lock().unwrap().writes,
+            vec![vec![0x10, 1], vec![0xA0, 1], vec![0x10, 2], vec![0xA0, 2]]
+        );
     }
 }
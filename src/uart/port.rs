@@ -0,0 +1,392 @@
+use std::{collections::VecDeque, time::Duration};
+
+use anyhow::Error;
+
+#[cfg(feature = "pi")]
+use rppal::uart::{Parity, Uart};
+
+#[cfg(any(feature = "pi", feature = "serial"))]
+use crate::config::UartConfig;
+
+/// The serial line to the Picos
+pub trait SerialPort: Send {
+    /// Hand bytes to the port. They might not be on the line until `drain`
+    /// returns.
+    fn write(&mut self, data: &[u8]) -> Result<(), Error>;
+
+    /// Block until everything written is on the line
+    fn drain(&mut self) -> Result<(), Error>;
+
+    /// Read whatever bytes have arrived, waiting up to `timeout` if there
+    /// aren't any yet. A zero timeout never blocks.
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error>;
+
+    /// Hold the line low for `duration_us`, then let it go back high for the
+    /// mark after the break
+    fn send_break(&mut self, duration_us: u64) -> Result<(), Error>;
+}
+
+/// Write `data` to the port `chunk_size` bytes at a time, or all at once
+/// without a chunk size, then wait for all of it to go out. `pause` is slept
+/// between chunks, which blocks the thread, so it's only for devices that
+/// need the pacing.
+pub fn write_chunked(
+    port: &mut dyn SerialPort,
+    data: &[u8],
+    chunk_size: Option<usize>,
+    pause: Duration,
+) -> Result<(), Error> {
+    let chunk_size = chunk_size.unwrap_or(data.len()).max(1);
+    for (i, chunk) in data.chunks(chunk_size).enumerate() {
+        if i > 0 && !pause.is_zero() {
+            std::thread::sleep(pause);
+        }
+        port.write(chunk)?;
+    }
+
+    port.drain()
+}
+
+/// The Pi's own UART
+#[cfg(feature = "pi")]
+pub struct PiPort {
+    uart: Uart,
+    baud: u32,
+    read_timeout: Duration,
+}
+
+#[cfg(feature = "pi")]
+impl PiPort {
+    pub fn open(config: &UartConfig) -> Result<Self, Error> {
+        let mut uart = Uart::with_path(&config.device, config.baud, Parity::None, 8, 1)?;
+        uart.set_read_mode(0, Duration::ZERO)?;
+
+        Ok(PiPort {
+            uart,
+            baud: config.baud,
+            read_timeout: Duration::ZERO,
+        })
+    }
+}
+
+#[cfg(feature = "pi")]
+impl SerialPort for PiPort {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.uart.write(data)?;
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<(), Error> {
+        Ok(self.uart.drain()?)
+    }
+
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        // Only touch the read mode when the timeout changes
+        if timeout != self.read_timeout {
+            self.uart.set_read_mode(0, timeout)?;
+            self.read_timeout = timeout;
+        }
+
+        Ok(self.uart.read(buf)?)
+    }
+
+    /// The UART can't hold a break itself, so a zero byte is sent slow
+    /// enough that its start and data bits last `duration_us`. The stop bit
+    /// is the mark after the break.
+    fn send_break(&mut self, duration_us: u64) -> Result<(), Error> {
+        let baud = (9_000_000 / duration_us.max(1)).max(1) as u32;
+        self.uart.drain()?;
+        self.uart.set_baud_rate(baud)?;
+        self.uart.write(&[0])?;
+        self.uart.drain()?;
+        self.uart.set_baud_rate(self.baud)?;
+
+        Ok(())
+    }
+}
+
+/// A USB serial adapter, for driving the Picos from a laptop
+#[cfg(feature = "serial")]
+pub struct UsbSerialPort {
+    port: Box<dyn serialport::SerialPort>,
+}
+
+#[cfg(feature = "serial")]
+impl UsbSerialPort {
+    pub fn open(config: &UartConfig) -> Result<Self, Error> {
+        let port = serialport::new(&config.device, config.baud)
+            .timeout(Duration::ZERO)
+            .open()?;
+
+        Ok(UsbSerialPort { port })
+    }
+}
+
+#[cfg(feature = "serial")]
+impl SerialPort for UsbSerialPort {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        Ok(std::io::Write::write_all(&mut self.port, data)?)
+    }
+
+    fn drain(&mut self) -> Result<(), Error> {
+        Ok(std::io::Write::flush(&mut self.port)?)
+    }
+
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        self.port.set_timeout(timeout)?;
+        match std::io::Read::read(&mut self.port, buf) {
+            Ok(read) => Ok(read),
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn send_break(&mut self, duration_us: u64) -> Result<(), Error> {
+        std::io::Write::flush(&mut self.port)?;
+        self.port.set_break()?;
+        std::thread::sleep(Duration::from_micros(duration_us));
+        self.port.clear_break()?;
+
+        Ok(())
+    }
+}
+
+/// Stands in for the UART on development machines. Nothing is ever read back.
+pub struct NullPort;
+
+impl SerialPort for NullPort {
+    fn write(&mut self, _data: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn read(&mut self, _buf: &mut [u8], _timeout: Duration) -> Result<usize, Error> {
+        Ok(0)
+    }
+
+    fn send_break(&mut self, _duration_us: u64) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Everything written comes back to be read once it's drained, like a wire
+/// from TX to RX. Nothing else can write to it, so reads never wait.
+#[derive(Default)]
+pub struct LoopbackPort {
+    /// How many times `write` was called
+    pub writes: usize,
+    /// Every break sent, in microseconds
+    pub breaks: Vec<u64>,
+    /// Written but not drained yet
+    unsent: Vec<u8>,
+    line: VecDeque<u8>,
+}
+
+impl SerialPort for LoopbackPort {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.writes += 1;
+        self.unsent.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<(), Error> {
+        self.line.extend(self.unsent.drain(..));
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8], _timeout: Duration) -> Result<usize, Error> {
+        let read = buf.len().min(self.line.len());
+        for (byte, sent) in buf.iter_mut().zip(self.line.drain(..read)) {
+            *byte = sent;
+        }
+
+        Ok(read)
+    }
+
+    fn send_break(&mut self, duration_us: u64) -> Result<(), Error> {
+        self.breaks.push(duration_us);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+
+    /// Acts like a line of Picos: every laser frame written gets an ok ack
+    /// carrying its sequence number, except every `drop_every`th ack which is
+    /// lost. DMX frames are never acked. Each drain is kept as one write.
+    pub struct MockPort {
+        pub writes: Arc<Mutex<Vec<Vec<u8>>>>,
+        pub drop_every: Option<usize>,
+        unsent: Vec<u8>,
+        acks: VecDeque<u8>,
+        acks_until_drop: Option<usize>,
+    }
+
+    impl MockPort {
+        pub fn new(drop_every: Option<usize>) -> Self {
+            MockPort {
+                writes: Arc::new(Mutex::new(Vec::new())),
+                drop_every,
+                unsent: Vec::new(),
+                acks: VecDeque::new(),
+                acks_until_drop: drop_every,
+            }
+        }
+    }
+
+    impl SerialPort for MockPort {
+        fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+            self.unsent.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn drain(&mut self) -> Result<(), Error> {
+            let data = std::mem::take(&mut self.unsent);
+            self.writes.lock().unwrap().push(data.clone());
+
+            // DMX controllers sit at 0xA-0xE
+            let id = data[0] >> 4;
+            if (0xA..=0xE).contains(&id) {
+                return Ok(());
+            }
+
+            if let Some(until_drop) = self.acks_until_drop.as_mut() {
+                *until_drop -= 1;
+                if *until_drop == 0 {
+                    self.acks_until_drop = self.drop_every;
+                    return Ok(());
+                }
+            }
+
+            let sequence = (data[3] >> 1) & 0xF;
+            self.acks.push_back(sequence << 4);
+
+            Ok(())
+        }
+
+        fn read(&mut self, buf: &mut [u8], _timeout: Duration) -> Result<usize, Error> {
+            let mut read = 0;
+            while read < buf.len() {
+                let Some(ack) = self.acks.pop_front() else {
+                    break;
+                };
+                buf[read] = ack;
+                read += 1;
+            }
+
+            Ok(read)
+        }
+
+        fn send_break(&mut self, _duration_us: u64) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keeps the size of every write
+    #[derive(Default)]
+    struct ChunkPort {
+        writes: Vec<usize>,
+        drains: usize,
+    }
+
+    impl SerialPort for ChunkPort {
+        fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+            self.writes.push(data.len());
+            Ok(())
+        }
+
+        fn drain(&mut self) -> Result<(), Error> {
+            self.drains += 1;
+            Ok(())
+        }
+
+        fn read(&mut self, _buf: &mut [u8], _timeout: Duration) -> Result<usize, Error> {
+            Ok(0)
+        }
+
+        fn send_break(&mut self, _duration_us: u64) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_chunk_boundaries() {
+        for (len, chunks) in [
+            (0, vec![]),
+            (1, vec![1]),
+            (7, vec![7]),
+            (8, vec![8]),
+            (9, vec![8, 1]),
+            (16, vec![8, 8]),
+            (20, vec![8, 8, 4]),
+        ] {
+            let mut port = ChunkPort::default();
+            write_chunked(&mut port, &vec![0; len], Some(8), Duration::ZERO).unwrap();
+            assert_eq!(port.writes, chunks, "{} bytes", len);
+            assert_eq!(port.drains, 1);
+        }
+    }
+
+    #[test]
+    fn test_loopback() {
+        let mut port = LoopbackPort::default();
+        write_chunked(&mut port, &[1, 2, 3, 4, 5], Some(2), Duration::ZERO).unwrap();
+        port.write(&[6]).unwrap();
+
+        // Only what was drained has made it around
+        let mut buf = [0; 4];
+        assert_eq!(port.read(&mut buf, Duration::ZERO).unwrap(), 4);
+        assert_eq!(buf, [1, 2, 3, 4]);
+        assert_eq!(port.read(&mut buf, Duration::ZERO).unwrap(), 1);
+        assert_eq!(buf[0], 5);
+
+        port.drain().unwrap();
+        assert_eq!(port.read(&mut buf, Duration::ZERO).unwrap(), 1);
+        assert_eq!(buf[0], 6);
+        assert_eq!(port.read(&mut buf, Duration::ZERO).unwrap(), 0);
+
+        port.send_break(100).unwrap();
+        assert_eq!(port.breaks, vec![100]);
+    }
+
+    #[test]
+    fn test_writes_per_payload() {
+        let payload = vec![0xAA; 512];
+        for (chunk_size, writes) in [
+            (None, 1),
+            (Some(1), 512),
+            (Some(8), 64),
+            (Some(100), 6),
+            (Some(512), 1),
+            (Some(1000), 1),
+        ] {
+            let mut port = LoopbackPort::default();
+            write_chunked(&mut port, &payload, chunk_size, Duration::ZERO).unwrap();
+            assert_eq!(port.writes, writes, "chunks of {:?}", chunk_size);
+
+            let mut buf = [0; 1024];
+            assert_eq!(port.read(&mut buf, Duration::ZERO).unwrap(), 512);
+        }
+
+        // Pauses between the two chunks
+        let mut port = LoopbackPort::default();
+        let started = std::time::Instant::now();
+        write_chunked(&mut port, &payload, Some(256), Duration::from_millis(20)).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+}